@@ -1,6 +1,6 @@
 /// AST nodes for bc language
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     /// Numeric literal (stored as string for arbitrary precision)
     Number(String),
@@ -69,7 +69,7 @@ pub enum Expr {
     Read,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Stmt {
     /// Expression statement (value is printed if not assignment)
     Expr(Expr),
@@ -124,35 +124,36 @@ pub enum Stmt {
     Empty,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum PrintItem {
     Expr(Expr),
     String(String),
 }
 
-#[derive(Debug, Clone)]
-#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct AutoVar {
     pub name: String,
     pub is_array: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Function {
     pub name: String,
+    /// Position of the `define` keyword
+    pub line: usize,
+    pub col: usize,
     pub params: Vec<FuncParam>,
     pub auto_vars: Vec<AutoVar>,
     pub body: Vec<Stmt>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct FuncParam {
     pub name: String,
-    #[allow(dead_code)]
     pub is_array: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Program {
     pub functions: Vec<Function>,
     pub statements: Vec<Stmt>,
@@ -0,0 +1,205 @@
+//! BCM: compiled bytecode in a file, for VMs other than the Z80 runtime
+//!
+//! All integers are little-endian. The file starts with a 40-byte header:
+//!
+//! | Offset | Size | Field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 4    | magic `BCM\0`                           |
+//! | 4      | 2    | format version (`VERSION`)              |
+//! | 6      | 2    | number of sections (4)                  |
+//! | 8      | 32   | per section: offset u32, length u32     |
+//!
+//! The sections, in table order:
+//!
+//! 1. Bytecode: the raw `Op` stream; jump targets and function offsets are
+//!    relative to its first byte.
+//! 2. Numbers: u16 count, then each constant in `BcNum::to_packed` form
+//!    (sign byte, digit count, scale, 25 bytes of packed BCD), read back
+//!    with `BcNum::from_packed`. `LoadNum n` pushes number n.
+//! 3. Strings: u16 count, then each as u16 byte length and UTF-8 bytes.
+//! 4. Functions: u16 count, then each as u8 name length, the name, u16
+//!    bytecode offset, u8 first variable slot, u8 parameter count and u8
+//!    auto count. `Call n` calls function n.
+
+use crate::bytecode::{BcNum, CompiledFunction, CompiledModule, PACKED_DIGIT_BYTES};
+
+pub const MAGIC: &[u8; 4] = b"BCM\0";
+/// Bumped whenever the layout changes
+pub const VERSION: u16 = 1;
+
+const SECTIONS: usize = 4;
+const HEADER_SIZE: usize = 8 + SECTIONS * 8;
+const PACKED_NUM_SIZE: usize = 3 + PACKED_DIGIT_BYTES;
+
+/// Serialize the module's bytecode, constants, strings and function table.
+/// Err names a count, length or offset too large for its field.
+pub fn write(module: &CompiledModule) -> Result<Vec<u8>, String> {
+    let mut numbers = field::<u16>(module.numbers.len(), "number count")?.to_le_bytes().to_vec();
+    for num in &module.numbers {
+        numbers.extend(num.to_packed());
+    }
+
+    let mut strings = field::<u16>(module.strings.len(), "string count")?.to_le_bytes().to_vec();
+    for s in &module.strings {
+        strings.extend(field::<u16>(s.len(), "string length")?.to_le_bytes());
+        strings.extend(s.as_bytes());
+    }
+
+    let mut functions = field::<u16>(module.functions.len(), "function count")?.to_le_bytes().to_vec();
+    for func in &module.functions {
+        functions.push(field(func.name.len(), "function name length")?);
+        functions.extend(func.name.as_bytes());
+        functions.extend(field::<u16>(func.bytecode_offset, "function offset")?.to_le_bytes());
+        functions.push(func.first_slot);
+        functions.push(field(func.param_count, "parameter count")?);
+        functions.push(field(func.local_count, "auto count")?);
+    }
+
+    let mut out = MAGIC.to_vec();
+    out.extend(VERSION.to_le_bytes());
+    out.extend((SECTIONS as u16).to_le_bytes());
+    let sections = [&module.bytecode, &numbers, &strings, &functions];
+    let mut offset = HEADER_SIZE;
+    for section in sections {
+        out.extend(field::<u32>(offset, "section offset")?.to_le_bytes());
+        out.extend(field::<u32>(section.len(), "section length")?.to_le_bytes());
+        offset += section.len();
+    }
+    for section in sections {
+        out.extend(section.iter());
+    }
+    Ok(out)
+}
+
+/// `value` as the integer type of its field
+fn field<T: TryFrom<usize>>(value: usize, what: &str) -> Result<T, String> {
+    T::try_from(value).map_err(|_| format!("{} {} does not fit in a BCM file", what, value))
+}
+
+/// Reads fields from one section, failing rather than running past its end
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    section: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| format!("{} section is truncated", self.section))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+/// Load a module written by `write`
+pub fn read(bytes: &[u8]) -> Result<CompiledModule, String> {
+    if bytes.len() < HEADER_SIZE || &bytes[..4] != MAGIC {
+        return Err("not a BCM file".to_string());
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != VERSION {
+        return Err(format!("BCM version {} is not supported (expected {})", version, VERSION));
+    }
+    let count = u16::from_le_bytes([bytes[6], bytes[7]]) as usize;
+    if count != SECTIONS {
+        return Err(format!("expected {} BCM sections, found {}", SECTIONS, count));
+    }
+
+    let section = |i: usize, section: &'static str| {
+        let entry = &bytes[8 + i * 8..16 + i * 8];
+        let offset = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) as usize;
+        let len = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]) as usize;
+        let data = bytes
+            .get(offset..offset.saturating_add(len))
+            .ok_or_else(|| format!("{} section runs past the end of the file", section))?;
+        Ok::<_, String>(Reader { bytes: data, pos: 0, section })
+    };
+    let bytecode = section(0, "bytecode")?;
+    let mut numbers = section(1, "numbers")?;
+    let mut strings = section(2, "strings")?;
+    let mut functions = section(3, "functions")?;
+
+    let mut module = CompiledModule::new();
+    module.bytecode = bytecode.bytes.to_vec();
+    for _ in 0..numbers.u16()? {
+        module.numbers.push(BcNum::from_packed(numbers.take(PACKED_NUM_SIZE)?));
+    }
+    for _ in 0..strings.u16()? {
+        let len = strings.u16()? as usize;
+        let s = String::from_utf8(strings.take(len)?.to_vec()).map_err(|_| "string is not UTF-8".to_string())?;
+        module.strings.push(s);
+    }
+    for _ in 0..functions.u16()? {
+        let len = functions.u8()? as usize;
+        let name = String::from_utf8(functions.take(len)?.to_vec())
+            .map_err(|_| "function name is not UTF-8".to_string())?;
+        let bytecode_offset = functions.u16()? as usize;
+        let first_slot = functions.u8()?;
+        let param_count = functions.u8()? as usize;
+        let local_count = functions.u8()? as usize;
+        module.functions.push(CompiledFunction { name, param_count, local_count, bytecode_offset, first_slot });
+    }
+    Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::interpreter::interpret;
+
+    const SOURCE: &str = "define f(n, x) { auto t; t = n * x; return t - 1.5 }\n\
+                          a[1] = -3.25\nf(4, a[1])\n\"done\\n\"\n";
+
+    #[test]
+    fn test_round_trip() {
+        let module = Compiler::compile(SOURCE).unwrap();
+        let blob = write(&module).unwrap();
+        assert_eq!(&blob[..4], MAGIC);
+
+        let loaded = read(&blob).unwrap();
+        assert_eq!(loaded.bytecode, module.bytecode);
+        assert_eq!(loaded.strings, module.strings);
+        let packed = |m: &CompiledModule| m.numbers.iter().map(BcNum::to_packed).collect::<Vec<_>>();
+        assert_eq!(packed(&loaded), packed(&module));
+        assert_eq!(format!("{:?}", loaded.functions), format!("{:?}", module.functions));
+        assert_eq!(interpret(&loaded).unwrap(), ["-14.50", "done"]);
+    }
+
+    #[test]
+    fn test_rejects_other_versions() {
+        let mut blob = write(&Compiler::compile("1\n").unwrap()).unwrap();
+        blob[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(read(&blob).unwrap_err(), format!("BCM version {} is not supported (expected {})", VERSION + 1, VERSION));
+
+        assert_eq!(read(b"BC80").unwrap_err(), "not a BCM file");
+        let blob = write(&Compiler::compile("1\n").unwrap()).unwrap();
+        assert!(read(&blob[..blob.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_rejects_fields_that_do_not_fit() {
+        let mut module = Compiler::compile("1\n").unwrap();
+        module.strings.push("x".repeat(70_000));
+        assert_eq!(write(&module).unwrap_err(), "string length 70000 does not fit in a BCM file");
+
+        let mut module = Compiler::compile("define f() { return 1 }\n").unwrap();
+        module.functions[0].name = "f".repeat(256);
+        assert_eq!(write(&module).unwrap_err(), "function name length 256 does not fit in a BCM file");
+        module.functions[0].name = "f".to_string();
+        module.functions[0].bytecode_offset = 0x10000;
+        assert_eq!(write(&module).unwrap_err(), "function offset 65536 does not fit in a BCM file");
+    }
+}
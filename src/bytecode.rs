@@ -1,12 +1,19 @@
+use crate::error::Warning;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
 /// Bytecode opcodes for bc VM
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
 pub enum Op {
     // Stack operations
-    Halt = 0x00,
+    Halt = 0x00,            // Stop the CPU
     Nop = 0x01,
     Pop = 0x02,
     Dup = 0x03,
+    Swap = 0x04,            // Exchange the top two stack entries
+    Quit = 0x05,            // Leave the program (to the monitor, if one is configured)
+    Trap = 0x06,            // Breakpoint: dump the value stack (source line follows)
 
     // Constants
     LoadZero = 0x10,        // Push 0
@@ -37,6 +44,7 @@ pub enum Op {
     Mod = 0x34,
     Pow = 0x35,
     Neg = 0x36,
+    DivRound = 0x37,        // Div, rounding the last digit half up (--round)
 
     // Comparison (returns 0 or 1)
     Eq = 0x40,
@@ -59,6 +67,12 @@ pub enum Op {
     Jump = 0x60,            // Unconditional jump (addr follows)
     JumpIfZero = 0x61,      // Jump if top of stack is zero
     JumpIfNotZero = 0x62,   // Jump if top of stack is not zero
+    JumpIfEq = 0x63,        // Pop b, a; jump if a == b (fused compare + branch)
+    JumpIfNe = 0x64,        // Pop b, a; jump if a != b
+    JumpIfLt = 0x65,        // Pop b, a; jump if a < b
+    JumpIfLe = 0x66,        // Pop b, a; jump if a <= b
+    JumpIfGt = 0x67,        // Pop b, a; jump if a > b
+    JumpIfGe = 0x68,        // Pop b, a; jump if a >= b
 
     // Functions
     Call = 0x70,            // Call function (function index follows)
@@ -71,9 +85,9 @@ pub enum Op {
     Sqrt = 0x82,            // Square root
 
     // I/O
-    Print = 0x90,           // Print top of stack
+    Print = 0x90,           // Pop and print, no newline (see PrintNewline)
     PrintStr = 0x91,        // Print string (index follows)
-    PrintNewline = 0x92,    // Print newline
+    PrintNewline = 0x92,    // Print CR LF; follows Print for expression statements
     Read = 0x93,            // Read number from input
 }
 
@@ -84,6 +98,9 @@ impl Op {
             0x01 => Some(Op::Nop),
             0x02 => Some(Op::Pop),
             0x03 => Some(Op::Dup),
+            0x04 => Some(Op::Swap),
+            0x05 => Some(Op::Quit),
+            0x06 => Some(Op::Trap),
 
             0x10 => Some(Op::LoadZero),
             0x11 => Some(Op::LoadOne),
@@ -110,6 +127,7 @@ impl Op {
             0x34 => Some(Op::Mod),
             0x35 => Some(Op::Pow),
             0x36 => Some(Op::Neg),
+            0x37 => Some(Op::DivRound),
 
             0x40 => Some(Op::Eq),
             0x41 => Some(Op::Ne),
@@ -128,6 +146,12 @@ impl Op {
             0x60 => Some(Op::Jump),
             0x61 => Some(Op::JumpIfZero),
             0x62 => Some(Op::JumpIfNotZero),
+            0x63 => Some(Op::JumpIfEq),
+            0x64 => Some(Op::JumpIfNe),
+            0x65 => Some(Op::JumpIfLt),
+            0x66 => Some(Op::JumpIfLe),
+            0x67 => Some(Op::JumpIfGt),
+            0x68 => Some(Op::JumpIfGe),
 
             0x70 => Some(Op::Call),
             0x71 => Some(Op::Return),
@@ -145,8 +169,31 @@ impl Op {
             _ => None,
         }
     }
+
+    /// Number of operand bytes following the opcode
+    pub fn operand_len(self) -> usize {
+        match self {
+            Op::LoadNum | Op::LoadStr | Op::PrintStr | Op::Trap => 2,
+            Op::LoadVar | Op::StoreVar | Op::LoadArray | Op::StoreArray | Op::Call => 1,
+            op if op.is_jump() => 2,
+            _ => 0,
+        }
+    }
+
+    /// True for opcodes whose operand is a bytecode address
+    pub fn is_jump(self) -> bool {
+        matches!(
+            self,
+            Op::Jump | Op::JumpIfZero | Op::JumpIfNotZero |
+            Op::JumpIfEq | Op::JumpIfNe | Op::JumpIfLt |
+            Op::JumpIfLe | Op::JumpIfGt | Op::JumpIfGe
+        )
+    }
 }
 
+/// Bytes of packed digits after a number's 3-byte header (50 digits)
+pub const PACKED_DIGIT_BYTES: usize = 25;
+
 /// A compiled bc number - stored as packed BCD digits
 #[derive(Debug, Clone)]
 pub struct BcNum {
@@ -179,7 +226,7 @@ impl BcNum {
         let s = s.trim_start_matches('-').trim_start_matches('+');
 
         let parts: Vec<&str> = s.split('.').collect();
-        let int_part = parts.get(0).unwrap_or(&"0");
+        let int_part = parts.first().unwrap_or(&"0");
         let dec_part = parts.get(1).unwrap_or(&"");
 
         let integer_digits: Vec<u8> = if int_part.is_empty() {
@@ -212,15 +259,65 @@ impl BcNum {
         }
     }
 
+    /// Parse a literal written in `radix` (2-16), as bc reads numbers after
+    /// `ibase=`. The fraction keeps as many decimal places as it has
+    /// digits, truncating. Returns None if a digit is not valid in `radix`.
+    pub fn parse_radix(s: &str, radix: u32) -> Option<Self> {
+        let s = s.trim();
+        let negative = s.starts_with('-');
+        let s = s.trim_start_matches('-').trim_start_matches('+');
+        let (int_part, frac_part) = s.split_once('.').unwrap_or((s, ""));
+
+        let digit = |c: char| c.to_digit(16).filter(|&d| d < radix);
+        let int_values: Vec<u32> = int_part.chars().map(digit).collect::<Option<_>>()?;
+        let frac_values: Vec<u32> = frac_part.chars().map(digit).collect::<Option<_>>()?;
+
+        // Integer part: multiply in each digit, decimal digits low to high
+        let mut integer_digits = vec![0u8];
+        for d in int_values {
+            let mut carry = d;
+            for digit in integer_digits.iter_mut() {
+                let v = *digit as u32 * radix + carry;
+                *digit = (v % 10) as u8;
+                carry = v / 10;
+            }
+            while carry > 0 {
+                integer_digits.push((carry % 10) as u8);
+                carry /= 10;
+            }
+        }
+        while integer_digits.len() > 1 && integer_digits[integer_digits.len() - 1] == 0 {
+            integer_digits.pop();
+        }
+        integer_digits.reverse();
+
+        // Fraction: from the last digit, f = (d + f) / radix by long
+        // division. Each step truncates, which still gives the truncated
+        // exact value.
+        let mut decimal_digits = vec![0u8; frac_values.len()];
+        for &d in frac_values.iter().rev() {
+            let mut rem = d;
+            for digit in decimal_digits.iter_mut() {
+                let v = rem * 10 + *digit as u32;
+                *digit = (v / radix) as u8;
+                rem = v % radix;
+            }
+        }
+
+        let is_zero = integer_digits == [0] && decimal_digits.iter().all(|&d| d == 0);
+        Some(BcNum { negative: negative && !is_zero, integer_digits, decimal_digits })
+    }
+
     /// Pack digits into bytes (2 digits per byte) for storage
     /// Format: [sign:1][len:1][scale:1][packed_digits...]
     /// This matches the runtime's expected format
     ///
-    /// All numbers are normalized to FIXED_PACKED_BYTES bytes of packed data
-    /// to ensure proper alignment during BCD arithmetic operations.
+    /// All numbers are normalized to PACKED_DIGIT_BYTES bytes of packed data
+    /// to ensure proper alignment during BCD arithmetic operations. The len
+    /// byte carries the significant digit count, so the printer can skip the
+    /// zero padding.
     pub fn to_packed(&self) -> Vec<u8> {
-        const FIXED_PACKED_BYTES: usize = 25;  // 50 digits max
-        const FIXED_DIGIT_COUNT: usize = FIXED_PACKED_BYTES * 2;
+        const FIXED_DIGIT_COUNT: usize = PACKED_DIGIT_BYTES * 2;
 
         let mut result = Vec::new();
 
@@ -237,7 +334,7 @@ impl BcNum {
 
         // Header: sign (1 byte) + total digit count (1 byte) + scale (1 byte)
         result.push(if self.negative { 0x80 } else { 0x00 });
-        result.push(FIXED_DIGIT_COUNT as u8);  // Always 50 digits
+        result.push(self.significant_digits() as u8);
         result.push(scale as u8);
 
         // Pack digits (2 per byte, high nibble first)
@@ -249,6 +346,52 @@ impl BcNum {
 
         result
     }
+
+    /// Inverse of `to_packed`: read the header and unpack the digits. The
+    /// integer part loses its leading zeros (keeping one), so packing the
+    /// result gives back the same bytes. Missing bytes read as zero, and
+    /// anything past PACKED_DIGIT_BYTES (a RAM slot's padding) is ignored.
+    pub fn from_packed(bytes: &[u8]) -> Self {
+        let byte = |i: usize| bytes.get(i).copied().unwrap_or(0);
+        let digits: Vec<u8> =
+            bytes.iter().skip(3).take(PACKED_DIGIT_BYTES).flat_map(|&b| [b >> 4, b & 0x0F]).collect();
+        let scale = (byte(2) as usize).min(digits.len());
+        let (integer, decimal) = digits.split_at(digits.len() - scale);
+
+        let leading_zeros = integer.iter().take_while(|&&d| d == 0).count();
+        let mut integer_digits = integer[leading_zeros..].to_vec();
+        if integer_digits.is_empty() {
+            integer_digits.push(0);
+        }
+
+        BcNum { negative: byte(0) & 0x80 != 0, integer_digits, decimal_digits: decimal.to_vec() }
+    }
+
+    /// Digits from the first non-zero integer digit through the last
+    /// decimal digit (at least 1, so zero still prints)
+    pub fn significant_digits(&self) -> usize {
+        let leading_zeros = self.integer_digits.iter().take_while(|&&d| d == 0).count();
+        let integer = self.integer_digits.len() - leading_zeros;
+        (integer + self.decimal_digits.len()).max(1)
+    }
+}
+
+impl fmt::Display for BcNum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.negative {
+            write!(f, "-")?;
+        }
+        for d in &self.integer_digits {
+            write!(f, "{}", d)?;
+        }
+        if !self.decimal_digits.is_empty() {
+            write!(f, ".")?;
+            for d in &self.decimal_digits {
+                write!(f, "{}", d)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Compiled module
@@ -258,6 +401,10 @@ pub struct CompiledModule {
     pub numbers: Vec<BcNum>,
     pub strings: Vec<String>,
     pub functions: Vec<CompiledFunction>,
+    /// Problems that do not stop compilation, such as unreachable code
+    pub warnings: Vec<Warning>,
+    /// Slots of the program's global variables, with arrays as `name[]`
+    pub variables: BTreeMap<String, u8>,
 }
 
 #[derive(Debug)]
@@ -265,8 +412,18 @@ pub struct CompiledModule {
 pub struct CompiledFunction {
     pub name: String,
     pub param_count: usize,
+    /// Autos plus the hidden temporaries the body uses
     pub local_count: usize,
     pub bytecode_offset: usize,
+    /// Variable slot of the first parameter; the other parameters and then
+    /// the autos follow it
+    pub first_slot: u8,
+}
+
+impl Default for CompiledModule {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CompiledModule {
@@ -276,6 +433,8 @@ impl CompiledModule {
             numbers: Vec::new(),
             strings: Vec::new(),
             functions: Vec::new(),
+            warnings: Vec::new(),
+            variables: BTreeMap::new(),
         }
     }
 
@@ -318,4 +477,208 @@ impl CompiledModule {
         self.bytecode[offset] = (val & 0xFF) as u8;
         self.bytecode[offset + 1] = ((val >> 8) & 0xFF) as u8;
     }
+
+    /// Distinct opcodes in the bytecode, in order of first use. Stray
+    /// bytes that are not opcodes are skipped.
+    pub fn used_ops(&self) -> Vec<Op> {
+        let mut ops = Vec::new();
+        let mut offset = 0;
+        while offset < self.bytecode.len() {
+            match Op::from_u8(self.bytecode[offset]) {
+                Some(op) => {
+                    if !ops.contains(&op) {
+                        ops.push(op);
+                    }
+                    offset += 1 + op.operand_len();
+                }
+                None => offset += 1,
+            }
+        }
+        ops
+    }
+
+    /// Listing of the constant table with each entry's packed ROM bytes
+    pub fn constants_listing(&self) -> String {
+        let mut out = String::new();
+        for (i, num) in self.numbers.iter().enumerate() {
+            let packed = num.to_packed();
+            let hex = |bytes: &[u8]| {
+                bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ")
+            };
+            out.push_str(&format!("#{} {}\n", i, num));
+            out.push_str(&format!(
+                "    header: {}  (sign={} len={} scale={})\n",
+                hex(&packed[..3]),
+                if packed[0] & 0x80 != 0 { '-' } else { '+' },
+                packed[1],
+                packed[2]
+            ));
+            out.push_str(&format!("    digits: {}\n", hex(&packed[3..])));
+        }
+        out
+    }
+
+    /// Listing of the compiled functions and where their bytecode starts
+    pub fn functions_listing(&self) -> String {
+        let mut out = String::new();
+        for func in &self.functions {
+            out.push_str(&format!(
+                "{:04X}: {} (params={} locals={})\n",
+                func.bytecode_offset, func.name, func.param_count, func.local_count
+            ));
+        }
+        out
+    }
+
+    /// Control-flow graph of the bytecode in Graphviz DOT format. Blocks
+    /// start at offset 0, at function entries and jump targets, and after
+    /// jumps and the instructions that leave a function or the program.
+    pub fn cfg_dot(&self) -> String {
+        let mut insns = Vec::new();
+        let mut offset = 0;
+        while let Some(op) = self.bytecode.get(offset).and_then(|&byte| Op::from_u8(byte)) {
+            let end = offset + 1 + op.operand_len();
+            let Some(operands) = self.bytecode.get(offset + 1..end) else { break };
+            insns.push((offset, op, operands));
+            offset = end;
+        }
+
+        let target = |operands: &[u8]| u16::from_le_bytes([operands[0], operands[1]]) as usize;
+        let ends_flow = |op: Op| matches!(op, Op::Return | Op::ReturnValue | Op::Quit | Op::Halt);
+        let mut leaders: BTreeSet<usize> = self.functions.iter().map(|f| f.bytecode_offset).collect();
+        leaders.insert(0);
+        for &(offset, op, operands) in &insns {
+            if op.is_jump() {
+                leaders.insert(target(operands));
+            }
+            if op.is_jump() || ends_flow(op) {
+                leaders.insert(offset + 1 + operands.len());
+            }
+        }
+
+        let mut out = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        let mut edges = String::new();
+        let mut block = 0;
+        for (i, &(offset, op, operands)) in insns.iter().enumerate() {
+            if leaders.contains(&offset) {
+                block = offset;
+                out.push_str(&format!("    b{:04X} [label=\"", offset));
+            }
+            let text = match op.operand_len() {
+                _ if op.is_jump() => format!(" -> {:04X}", target(operands)),
+                2 => format!(" #{}", target(operands)),
+                1 => format!(" @{}", operands[0]),
+                _ => String::new(),
+            };
+            out.push_str(&format!("{:04X}: {:?}{}\\l", offset, op, text));
+
+            let next = insns.get(i + 1).map(|&(next, _, _)| next);
+            if next.is_some_and(|next| !leaders.contains(&next)) {
+                continue;
+            }
+            out.push_str("\"];\n");
+            if op.is_jump() {
+                edges.push_str(&format!("    b{:04X} -> b{:04X};\n", block, target(operands)));
+            }
+            if let Some(next) = next.filter(|_| op != Op::Jump && !ends_flow(op)) {
+                edges.push_str(&format!("    b{:04X} -> b{:04X};\n", block, next));
+            }
+        }
+        out.push_str(&edges);
+        out.push_str("}\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_functions_listing() {
+        let module = crate::compiler::Compiler::compile(
+            "define f(x) { return x }\ndefine g(a, b) { auto t; t = a; return t + b }\n",
+        )
+        .unwrap();
+        let listing = module.functions_listing();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines.len(), 2);
+        // Function bodies follow the main program's Halt
+        assert_eq!(lines[0], "0001: f (params=1 locals=0)");
+        assert_eq!(lines[1], "0006: g (params=2 locals=1)");
+    }
+
+    #[test]
+    fn test_cfg_dot() {
+        let module = crate::compiler::Compiler::compile("i = 0\nwhile (i < 3) i = i + 1\nprint i\n").unwrap();
+        let dot = module.cfg_dot();
+        assert!(dot.starts_with("digraph cfg {\n") && dot.ends_with("}\n"));
+
+        let edges: Vec<(usize, usize)> = dot
+            .lines()
+            .filter_map(|line| {
+                let (from, to) = line.trim().trim_end_matches(';').split_once(" -> ")?;
+                let block = |name: &str| usize::from_str_radix(name.strip_prefix('b')?, 16).ok();
+                Some((block(from)?, block(to)?))
+            })
+            .collect();
+        // The loop condition's block jumps out past the body, and the body
+        // jumps back up to the condition
+        let back_edges: Vec<_> = edges.iter().filter(|(from, to)| to <= from).collect();
+        assert_eq!(back_edges.len(), 1, "{}", dot);
+        let (body, cond) = *back_edges[0];
+        assert!(edges.iter().any(|&(from, to)| from == cond && to > body), "{}", dot);
+    }
+
+    #[test]
+    fn test_constants_listing() {
+        let mut module = CompiledModule::new();
+        module.add_number(BcNum::parse("12"));
+        module.add_number(BcNum::parse("-1.5"));
+        let listing = module.constants_listing();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "#0 12");
+        // Digits right-aligned in the padded field, len counts only the real ones
+        assert_eq!(lines[1], "    header: 00 02 00  (sign=+ len=2 scale=0)");
+        assert!(lines[2].ends_with("00 00 12"));
+        assert_eq!(lines[2].split_whitespace().count(), 1 + 25);
+        assert_eq!(lines[3], "#1 -1.5");
+        assert_eq!(lines[4], "    header: 80 02 01  (sign=- len=2 scale=1)");
+    }
+
+    #[test]
+    fn test_from_packed_round_trip() {
+        for value in ["0", "7", "-12", "123.45", "-0.005", ".5", "1.000", "99999999999999999999", "-3.14159265358979"] {
+            let packed = BcNum::parse(value).to_packed();
+            let unpacked = BcNum::from_packed(&packed);
+            assert_eq!(unpacked.to_packed(), packed, "{}", value);
+            assert_eq!(unpacked.to_string(), BcNum::parse(value).to_string(), "{}", value);
+        }
+        assert_eq!(BcNum::from_packed(&[0x80, 3, 1, 0x01, 0x25]).to_string(), "-12.5");
+        assert_eq!(BcNum::from_packed(&[]).to_string(), "0");
+        // A 53-byte RAM slot: padding after the digits is not part of it
+        let mut slot = BcNum::parse("4.2").to_packed();
+        slot.resize(53, 0xFF);
+        assert_eq!(BcNum::from_packed(&slot).to_string(), "4.2");
+    }
+
+    #[test]
+    fn test_parse_radix() {
+        let decimal = |n: &BcNum| n.to_string();
+        assert_eq!(decimal(&BcNum::parse_radix("FF", 16).unwrap()), "255");
+        assert_eq!(decimal(&BcNum::parse_radix("101.1", 2).unwrap()), "5.5");
+        assert_eq!(decimal(&BcNum::parse_radix(".8", 16).unwrap()), "0.5");
+        // One base-3 place truncates to one decimal place: 1/3 = .3
+        assert_eq!(decimal(&BcNum::parse_radix(".1", 3).unwrap()), "0.3");
+        assert!(BcNum::parse_radix("12", 2).is_none());
+        assert!(BcNum::parse_radix("G", 16).is_none());
+    }
+
+    #[test]
+    fn test_significant_digits() {
+        assert_eq!(BcNum::parse("0").significant_digits(), 1);
+        assert_eq!(BcNum::parse("007").significant_digits(), 1);
+        assert_eq!(BcNum::parse("123.45").significant_digits(), 5);
+        assert_eq!(BcNum::parse(".05").significant_digits(), 2);
+    }
 }
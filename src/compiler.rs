@@ -1,19 +1,76 @@
 use crate::ast::*;
 use crate::bytecode::*;
-use crate::parser::Parser;
+use crate::error::{BuildError, Warning};
+use crate::format;
+use crate::parser::{Parser, StatementLines};
+use crate::simplify;
 use std::collections::HashMap;
 
+/// Variable slots the runtime reserves at VARS_BASE. Parameters, autos,
+/// arrays and the compiler's hidden temporaries all take one.
+pub const MAX_VAR_SLOTS: u8 = 26;
+
+/// Using this many variable slots draws a warning that the program is
+/// close to running out
+pub const VAR_SLOTS_WARNING: u8 = MAX_VAR_SLOTS - 2;
+
+/// Largest program the runtime can address: bytecode is loaded at 0x2000
+/// and jump targets are 16-bit addresses
+pub const MAX_BYTECODE_SIZE: usize = 0x10000 - 0x2000;
+
+/// Choices that change how source is compiled
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileOptions {
+    /// Base numeric literals are written in (2-16). This is fixed for the
+    /// whole program: `ibase=` statements only affect read().
+    pub ibase: u32,
+    /// Round the last digit of `/` half up instead of truncating as bc does
+    pub round_division: bool,
+    /// Source lines to stop at: a Trap runs before the statement that
+    /// starts on each one
+    pub breakpoints: Vec<usize>,
+    /// Evaluate bare expression statements without printing their values,
+    /// so only `print` and strings produce output
+    pub quiet: bool,
+    /// Base the literals in each named function are written in, where it
+    /// differs from `ibase` (a session's functions defined under another)
+    pub function_ibase: HashMap<String, u32>,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            ibase: 10,
+            round_division: false,
+            breakpoints: Vec::new(),
+            quiet: false,
+            function_ibase: HashMap::new(),
+        }
+    }
+}
+
 pub struct Compiler {
     module: CompiledModule,
     variables: HashMap<String, u8>,
     next_var_slot: u8,
+    /// Most slots in use at once, across main and every function
+    peak_var_slots: u8,
     loop_stack: Vec<LoopContext>,
     functions: HashMap<String, u8>,
+    options: CompileOptions,
 }
 
 struct LoopContext {
     break_patches: Vec<usize>,
-    continue_target: usize,
+    /// Known continue address, or None if it lies after the body (for-loop update)
+    continue_target: Option<usize>,
+    continue_patches: Vec<usize>,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Compiler {
@@ -22,66 +79,220 @@ impl Compiler {
             module: CompiledModule::new(),
             variables: HashMap::new(),
             next_var_slot: 0,
+            peak_var_slots: 0,
             loop_stack: Vec::new(),
             functions: HashMap::new(),
+            options: CompileOptions::default(),
         }
     }
 
-    pub fn compile(source: &str) -> Result<CompiledModule, String> {
+    pub fn compile(source: &str) -> Result<CompiledModule, BuildError> {
+        Self::compile_with(source, &CompileOptions::default())
+    }
+
+    pub fn compile_with(source: &str, options: &CompileOptions) -> Result<CompiledModule, BuildError> {
+        for &ibase in std::iter::once(&options.ibase).chain(options.function_ibase.values()) {
+            if !(2..=16).contains(&ibase) {
+                return Err(BuildError::compile(format!("ibase {} is not between 2 and 16", ibase)));
+            }
+        }
         let mut parser = Parser::new(source);
-        let program = parser.parse()?;
+        let mut program = parser.parse()?;
+        let warnings = simplify::simplify_program(&mut program, parser.statement_lines());
+
+        let mut compiler = Compiler { options: options.clone(), ..Compiler::new() };
+        compiler.module.warnings = warnings;
+        compiler.compile_program(&program, parser.statement_lines())?;
+        compiler.check_size()?;
+
+        Ok(compiler.module)
+    }
+
+    /// Compile a lone expression into a module that prints its value (with
+    /// no newline) and halts, for tiny evaluation ROMs
+    pub fn compile_expression(source: &str) -> Result<CompiledModule, BuildError> {
+        let mut parser = Parser::new(source);
+        let mut expr = parser.parse_expression()?;
+        let warnings = simplify::simplify_expression(&mut expr);
 
         let mut compiler = Compiler::new();
-        compiler.compile_program(&program)?;
+        compiler.module.warnings = warnings;
+        compiler.compile_expr(&expr)?;
+        compiler.module.emit(Op::Print);
+        compiler.module.emit(Op::Halt);
+        compiler.check_size()?;
 
         Ok(compiler.module)
     }
 
-    fn compile_program(&mut self, program: &Program) -> Result<(), String> {
+    /// Offsets are written as u16, so a program past the addressable range
+    /// would wrap its jumps instead of failing
+    fn check_size(&self) -> Result<(), BuildError> {
+        let size = self.module.current_offset();
+        if size > MAX_BYTECODE_SIZE {
+            return Err(BuildError::compile(format!(
+                "program too large: {} bytes of bytecode, the runtime addresses at most {}",
+                size, MAX_BYTECODE_SIZE
+            )));
+        }
+        Ok(())
+    }
+
+    /// Warn about the first statement in each block of a function body
+    /// that follows an unconditional return. It is still compiled. The
+    /// AST has no statement positions, so the warning points at `define`.
+    fn check_reachable(&mut self, func: &Function, stmts: &[Stmt]) {
+        let mut returned = false;
+        for stmt in stmts {
+            if matches!(stmt, Stmt::Empty | Stmt::Auto(_)) {
+                continue;
+            }
+            if returned {
+                let source = format::format_stmt_source(stmt);
+                self.module.warnings.push(Warning {
+                    line: func.line,
+                    col: func.col,
+                    message: format!(
+                        "unreachable statement after return in {}(): {}",
+                        func.name,
+                        source.lines().next().unwrap_or_default()
+                    ),
+                });
+                break;
+            }
+            match stmt {
+                Stmt::Block(body) => self.check_reachable(func, body),
+                Stmt::If { then_branch, else_branch, .. } => {
+                    self.check_reachable(func, std::slice::from_ref(then_branch));
+                    if let Some(else_branch) = else_branch {
+                        self.check_reachable(func, std::slice::from_ref(else_branch));
+                    }
+                }
+                Stmt::While { body, .. } | Stmt::For { body, .. } => {
+                    self.check_reachable(func, std::slice::from_ref(body))
+                }
+                _ => {}
+            }
+            returned = always_returns(stmt);
+        }
+    }
+
+    /// If the user asked to stop at `line`, print where and Trap
+    fn compile_breakpoint(&mut self, line: usize) {
+        if !self.options.breakpoints.contains(&line) {
+            return;
+        }
+        let message = self.module.add_string(format!("break at line {}:", line));
+        self.module.emit(Op::PrintStr);
+        self.module.emit_u16(message);
+        self.module.emit(Op::Trap);
+        self.module.emit_u16(line as u16);
+    }
+
+    /// Opcode for a `/` in the source. Divisions the compiler introduces
+    /// itself (for `%`, `sqrt`, negative powers) always truncate.
+    fn div_op(&self) -> Op {
+        if self.options.round_division {
+            Op::DivRound
+        } else {
+            Op::Div
+        }
+    }
+
+    fn compile_program(&mut self, program: &Program, lines: &StatementLines) -> Result<(), BuildError> {
         // First pass: register all functions
         for (i, func) in program.functions.iter().enumerate() {
             self.functions.insert(func.name.clone(), i as u8);
         }
 
+        let known = lines.main.iter().chain(lines.functions.iter().flatten());
+        let known: Vec<usize> = known.copied().collect();
+        if let Some(line) = self.options.breakpoints.iter().find(|line| !known.contains(line)) {
+            return Err(BuildError::compile(format!("breakpoint at line {}: no statement starts there", line)));
+        }
+
         // Compile main statements
-        for stmt in &program.statements {
+        for (stmt, &line) in program.statements.iter().zip(&lines.main) {
+            self.compile_breakpoint(line);
             self.compile_stmt(stmt)?;
         }
 
-        // Add halt at end of main code
-        self.module.emit(Op::Halt);
+        // Running off the end of main leaves the program like quit
+        self.module.emit(Op::Quit);
 
-        // Compile functions
+        // In bc only parameters and autos are local: any other name in a
+        // function body is a global. Give those their slots now, so no
+        // function's locals land on them.
         for func in &program.functions {
-            self.compile_function(func)?;
+            let mut names = Vec::new();
+            for stmt in &func.body {
+                collect_names(stmt, &mut names);
+            }
+            let is_local = |name: &String| {
+                func.params.iter().any(|p| *name == local_key(&p.name, p.is_array))
+                    || func.auto_vars.iter().any(|a| *name == local_key(&a.name, a.is_array))
+            };
+            for name in names.iter().filter(|name| !is_local(name)) {
+                self.get_or_create_var(name)?;
+            }
+        }
+
+        // Compile functions
+        for (func, lines) in program.functions.iter().zip(&lines.functions) {
+            self.compile_function(func, lines)?;
+        }
+
+        // Hidden temporaries have a space in their names
+        self.module.variables = self
+            .variables
+            .iter()
+            .filter(|(name, _)| !name.contains(' '))
+            .map(|(name, &slot)| (name.clone(), slot))
+            .collect();
+
+        if self.peak_var_slots >= VAR_SLOTS_WARNING {
+            self.module.warnings.push(Warning {
+                line: 0,
+                col: 0,
+                message: format!("{} of the runtime's {} variable slots used", self.peak_var_slots, MAX_VAR_SLOTS),
+            });
         }
 
         Ok(())
     }
 
-    fn compile_function(&mut self, func: &Function) -> Result<(), String> {
+    fn compile_function(&mut self, func: &Function, lines: &[usize]) -> Result<(), BuildError> {
         let offset = self.module.current_offset();
 
         // Save current variable state
         let saved_vars = self.variables.clone();
         let saved_next = self.next_var_slot;
+        let saved_ibase = self.options.ibase;
+        let first_slot = self.next_var_slot;
+        if let Some(&ibase) = self.options.function_ibase.get(&func.name) {
+            self.options.ibase = ibase;
+        }
+
+        // The body gets its own hidden temporaries rather than main's: a
+        // store to a global slot stops Return from reclaiming the heap
+        self.variables.retain(|name, _| !name.contains(' '));
 
         // Add parameters as local variables
         for param in &func.params {
-            let slot = self.next_var_slot;
-            self.variables.insert(param.name.clone(), slot);
-            self.next_var_slot += 1;
+            self.new_var(local_key(&param.name, param.is_array))?;
         }
 
-        // Add auto variables
+        // Add auto variables. Call resets them to zero on entry, which for
+        // an array means fresh, zeroed storage on first use in each call.
         for auto_var in &func.auto_vars {
-            let slot = self.next_var_slot;
-            self.variables.insert(auto_var.name.clone(), slot);
-            self.next_var_slot += 1;
+            self.new_var(local_key(&auto_var.name, auto_var.is_array))?;
         }
 
+        self.check_reachable(func, &func.body);
+
         // Compile body
-        for stmt in &func.body {
+        for (stmt, &line) in func.body.iter().zip(lines) {
+            self.compile_breakpoint(line);
             self.compile_stmt(stmt)?;
         }
 
@@ -89,27 +300,39 @@ impl Compiler {
         self.module.emit(Op::LoadZero);
         self.module.emit(Op::ReturnValue);
 
-        // Record function info
+        // Hidden temporaries the body created (for %, ^, sqrt and array
+        // updates) follow the autos. They count as locals, so Call saves
+        // whatever a caller keeps in those slots.
         self.module.functions.push(CompiledFunction {
             name: func.name.clone(),
             param_count: func.params.len(),
-            local_count: func.auto_vars.len(),
+            local_count: (self.next_var_slot - first_slot) as usize - func.params.len(),
             bytecode_offset: offset,
+            first_slot,
         });
 
         // Restore variable state
         self.variables = saved_vars;
         self.next_var_slot = saved_next;
+        self.options.ibase = saved_ibase;
 
         Ok(())
     }
 
-    fn compile_stmt(&mut self, stmt: &Stmt) -> Result<(), String> {
+    fn compile_stmt(&mut self, stmt: &Stmt) -> Result<(), BuildError> {
         match stmt {
+            // A bare string is printed as-is, with no newline, as in bc
+            Stmt::Expr(Expr::String(s)) => {
+                let idx = self.module.add_string(s.clone());
+                self.module.emit(Op::PrintStr);
+                self.module.emit_u16(idx);
+            }
+
             Stmt::Expr(expr) => {
                 self.compile_expr(expr)?;
-                // If it's not an assignment, print the result
-                if !Self::is_assignment(expr) {
+                // If it's not an assignment, print the result on its own
+                // line (also inside functions, as in bc)
+                if !Self::is_assignment(expr) && !self.options.quiet {
                     self.module.emit(Op::Print);
                     self.module.emit(Op::PrintNewline);
                 } else {
@@ -118,6 +341,7 @@ impl Compiler {
             }
 
             Stmt::Print(items) => {
+                // `print` never adds a newline; strings supply their own
                 for item in items {
                     match item {
                         PrintItem::Expr(expr) => {
@@ -140,11 +364,7 @@ impl Compiler {
             }
 
             Stmt::If { cond, then_branch, else_branch } => {
-                self.compile_expr(cond)?;
-
-                let else_jump = self.module.current_offset();
-                self.module.emit(Op::JumpIfZero);
-                self.module.emit_u16(0); // Placeholder
+                let else_jump = self.compile_jump_unless(cond)?;
 
                 self.compile_stmt(then_branch)?;
 
@@ -171,14 +391,11 @@ impl Compiler {
 
                 self.loop_stack.push(LoopContext {
                     break_patches: Vec::new(),
-                    continue_target: loop_start,
+                    continue_target: Some(loop_start),
+                    continue_patches: Vec::new(),
                 });
 
-                self.compile_expr(cond)?;
-
-                let exit_jump = self.module.current_offset();
-                self.module.emit(Op::JumpIfZero);
-                self.module.emit_u16(0); // Placeholder
+                let exit_jump = self.compile_jump_unless(cond)?;
 
                 self.compile_stmt(body)?;
 
@@ -203,31 +420,29 @@ impl Compiler {
                 }
 
                 let loop_start = self.module.current_offset();
-                let update_target = loop_start; // Will be adjusted
 
+                // The update section comes after the body, so continues
+                // inside the body are patched once its address is known
                 self.loop_stack.push(LoopContext {
                     break_patches: Vec::new(),
-                    continue_target: update_target, // Temporary
+                    continue_target: None,
+                    continue_patches: Vec::new(),
                 });
 
                 // Compile condition
-                let exit_jump = if let Some(cond_expr) = cond {
-                    self.compile_expr(cond_expr)?;
-                    let jump = self.module.current_offset();
-                    self.module.emit(Op::JumpIfZero);
-                    self.module.emit_u16(0);
-                    Some(jump)
-                } else {
-                    None
+                let exit_jump = match cond {
+                    Some(cond_expr) => Some(self.compile_jump_unless(cond_expr)?),
+                    None => None,
                 };
 
                 // Compile body
                 self.compile_stmt(body)?;
 
-                // Update continue target to point to update section
-                let continue_addr = self.module.current_offset();
-                if let Some(ctx) = self.loop_stack.last_mut() {
-                    ctx.continue_target = continue_addr;
+                // Point continues at the update section
+                let continue_addr = self.module.current_offset() as u16;
+                let patches = std::mem::take(&mut self.loop_stack.last_mut().unwrap().continue_patches);
+                for patch in patches {
+                    self.module.patch_u16(patch + 1, continue_addr);
                 }
 
                 // Compile update
@@ -261,16 +476,23 @@ impl Compiler {
                     self.module.emit_u16(0); // Placeholder
                     ctx.break_patches.push(jump);
                 } else {
-                    return Err("break outside loop".to_string());
+                    return Err(BuildError::compile("break outside loop"));
                 }
             }
 
             Stmt::Continue => {
-                if let Some(ctx) = self.loop_stack.last() {
+                if let Some(ctx) = self.loop_stack.last_mut() {
+                    let jump = self.module.current_offset();
                     self.module.emit(Op::Jump);
-                    self.module.emit_u16(ctx.continue_target as u16);
+                    match ctx.continue_target {
+                        Some(target) => self.module.emit_u16(target as u16),
+                        None => {
+                            self.module.emit_u16(0); // Placeholder
+                            ctx.continue_patches.push(jump);
+                        }
+                    }
                 } else {
-                    return Err("continue outside loop".to_string());
+                    return Err(BuildError::compile("continue outside loop"));
                 }
             }
 
@@ -283,7 +505,10 @@ impl Compiler {
                 }
             }
 
-            Stmt::Quit | Stmt::Halt => {
+            Stmt::Quit => {
+                self.module.emit(Op::Quit);
+            }
+            Stmt::Halt => {
                 self.module.emit(Op::Halt);
             }
 
@@ -297,7 +522,7 @@ impl Compiler {
         Ok(())
     }
 
-    fn compile_expr(&mut self, expr: &Expr) -> Result<(), String> {
+    fn compile_expr(&mut self, expr: &Expr) -> Result<(), BuildError> {
         match expr {
             Expr::Number(s) => {
                 if s == "0" {
@@ -305,27 +530,35 @@ impl Compiler {
                 } else if s == "1" {
                     self.module.emit(Op::LoadOne);
                 } else {
-                    let num = BcNum::parse(s);
+                    // The lexer takes A-F whatever the base; reject digits
+                    // the base does not have rather than dropping them
+                    let ibase = self.options.ibase;
+                    let num = BcNum::parse_radix(s, ibase).ok_or_else(|| {
+                        BuildError::compile(format!("{} is not a valid base {} number", s, ibase))
+                    })?;
                     let idx = self.module.add_number(num);
                     self.module.emit(Op::LoadNum);
                     self.module.emit_u16(idx);
                 }
             }
 
+            // Strings are only printed (by print or as a bare statement);
+            // anywhere else the runtime would read them as BCD numbers
             Expr::String(s) => {
-                let idx = self.module.add_string(s.clone());
-                self.module.emit(Op::LoadStr);
-                self.module.emit_u16(idx);
+                return Err(BuildError::compile(format!(
+                    "Type error: string \"{}\" used as a number",
+                    s
+                )));
             }
 
             Expr::Var(name) => {
-                let slot = self.get_or_create_var(name);
+                let slot = self.get_or_create_var(name)?;
                 self.module.emit(Op::LoadVar);
                 self.module.emit_u8(slot);
             }
 
             Expr::ArrayElement(name, index) => {
-                let slot = self.get_or_create_var(name);
+                let slot = self.get_or_create_var(&array_key(name))?;
                 self.compile_expr(index)?;
                 self.module.emit(Op::LoadArray);
                 self.module.emit_u8(slot);
@@ -368,19 +601,19 @@ impl Compiler {
             Expr::Div(a, b) => {
                 self.compile_expr(a)?;
                 self.compile_expr(b)?;
-                self.module.emit(Op::Div);
+                self.module.emit(self.div_op());
             }
 
             Expr::Mod(a, b) => {
                 self.compile_expr(a)?;
                 self.compile_expr(b)?;
-                self.module.emit(Op::Mod);
+                self.compile_mod()?;
             }
 
             Expr::Pow(a, b) => {
                 self.compile_expr(a)?;
                 self.compile_expr(b)?;
-                self.module.emit(Op::Pow);
+                self.compile_pow()?;
             }
 
             Expr::Neg(a) => {
@@ -395,9 +628,11 @@ impl Compiler {
             }
 
             Expr::Ne(a, b) => {
+                // a != b is !(a == b), so the runtime needs no Ne handler
                 self.compile_expr(a)?;
                 self.compile_expr(b)?;
-                self.module.emit(Op::Ne);
+                self.module.emit(Op::Eq);
+                self.module.emit(Op::Not);
             }
 
             Expr::Lt(a, b) => {
@@ -407,9 +642,11 @@ impl Compiler {
             }
 
             Expr::Le(a, b) => {
+                // a <= b is !(a > b), so the runtime needs no Le handler
                 self.compile_expr(a)?;
                 self.compile_expr(b)?;
-                self.module.emit(Op::Le);
+                self.module.emit(Op::Gt);
+                self.module.emit(Op::Not);
             }
 
             Expr::Gt(a, b) => {
@@ -419,9 +656,11 @@ impl Compiler {
             }
 
             Expr::Ge(a, b) => {
+                // a >= b is !(a < b), so the runtime needs no Ge handler
                 self.compile_expr(a)?;
                 self.compile_expr(b)?;
-                self.module.emit(Op::Ge);
+                self.module.emit(Op::Lt);
+                self.module.emit(Op::Not);
             }
 
             Expr::And(a, b) => {
@@ -443,32 +682,32 @@ impl Compiler {
 
             Expr::PreInc(a) => {
                 // ++x: increment and return new value
-                self.compile_expr(a)?;
-                self.module.emit(Op::Inc);
-                self.module.emit(Op::Dup);
-                self.compile_store(a)?;
+                self.compile_update(a, false, |c| {
+                    c.module.emit(Op::Inc);
+                    Ok(())
+                })?;
             }
 
             Expr::PreDec(a) => {
-                self.compile_expr(a)?;
-                self.module.emit(Op::Dec);
-                self.module.emit(Op::Dup);
-                self.compile_store(a)?;
+                self.compile_update(a, false, |c| {
+                    c.module.emit(Op::Dec);
+                    Ok(())
+                })?;
             }
 
             Expr::PostInc(a) => {
                 // x++: return old value, then increment
-                self.compile_expr(a)?;
-                self.module.emit(Op::Dup);
-                self.module.emit(Op::Inc);
-                self.compile_store(a)?;
+                self.compile_update(a, true, |c| {
+                    c.module.emit(Op::Inc);
+                    Ok(())
+                })?;
             }
 
             Expr::PostDec(a) => {
-                self.compile_expr(a)?;
-                self.module.emit(Op::Dup);
-                self.module.emit(Op::Dec);
-                self.compile_store(a)?;
+                self.compile_update(a, true, |c| {
+                    c.module.emit(Op::Dec);
+                    Ok(())
+                })?;
             }
 
             Expr::Assign(target, value) => {
@@ -478,51 +717,49 @@ impl Compiler {
             }
 
             Expr::AddAssign(target, value) => {
-                self.compile_expr(target)?;
-                self.compile_expr(value)?;
-                self.module.emit(Op::Add);
-                self.module.emit(Op::Dup);
-                self.compile_store(target)?;
+                self.compile_update(target, false, |c| {
+                    c.compile_expr(value)?;
+                    c.module.emit(Op::Add);
+                    Ok(())
+                })?;
             }
 
             Expr::SubAssign(target, value) => {
-                self.compile_expr(target)?;
-                self.compile_expr(value)?;
-                self.module.emit(Op::Sub);
-                self.module.emit(Op::Dup);
-                self.compile_store(target)?;
+                self.compile_update(target, false, |c| {
+                    c.compile_expr(value)?;
+                    c.module.emit(Op::Sub);
+                    Ok(())
+                })?;
             }
 
             Expr::MulAssign(target, value) => {
-                self.compile_expr(target)?;
-                self.compile_expr(value)?;
-                self.module.emit(Op::Mul);
-                self.module.emit(Op::Dup);
-                self.compile_store(target)?;
+                self.compile_update(target, false, |c| {
+                    c.compile_expr(value)?;
+                    c.module.emit(Op::Mul);
+                    Ok(())
+                })?;
             }
 
             Expr::DivAssign(target, value) => {
-                self.compile_expr(target)?;
-                self.compile_expr(value)?;
-                self.module.emit(Op::Div);
-                self.module.emit(Op::Dup);
-                self.compile_store(target)?;
+                self.compile_update(target, false, |c| {
+                    c.compile_expr(value)?;
+                    c.module.emit(c.div_op());
+                    Ok(())
+                })?;
             }
 
             Expr::ModAssign(target, value) => {
-                self.compile_expr(target)?;
-                self.compile_expr(value)?;
-                self.module.emit(Op::Mod);
-                self.module.emit(Op::Dup);
-                self.compile_store(target)?;
+                self.compile_update(target, false, |c| {
+                    c.compile_expr(value)?;
+                    c.compile_mod()
+                })?;
             }
 
             Expr::PowAssign(target, value) => {
-                self.compile_expr(target)?;
-                self.compile_expr(value)?;
-                self.module.emit(Op::Pow);
-                self.module.emit(Op::Dup);
-                self.compile_store(target)?;
+                self.compile_update(target, false, |c| {
+                    c.compile_expr(value)?;
+                    c.compile_pow()
+                })?;
             }
 
             Expr::Call(name, args) => {
@@ -536,7 +773,7 @@ impl Compiler {
                     self.module.emit(Op::Call);
                     self.module.emit_u8(idx);
                 } else {
-                    return Err(format!("Undefined function: {}", name));
+                    return Err(BuildError::compile(format!("Undefined function: {}", name)));
                 }
             }
 
@@ -552,7 +789,7 @@ impl Compiler {
 
             Expr::Sqrt(a) => {
                 self.compile_expr(a)?;
-                self.module.emit(Op::Sqrt);
+                self.compile_sqrt()?;
             }
 
             Expr::Read => {
@@ -563,15 +800,354 @@ impl Compiler {
         Ok(())
     }
 
-    fn compile_store(&mut self, target: &Expr) -> Result<(), String> {
+    /// Lower a % b (both on the stack) the way bc defines it:
+    /// a - (a / b) * b, with the division done at the current scale. The
+    /// product keeps scale + scale(b) digits, so the result has scale
+    /// max(scale + scale(b), scale(a)).
+    fn compile_mod(&mut self) -> Result<(), BuildError> {
+        let a = self.get_or_create_var("mod a")?;
+        let b = self.get_or_create_var("mod b")?;
+
+        self.module.emit(Op::StoreVar);
+        self.module.emit_u8(b);
+        self.module.emit(Op::StoreVar);
+        self.module.emit_u8(a);
+
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(a);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(a);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(b);
+        self.module.emit(Op::Div);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(b);
+        self.module.emit(Op::Mul);
+        self.module.emit(Op::Sub);
+        Ok(())
+    }
+
+    /// Lower x ^ n (n on top of the stack) to repeated multiplication. Like
+    /// bc, n must have no fractional digits; a negative n gives 1 / x^|n|
+    /// at the current scale.
+    fn compile_pow(&mut self) -> Result<(), BuildError> {
+        let x = self.get_or_create_var("pow x")?;
+        let n = self.get_or_create_var("pow n")?;
+        let k = self.get_or_create_var("pow k")?;
+        let r = self.get_or_create_var("pow r")?;
+
+        self.module.emit(Op::StoreVar);
+        self.module.emit_u8(n);
+        self.module.emit(Op::StoreVar);
+        self.module.emit_u8(x);
+
+        // scale(n) != 0 is a runtime error
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(n);
+        self.module.emit(Op::ScaleOf);
+        self.module.emit(Op::LoadZero);
+        self.module.emit(Op::Eq);
+        let integral = self.module.current_offset();
+        self.module.emit(Op::JumpIfNotZero);
+        self.module.emit_u16(0);
+        let msg = self.module.add_string("Runtime error: non-zero scale in exponent\n".to_string());
+        self.module.emit(Op::PrintStr);
+        self.module.emit_u16(msg);
+        self.module.emit(Op::Halt);
+        let addr = self.module.current_offset() as u16;
+        self.module.patch_u16(integral + 1, addr);
+
+        // k = |n|, r = 1
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(n);
+        self.module.emit(Op::LoadZero);
+        self.module.emit(Op::Lt);
+        let positive = self.module.current_offset();
+        self.module.emit(Op::JumpIfZero);
+        self.module.emit_u16(0);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(n);
+        self.module.emit(Op::Neg);
+        let have_k = self.module.current_offset();
+        self.module.emit(Op::Jump);
+        self.module.emit_u16(0);
+        let addr = self.module.current_offset() as u16;
+        self.module.patch_u16(positive + 1, addr);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(n);
+        let addr = self.module.current_offset() as u16;
+        self.module.patch_u16(have_k + 1, addr);
+        self.module.emit(Op::StoreVar);
+        self.module.emit_u8(k);
+        self.module.emit(Op::LoadOne);
+        self.module.emit(Op::StoreVar);
+        self.module.emit_u8(r);
+
+        // while (k > 0) { r = r * x; k = k - 1 }
+        let loop_start = self.module.current_offset() as u16;
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(k);
+        self.module.emit(Op::LoadZero);
+        self.module.emit(Op::Gt);
+        let done = self.module.current_offset();
+        self.module.emit(Op::JumpIfZero);
+        self.module.emit_u16(0);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(r);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(x);
+        self.module.emit(Op::Mul);
+        self.module.emit(Op::StoreVar);
+        self.module.emit_u8(r);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(k);
+        self.module.emit(Op::LoadOne);
+        self.module.emit(Op::Sub);
+        self.module.emit(Op::StoreVar);
+        self.module.emit_u8(k);
+        self.module.emit(Op::Jump);
+        self.module.emit_u16(loop_start);
+        let addr = self.module.current_offset() as u16;
+        self.module.patch_u16(done + 1, addr);
+
+        // n < 0: r = 1 / r
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(n);
+        self.module.emit(Op::LoadZero);
+        self.module.emit(Op::Lt);
+        let finished = self.module.current_offset();
+        self.module.emit(Op::JumpIfZero);
+        self.module.emit_u16(0);
+        self.module.emit(Op::LoadOne);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(r);
+        self.module.emit(Op::Div);
+        self.module.emit(Op::StoreVar);
+        self.module.emit_u8(r);
+        let addr = self.module.current_offset() as u16;
+        self.module.patch_u16(finished + 1, addr);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(r);
+        Ok(())
+    }
+
+    /// Lower sqrt of the value on the stack to a Newton iteration at
+    /// max(scale, scale(x)), as in bc: starting above the root at x+1,
+    /// g = (g + x/g) / 2 shrinks until it stops decreasing. Divisions
+    /// truncate, so this leaves sqrt(x) truncated to that many digits.
+    /// `scale` is raised for the iteration and put back afterwards. A
+    /// negative argument prints an error and halts.
+    fn compile_sqrt(&mut self) -> Result<(), BuildError> {
+        // Hidden slots; the names cannot clash with bc identifiers
+        let x = self.get_or_create_var("sqrt x")?;
+        let g = self.get_or_create_var("sqrt g")?;
+        let next = self.get_or_create_var("sqrt next")?;
+        let saved_scale = self.get_or_create_var("sqrt scale")?;
+
+        self.module.emit(Op::StoreVar);
+        self.module.emit_u8(x);
+
+        // x < 0 is a runtime error
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(x);
+        self.module.emit(Op::LoadZero);
+        self.module.emit(Op::Lt);
+        let non_negative = self.module.current_offset();
+        self.module.emit(Op::JumpIfZero);
+        self.module.emit_u16(0);
+        let msg = self.module.add_string("Runtime error: square root of a negative number\n".to_string());
+        self.module.emit(Op::PrintStr);
+        self.module.emit_u16(msg);
+        self.module.emit(Op::Halt);
+        let addr = self.module.current_offset() as u16;
+        self.module.patch_u16(non_negative + 1, addr);
+
+        // sqrt(0) = 0, which would otherwise end with a division by zero
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(x);
+        self.module.emit(Op::LoadZero);
+        self.module.emit(Op::Eq);
+        let non_zero = self.module.current_offset();
+        self.module.emit(Op::JumpIfZero);
+        self.module.emit_u16(0);
+        self.module.emit(Op::LoadZero);
+        let zero_done = self.module.current_offset();
+        self.module.emit(Op::Jump);
+        self.module.emit_u16(0);
+        let addr = self.module.current_offset() as u16;
+        self.module.patch_u16(non_zero + 1, addr);
+
+        // scale = max(scale, scale(x)) until the root is found
+        self.module.emit(Op::LoadScale);
+        self.module.emit(Op::StoreVar);
+        self.module.emit_u8(saved_scale);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(x);
+        self.module.emit(Op::ScaleOf);
+        self.module.emit(Op::LoadScale);
+        self.module.emit(Op::Gt);
+        let scale_kept = self.module.current_offset();
+        self.module.emit(Op::JumpIfZero);
+        self.module.emit_u16(0);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(x);
+        self.module.emit(Op::ScaleOf);
+        self.module.emit(Op::StoreScale);
+        let addr = self.module.current_offset() as u16;
+        self.module.patch_u16(scale_kept + 1, addr);
+
+        // g = x/1 + 1/1; dividing by one keeps every iterate at exactly
+        // `scale` digits
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(x);
+        self.module.emit(Op::LoadOne);
+        self.module.emit(Op::Div);
+        self.module.emit(Op::LoadOne);
+        self.module.emit(Op::LoadOne);
+        self.module.emit(Op::Div);
+        self.module.emit(Op::Add);
+        self.module.emit(Op::StoreVar);
+        self.module.emit_u8(g);
+
+        // next = (g + x/g) / 2
+        let loop_start = self.module.current_offset() as u16;
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(g);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(x);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(g);
+        self.module.emit(Op::Div);
+        self.module.emit(Op::Add);
+        let two = self.module.add_number(BcNum::parse("2"));
+        self.module.emit(Op::LoadNum);
+        self.module.emit_u16(two);
+        self.module.emit(Op::Div);
+        self.module.emit(Op::StoreVar);
+        self.module.emit_u8(next);
+
+        // Stop once next >= g
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(next);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(g);
+        self.module.emit(Op::Lt);
+        let converged = self.module.current_offset();
+        self.module.emit(Op::JumpIfZero);
+        self.module.emit_u16(0);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(next);
+        self.module.emit(Op::StoreVar);
+        self.module.emit_u8(g);
+        self.module.emit(Op::Jump);
+        self.module.emit_u16(loop_start);
+
+        let addr = self.module.current_offset() as u16;
+        self.module.patch_u16(converged + 1, addr);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(g);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(saved_scale);
+        self.module.emit(Op::StoreScale);
+
+        let addr = self.module.current_offset() as u16;
+        self.module.patch_u16(zero_done + 1, addr);
+        Ok(())
+    }
+
+    /// Read `target`, run `update` to turn its value into the new one,
+    /// store that back and leave the new value, or the old one if `post`,
+    /// on the stack. An array element's index is evaluated only once: it
+    /// stays on the stack under the value until the store.
+    fn compile_update(
+        &mut self,
+        target: &Expr,
+        post: bool,
+        update: impl FnOnce(&mut Self) -> Result<(), BuildError>,
+    ) -> Result<(), BuildError> {
+        let Expr::ArrayElement(name, index) = target else {
+            self.compile_expr(target)?;
+            if post {
+                self.module.emit(Op::Dup);
+                update(self)?;
+            } else {
+                update(self)?;
+                self.module.emit(Op::Dup);
+            }
+            return self.compile_store(target);
+        };
+
+        let slot = self.get_or_create_var(&array_key(name))?;
+        self.compile_expr(index)?;
+        self.module.emit(Op::Dup);
+        self.module.emit(Op::LoadArray);
+        self.module.emit_u8(slot);
+
+        // The result waits in a hidden variable while the new value and
+        // index are swapped into StoreArray's order. `update` only runs
+        // while it is live when it is a bare Inc or Dec, so nested updates
+        // cannot clobber it.
+        let result = self.get_or_create_var("update result")?;
+        if post {
+            self.module.emit(Op::StoreVar);
+            self.module.emit_u8(result);
+            self.module.emit(Op::LoadVar);
+            self.module.emit_u8(result);
+            update(self)?;
+        } else {
+            update(self)?;
+            self.module.emit(Op::StoreVar);
+            self.module.emit_u8(result);
+            self.module.emit(Op::LoadVar);
+            self.module.emit_u8(result);
+        }
+        self.module.emit(Op::Swap);
+        self.module.emit(Op::StoreArray);
+        self.module.emit_u8(slot);
+        self.module.emit(Op::LoadVar);
+        self.module.emit_u8(result);
+        Ok(())
+    }
+
+    /// Compile an `if` or loop condition and a jump, taken when it is false,
+    /// whose offset is returned for patching. A comparison branches with a
+    /// fused `JumpIf<cmp>` rather than pushing 0 or 1 for `JumpIfZero`.
+    fn compile_jump_unless(&mut self, cond: &Expr) -> Result<usize, BuildError> {
+        let fused = match cond {
+            Expr::Eq(a, b) => Some((Op::JumpIfNe, a, b)),
+            Expr::Ne(a, b) => Some((Op::JumpIfEq, a, b)),
+            Expr::Lt(a, b) => Some((Op::JumpIfGe, a, b)),
+            Expr::Le(a, b) => Some((Op::JumpIfGt, a, b)),
+            Expr::Gt(a, b) => Some((Op::JumpIfLe, a, b)),
+            Expr::Ge(a, b) => Some((Op::JumpIfLt, a, b)),
+            _ => None,
+        };
+        let op = match fused {
+            Some((op, a, b)) => {
+                self.compile_expr(a)?;
+                self.compile_expr(b)?;
+                op
+            }
+            None => {
+                self.compile_expr(cond)?;
+                Op::JumpIfZero
+            }
+        };
+        let jump = self.module.current_offset();
+        self.module.emit(op);
+        self.module.emit_u16(0); // Placeholder
+        Ok(jump)
+    }
+
+    fn compile_store(&mut self, target: &Expr) -> Result<(), BuildError> {
         match target {
             Expr::Var(name) => {
-                let slot = self.get_or_create_var(name);
+                let slot = self.get_or_create_var(name)?;
                 self.module.emit(Op::StoreVar);
                 self.module.emit_u8(slot);
             }
             Expr::ArrayElement(name, index) => {
-                let slot = self.get_or_create_var(name);
+                let slot = self.get_or_create_var(&array_key(name))?;
                 self.compile_expr(index)?;
                 self.module.emit(Op::StoreArray);
                 self.module.emit_u8(slot);
@@ -585,20 +1161,30 @@ impl Compiler {
             Expr::Obase => {
                 self.module.emit(Op::StoreObase);
             }
-            _ => return Err("Invalid assignment target".to_string()),
+            _ => return Err(BuildError::compile("Invalid assignment target")),
         }
         Ok(())
     }
 
-    fn get_or_create_var(&mut self, name: &str) -> u8 {
-        if let Some(&slot) = self.variables.get(name) {
-            slot
-        } else {
-            let slot = self.next_var_slot;
-            self.variables.insert(name.to_string(), slot);
-            self.next_var_slot += 1;
-            slot
+    fn get_or_create_var(&mut self, name: &str) -> Result<u8, BuildError> {
+        match self.variables.get(name) {
+            Some(&slot) => Ok(slot),
+            None => self.new_var(name.to_string()),
+        }
+    }
+
+    fn new_var(&mut self, name: String) -> Result<u8, BuildError> {
+        if self.next_var_slot == MAX_VAR_SLOTS {
+            return Err(BuildError::compile(format!(
+                "too many variables: the runtime has {} slots",
+                MAX_VAR_SLOTS
+            )));
         }
+        let slot = self.next_var_slot;
+        self.variables.insert(name, slot);
+        self.next_var_slot += 1;
+        self.peak_var_slots = self.peak_var_slots.max(self.next_var_slot);
+        Ok(slot)
     }
 
     fn is_assignment(expr: &Expr) -> bool {
@@ -619,6 +1205,125 @@ impl Compiler {
     }
 }
 
+/// True if every path through the statement ends in a return
+fn always_returns(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Return(_) => true,
+        Stmt::Block(body) => {
+            for stmt in body {
+                if always_returns(stmt) {
+                    return true;
+                }
+                if matches!(stmt, Stmt::Break | Stmt::Continue) {
+                    return false;
+                }
+            }
+            false
+        }
+        Stmt::If { then_branch, else_branch: Some(else_branch), .. } => {
+            always_returns(then_branch) && always_returns(else_branch)
+        }
+        _ => false,
+    }
+}
+
+/// Variable-table name of array `name`, which has its own slot: in bc `a`
+/// and `a[]` are unrelated
+fn array_key(name: &str) -> String {
+    format!("{}[]", name)
+}
+
+/// Variable-table name of a parameter or auto
+fn local_key(name: &str, is_array: bool) -> String {
+    if is_array {
+        array_key(name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// Append the variable-table names of every variable and array the
+/// statement mentions
+fn collect_names(stmt: &Stmt, out: &mut Vec<String>) {
+    match stmt {
+        Stmt::Expr(expr) | Stmt::Return(Some(expr)) => collect_expr_names(expr, out),
+        Stmt::Print(items) => {
+            for item in items {
+                if let PrintItem::Expr(expr) = item {
+                    collect_expr_names(expr, out);
+                }
+            }
+        }
+        Stmt::Block(stmts) => stmts.iter().for_each(|stmt| collect_names(stmt, out)),
+        Stmt::If { cond, then_branch, else_branch } => {
+            collect_expr_names(cond, out);
+            collect_names(then_branch, out);
+            if let Some(else_branch) = else_branch {
+                collect_names(else_branch, out);
+            }
+        }
+        Stmt::While { cond, body } => {
+            collect_expr_names(cond, out);
+            collect_names(body, out);
+        }
+        Stmt::For { init, cond, update, body } => {
+            for expr in [init, cond, update].into_iter().flatten() {
+                collect_expr_names(expr, out);
+            }
+            collect_names(body, out);
+        }
+        _ => {}
+    }
+}
+
+fn collect_expr_names(expr: &Expr, out: &mut Vec<String>) {
+    let name = match expr {
+        Expr::Var(name) => name.clone(),
+        Expr::ArrayElement(name, index) => {
+            collect_expr_names(index, out);
+            array_key(name)
+        }
+        Expr::Not(a)
+        | Expr::Neg(a)
+        | Expr::PreInc(a)
+        | Expr::PreDec(a)
+        | Expr::PostInc(a)
+        | Expr::PostDec(a)
+        | Expr::Length(a)
+        | Expr::ScaleFunc(a)
+        | Expr::Sqrt(a) => return collect_expr_names(a, out),
+        Expr::Add(a, b)
+        | Expr::Sub(a, b)
+        | Expr::Mul(a, b)
+        | Expr::Div(a, b)
+        | Expr::Mod(a, b)
+        | Expr::Pow(a, b)
+        | Expr::Eq(a, b)
+        | Expr::Ne(a, b)
+        | Expr::Lt(a, b)
+        | Expr::Le(a, b)
+        | Expr::Gt(a, b)
+        | Expr::Ge(a, b)
+        | Expr::And(a, b)
+        | Expr::Or(a, b)
+        | Expr::Assign(a, b)
+        | Expr::AddAssign(a, b)
+        | Expr::SubAssign(a, b)
+        | Expr::MulAssign(a, b)
+        | Expr::DivAssign(a, b)
+        | Expr::ModAssign(a, b)
+        | Expr::PowAssign(a, b) => {
+            collect_expr_names(a, out);
+            return collect_expr_names(b, out);
+        }
+        Expr::Call(_, args) => return args.iter().for_each(|arg| collect_expr_names(arg, out)),
+        _ => return,
+    };
+    if !out.contains(&name) {
+        out.push(name);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -626,7 +1331,7 @@ mod tests {
     #[test]
     fn test_compile_number() {
         let module = Compiler::compile("42").unwrap();
-        assert!(module.bytecode.len() > 0);
+        assert!(!module.bytecode.is_empty());
     }
 
     #[test]
@@ -635,9 +1340,205 @@ mod tests {
         assert!(module.bytecode.contains(&(Op::Add as u8)));
     }
 
+    #[test]
+    fn test_negated_comparisons_lowered() {
+        let module = Compiler::compile("3 <= 3").unwrap();
+        let gt = module.bytecode.iter().position(|&b| b == Op::Gt as u8).unwrap();
+        assert_eq!(module.bytecode[gt + 1], Op::Not as u8);
+        assert!(!module.bytecode.contains(&(Op::Le as u8)));
+    }
+
+    #[test]
+    fn test_function_expression_statement_prints_line() {
+        let module = Compiler::compile("define f(x) { x; print x; return x }").unwrap();
+        let body = &module.bytecode[module.functions[0].bytecode_offset..];
+        let print = body.iter().position(|&b| b == Op::Print as u8).unwrap();
+        assert_eq!(body[print + 1], Op::PrintNewline as u8);
+        // The print statement adds no second newline
+        assert_eq!(body.iter().filter(|&&b| b == Op::PrintNewline as u8).count(), 1);
+    }
+
+    #[test]
+    fn test_string_type_errors() {
+        let sources = [
+            "\"x\" + 1",
+            "a = \"x\"",
+            "print 1 - \"x\"",
+            "if (\"x\" < 2) 1",
+            "sqrt(\"x\")",
+            // Not hidden by the identity simplifier
+            "\"x\" + 0",
+            "\"x\" * 0",
+        ];
+        for source in sources {
+            let err = Compiler::compile(source).unwrap_err();
+            assert!(matches!(&err, BuildError::Compile { msg } if msg.starts_with("Type error")), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_bare_string_statement() {
+        let module = Compiler::compile("\"hi\"").unwrap();
+        assert_eq!(module.bytecode, vec![Op::PrintStr as u8, 0, 0, Op::Quit as u8]);
+        assert_eq!(module.strings, vec!["hi".to_string()]);
+    }
+
+    #[test]
+    fn test_quit_and_halt_differ() {
+        let quit = Compiler::compile("quit").unwrap();
+        let halt = Compiler::compile("halt").unwrap();
+        assert_eq!(quit.bytecode, vec![Op::Quit as u8, Op::Quit as u8]);
+        assert_eq!(halt.bytecode, vec![Op::Halt as u8, Op::Quit as u8]);
+    }
+
+    #[test]
+    fn test_compile_expression() {
+        let module = Compiler::compile_expression("2+2").unwrap();
+        assert_eq!(module.bytecode[module.bytecode.len() - 2..], [Op::Print as u8, Op::Halt as u8]);
+        assert!(module.bytecode.contains(&(Op::Add as u8)));
+
+        // Statements and trailing input are not expressions
+        assert!(Compiler::compile_expression("print 1").is_err());
+        assert!(Compiler::compile_expression("1 2").is_err());
+    }
+
+    #[test]
+    fn test_literal_ibase() {
+        let at_ibase = |source, ibase| Compiler::compile_with(source, &CompileOptions { ibase, ..Default::default() });
+        let module = at_ibase("FF", 16).unwrap();
+        assert_eq!(module.numbers[0].to_string(), "255");
+        assert_eq!(module.bytecode[0], Op::LoadNum as u8);
+        assert!(at_ibase("102", 2).is_err());
+        assert!(at_ibase("1", 17).is_err());
+    }
+
+    #[test]
+    fn test_literal_digits_checked_against_ibase() {
+        let err = Compiler::compile("x = 1A").unwrap_err();
+        assert_eq!(err, BuildError::compile("1A is not a valid base 10 number"));
+        let options = CompileOptions { ibase: 16, ..Default::default() };
+        let module = Compiler::compile_with("x = 1A", &options).unwrap();
+        assert_eq!(module.numbers[0].to_string(), "26");
+        // Decimal literals come out as before
+        assert_eq!(Compiler::compile("1.50").unwrap().numbers[0].to_string(), "1.50");
+    }
+
+    #[test]
+    fn test_too_many_variables() {
+        let names: Vec<String> = (0..30).map(|i| format!("v{}", i)).collect();
+        let fits = names[..26].iter().map(|name| format!("{} = 1\n", name)).collect::<String>();
+        assert!(Compiler::compile(&fits).is_ok());
+        let source = names.iter().map(|name| format!("{} = 1\n", name)).collect::<String>();
+        let err = Compiler::compile(&source).unwrap_err();
+        assert_eq!(err, BuildError::compile("too many variables: the runtime has 26 slots"));
+        // Hidden temporaries count too
+        assert!(Compiler::compile(&format!("{}x % 2\n", fits)).is_err());
+    }
+
+    #[test]
+    fn test_program_too_large() {
+        // Each line stores a constant: a few bytes of bytecode apiece
+        let line = "x = 7\n";
+        let per_line = Compiler::compile(&line.repeat(2)).unwrap().bytecode.len()
+            - Compiler::compile(line).unwrap().bytecode.len();
+        let fits = MAX_BYTECODE_SIZE / per_line - 1;
+        assert!(Compiler::compile(&line.repeat(fits)).is_ok());
+        let err = Compiler::compile(&line.repeat(fits + 2)).unwrap_err();
+        assert!(err.to_string().contains("program too large"));
+    }
+
+    #[test]
+    fn test_unreachable_after_return() {
+        let module = Compiler::compile("x = 1\n  define f() { return 1; x = 2 }").unwrap();
+        assert_eq!(
+            module.warnings,
+            vec![Warning {
+                line: 2,
+                col: 3,
+                message: "unreachable statement after return in f(): x = 2".to_string()
+            }]
+        );
+        assert_eq!(
+            module.warnings[0].to_string(),
+            "warning at 2:3: unreachable statement after return in f(): x = 2"
+        );
+
+        // Both branches return, so the print is dead; a trailing `;` is fine
+        let module = Compiler::compile("define g(n) { if (n) return 1 else { return 2; }; print n }").unwrap();
+        assert_eq!(module.warnings.len(), 1);
+        assert!(module.warnings[0].message.ends_with("print n"));
+
+        let module = Compiler::compile("define h(n) { if (n) return 1; return 2; }\nh(1)").unwrap();
+        assert!(module.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_var_slots_warning() {
+        let names: Vec<String> = (0..VAR_SLOTS_WARNING).map(|i| format!("v{} = 1", i)).collect();
+        let module = Compiler::compile(&names.join("\n")).unwrap();
+        assert_eq!(module.warnings.len(), 1);
+        assert_eq!(module.warnings[0].to_string(), "warning: 24 of the runtime's 26 variable slots used");
+
+        let module = Compiler::compile(&names[1..].join("\n")).unwrap();
+        assert!(module.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_condition_branches_on_comparison() {
+        let ops = Compiler::compile("if (a < b) print 2\nwhile (a != b) a = a + 2\n").unwrap().used_ops();
+        assert!(ops.contains(&Op::JumpIfGe) && ops.contains(&Op::JumpIfEq));
+        for op in [Op::Lt, Op::Eq, Op::Not, Op::LoadZero, Op::LoadOne, Op::JumpIfZero] {
+            assert!(!ops.contains(&op), "{:?} emitted", op);
+        }
+
+        // Anything else is still tested against zero
+        let ops = Compiler::compile("if (a) print 2\n").unwrap().used_ops();
+        assert!(ops.contains(&Op::JumpIfZero));
+    }
+
+    #[test]
+    fn test_quiet() {
+        let quiet = CompileOptions { quiet: true, ..Default::default() };
+        let ops = Compiler::compile_with("2+2\nx = 3\n\"hi\"\n", &quiet).unwrap().used_ops();
+        assert!(!ops.contains(&Op::Print) && !ops.contains(&Op::PrintNewline));
+        assert!(ops.contains(&Op::PrintStr));
+
+        let ops = Compiler::compile_with("print 2+2\n", &quiet).unwrap().used_ops();
+        assert!(ops.contains(&Op::Print));
+    }
+
     #[test]
     fn test_compile_variable() {
         let module = Compiler::compile("a = 5").unwrap();
         assert!(module.bytecode.contains(&(Op::StoreVar as u8)));
     }
+
+    #[test]
+    fn test_compile_for_without_condition() {
+        // No exit test is emitted; break must land past the back-edge
+        let module = Compiler::compile("for(;;) break").unwrap();
+        assert!(!module.bytecode.contains(&(Op::JumpIfZero as u8)));
+        let halt = module.bytecode.len() as u16 - 1;
+        assert_eq!(module.bytecode[0], Op::Jump as u8);
+        assert_eq!(u16::from_le_bytes([module.bytecode[1], module.bytecode[2]]), halt);
+    }
+
+    #[test]
+    fn test_for_continue_targets_update() {
+        let module = Compiler::compile("for(i=0;i<3;i++){continue}").unwrap();
+        let bc = &module.bytecode;
+        // Body is a single Jump; the update starts right after it
+        let cont = bc.iter().rposition(|&b| b == Op::JumpIfGe as u8).unwrap() + 3;
+        assert_eq!(bc[cont], Op::Jump as u8);
+        let target = u16::from_le_bytes([bc[cont + 1], bc[cont + 2]]) as usize;
+        assert_eq!(target, cont + 3);
+        assert_eq!(bc[target], Op::LoadVar as u8);
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        assert_eq!(Compiler::compile("break").unwrap_err(), BuildError::compile("break outside loop"));
+        assert!(matches!(Compiler::compile("f(1)"), Err(BuildError::Compile { .. })));
+        assert!(matches!(Compiler::compile("1 +"), Err(BuildError::Parse { .. })));
+    }
 }
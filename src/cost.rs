@@ -0,0 +1,128 @@
+//! Static cost estimate for compiled bytecode
+//!
+//! Counts the bytecode instructions and adds up a rough Z80 cycle cost for
+//! each one's runtime handler. Loop bodies (the instructions between a
+//! backward jump and its target) are counted `LOOP_ITERATIONS` times per
+//! level of nesting, and function bodies once. BCD arithmetic depends on
+//! the digits involved, so the figures are for comparing builds, such as
+//! with and without `--optimize`, not for predicting run time.
+
+use crate::bytecode::{CompiledModule, Op};
+
+/// Iterations assumed for every loop
+pub const LOOP_ITERATIONS: u64 = 10;
+
+/// What running the program once is estimated to cost
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CostEstimate {
+    /// Bytecode instructions in the program, each counted once
+    pub instructions: usize,
+    /// Z80 cycles, with loop bodies weighted by LOOP_ITERATIONS
+    pub cycles: u64,
+}
+
+/// Approximate Z80 cycles the runtime spends on one instruction, dispatch
+/// included. Measured on small operands (a few digits each).
+pub fn op_cycles(op: Op) -> u64 {
+    match op {
+        Op::Halt | Op::Nop | Op::Pop | Op::Dup | Op::Swap | Op::Quit => 300,
+        Op::LoadZero | Op::LoadOne | Op::LoadNum | Op::LoadStr | Op::LoadVar | Op::StoreVar => 500,
+        Op::LoadScale | Op::StoreScale | Op::LoadIbase | Op::StoreIbase => 500,
+        Op::LoadObase | Op::StoreObase | Op::LoadLast => 500,
+        Op::LoadArray | Op::StoreArray => 9_000,
+        Op::Add => 7_700,
+        Op::Sub => 9_200,
+        Op::Mul => 160_000,
+        Op::Div | Op::DivRound => 240_000,
+        Op::Mod => 400_000,
+        Op::Pow => 850_000,
+        Op::Neg => 1_900,
+        Op::Eq | Op::Ne | Op::Lt | Op::Le | Op::Gt | Op::Ge => 7_300,
+        Op::And | Op::Or => 700,
+        Op::Not => 2_400,
+        Op::Inc | Op::Dec => 8_600,
+        Op::Jump => 400,
+        Op::JumpIfZero | Op::JumpIfNotZero => 600,
+        Op::JumpIfEq | Op::JumpIfNe | Op::JumpIfLt => 7_500,
+        Op::JumpIfLe | Op::JumpIfGt | Op::JumpIfGe => 7_500,
+        Op::Call => 3_000,
+        Op::Return | Op::ReturnValue => 2_000,
+        Op::Length | Op::ScaleOf => 3_300,
+        Op::Sqrt => 2_500_000,
+        Op::Print => 2_400,
+        Op::PrintStr => 700,
+        Op::PrintNewline => 400,
+        Op::Read => 20_000,
+        Op::Trap => 5_000,
+    }
+}
+
+/// Estimate the cost of the module's bytecode. Decoding stops at the first
+/// byte that is not an opcode.
+pub fn estimate(module: &CompiledModule) -> CostEstimate {
+    let mut insns = Vec::new();
+    let mut offset = 0;
+    while let Some(op) = module.bytecode.get(offset).and_then(|&byte| Op::from_u8(byte)) {
+        insns.push((offset, op));
+        offset += 1 + op.operand_len();
+    }
+
+    // A jump to an earlier instruction closes a loop over that range
+    let loops: Vec<(usize, usize)> = insns
+        .iter()
+        .filter(|(_, op)| op.is_jump())
+        .filter_map(|&(offset, _)| {
+            let operand = module.bytecode.get(offset + 1..offset + 3)?;
+            let target = u16::from_le_bytes([operand[0], operand[1]]) as usize;
+            (target <= offset).then_some((target, offset))
+        })
+        .collect();
+
+    let cycles = insns
+        .iter()
+        .map(|&(offset, op)| {
+            let depth = loops.iter().filter(|&&(start, end)| (start..=end).contains(&offset)).count();
+            op_cycles(op) * LOOP_ITERATIONS.pow(depth as u32)
+        })
+        .sum();
+
+    CostEstimate { instructions: insns.len(), cycles }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+
+    fn estimate_source(source: &str) -> CostEstimate {
+        estimate(&Compiler::compile(source).unwrap())
+    }
+
+    #[test]
+    fn test_loop_body_weighted() {
+        // y = y * 2 compiles to these six instructions
+        let extra: u64 = [Op::LoadVar, Op::LoadNum, Op::Mul, Op::Dup, Op::StoreVar, Op::Pop]
+            .into_iter()
+            .map(op_cycles)
+            .sum();
+
+        let small = estimate_source("for (i = 0; i < 5; i++) { x = x + 1 }\n");
+        let big = estimate_source("for (i = 0; i < 5; i++) { x = x + 1; y = y * 2 }\n");
+        assert_eq!(big.instructions, small.instructions + 6);
+        assert_eq!(big.cycles - small.cycles, LOOP_ITERATIONS * extra);
+
+        let after = estimate_source("for (i = 0; i < 5; i++) { x = x + 1 }\ny = y * 2\n");
+        assert_eq!(after.cycles - small.cycles, extra);
+
+        let nested = estimate_source("for (i = 0; i < 5; i++) { x = x + 1; while (y < 3) y = y * 2 }\n");
+        assert!(nested.cycles > small.cycles + LOOP_ITERATIONS * LOOP_ITERATIONS * extra);
+    }
+
+    #[test]
+    fn test_straight_line() {
+        let cost = estimate_source("print 1\n");
+        // LoadOne, Print, Quit
+        assert_eq!(cost.instructions, 3);
+        assert_eq!(cost.cycles, op_cycles(Op::LoadOne) + op_cycles(Op::Print) + op_cycles(Op::Quit));
+    }
+}
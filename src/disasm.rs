@@ -0,0 +1,304 @@
+//! Z80 disassembler for annotated ROM dumps
+//!
+//! Decodes the documented Z80 instruction set (plus the IXH/IXL forms) so
+//! the generated runtime can be read back as mnemonics. `annotate` lists
+//! the runtime region with the symbol map's routine names as labels and
+//! each VM dispatch case commented with its bytecode opcode.
+
+use crate::bytecode::Op;
+use std::collections::BTreeMap;
+
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+const ALU: [&str; 8] = ["ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP "];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SLL", "SRL"];
+const BLOCK: [[&str; 4]; 4] = [
+    ["LDI", "CPI", "INI", "OUTI"],
+    ["LDD", "CPD", "IND", "OUTD"],
+    ["LDIR", "CPIR", "INIR", "OTIR"],
+    ["LDDR", "CPDR", "INDR", "OTDR"],
+];
+
+/// Reads one instruction, tracking how many bytes it has used
+struct Decoder<'a> {
+    code: &'a [u8],
+    addr: u16,
+    len: usize,
+    /// "IX" or "IY" after a DD or FD prefix
+    index: Option<&'static str>,
+}
+
+impl Decoder<'_> {
+    fn byte(&mut self) -> u8 {
+        let byte = self.code.get(self.addr as usize + self.len).copied().unwrap_or(0);
+        self.len += 1;
+        byte
+    }
+
+    fn word(&mut self) -> u16 {
+        let lo = self.byte() as u16;
+        lo | (self.byte() as u16) << 8
+    }
+
+    fn nn(&mut self) -> String {
+        format!("0x{:04X}", self.word())
+    }
+
+    fn n(&mut self) -> String {
+        format!("0x{:02X}", self.byte())
+    }
+
+    /// Target of a relative jump whose displacement is the next byte
+    fn rel(&mut self) -> String {
+        let d = self.byte() as i8;
+        let target = self.addr.wrapping_add(self.len as u16).wrapping_add(d as u16);
+        format!("0x{:04X}", target)
+    }
+
+    fn hl(&self) -> &'static str {
+        self.index.unwrap_or("HL")
+    }
+
+    /// An 8-bit register operand. With an index prefix, `(HL)` becomes
+    /// `(IX+d)` and, unless `plain` is set because the instruction also
+    /// addresses memory, H and L become the index register's halves.
+    fn r(&mut self, i: u8, plain: bool) -> String {
+        match (self.index, i) {
+            (Some(index), 6) => {
+                let d = self.byte() as i8;
+                if d < 0 {
+                    format!("({}-0x{:02X})", index, -(d as i16))
+                } else {
+                    format!("({}+0x{:02X})", index, d)
+                }
+            }
+            (Some(index), 4 | 5) if !plain => format!("{}{}", index, R[i as usize]),
+            _ => R[i as usize].to_string(),
+        }
+    }
+
+    fn rp(&self, p: u8) -> &'static str {
+        if p == 2 { self.hl() } else { RP[p as usize] }
+    }
+
+    fn rp2(&self, p: u8) -> &'static str {
+        if p == 2 { self.hl() } else { RP2[p as usize] }
+    }
+
+    fn decode(&mut self) -> String {
+        let op = self.byte();
+        let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+        let (p, q) = (y >> 1, y & 1);
+        match (x, z) {
+            (0, 0) => match y {
+                0 => "NOP".into(),
+                1 => "EX AF,AF'".into(),
+                2 => format!("DJNZ {}", self.rel()),
+                3 => format!("JR {}", self.rel()),
+                _ => format!("JR {},{}", CC[y as usize - 4], self.rel()),
+            },
+            (0, 1) if q == 0 => format!("LD {},{}", self.rp(p), self.nn()),
+            (0, 1) => format!("ADD {},{}", self.hl(), self.rp(p)),
+            (0, 2) => match (q, p) {
+                (0, 0) => "LD (BC),A".into(),
+                (0, 1) => "LD (DE),A".into(),
+                (0, 2) => format!("LD ({}),{}", self.nn(), self.hl()),
+                (0, _) => format!("LD ({}),A", self.nn()),
+                (_, 0) => "LD A,(BC)".into(),
+                (_, 1) => "LD A,(DE)".into(),
+                (_, 2) => format!("LD {},({})", self.hl(), self.nn()),
+                _ => format!("LD A,({})", self.nn()),
+            },
+            (0, 3) => format!("{} {}", if q == 0 { "INC" } else { "DEC" }, self.rp(p)),
+            (0, 4) => format!("INC {}", self.r(y, false)),
+            (0, 5) => format!("DEC {}", self.r(y, false)),
+            (0, 6) => {
+                let r = self.r(y, false);
+                format!("LD {},{}", r, self.n())
+            }
+            (0, _) => ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"][y as usize].into(),
+            (1, _) if y == 6 && z == 6 => "HALT".into(),
+            (1, _) => {
+                let plain = y == 6 || z == 6;
+                let dst = self.r(y, plain);
+                format!("LD {},{}", dst, self.r(z, plain))
+            }
+            (2, _) => format!("{}{}", ALU[y as usize], self.r(z, false)),
+            (_, 0) => format!("RET {}", CC[y as usize]),
+            (_, 1) => match (q, p) {
+                (0, _) => format!("POP {}", self.rp2(p)),
+                (_, 0) => "RET".into(),
+                (_, 1) => "EXX".into(),
+                (_, 2) => format!("JP ({})", self.hl()),
+                _ => format!("LD SP,{}", self.hl()),
+            },
+            (_, 2) => format!("JP {},{}", CC[y as usize], self.nn()),
+            (_, 3) => match y {
+                0 => format!("JP {}", self.nn()),
+                1 => self.decode_cb(),
+                2 => format!("OUT ({}),A", self.n()),
+                3 => format!("IN A,({})", self.n()),
+                4 => format!("EX (SP),{}", self.hl()),
+                5 => "EX DE,HL".into(),
+                6 => "DI".into(),
+                _ => "EI".into(),
+            },
+            (_, 4) => format!("CALL {},{}", CC[y as usize], self.nn()),
+            (_, 5) => match (q, p) {
+                (0, _) => format!("PUSH {}", self.rp2(p)),
+                (_, 0) => format!("CALL {}", self.nn()),
+                (_, 2) => self.decode_ed(),
+                // A second index prefix cancels the first
+                _ if self.index.is_some() => {
+                    self.len -= 1;
+                    format!("DB 0x{:02X}", self.code[self.addr as usize])
+                }
+                _ => {
+                    self.index = Some(if p == 1 { "IX" } else { "IY" });
+                    self.decode()
+                }
+            },
+            (_, 6) => format!("{}{}", ALU[y as usize], self.n()),
+            _ => format!("RST 0x{:02X}", y * 8),
+        }
+    }
+
+    fn decode_cb(&mut self) -> String {
+        // With an index prefix the displacement comes before the opcode
+        let target = self.index.map(|_| self.r(6, true));
+        let op = self.byte();
+        let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+        let operand = target.unwrap_or_else(|| R[z as usize].to_string());
+        match x {
+            0 => format!("{} {}", ROT[y as usize], operand),
+            1 => format!("BIT {},{}", y, operand),
+            2 => format!("RES {},{}", y, operand),
+            _ => format!("SET {},{}", y, operand),
+        }
+    }
+
+    fn decode_ed(&mut self) -> String {
+        let op = self.byte();
+        let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+        let (p, q) = (y >> 1, y & 1);
+        match (x, z) {
+            (1, 0) if y == 6 => "IN (C)".into(),
+            (1, 0) => format!("IN {},(C)", R[y as usize]),
+            (1, 1) if y == 6 => "OUT (C),0".into(),
+            (1, 1) => format!("OUT (C),{}", R[y as usize]),
+            (1, 2) => format!("{} HL,{}", if q == 0 { "SBC" } else { "ADC" }, RP[p as usize]),
+            (1, 3) if q == 0 => format!("LD ({}),{}", self.nn(), RP[p as usize]),
+            (1, 3) => format!("LD {},({})", RP[p as usize], self.nn()),
+            (1, 4) => "NEG".into(),
+            (1, 5) => if y == 1 { "RETI" } else { "RETN" }.into(),
+            (1, 6) => format!("IM {}", [0, 0, 1, 2][y as usize & 3]),
+            (1, _) => ["LD I,A", "LD R,A", "LD A,I", "LD A,R", "RRD", "RLD", "NOP", "NOP"][y as usize].into(),
+            (2, 0..=3) if y >= 4 => BLOCK[y as usize - 4][z as usize].into(),
+            _ => format!("DB 0xED,0x{:02X}", op),
+        }
+    }
+}
+
+/// Decode the instruction at `addr`, returning its mnemonic and length.
+/// Bytes past the end of `code` read as zero.
+pub fn decode(code: &[u8], addr: u16) -> (String, usize) {
+    let mut decoder = Decoder { code, addr, len: 0, index: None };
+    let text = decoder.decode();
+    (text, decoder.len)
+}
+
+/// List the ROM's runtime region (up to the `padding` symbol) as Z80
+/// mnemonics, one instruction per line with its address and bytes. Each
+/// routine in `symbols` starts a labelled block, calls and jumps to a
+/// routine are commented with its name, and the `CP n` that selects a VM
+/// opcode's handler is commented with the opcode's name.
+/// An instruction that would run over a label is shown as `DB` bytes so
+/// every label lands on its own address.
+pub fn annotate(rom: &[u8], symbols: &BTreeMap<String, u16>) -> String {
+    let end = symbols.get("padding").map_or(rom.len(), |&addr| addr as usize).min(rom.len());
+    let vm_loop = symbols.get("vm_loop").map_or(usize::MAX, |&addr| addr as usize);
+    let mut labels: BTreeMap<usize, Vec<&str>> = BTreeMap::new();
+    for (name, &addr) in symbols {
+        if (addr as usize) < end {
+            labels.entry(addr as usize).or_default().push(name);
+        }
+    }
+
+    let mut out = String::new();
+    let mut addr = 0;
+    let mut prev = "";
+    while addr < end {
+        for name in labels.get(&addr).into_iter().flatten() {
+            out.push_str(&format!("{}:\n", name));
+        }
+
+        let (mut text, mut len) = decode(rom, addr as u16);
+        let next_label = labels.range(addr + 1..).next().map_or(end, |(&next, _)| next);
+        if addr + len > next_label.min(end) {
+            len = next_label.min(end) - addr;
+            let bytes: Vec<String> = rom[addr..addr + len].iter().map(|b| format!("0x{:02X}", b)).collect();
+            text = format!("DB {}", bytes.join(","));
+        }
+
+        let bytes: Vec<String> = rom[addr..addr + len].iter().map(|b| format!("{:02X}", b)).collect();
+        let line = format!("    {:04X}  {:<12} {}", addr, bytes.join(" "), text);
+        // Dispatch cases compare the opcode saved in B (or, for the first
+        // after Halt's test, still in A)
+        let dispatch = addr >= vm_loop && len == 2 && rom[addr] == 0xFE && matches!(prev, "LD A,B" | "HALT");
+        let target = text
+            .rsplit(['x', ' '])
+            .next()
+            .filter(|_| text.starts_with("CALL") || text.starts_with("JP"))
+            .and_then(|hex| u16::from_str_radix(hex, 16).ok());
+        let comment = match rom.get(addr + 1).filter(|_| dispatch).and_then(|&b| Op::from_u8(b)) {
+            Some(op) => Some(format!("{:?}", op)),
+            None => target.and_then(|target| labels.get(&(target as usize))).map(|names| names.join(", ")),
+        };
+        match comment {
+            Some(comment) => out.push_str(&format!("{:<44} ; {}\n", line, comment)),
+            None => out.push_str(&format!("{}\n", line)),
+        }
+
+        prev = if text == "LD A,B" { "LD A,B" } else if text == "HALT" { "HALT" } else { "" };
+        addr += len;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{build_rom, Z80Config};
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(&[0x21, 0x34, 0x12], 0), ("LD HL,0x1234".to_string(), 3));
+        assert_eq!(decode(&[0x00, 0x20, 0xFD], 1), ("JR NZ,0x0000".to_string(), 2));
+        assert_eq!(decode(&[0xCB, 0x7E], 0), ("BIT 7,(HL)".to_string(), 2));
+        assert_eq!(decode(&[0xDD, 0x66, 0x02], 0), ("LD H,(IX+0x02)".to_string(), 3));
+        assert_eq!(decode(&[0xFD, 0xCB, 0xFF, 0xC6], 0), ("SET 0,(IY-0x01)".to_string(), 4));
+        assert_eq!(decode(&[0xED, 0xB0], 0), ("LDIR".to_string(), 2));
+        assert_eq!(decode(&[0xED, 0x52], 0), ("SBC HL,DE".to_string(), 2));
+        assert_eq!(decode(&[0xD9], 0), ("EXX".to_string(), 1));
+    }
+
+    #[test]
+    fn test_annotate_labels_and_dispatch() {
+        let artifacts = build_rom("x = 2\nx + 3\n", &Z80Config::default()).unwrap();
+        let listing = annotate(&artifacts.rom, &artifacts.symbols);
+
+        let mut lines = listing.lines();
+        lines.find(|line| *line == "bcd_add:").expect("no bcd_add label");
+        let first = lines.next().unwrap();
+        assert!(first.starts_with(&format!("    {:04X}  ", artifacts.symbols["bcd_add"])), "{}", first);
+
+        assert!(listing.contains("vm_loop:\n"));
+        assert!(listing.lines().any(|line| line.contains("CALL 0x") && line.ends_with("; push_vstack")));
+        assert!(listing.lines().any(|line| line.contains("CP 0x30") && line.ends_with("; Add")));
+
+        // A one-byte instruction at the very end of the image
+        assert!(annotate(&[0xC9], &BTreeMap::new()).ends_with("RET\n"));
+    }
+}
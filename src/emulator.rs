@@ -0,0 +1,1133 @@
+//! Z80 emulator for running generated ROMs
+//!
+//! Emulates the documented Z80 instruction set (plus the common undocumented
+//! IXH/IXL forms) on a flat 64KB address space with the ROM loaded at 0x0000.
+//! A 6850 ACIA is modelled on ports 0x80 (status) and 0x81 (data), matching the
+//! RetroShield serial interface the generated code talks to.
+//!
+//! T-states are counted per instruction so tests can compare the cost of
+//! different code sequences; counts follow the Zilog timing tables.
+
+use std::collections::VecDeque;
+
+const ACIA_STATUS_PORT: u8 = 0x80;
+const ACIA_DATA_PORT: u8 = 0x81;
+/// The runtime's Trap handler writes here with DE = source line
+const TRAP_PORT: u8 = crate::z80::TRAP_PORT;
+
+// Flag bits
+const FLAG_C: u8 = 0x01;
+const FLAG_N: u8 = 0x02;
+const FLAG_PV: u8 = 0x04;
+const FLAG_X: u8 = 0x08;
+const FLAG_H: u8 = 0x10;
+const FLAG_Y: u8 = 0x20;
+const FLAG_Z: u8 = 0x40;
+const FLAG_S: u8 = 0x80;
+
+/// Base T-states for unprefixed opcodes (conditional branches not taken)
+const CYCLES: [u8; 256] = [
+    4, 10, 7, 6, 4, 4, 7, 4, 4, 11, 7, 6, 4, 4, 7, 4,
+    8, 10, 7, 6, 4, 4, 7, 4, 12, 11, 7, 6, 4, 4, 7, 4,
+    7, 10, 16, 6, 4, 4, 7, 4, 7, 11, 16, 6, 4, 4, 7, 4,
+    7, 10, 13, 6, 11, 11, 10, 4, 7, 11, 13, 6, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    7, 7, 7, 7, 7, 7, 4, 7, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    5, 10, 10, 10, 10, 11, 7, 11, 5, 10, 10, 0, 10, 17, 7, 11,
+    5, 10, 10, 11, 10, 11, 7, 11, 5, 4, 10, 11, 10, 0, 7, 11,
+    5, 10, 10, 19, 10, 11, 7, 11, 5, 4, 10, 4, 10, 0, 7, 11,
+    5, 10, 10, 4, 10, 11, 7, 11, 5, 6, 10, 4, 10, 0, 7, 11,
+];
+
+/// Which register pair stands in for HL (changed by DD/FD prefixes)
+#[derive(Clone, Copy, PartialEq)]
+enum Index {
+    HL,
+    IX,
+    IY,
+}
+
+pub struct Machine {
+    mem: Vec<u8>,
+    a: u8,
+    f: u8,
+    b: u8,
+    c: u8,
+    d: u8,
+    e: u8,
+    h: u8,
+    l: u8,
+    alt: [u8; 8],
+    ix: u16,
+    iy: u16,
+    sp: u16,
+    pc: u16,
+    i: u8,
+    r: u8,
+    iff1: bool,
+    iff2: bool,
+    halted: bool,
+    /// Total T-states executed
+    pub cycles: u64,
+    /// Bytes written to memory, for comparing the memory traffic of code
+    /// sequences
+    pub writes: u64,
+    /// Source line of the breakpoint the program stopped at
+    trap: Option<u16>,
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+fn sz53(v: u8) -> u8 {
+    let mut f = v & (FLAG_S | FLAG_Y | FLAG_X);
+    if v == 0 {
+        f |= FLAG_Z;
+    }
+    f
+}
+
+fn sz53p(v: u8) -> u8 {
+    let mut f = sz53(v);
+    if v.count_ones() % 2 == 0 {
+        f |= FLAG_PV;
+    }
+    f
+}
+
+impl Machine {
+    pub fn new(rom: &[u8]) -> Self {
+        let mut mem = vec![0u8; 0x10000];
+        let len = rom.len().min(0x10000);
+        mem[..len].copy_from_slice(&rom[..len]);
+        Machine {
+            mem,
+            a: 0xFF,
+            f: 0xFF,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+            alt: [0; 8],
+            ix: 0,
+            iy: 0,
+            sp: 0xFFFF,
+            pc: 0,
+            i: 0,
+            r: 0,
+            iff1: false,
+            iff2: false,
+            halted: false,
+            cycles: 0,
+            writes: 0,
+            trap: None,
+            input: VecDeque::new(),
+            output: Vec::new(),
+        }
+    }
+
+    /// Queue bytes to be returned by reads of the ACIA data port
+    pub fn send(&mut self, text: &str) {
+        self.input.extend(text.bytes());
+    }
+
+    /// Everything written to the ACIA data port so far
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+
+    /// Read a byte of memory
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.mem[addr as usize]
+    }
+
+    /// Run until HALT, a breakpoint or until the cycle budget is spent.
+    /// Returns true if the CPU halted.
+    pub fn run(&mut self, max_cycles: u64) -> bool {
+        let limit = self.cycles + max_cycles;
+        while !self.halted && self.trap.is_none() && self.cycles < limit {
+            self.step();
+        }
+        self.halted
+    }
+
+    /// Line of the breakpoint `run` stopped at, if any. Clearing it lets
+    /// the next `run` carry on from there.
+    pub fn take_trap(&mut self) -> Option<u16> {
+        self.trap.take()
+    }
+
+    // ---------------------------------------------------------------
+    // Memory and I/O
+    // ---------------------------------------------------------------
+
+    fn read8(&self, addr: u16) -> u8 {
+        self.mem[addr as usize]
+    }
+
+    fn write8(&mut self, addr: u16, val: u8) {
+        self.writes += 1;
+        self.mem[addr as usize] = val;
+    }
+
+    fn read16(&self, addr: u16) -> u16 {
+        self.read8(addr) as u16 | ((self.read8(addr.wrapping_add(1)) as u16) << 8)
+    }
+
+    fn write16(&mut self, addr: u16, val: u16) {
+        self.write8(addr, val as u8);
+        self.write8(addr.wrapping_add(1), (val >> 8) as u8);
+    }
+
+    fn fetch8(&mut self) -> u8 {
+        let v = self.read8(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        v
+    }
+
+    fn fetch16(&mut self) -> u16 {
+        let lo = self.fetch8() as u16;
+        let hi = self.fetch8() as u16;
+        lo | (hi << 8)
+    }
+
+    fn push16(&mut self, val: u16) {
+        self.sp = self.sp.wrapping_sub(2);
+        self.write16(self.sp, val);
+    }
+
+    fn pop16(&mut self) -> u16 {
+        let v = self.read16(self.sp);
+        self.sp = self.sp.wrapping_add(2);
+        v
+    }
+
+    fn port_in(&mut self, port: u8) -> u8 {
+        match port {
+            ACIA_STATUS_PORT => 0x02 | if self.input.is_empty() { 0 } else { 0x01 },
+            ACIA_DATA_PORT => self.input.pop_front().unwrap_or(0),
+            _ => 0xFF,
+        }
+    }
+
+    fn port_out(&mut self, port: u8, val: u8) {
+        if port == ACIA_DATA_PORT {
+            self.output.push(val);
+        } else if port == TRAP_PORT {
+            self.trap = Some(self.de());
+        }
+    }
+
+    // ---------------------------------------------------------------
+    // Register access
+    // ---------------------------------------------------------------
+
+    fn bc(&self) -> u16 {
+        ((self.b as u16) << 8) | self.c as u16
+    }
+
+    fn de(&self) -> u16 {
+        ((self.d as u16) << 8) | self.e as u16
+    }
+
+    fn hl(&self) -> u16 {
+        ((self.h as u16) << 8) | self.l as u16
+    }
+
+    fn set_bc(&mut self, v: u16) {
+        self.b = (v >> 8) as u8;
+        self.c = v as u8;
+    }
+
+    fn set_de(&mut self, v: u16) {
+        self.d = (v >> 8) as u8;
+        self.e = v as u8;
+    }
+
+    fn set_hl(&mut self, v: u16) {
+        self.h = (v >> 8) as u8;
+        self.l = v as u8;
+    }
+
+    fn idx_reg(&self, idx: Index) -> u16 {
+        match idx {
+            Index::HL => self.hl(),
+            Index::IX => self.ix,
+            Index::IY => self.iy,
+        }
+    }
+
+    fn set_idx_reg(&mut self, idx: Index, v: u16) {
+        match idx {
+            Index::HL => self.set_hl(v),
+            Index::IX => self.ix = v,
+            Index::IY => self.iy = v,
+        }
+    }
+
+    /// rp table: BC, DE, HL/IX/IY, SP
+    fn rp(&self, p: u8, idx: Index) -> u16 {
+        match p {
+            0 => self.bc(),
+            1 => self.de(),
+            2 => self.idx_reg(idx),
+            _ => self.sp,
+        }
+    }
+
+    fn set_rp(&mut self, p: u8, idx: Index, v: u16) {
+        match p {
+            0 => self.set_bc(v),
+            1 => self.set_de(v),
+            2 => self.set_idx_reg(idx, v),
+            _ => self.sp = v,
+        }
+    }
+
+    /// rp2 table: BC, DE, HL/IX/IY, AF
+    fn rp2(&self, p: u8, idx: Index) -> u16 {
+        match p {
+            3 => ((self.a as u16) << 8) | self.f as u16,
+            _ => self.rp(p, idx),
+        }
+    }
+
+    fn set_rp2(&mut self, p: u8, idx: Index, v: u16) {
+        match p {
+            3 => {
+                self.a = (v >> 8) as u8;
+                self.f = v as u8;
+            }
+            _ => self.set_rp(p, idx, v),
+        }
+    }
+
+    /// 8-bit register by table index (6 = (HL) is handled by the caller).
+    /// With an index prefix, H and L become the halves of IX/IY.
+    fn reg8(&self, r: u8, idx: Index) -> u8 {
+        match r {
+            0 => self.b,
+            1 => self.c,
+            2 => self.d,
+            3 => self.e,
+            4 => match idx {
+                Index::HL => self.h,
+                Index::IX => (self.ix >> 8) as u8,
+                Index::IY => (self.iy >> 8) as u8,
+            },
+            5 => match idx {
+                Index::HL => self.l,
+                Index::IX => self.ix as u8,
+                Index::IY => self.iy as u8,
+            },
+            7 => self.a,
+            _ => unreachable!("(HL) operand must be handled by caller"),
+        }
+    }
+
+    fn set_reg8(&mut self, r: u8, idx: Index, v: u8) {
+        match r {
+            0 => self.b = v,
+            1 => self.c = v,
+            2 => self.d = v,
+            3 => self.e = v,
+            4 => match idx {
+                Index::HL => self.h = v,
+                Index::IX => self.ix = (self.ix & 0x00FF) | ((v as u16) << 8),
+                Index::IY => self.iy = (self.iy & 0x00FF) | ((v as u16) << 8),
+            },
+            5 => match idx {
+                Index::HL => self.l = v,
+                Index::IX => self.ix = (self.ix & 0xFF00) | v as u16,
+                Index::IY => self.iy = (self.iy & 0xFF00) | v as u16,
+            },
+            7 => self.a = v,
+            _ => unreachable!("(HL) operand must be handled by caller"),
+        }
+    }
+
+    /// Address of the (HL) / (IX+d) / (IY+d) operand, fetching d if needed
+    fn operand_addr(&mut self, idx: Index) -> u16 {
+        match idx {
+            Index::HL => self.hl(),
+            _ => {
+                let d = self.fetch8() as i8;
+                self.idx_reg(idx).wrapping_add(d as u16)
+            }
+        }
+    }
+
+    fn condition(&self, cc: u8) -> bool {
+        match cc {
+            0 => self.f & FLAG_Z == 0,
+            1 => self.f & FLAG_Z != 0,
+            2 => self.f & FLAG_C == 0,
+            3 => self.f & FLAG_C != 0,
+            4 => self.f & FLAG_PV == 0,
+            5 => self.f & FLAG_PV != 0,
+            6 => self.f & FLAG_S == 0,
+            _ => self.f & FLAG_S != 0,
+        }
+    }
+
+    // ---------------------------------------------------------------
+    // ALU
+    // ---------------------------------------------------------------
+
+    fn add8(&mut self, v: u8, carry: bool) {
+        let a = self.a;
+        let c = carry as u16;
+        let r = a as u16 + v as u16 + c;
+        let res = r as u8;
+        let mut f = sz53(res) | ((a ^ v ^ res) & FLAG_H);
+        if r > 0xFF {
+            f |= FLAG_C;
+        }
+        if (a ^ !v) & (a ^ res) & 0x80 != 0 {
+            f |= FLAG_PV;
+        }
+        self.a = res;
+        self.f = f;
+    }
+
+    fn sub8_flags(&self, v: u8, carry: bool) -> (u8, u8) {
+        let a = self.a;
+        let c = carry as u16;
+        let res = (a as u16).wrapping_sub(v as u16).wrapping_sub(c) as u8;
+        let mut f = FLAG_N | sz53(res) | ((a ^ v ^ res) & FLAG_H);
+        if (a as u16) < v as u16 + c {
+            f |= FLAG_C;
+        }
+        if (a ^ v) & (a ^ res) & 0x80 != 0 {
+            f |= FLAG_PV;
+        }
+        (res, f)
+    }
+
+    fn alu(&mut self, op: u8, v: u8) {
+        match op {
+            0 => self.add8(v, false),
+            1 => self.add8(v, self.f & FLAG_C != 0),
+            2 => {
+                let (res, f) = self.sub8_flags(v, false);
+                self.a = res;
+                self.f = f;
+            }
+            3 => {
+                let (res, f) = self.sub8_flags(v, self.f & FLAG_C != 0);
+                self.a = res;
+                self.f = f;
+            }
+            4 => {
+                self.a &= v;
+                self.f = sz53p(self.a) | FLAG_H;
+            }
+            5 => {
+                self.a ^= v;
+                self.f = sz53p(self.a);
+            }
+            6 => {
+                self.a |= v;
+                self.f = sz53p(self.a);
+            }
+            _ => {
+                let (_, f) = self.sub8_flags(v, false);
+                self.f = (f & !(FLAG_X | FLAG_Y)) | (v & (FLAG_X | FLAG_Y));
+            }
+        }
+    }
+
+    fn inc8(&mut self, v: u8) -> u8 {
+        let res = v.wrapping_add(1);
+        let mut f = (self.f & FLAG_C) | sz53(res);
+        if v & 0x0F == 0x0F {
+            f |= FLAG_H;
+        }
+        if v == 0x7F {
+            f |= FLAG_PV;
+        }
+        self.f = f;
+        res
+    }
+
+    fn dec8(&mut self, v: u8) -> u8 {
+        let res = v.wrapping_sub(1);
+        let mut f = (self.f & FLAG_C) | FLAG_N | sz53(res);
+        if v & 0x0F == 0 {
+            f |= FLAG_H;
+        }
+        if v == 0x80 {
+            f |= FLAG_PV;
+        }
+        self.f = f;
+        res
+    }
+
+    fn add16(&mut self, a: u16, b: u16) -> u16 {
+        let r = a as u32 + b as u32;
+        let res = r as u16;
+        let mut f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV)) | ((res >> 8) as u8 & (FLAG_X | FLAG_Y));
+        if (a ^ b ^ res) & 0x1000 != 0 {
+            f |= FLAG_H;
+        }
+        if r > 0xFFFF {
+            f |= FLAG_C;
+        }
+        self.f = f;
+        res
+    }
+
+    fn adc16(&mut self, a: u16, b: u16) -> u16 {
+        let c = (self.f & FLAG_C) as u32;
+        let r = a as u32 + b as u32 + c;
+        let res = r as u16;
+        let mut f = (res >> 8) as u8 & (FLAG_S | FLAG_X | FLAG_Y);
+        if res == 0 {
+            f |= FLAG_Z;
+        }
+        if (a ^ b ^ res) & 0x1000 != 0 {
+            f |= FLAG_H;
+        }
+        if (a ^ !b) & (a ^ res) & 0x8000 != 0 {
+            f |= FLAG_PV;
+        }
+        if r > 0xFFFF {
+            f |= FLAG_C;
+        }
+        self.f = f;
+        res
+    }
+
+    fn sbc16(&mut self, a: u16, b: u16) -> u16 {
+        let c = (self.f & FLAG_C) as u32;
+        let res = (a as u32).wrapping_sub(b as u32).wrapping_sub(c) as u16;
+        let mut f = FLAG_N | ((res >> 8) as u8 & (FLAG_S | FLAG_X | FLAG_Y));
+        if res == 0 {
+            f |= FLAG_Z;
+        }
+        if (a ^ b ^ res) & 0x1000 != 0 {
+            f |= FLAG_H;
+        }
+        if (a ^ b) & (a ^ res) & 0x8000 != 0 {
+            f |= FLAG_PV;
+        }
+        if (a as u32) < b as u32 + c {
+            f |= FLAG_C;
+        }
+        self.f = f;
+        res
+    }
+
+    fn rot(&mut self, op: u8, v: u8) -> u8 {
+        let carry_in = self.f & FLAG_C;
+        let (res, carry) = match op {
+            0 => (v.rotate_left(1), v >> 7),
+            1 => (v.rotate_right(1), v & 1),
+            2 => ((v << 1) | carry_in, v >> 7),
+            3 => ((v >> 1) | (carry_in << 7), v & 1),
+            4 => (v << 1, v >> 7),
+            5 => ((v >> 1) | (v & 0x80), v & 1),
+            6 => ((v << 1) | 1, v >> 7),
+            _ => (v >> 1, v & 1),
+        };
+        self.f = sz53p(res) | carry;
+        res
+    }
+
+    fn daa(&mut self) {
+        let a = self.a;
+        let mut correction = 0u8;
+        let mut carry = self.f & FLAG_C;
+        if self.f & FLAG_H != 0 || a & 0x0F > 9 {
+            correction |= 0x06;
+        }
+        if carry != 0 || a > 0x99 {
+            correction |= 0x60;
+            carry = FLAG_C;
+        }
+        let res = if self.f & FLAG_N != 0 {
+            a.wrapping_sub(correction)
+        } else {
+            a.wrapping_add(correction)
+        };
+        self.f = sz53p(res) | ((a ^ res) & FLAG_H) | (self.f & FLAG_N) | carry;
+        self.a = res;
+    }
+
+    // ---------------------------------------------------------------
+    // Execution
+    // ---------------------------------------------------------------
+
+    /// Execute one instruction
+    pub fn step(&mut self) {
+        if self.halted {
+            self.cycles += 4;
+            return;
+        }
+        let mut idx = Index::HL;
+        let mut op = self.fetch8();
+        self.r = (self.r & 0x80) | (self.r.wrapping_add(1) & 0x7F);
+        // Any run of DD/FD prefixes: the last one wins
+        while op == 0xDD || op == 0xFD {
+            idx = if op == 0xDD { Index::IX } else { Index::IY };
+            self.cycles += 4;
+            op = self.fetch8();
+        }
+        match op {
+            0xCB => self.exec_cb(idx),
+            0xED => self.exec_ed(),
+            _ => self.exec_main(op, idx),
+        }
+    }
+
+    fn exec_main(&mut self, op: u8, idx: Index) {
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let p = y >> 1;
+        let q = y & 1;
+        self.cycles += CYCLES[op as usize] as u64;
+        // Extra cost of computing (IX+d)
+        let displaced = idx != Index::HL;
+
+        match x {
+            0 => match z {
+                0 => match y {
+                    0 => {}
+                    1 => {
+                        std::mem::swap(&mut self.a, &mut self.alt[6]);
+                        std::mem::swap(&mut self.f, &mut self.alt[7]);
+                    }
+                    2 => {
+                        let d = self.fetch8() as i8;
+                        self.b = self.b.wrapping_sub(1);
+                        if self.b != 0 {
+                            self.pc = self.pc.wrapping_add(d as u16);
+                            self.cycles += 5;
+                        }
+                    }
+                    3 => {
+                        let d = self.fetch8() as i8;
+                        self.pc = self.pc.wrapping_add(d as u16);
+                    }
+                    _ => {
+                        let d = self.fetch8() as i8;
+                        if self.condition(y - 4) {
+                            self.pc = self.pc.wrapping_add(d as u16);
+                            self.cycles += 5;
+                        }
+                    }
+                },
+                1 => {
+                    if q == 0 {
+                        let nn = self.fetch16();
+                        self.set_rp(p, idx, nn);
+                    } else {
+                        let a = self.idx_reg(idx);
+                        let b = self.rp(p, idx);
+                        let r = self.add16(a, b);
+                        self.set_idx_reg(idx, r);
+                    }
+                }
+                2 => match (q, p) {
+                    (0, 0) => self.write8(self.bc(), self.a),
+                    (0, 1) => self.write8(self.de(), self.a),
+                    (0, 2) => {
+                        let nn = self.fetch16();
+                        self.write16(nn, self.idx_reg(idx));
+                    }
+                    (0, _) => {
+                        let nn = self.fetch16();
+                        self.write8(nn, self.a);
+                    }
+                    (_, 0) => self.a = self.read8(self.bc()),
+                    (_, 1) => self.a = self.read8(self.de()),
+                    (_, 2) => {
+                        let nn = self.fetch16();
+                        let v = self.read16(nn);
+                        self.set_idx_reg(idx, v);
+                    }
+                    (_, _) => {
+                        let nn = self.fetch16();
+                        self.a = self.read8(nn);
+                    }
+                },
+                3 => {
+                    let v = self.rp(p, idx);
+                    let v = if q == 0 { v.wrapping_add(1) } else { v.wrapping_sub(1) };
+                    self.set_rp(p, idx, v);
+                }
+                4 | 5 => {
+                    if y == 6 {
+                        let addr = self.operand_addr(idx);
+                        if displaced {
+                            self.cycles += 8;
+                        }
+                        let v = self.read8(addr);
+                        let r = if z == 4 { self.inc8(v) } else { self.dec8(v) };
+                        self.write8(addr, r);
+                    } else {
+                        let v = self.reg8(y, idx);
+                        let r = if z == 4 { self.inc8(v) } else { self.dec8(v) };
+                        self.set_reg8(y, idx, r);
+                    }
+                }
+                6 => {
+                    if y == 6 {
+                        let addr = self.operand_addr(idx);
+                        if displaced {
+                            self.cycles += 5;
+                        }
+                        let n = self.fetch8();
+                        self.write8(addr, n);
+                    } else {
+                        let n = self.fetch8();
+                        self.set_reg8(y, idx, n);
+                    }
+                }
+                _ => match y {
+                    0 => {
+                        self.a = self.a.rotate_left(1);
+                        self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV))
+                            | (self.a & (FLAG_X | FLAG_Y | FLAG_C));
+                    }
+                    1 => {
+                        let c = self.a & 1;
+                        self.a = self.a.rotate_right(1);
+                        self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV))
+                            | (self.a & (FLAG_X | FLAG_Y))
+                            | c;
+                    }
+                    2 => {
+                        let c = self.a >> 7;
+                        self.a = (self.a << 1) | (self.f & FLAG_C);
+                        self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV))
+                            | (self.a & (FLAG_X | FLAG_Y))
+                            | c;
+                    }
+                    3 => {
+                        let c = self.a & 1;
+                        self.a = (self.a >> 1) | ((self.f & FLAG_C) << 7);
+                        self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV))
+                            | (self.a & (FLAG_X | FLAG_Y))
+                            | c;
+                    }
+                    4 => self.daa(),
+                    5 => {
+                        self.a = !self.a;
+                        self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV | FLAG_C))
+                            | FLAG_H
+                            | FLAG_N
+                            | (self.a & (FLAG_X | FLAG_Y));
+                    }
+                    6 => {
+                        self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV))
+                            | (self.a & (FLAG_X | FLAG_Y))
+                            | FLAG_C;
+                    }
+                    _ => {
+                        let c = self.f & FLAG_C;
+                        self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV))
+                            | (self.a & (FLAG_X | FLAG_Y))
+                            | if c != 0 { FLAG_H } else { FLAG_C };
+                    }
+                },
+            },
+            1 => {
+                if y == 6 && z == 6 {
+                    self.halted = true;
+                } else if y == 6 {
+                    // LD (HL),r - with an index prefix the source is the real H/L
+                    let addr = self.operand_addr(idx);
+                    if displaced {
+                        self.cycles += 8;
+                    }
+                    let v = self.reg8(z, Index::HL);
+                    self.write8(addr, v);
+                } else if z == 6 {
+                    let addr = self.operand_addr(idx);
+                    if displaced {
+                        self.cycles += 8;
+                    }
+                    let v = self.read8(addr);
+                    self.set_reg8(y, Index::HL, v);
+                } else {
+                    let v = self.reg8(z, idx);
+                    self.set_reg8(y, idx, v);
+                }
+            }
+            2 => {
+                let v = if z == 6 {
+                    let addr = self.operand_addr(idx);
+                    if displaced {
+                        self.cycles += 8;
+                    }
+                    self.read8(addr)
+                } else {
+                    self.reg8(z, idx)
+                };
+                self.alu(y, v);
+            }
+            _ => match z {
+                0 => {
+                    if self.condition(y) {
+                        self.pc = self.pop16();
+                        self.cycles += 6;
+                    }
+                }
+                1 => {
+                    if q == 0 {
+                        let v = self.pop16();
+                        self.set_rp2(p, idx, v);
+                    } else {
+                        match p {
+                            0 => self.pc = self.pop16(),
+                            1 => {
+                                let regs = [self.b, self.c, self.d, self.e, self.h, self.l];
+                                self.b = self.alt[0];
+                                self.c = self.alt[1];
+                                self.d = self.alt[2];
+                                self.e = self.alt[3];
+                                self.h = self.alt[4];
+                                self.l = self.alt[5];
+                                self.alt[..6].copy_from_slice(&regs);
+                            }
+                            2 => self.pc = self.idx_reg(idx),
+                            _ => self.sp = self.idx_reg(idx),
+                        }
+                    }
+                }
+                2 => {
+                    let nn = self.fetch16();
+                    if self.condition(y) {
+                        self.pc = nn;
+                    }
+                }
+                3 => match y {
+                    0 => self.pc = self.fetch16(),
+                    2 => {
+                        let n = self.fetch8();
+                        self.port_out(n, self.a);
+                    }
+                    3 => {
+                        let n = self.fetch8();
+                        self.a = self.port_in(n);
+                    }
+                    4 => {
+                        let v = self.read16(self.sp);
+                        self.write16(self.sp, self.idx_reg(idx));
+                        self.set_idx_reg(idx, v);
+                    }
+                    5 => {
+                        let de = self.de();
+                        self.set_de(self.hl());
+                        self.set_hl(de);
+                    }
+                    6 => {
+                        self.iff1 = false;
+                        self.iff2 = false;
+                    }
+                    7 => {
+                        self.iff1 = true;
+                        self.iff2 = true;
+                    }
+                    _ => unreachable!("CB prefix handled in step"),
+                },
+                4 => {
+                    let nn = self.fetch16();
+                    if self.condition(y) {
+                        self.push16(self.pc);
+                        self.pc = nn;
+                        self.cycles += 7;
+                    }
+                }
+                5 => {
+                    if q == 0 {
+                        let v = self.rp2(p, idx);
+                        self.push16(v);
+                    } else {
+                        // p == 0 is CALL nn; prefixes are consumed in step
+                        let nn = self.fetch16();
+                        self.push16(self.pc);
+                        self.pc = nn;
+                    }
+                }
+                6 => {
+                    let n = self.fetch8();
+                    self.alu(y, n);
+                }
+                _ => {
+                    self.push16(self.pc);
+                    self.pc = (y as u16) * 8;
+                }
+            },
+        }
+    }
+
+    fn exec_cb(&mut self, idx: Index) {
+        // DDCB/FDCB put the displacement before the opcode
+        let addr = if idx != Index::HL {
+            Some(self.operand_addr(idx))
+        } else {
+            None
+        };
+        let op = self.fetch8();
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+
+        let (v, addr) = match addr {
+            Some(a) => {
+                self.cycles += if x == 1 { 16 } else { 19 };
+                (self.read8(a), Some(a))
+            }
+            None if z == 6 => {
+                self.cycles += if x == 1 { 12 } else { 15 };
+                let a = self.hl();
+                (self.read8(a), Some(a))
+            }
+            None => {
+                self.cycles += 8;
+                (self.reg8(z, Index::HL), None)
+            }
+        };
+
+        let result = match x {
+            0 => Some(self.rot(y, v)),
+            1 => {
+                let bit = v & (1 << y);
+                let mut f = (self.f & FLAG_C) | FLAG_H | (v & (FLAG_X | FLAG_Y));
+                if bit == 0 {
+                    f |= FLAG_Z | FLAG_PV;
+                }
+                if y == 7 && bit != 0 {
+                    f |= FLAG_S;
+                }
+                self.f = f;
+                None
+            }
+            2 => Some(v & !(1 << y)),
+            _ => Some(v | (1 << y)),
+        };
+
+        if let Some(r) = result {
+            match addr {
+                Some(a) => {
+                    self.write8(a, r);
+                    // Undocumented: DDCB forms also copy the result to a register
+                    if idx != Index::HL && z != 6 {
+                        self.set_reg8(z, Index::HL, r);
+                    }
+                }
+                None => self.set_reg8(z, Index::HL, r),
+            }
+        }
+    }
+
+    fn exec_ed(&mut self) {
+        let op = self.fetch8();
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let p = y >> 1;
+        let q = y & 1;
+
+        if x == 1 {
+            match z {
+                0 => {
+                    self.cycles += 12;
+                    let v = self.port_in(self.c);
+                    self.f = (self.f & FLAG_C) | sz53p(v);
+                    if y != 6 {
+                        self.set_reg8(y, Index::HL, v);
+                    }
+                }
+                1 => {
+                    self.cycles += 12;
+                    let v = if y == 6 { 0 } else { self.reg8(y, Index::HL) };
+                    self.port_out(self.c, v);
+                }
+                2 => {
+                    self.cycles += 15;
+                    let hl = self.hl();
+                    let v = self.rp(p, Index::HL);
+                    let r = if q == 0 { self.sbc16(hl, v) } else { self.adc16(hl, v) };
+                    self.set_hl(r);
+                }
+                3 => {
+                    self.cycles += 20;
+                    let nn = self.fetch16();
+                    if q == 0 {
+                        self.write16(nn, self.rp(p, Index::HL));
+                    } else {
+                        let v = self.read16(nn);
+                        self.set_rp(p, Index::HL, v);
+                    }
+                }
+                4 => {
+                    self.cycles += 8;
+                    let v = self.a;
+                    self.a = 0;
+                    self.alu(2, v);
+                }
+                5 => {
+                    self.cycles += 14;
+                    self.pc = self.pop16();
+                    self.iff1 = self.iff2;
+                }
+                6 => self.cycles += 8,
+                _ => match y {
+                    0 => {
+                        self.cycles += 9;
+                        self.i = self.a;
+                    }
+                    1 => {
+                        self.cycles += 9;
+                        self.r = self.a;
+                    }
+                    2 | 3 => {
+                        self.cycles += 9;
+                        self.a = if y == 2 { self.i } else { self.r };
+                        self.f = (self.f & FLAG_C) | sz53(self.a) | if self.iff2 { FLAG_PV } else { 0 };
+                    }
+                    4 | 5 => {
+                        self.cycles += 18;
+                        let addr = self.hl();
+                        let v = self.read8(addr);
+                        let (mem, a) = if y == 4 {
+                            // RRD
+                            (((self.a & 0x0F) << 4) | (v >> 4), (self.a & 0xF0) | (v & 0x0F))
+                        } else {
+                            // RLD
+                            ((v << 4) | (self.a & 0x0F), (self.a & 0xF0) | (v >> 4))
+                        };
+                        self.write8(addr, mem);
+                        self.a = a;
+                        self.f = (self.f & FLAG_C) | sz53p(a);
+                    }
+                    _ => self.cycles += 8,
+                },
+            }
+        } else if x == 2 && y >= 4 && z <= 3 {
+            self.block_op(y, z);
+        } else {
+            self.cycles += 8;
+        }
+    }
+
+    fn block_op(&mut self, y: u8, z: u8) {
+        let decrement = y & 1 == 1;
+        let repeat = y >= 6;
+        let step = |v: u16| if decrement { v.wrapping_sub(1) } else { v.wrapping_add(1) };
+        self.cycles += 16;
+
+        let again = match z {
+            0 => {
+                // LDI/LDD/LDIR/LDDR
+                let v = self.read8(self.hl());
+                self.write8(self.de(), v);
+                self.set_hl(step(self.hl()));
+                self.set_de(step(self.de()));
+                let bc = self.bc().wrapping_sub(1);
+                self.set_bc(bc);
+                self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_C)) | if bc != 0 { FLAG_PV } else { 0 };
+                bc != 0
+            }
+            1 => {
+                // CPI/CPD/CPIR/CPDR
+                let v = self.read8(self.hl());
+                let (res, f) = self.sub8_flags(v, false);
+                self.set_hl(step(self.hl()));
+                let bc = self.bc().wrapping_sub(1);
+                self.set_bc(bc);
+                self.f = (self.f & FLAG_C)
+                    | (f & (FLAG_S | FLAG_Z | FLAG_H | FLAG_X | FLAG_Y))
+                    | FLAG_N
+                    | if bc != 0 { FLAG_PV } else { 0 };
+                bc != 0 && res != 0
+            }
+            2 => {
+                // INI/IND/INIR/INDR
+                let v = self.port_in(self.c);
+                self.write8(self.hl(), v);
+                self.set_hl(step(self.hl()));
+                self.b = self.b.wrapping_sub(1);
+                self.f = (self.f & FLAG_C) | FLAG_N | sz53(self.b);
+                self.b != 0
+            }
+            _ => {
+                // OUTI/OUTD/OTIR/OTDR
+                let v = self.read8(self.hl());
+                self.b = self.b.wrapping_sub(1);
+                self.port_out(self.c, v);
+                self.set_hl(step(self.hl()));
+                self.f = (self.f & FLAG_C) | FLAG_N | sz53(self.b);
+                self.b != 0
+            }
+        };
+
+        if repeat && again {
+            self.pc = self.pc.wrapping_sub(2);
+            self.cycles += 5;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(program: &[u8]) -> Machine {
+        let mut m = Machine::new(program);
+        assert!(m.run(100_000));
+        m
+    }
+
+    #[test]
+    fn test_acia_output() {
+        // LD A,'H'; OUT (0x81),A; LD A,'i'; OUT (0x81),A; HALT
+        let m = run(&[0x3E, b'H', 0xD3, 0x81, 0x3E, b'i', 0xD3, 0x81, 0x76]);
+        assert_eq!(m.output(), "Hi");
+        assert_eq!(m.cycles, 7 + 11 + 7 + 11 + 4);
+    }
+
+    #[test]
+    fn test_daa() {
+        // LD A,0x19; ADD A,0x28; DAA -> 0x47
+        let m = run(&[0x3E, 0x19, 0xC6, 0x28, 0x27, 0x76]);
+        assert_eq!(m.a, 0x47);
+        // LD A,0x10; SUB 0x01; DAA -> 0x09
+        let m = run(&[0x3E, 0x10, 0xD6, 0x01, 0x27, 0x76]);
+        assert_eq!(m.a, 0x09);
+    }
+
+    #[test]
+    fn test_ldir_and_index() {
+        // LD HL,src; LD DE,0x9000; LD BC,3; LDIR; LD IX,0x9000; LD A,(IX+2); HALT
+        let mut prog = vec![
+            0x21, 0x16, 0x00, 0x11, 0x00, 0x90, 0x01, 0x03, 0x00, 0xED, 0xB0,
+            0xDD, 0x21, 0x00, 0x90, 0xDD, 0x7E, 0x02, 0x76,
+        ];
+        prog.resize(0x16, 0);
+        prog.extend([1, 2, 3]);
+        let m = run(&prog);
+        assert_eq!(&m.mem[0x9000..0x9003], &[1, 2, 3]);
+        assert_eq!(m.a, 3);
+    }
+
+    #[test]
+    fn test_acia_input() {
+        // IN A,(0x80); AND 1; JR Z,-6; IN A,(0x81); HALT
+        let mut m = Machine::new(&[0xDB, 0x80, 0xE6, 0x01, 0x28, 0xFA, 0xDB, 0x81, 0x76]);
+        m.send("x");
+        assert!(m.run(1000));
+        assert_eq!(m.a, b'x');
+    }
+}
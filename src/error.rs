@@ -0,0 +1,57 @@
+//! Errors reported by the compile pipeline
+
+use std::error::Error;
+use std::fmt;
+
+/// Why a build failed. Lex and parse errors carry the 1-based source
+/// position of the offending token.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildError {
+    Lex { line: usize, col: usize, msg: String },
+    Parse { line: usize, col: usize, msg: String },
+    Compile { msg: String },
+    /// The generated ROM image is unusable (e.g. too large)
+    Rom { msg: String },
+}
+
+impl BuildError {
+    pub fn compile(msg: impl Into<String>) -> Self {
+        BuildError::Compile { msg: msg.into() }
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::Lex { line, col, msg } => write!(f, "Lex error at {}:{}: {}", line, col, msg),
+            BuildError::Parse { line, col, msg } => write!(f, "Parse error at {}:{}: {}", line, col, msg),
+            BuildError::Compile { msg } => write!(f, "Compile error: {}", msg),
+            BuildError::Rom { msg } => write!(f, "ROM error: {}", msg),
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+/// Something suspicious the compiler noticed that does not stop the build.
+/// `line` and `col` are 1-based, or 0 when the warning is about the whole
+/// program rather than one place in it; `col` alone is 0 when only the
+/// line is known.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub line: usize,
+    pub col: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "warning: {}", self.message)
+        } else if self.col == 0 {
+            write!(f, "warning at line {}: {}", self.line, self.message)
+        } else {
+            write!(f, "warning at {}:{}: {}", self.line, self.col, self.message)
+        }
+    }
+}
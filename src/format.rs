@@ -0,0 +1,335 @@
+//! Pretty-printer that turns a parsed program back into bc source
+//!
+//! Output is canonical rather than a copy of the input: functions come
+//! first, bodies are indented by four spaces, binary operators are spaced,
+//! and parentheses appear only where precedence needs them. Parsing the
+//! output gives back the same AST.
+
+use crate::ast::*;
+
+const INDENT: &str = "    ";
+
+// Binding strength, loosest first, following the parser's descent
+const ASSIGN: u8 = 1;
+const OR: u8 = 2;
+const AND: u8 = 3;
+const NOT: u8 = 4;
+const COMPARE: u8 = 5;
+const ADDITIVE: u8 = 6;
+const MULTIPLICATIVE: u8 = 7;
+const POWER: u8 = 8;
+const UNARY: u8 = 9;
+const POSTFIX: u8 = 10;
+const PRIMARY: u8 = 11;
+
+/// Format a whole program as bc source
+pub fn format_program(program: &Program) -> String {
+    let mut out = String::new();
+    for func in &program.functions {
+        format_function(func, &mut out);
+    }
+    for stmt in &program.statements {
+        format_stmt(stmt, 0, &mut out);
+        out.push('\n');
+    }
+    out
+}
+
+/// Format a single statement; blocks inside it span several lines
+pub fn format_stmt_source(stmt: &Stmt) -> String {
+    let mut out = String::new();
+    format_stmt(stmt, 0, &mut out);
+    out
+}
+
+/// Format a single expression
+pub fn format_expr(expr: &Expr) -> String {
+    expr_at(expr, ASSIGN)
+}
+
+fn format_function(func: &Function, out: &mut String) {
+    let params: Vec<String> = func.params.iter().map(|p| declared(&p.name, p.is_array)).collect();
+    out.push_str(&format!("define {}({}) {{\n", func.name, params.join(", ")));
+    if !func.auto_vars.is_empty() {
+        out.push_str(INDENT);
+        format_auto(&func.auto_vars, out);
+        out.push('\n');
+    }
+    for stmt in &func.body {
+        out.push_str(INDENT);
+        format_stmt(stmt, 1, out);
+        out.push('\n');
+    }
+    out.push_str("}\n");
+}
+
+fn declared(name: &str, is_array: bool) -> String {
+    if is_array {
+        format!("{}[]", name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn format_auto(vars: &[AutoVar], out: &mut String) {
+    let names: Vec<String> = vars.iter().map(|v| declared(&v.name, v.is_array)).collect();
+    out.push_str("auto ");
+    out.push_str(&names.join(", "));
+}
+
+/// Append a statement whose first line is already indented to `depth`
+fn format_stmt(stmt: &Stmt, depth: usize, out: &mut String) {
+    match stmt {
+        Stmt::Expr(expr) => out.push_str(&format_expr(expr)),
+        Stmt::Print(items) => {
+            let items: Vec<String> = items
+                .iter()
+                .map(|item| match item {
+                    PrintItem::Expr(expr) => format_expr(expr),
+                    PrintItem::String(s) => quote(s),
+                })
+                .collect();
+            out.push_str("print");
+            if !items.is_empty() {
+                out.push(' ');
+                out.push_str(&items.join(", "));
+            }
+        }
+        Stmt::Block(stmts) => format_block(stmts, depth, out),
+        Stmt::If { cond, then_branch, else_branch } => {
+            out.push_str(&format!("if ({}) ", format_expr(cond)));
+            match else_branch {
+                Some(else_branch) => {
+                    // An else after an unbraced else-less if would bind to it
+                    if dangles(then_branch) {
+                        format_block(std::slice::from_ref(then_branch), depth, out);
+                    } else {
+                        format_stmt(then_branch, depth, out);
+                    }
+                    out.push_str(" else ");
+                    format_stmt(else_branch, depth, out);
+                }
+                None => format_stmt(then_branch, depth, out),
+            }
+        }
+        Stmt::While { cond, body } => {
+            out.push_str(&format!("while ({}) ", format_expr(cond)));
+            format_stmt(body, depth, out);
+        }
+        Stmt::For { init, cond, update, body } => {
+            let part = |e: &Option<Expr>| e.as_ref().map(format_expr).unwrap_or_default();
+            out.push_str(&format!("for ({}; {}; {}) ", part(init), part(cond), part(update)));
+            format_stmt(body, depth, out);
+        }
+        Stmt::Break => out.push_str("break"),
+        Stmt::Continue => out.push_str("continue"),
+        Stmt::Return(None) => out.push_str("return"),
+        Stmt::Return(Some(expr)) => out.push_str(&format!("return ({})", format_expr(expr))),
+        Stmt::Quit => out.push_str("quit"),
+        Stmt::Halt => out.push_str("halt"),
+        Stmt::Auto(vars) => format_auto(vars, out),
+        Stmt::Empty => out.push(';'),
+    }
+}
+
+fn format_block(stmts: &[Stmt], depth: usize, out: &mut String) {
+    if stmts.is_empty() {
+        out.push_str("{}");
+        return;
+    }
+    out.push_str("{\n");
+    for stmt in stmts {
+        out.push_str(&INDENT.repeat(depth + 1));
+        format_stmt(stmt, depth + 1, out);
+        out.push('\n');
+    }
+    out.push_str(&INDENT.repeat(depth));
+    out.push('}');
+}
+
+/// Whether the statement ends in an if with no else
+fn dangles(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::If { else_branch: None, .. } => true,
+        Stmt::If { else_branch: Some(body), .. }
+        | Stmt::While { body, .. }
+        | Stmt::For { body, .. } => dangles(body),
+        _ => false,
+    }
+}
+
+fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for ch in s.chars() {
+        match ch {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            _ => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Format `expr`, parenthesized if it binds more loosely than `min`
+fn expr_at(expr: &Expr, min: u8) -> String {
+    let (prec, text) = render(expr);
+    if prec < min {
+        format!("({})", text)
+    } else {
+        text
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Assoc {
+    Left,
+    Right,
+}
+
+/// Operator, precedence and associativity of a binary expression, with its
+/// operands. This mirrors parse_assignment down to parse_power.
+fn binary_op(expr: &Expr) -> Option<(&'static str, u8, Assoc, &Expr, &Expr)> {
+    let (op, prec, assoc, a, b) = match expr {
+        Expr::Assign(a, b) => ("=", ASSIGN, Assoc::Right, a, b),
+        Expr::AddAssign(a, b) => ("+=", ASSIGN, Assoc::Right, a, b),
+        Expr::SubAssign(a, b) => ("-=", ASSIGN, Assoc::Right, a, b),
+        Expr::MulAssign(a, b) => ("*=", ASSIGN, Assoc::Right, a, b),
+        Expr::DivAssign(a, b) => ("/=", ASSIGN, Assoc::Right, a, b),
+        Expr::ModAssign(a, b) => ("%=", ASSIGN, Assoc::Right, a, b),
+        Expr::PowAssign(a, b) => ("^=", ASSIGN, Assoc::Right, a, b),
+        Expr::Or(a, b) => ("||", OR, Assoc::Left, a, b),
+        Expr::And(a, b) => ("&&", AND, Assoc::Left, a, b),
+        Expr::Eq(a, b) => ("==", COMPARE, Assoc::Left, a, b),
+        Expr::Ne(a, b) => ("!=", COMPARE, Assoc::Left, a, b),
+        Expr::Lt(a, b) => ("<", COMPARE, Assoc::Left, a, b),
+        Expr::Le(a, b) => ("<=", COMPARE, Assoc::Left, a, b),
+        Expr::Gt(a, b) => (">", COMPARE, Assoc::Left, a, b),
+        Expr::Ge(a, b) => (">=", COMPARE, Assoc::Left, a, b),
+        Expr::Add(a, b) => ("+", ADDITIVE, Assoc::Left, a, b),
+        Expr::Sub(a, b) => ("-", ADDITIVE, Assoc::Left, a, b),
+        Expr::Mul(a, b) => ("*", MULTIPLICATIVE, Assoc::Left, a, b),
+        Expr::Div(a, b) => ("/", MULTIPLICATIVE, Assoc::Left, a, b),
+        Expr::Mod(a, b) => ("%", MULTIPLICATIVE, Assoc::Left, a, b),
+        Expr::Pow(a, b) => ("^", POWER, Assoc::Right, a, b),
+        _ => return None,
+    };
+    Some((op, prec, assoc, a, b))
+}
+
+fn render(expr: &Expr) -> (u8, String) {
+    if let Some((op, prec, assoc, a, b)) = binary_op(expr) {
+        // The operand on the side the operator groups towards may sit at
+        // the same level; the other side needs a tighter one
+        let (left, right) = match assoc {
+            Assoc::Left => (prec, prec + 1),
+            Assoc::Right => (prec + 1, prec),
+        };
+        return (prec, format!("{} {} {}", expr_at(a, left), op, expr_at(b, right)));
+    }
+
+    let call = |name: &str, a: &Expr| (PRIMARY, format!("{}({})", name, format_expr(a)));
+    match expr {
+        Expr::Number(n) => (PRIMARY, n.clone()),
+        Expr::String(s) => (PRIMARY, quote(s)),
+        Expr::Var(name) => (PRIMARY, name.clone()),
+        Expr::ArrayElement(name, index) => (PRIMARY, format!("{}[{}]", name, format_expr(index))),
+        Expr::Scale => (PRIMARY, "scale".to_string()),
+        Expr::Ibase => (PRIMARY, "ibase".to_string()),
+        Expr::Obase => (PRIMARY, "obase".to_string()),
+        Expr::Last => (PRIMARY, "last".to_string()),
+
+        Expr::Not(a) => (NOT, format!("!{}", expr_at(a, NOT))),
+        Expr::Neg(a) => {
+            // "- -x", since "--x" would lex as a decrement
+            let operand = expr_at(a, UNARY);
+            let space = if operand.starts_with('-') { " " } else { "" };
+            (UNARY, format!("-{}{}", space, operand))
+        }
+        Expr::PreInc(a) => (UNARY, format!("++{}", expr_at(a, POSTFIX))),
+        Expr::PreDec(a) => (UNARY, format!("--{}", expr_at(a, POSTFIX))),
+        Expr::PostInc(a) => (POSTFIX, format!("{}++", expr_at(a, POSTFIX))),
+        Expr::PostDec(a) => (POSTFIX, format!("{}--", expr_at(a, POSTFIX))),
+
+        Expr::Call(name, args) => {
+            let args: Vec<String> = args.iter().map(format_expr).collect();
+            (PRIMARY, format!("{}({})", name, args.join(", ")))
+        }
+        Expr::Length(a) => call("length", a),
+        Expr::ScaleFunc(a) => call("scale", a),
+        Expr::Sqrt(a) => call("sqrt", a),
+        Expr::Read => (PRIMARY, "read()".to_string()),
+        _ => unreachable!("binary operators are handled above"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn round_trip(source: &str) -> String {
+        let program = Parser::new(source).parse().unwrap();
+        let formatted = format_program(&program);
+        let reparsed = Parser::new(&formatted)
+            .parse()
+            .unwrap_or_else(|e| panic!("{}\n{}", e, formatted));
+        assert_eq!(program, reparsed, "{}", formatted);
+        formatted
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip("define f(x, a[]) { auto i, b[]; for (i = 0; i < x; i++) b[i] = a[i] ^ 2; return (b[x-1]) }\nf(3, q)");
+        round_trip("if (a) if (b) print 1 else print 2\nwhile (!(x >= 10) && y != 0 || z) { x += 1; --y }");
+        round_trip("print \"a\\tb\\\"c\\n\", 1, \"\\\\\"\nx = y = -(-3)\n-2^2 + (-2)^2\nfor (;;) { break; continue }");
+        round_trip("scale = 20; ibase = obase; sqrt(length(scale(x))) % read()\nquit; halt\nlast");
+    }
+
+    #[test]
+    fn test_parentheses_only_where_needed() {
+        let formatted = round_trip("(1 + 2) * 3\n1 + (2 * 3)\n2 ^ (3 ^ 2)\n(2 ^ 3) ^ 2\na - (b - c)\n(a - b) - c\n(x = 1) + 2\n(a < b) == 1");
+        assert_eq!(
+            formatted,
+            "(1 + 2) * 3\n1 + 2 * 3\n2 ^ 3 ^ 2\n(2 ^ 3) ^ 2\na - (b - c)\na - b - c\n(x = 1) + 2\na < b == 1\n"
+        );
+        assert_eq!(
+            round_trip("define f(x) { auto y\nif (x) { y = 1 } else y = 2\nreturn y }"),
+            "define f(x) {\n    auto y\n    if (x) {\n        y = 1\n    } else y = 2\n    return (y)\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_precedence_edge_cases() {
+        let cases = [
+            ("a * (b + c)", "a * (b + c)"),
+            ("(a * b) + c", "a * b + c"),
+            ("a / (b * c)", "a / (b * c)"),
+            ("(a / b) * c", "a / b * c"),
+            ("a = (b = c)", "a = b = c"),
+            ("(a = b) = c", "(a = b) = c"),
+            ("a += b -= 1", "a += b -= 1"),
+            ("(a < b) < c", "a < b < c"),
+            ("a < (b < c)", "a < (b < c)"),
+            ("-(2 ^ 2)", "-(2 ^ 2)"),
+            ("(-2) ^ 2", "-2 ^ 2"),
+            ("2 ^ -x", "2 ^ -x"),
+            ("!(a && b)", "!(a && b)"),
+            ("(!a) && b", "!a && b"),
+            ("a || (b && c)", "a || b && c"),
+            ("(a || b) && c", "(a || b) && c"),
+            ("!(a < b)", "!a < b"),
+            ("- (-x)", "- -x"),
+            ("(x++)++", "x++++"),
+            ("-(x++)", "-x++"),
+        ];
+        for (source, expected) in cases {
+            let expr = Parser::new(source).parse_expression().unwrap();
+            let formatted = format_expr(&expr);
+            assert_eq!(formatted, expected, "{}", source);
+            assert_eq!(Parser::new(&formatted).parse_expression().unwrap(), expr, "{}", source);
+        }
+    }
+}
@@ -0,0 +1,949 @@
+//! Host-side bytecode interpreter
+//!
+//! Runs a `CompiledModule` in Rust with `BcNum` values, so programs can be
+//! checked without emulating a Z80. Numbers follow the runtime's rules: at
+//! most 50 digits, a product keeps the digits of both operands, and a
+//! quotient is cut off at `scale`. Output is what the ROM would print, one
+//! string per line. `repl` runs an interactive session on the host.
+
+use crate::ast::{Function, Program, Stmt};
+use crate::bytecode::{BcNum, CompiledModule, Op};
+use crate::compiler::{CompileOptions, Compiler};
+use crate::format::format_program;
+use crate::parser::Parser;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+/// Digits a number may hold, as in the runtime's packed format
+const MAX_DIGITS: usize = 50;
+/// Elements per array, as in the runtime
+const ARRAY_SIZE: usize = 256;
+
+/// Run a compiled program and return the lines it prints. Runtime errors
+/// print the ROM's message and stop, like the ROM; Err is for what the
+/// host cannot do (`read()`) or malformed bytecode.
+pub fn interpret(module: &CompiledModule) -> Result<Vec<String>, String> {
+    interpret_in(module, &mut Env::default())
+}
+
+/// Run a compiled program starting from the variables and settings in
+/// `env`, and leave the ones it ends with there. On Err `env` is unchanged.
+pub fn interpret_in(module: &CompiledModule, env: &mut Env) -> Result<Vec<String>, String> {
+    let mut machine = Machine::new(module);
+    machine.load_env(env);
+    if let Err(Stop::Error(msg)) = machine.run() {
+        return Err(msg);
+    }
+    machine.save_env(env);
+    let mut lines: Vec<String> = machine.out.split('\n').map(String::from).collect();
+    if lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    Ok(lines)
+}
+
+/// What one run leaves for the next: global variables and arrays by name
+/// (arrays as `name[]`, like `CompiledModule::variables`), `scale`,
+/// `ibase`, `obase` and `last`. Slots are not kept, since each compile
+/// numbers them afresh.
+#[derive(Debug, Clone)]
+pub struct Env {
+    pub values: HashMap<String, BcNum>,
+    pub arrays: HashMap<String, Vec<BcNum>>,
+    pub scale: usize,
+    pub ibase: u8,
+    pub obase: u8,
+    pub last: BcNum,
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Env {
+            values: HashMap::new(),
+            arrays: HashMap::new(),
+            scale: 0,
+            ibase: 10,
+            obase: 10,
+            last: BcNum::zero(),
+        }
+    }
+}
+
+/// An interactive session: each input is compiled and run on its own, with
+/// the functions defined so far and an `Env` carried over
+#[derive(Default)]
+pub struct Session {
+    /// Each function with the ibase its literals were written in
+    functions: Vec<(Function, u32)>,
+    env: Env,
+    quit: bool,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether an input has contained `quit`
+    pub fn quit(&self) -> bool {
+        self.quit
+    }
+
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+
+    /// Run one input and return what it printed. Err is a parse or compile
+    /// error, or something the host cannot run (after any output before
+    /// it); the session is unchanged.
+    /// Literals are read in the `ibase` earlier inputs left, and a function
+    /// keeps the one it was defined under. As in bc, an
+    /// input containing `quit` ends the session unrun.
+    pub fn eval(&mut self, source: &str) -> Result<String, String> {
+        let program = Parser::new(source).parse().map_err(|e| e.to_string())?;
+        if program.statements.iter().any(|stmt| matches!(stmt, Stmt::Quit)) {
+            self.quit = true;
+            return Ok(String::new());
+        }
+
+        // Earlier definitions follow the input, so its line numbers hold
+        let defined = |name: &str| program.functions.iter().any(|func| func.name == name);
+        let earlier: Vec<(Function, u32)> =
+            self.functions.iter().filter(|(func, _)| !defined(&func.name)).cloned().collect();
+        let functions = earlier.iter().map(|(func, _)| func.clone()).collect();
+        let source = format!("{}\n{}", source, format_program(&Program { functions, statements: Vec::new() }));
+        let ibase = self.env.ibase as u32;
+        let function_ibase = earlier.iter().map(|(func, ibase)| (func.name.clone(), *ibase)).collect();
+        let options = CompileOptions { ibase, function_ibase, ..Default::default() };
+        let module = Compiler::compile_with(&source, &options).map_err(|e| e.to_string())?;
+
+        let mut machine = Machine::new(&module);
+        machine.load_env(&self.env);
+        if let Err(Stop::Error(msg)) = machine.run() {
+            return Err(machine.out + &msg);
+        }
+        machine.save_env(&mut self.env);
+        self.functions = earlier;
+        self.functions.extend(program.functions.into_iter().map(|func| (func, ibase)));
+        Ok(machine.out)
+    }
+}
+
+/// Read bc from `input` a line at a time, run each in a `Session` and write
+/// what it prints to `output`, until `quit` or the end of the input. A line
+/// that leaves a `{` open waits for the lines that close it. Errors are
+/// written to `output` too, and the session carries on.
+pub fn repl(input: impl BufRead, output: &mut impl Write) -> io::Result<()> {
+    let mut session = Session::new();
+    let mut pending = String::new();
+    for line in input.lines() {
+        pending.push_str(&line?);
+        pending.push('\n');
+        if pending.matches('{').count() > pending.matches('}').count() {
+            continue;
+        }
+        match session.eval(&pending) {
+            Ok(out) => write!(output, "{}", out)?,
+            Err(msg) => writeln!(output, "{}", msg)?,
+        }
+        output.flush()?;
+        pending.clear();
+        if session.quit() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Why execution ended early
+enum Stop {
+    /// Halt or Quit
+    Done,
+    /// A runtime error the ROM reports itself (already printed)
+    Runtime,
+    Error(String),
+}
+
+struct Frame {
+    return_pc: usize,
+    first_slot: usize,
+    saved: Vec<(BcNum, Vec<BcNum>)>,
+}
+
+struct Machine<'a> {
+    module: &'a CompiledModule,
+    pc: usize,
+    stack: Vec<BcNum>,
+    vars: Vec<BcNum>,
+    /// Array storage by slot; empty until first used
+    arrays: Vec<Vec<BcNum>>,
+    frames: Vec<Frame>,
+    scale: usize,
+    ibase: u8,
+    obase: u8,
+    last: BcNum,
+    out: String,
+}
+
+impl<'a> Machine<'a> {
+    fn new(module: &'a CompiledModule) -> Self {
+        Machine {
+            module,
+            pc: 0,
+            stack: Vec::new(),
+            vars: vec![BcNum::zero(); 256],
+            arrays: vec![Vec::new(); 256],
+            frames: Vec::new(),
+            scale: 0,
+            ibase: 10,
+            obase: 10,
+            last: BcNum::zero(),
+            out: String::new(),
+        }
+    }
+
+    fn load_env(&mut self, env: &Env) {
+        for (name, &slot) in &self.module.variables {
+            if let Some(value) = env.values.get(name) {
+                self.vars[slot as usize] = value.clone();
+            }
+            if let Some(array) = env.arrays.get(name) {
+                self.arrays[slot as usize] = array.clone();
+            }
+        }
+        self.scale = env.scale;
+        self.ibase = env.ibase;
+        self.obase = env.obase;
+        self.last = env.last.clone();
+    }
+
+    fn save_env(&mut self, env: &mut Env) {
+        for (name, &slot) in &self.module.variables {
+            env.values.insert(name.clone(), self.vars[slot as usize].clone());
+            env.arrays.insert(name.clone(), std::mem::take(&mut self.arrays[slot as usize]));
+        }
+        env.scale = self.scale;
+        env.ibase = self.ibase;
+        env.obase = self.obase;
+        env.last = self.last.clone();
+    }
+
+    fn run(&mut self) -> Result<(), Stop> {
+        loop {
+            match self.step() {
+                Ok(()) => {}
+                Err(Stop::Done) | Err(Stop::Runtime) => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn fetch(&mut self) -> Result<u8, Stop> {
+        let byte = *self.module.bytecode.get(self.pc).ok_or_else(|| {
+            Stop::Error(format!("ran off the end of the bytecode at {:04X}", self.pc))
+        })?;
+        self.pc += 1;
+        Ok(byte)
+    }
+
+    fn fetch_u16(&mut self) -> Result<usize, Stop> {
+        let low = self.fetch()? as usize;
+        Ok(low | (self.fetch()? as usize) << 8)
+    }
+
+    fn pop(&mut self) -> Result<BcNum, Stop> {
+        self.stack.pop().ok_or_else(|| Stop::Error(format!("value stack empty at {:04X}", self.pc)))
+    }
+
+    /// Print the ROM's runtime error message and stop
+    fn runtime_error(&mut self, msg: &str) -> Stop {
+        self.out.push_str(&format!("Runtime error: {}\n", msg));
+        Stop::Runtime
+    }
+
+    fn push_checked(&mut self, result: Option<BcNum>) -> Result<(), Stop> {
+        match result {
+            Some(num) => {
+                self.stack.push(num);
+                Ok(())
+            }
+            None => Err(self.runtime_error("overflow")),
+        }
+    }
+
+    fn array_index(&mut self) -> Result<usize, Stop> {
+        let index = self.pop()?;
+        match to_integer(&index) {
+            Some(i) if !index.negative && i < ARRAY_SIZE => Ok(i),
+            _ => Err(self.runtime_error("array index out of range")),
+        }
+    }
+
+    fn array(&mut self, slot: usize) -> &mut Vec<BcNum> {
+        let array = &mut self.arrays[slot];
+        if array.is_empty() {
+            *array = vec![BcNum::zero(); ARRAY_SIZE];
+        }
+        array
+    }
+
+    fn step(&mut self) -> Result<(), Stop> {
+        let at = self.pc;
+        let byte = self.fetch()?;
+        let op = Op::from_u8(byte).ok_or_else(|| Stop::Error(format!("unknown opcode {:02X} at {:04X}", byte, at)))?;
+        match op {
+            Op::Halt | Op::Quit => return Err(Stop::Done),
+            Op::Nop => {}
+            Op::Trap => {
+                // The ROM dumps the value stack and carries on, unless an
+                // emulator stops it there
+                self.fetch_u16()?;
+                for value in &self.stack {
+                    self.out.push_str(&format!(" {}", format_decimal(value)));
+                }
+                self.out.push('\n');
+            }
+            Op::Pop => {
+                self.pop()?;
+            }
+            Op::Dup => {
+                let top = self.pop()?;
+                self.stack.push(top.clone());
+                self.stack.push(top);
+            }
+            Op::Swap => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.stack.push(b);
+                self.stack.push(a);
+            }
+
+            Op::LoadZero => self.stack.push(BcNum::zero()),
+            Op::LoadOne => self.stack.push(BcNum::one()),
+            Op::LoadNum => {
+                let index = self.fetch_u16()?;
+                let num = self.module.numbers.get(index).ok_or_else(|| Stop::Error(format!("no constant #{}", index)))?;
+                self.stack.push(num.clone());
+            }
+            Op::LoadStr => return Err(Stop::Error("LoadStr is not supported".to_string())),
+
+            Op::LoadVar => {
+                let slot = self.fetch()? as usize;
+                self.stack.push(self.vars[slot].clone());
+            }
+            Op::StoreVar => {
+                let slot = self.fetch()? as usize;
+                self.vars[slot] = self.pop()?;
+            }
+            Op::LoadArray => {
+                let slot = self.fetch()? as usize;
+                let index = self.array_index()?;
+                let value = self.array(slot)[index].clone();
+                self.stack.push(value);
+            }
+            Op::StoreArray => {
+                let slot = self.fetch()? as usize;
+                let index = self.array_index()?;
+                let value = self.pop()?;
+                self.array(slot)[index] = value;
+            }
+
+            Op::LoadScale => self.stack.push(from_integer(self.scale)),
+            Op::StoreScale => self.scale = to_byte(&self.pop()?) as usize,
+            Op::LoadIbase => self.stack.push(from_integer(self.ibase as usize)),
+            Op::StoreIbase => self.ibase = to_byte(&self.pop()?).clamp(2, 16),
+            Op::LoadObase => self.stack.push(from_integer(self.obase as usize)),
+            Op::StoreObase => self.obase = to_byte(&self.pop()?).clamp(2, 16),
+            Op::LoadLast => self.stack.push(self.last.clone()),
+
+            Op::Add | Op::Sub => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let b = if op == Op::Sub { negate(&b) } else { b };
+                self.push_checked(add(&a, &b))?;
+            }
+            Op::Mul => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push_checked(mul(&a, &b))?;
+            }
+            Op::Div | Op::DivRound => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let q = div(&a, &b, self.scale, op == Op::DivRound).ok_or_else(|| Stop::Error("divide by zero".to_string()))?;
+                self.stack.push(q);
+            }
+            Op::Mod => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let q = div(&a, &b, self.scale, false).ok_or_else(|| Stop::Error("divide by zero".to_string()))?;
+                let product = mul(&q, &b);
+                self.push_checked(product.and_then(|p| add(&a, &negate(&p))))?;
+            }
+            Op::Pow => {
+                let n = self.pop()?;
+                let x = self.pop()?;
+                let result = pow(&x, &n, self.scale).ok_or_else(|| Stop::Error("bad exponent".to_string()))?;
+                self.push_checked(result)?;
+            }
+            Op::Neg => {
+                let a = self.pop()?;
+                self.stack.push(negate(&a));
+            }
+
+            Op::Eq | Op::Ne | Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let truth = compare_matches(op, compare(&a, &b));
+                self.stack.push(from_integer(truth as usize));
+            }
+            Op::And | Op::Or => {
+                let b = !is_zero(&self.pop()?);
+                let a = !is_zero(&self.pop()?);
+                let truth = if op == Op::And { a && b } else { a || b };
+                self.stack.push(from_integer(truth as usize));
+            }
+            Op::Not => {
+                let a = self.pop()?;
+                self.stack.push(from_integer(is_zero(&a) as usize));
+            }
+
+            Op::Inc | Op::Dec => {
+                let a = self.pop()?;
+                let one = if op == Op::Inc { BcNum::one() } else { negate(&BcNum::one()) };
+                self.push_checked(add(&a, &one))?;
+            }
+
+            Op::Jump => self.pc = self.fetch_u16()?,
+            Op::JumpIfZero | Op::JumpIfNotZero => {
+                let target = self.fetch_u16()?;
+                if is_zero(&self.pop()?) == (op == Op::JumpIfZero) {
+                    self.pc = target;
+                }
+            }
+            Op::JumpIfEq | Op::JumpIfNe | Op::JumpIfLt | Op::JumpIfLe | Op::JumpIfGt | Op::JumpIfGe => {
+                let target = self.fetch_u16()?;
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let compare_op = match op {
+                    Op::JumpIfEq => Op::Eq,
+                    Op::JumpIfNe => Op::Ne,
+                    Op::JumpIfLt => Op::Lt,
+                    Op::JumpIfLe => Op::Le,
+                    Op::JumpIfGt => Op::Gt,
+                    _ => Op::Ge,
+                };
+                if compare_matches(compare_op, compare(&a, &b)) {
+                    self.pc = target;
+                }
+            }
+
+            Op::Call => self.call()?,
+            Op::Return => self.ret(BcNum::zero())?,
+            Op::ReturnValue => {
+                let value = self.pop()?;
+                self.ret(value)?;
+            }
+
+            Op::Length => {
+                let a = self.pop()?;
+                self.stack.push(from_integer(length(&a)));
+            }
+            Op::ScaleOf => {
+                let a = self.pop()?;
+                self.stack.push(from_integer(a.decimal_digits.len()));
+            }
+            Op::Sqrt => {
+                let a = self.pop()?;
+                if a.negative {
+                    return Err(self.runtime_error("square root of a negative number"));
+                }
+                self.stack.push(sqrt(&a, self.scale.max(a.decimal_digits.len())));
+            }
+
+            Op::Print => {
+                let a = self.pop()?;
+                let text = if self.obase == 10 { format_decimal(&a) } else { format_base(&a, self.obase) };
+                self.out.push_str(&text);
+                self.last = a;
+            }
+            Op::PrintStr => {
+                let index = self.fetch_u16()?;
+                let s = self.module.strings.get(index).ok_or_else(|| Stop::Error(format!("no string #{}", index)))?;
+                self.out.push_str(s);
+            }
+            Op::PrintNewline => self.out.push('\n'),
+            Op::Read => return Err(Stop::Error("read() needs input, which the host interpreter does not have".to_string())),
+        }
+        Ok(())
+    }
+
+    /// Save the callee's slots, pop the arguments into its parameters and
+    /// zero its autos, as the runtime's Call does
+    fn call(&mut self) -> Result<(), Stop> {
+        let index = self.fetch()? as usize;
+        let func = self.module.functions.get(index).ok_or_else(|| Stop::Error(format!("no function #{}", index)))?;
+        let first_slot = func.first_slot as usize;
+        let slots = first_slot..first_slot + func.param_count + func.local_count;
+
+        let saved = slots
+            .clone()
+            .map(|slot| (self.vars[slot].clone(), std::mem::take(&mut self.arrays[slot])))
+            .collect();
+        self.frames.push(Frame { return_pc: self.pc, first_slot, saved });
+
+        for slot in slots.clone().rev() {
+            self.vars[slot] = if slot < first_slot + func.param_count { self.pop()? } else { BcNum::zero() };
+        }
+        self.pc = func.bytecode_offset;
+        Ok(())
+    }
+
+    fn ret(&mut self, value: BcNum) -> Result<(), Stop> {
+        let frame = self.frames.pop().ok_or_else(|| Stop::Error("return outside a function".to_string()))?;
+        for (i, (var, array)) in frame.saved.into_iter().enumerate() {
+            self.vars[frame.first_slot + i] = var;
+            self.arrays[frame.first_slot + i] = array;
+        }
+        self.pc = frame.return_pc;
+        self.stack.push(value);
+        Ok(())
+    }
+}
+
+// =====================================================
+// Arithmetic on BcNum
+// =====================================================
+//
+// Magnitudes are digit vectors, least significant first, holding
+// value * 10^scale.
+
+fn digits_of(num: &BcNum) -> Vec<u8> {
+    num.integer_digits.iter().chain(&num.decimal_digits).rev().copied().collect()
+}
+
+/// Build a BcNum from a magnitude at `scale`, trimming leading zeros
+fn from_digits(negative: bool, mut digits: Vec<u8>, scale: usize) -> BcNum {
+    while digits.len() < scale + 1 {
+        digits.push(0);
+    }
+    let decimal_digits: Vec<u8> = digits[..scale].iter().rev().copied().collect();
+    let mut integer_digits: Vec<u8> = digits[scale..].iter().rev().copied().collect();
+    let leading = integer_digits.iter().take_while(|&&d| d == 0).count().min(integer_digits.len() - 1);
+    integer_digits.drain(..leading);
+    let zero = integer_digits == [0] && decimal_digits.iter().all(|&d| d == 0);
+    BcNum { negative: negative && !zero, integer_digits, decimal_digits }
+}
+
+fn from_integer(value: usize) -> BcNum {
+    BcNum::parse(&value.to_string())
+}
+
+fn trim(digits: &mut Vec<u8>) {
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+}
+
+/// Significant digits of a magnitude
+fn digit_count(digits: &[u8]) -> usize {
+    digits.iter().rposition(|&d| d != 0).map_or(0, |i| i + 1)
+}
+
+fn shifted(digits: &[u8], places: usize) -> Vec<u8> {
+    let mut out = vec![0; places];
+    out.extend_from_slice(digits);
+    out
+}
+
+fn mag_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let (la, lb) = (digit_count(a), digit_count(b));
+    la.cmp(&lb).then_with(|| a[..la].iter().rev().cmp(b[..lb].iter().rev()))
+}
+
+fn mag_add(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0;
+    for i in 0..a.len().max(b.len()) {
+        let sum = a.get(i).unwrap_or(&0) + b.get(i).unwrap_or(&0) + carry;
+        out.push(sum % 10);
+        carry = sum / 10;
+    }
+    out.push(carry);
+    out
+}
+
+/// a - b for a >= b
+fn mag_sub(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(a.len());
+    let mut borrow = 0;
+    for (i, &da) in a.iter().enumerate() {
+        let mut d = da as i8 - *b.get(i).unwrap_or(&0) as i8 - borrow;
+        borrow = (d < 0) as i8;
+        if d < 0 {
+            d += 10;
+        }
+        out.push(d as u8);
+    }
+    out
+}
+
+fn mag_mul(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut out = vec![0u32; a.len() + b.len() + 1];
+    for (i, &da) in a.iter().enumerate() {
+        for (j, &db) in b.iter().enumerate() {
+            out[i + j] += da as u32 * db as u32;
+        }
+    }
+    let mut carry = 0;
+    for d in out.iter_mut() {
+        let v = *d + carry;
+        *d = v % 10;
+        carry = v / 10;
+    }
+    out.into_iter().map(|d| d as u8).collect()
+}
+
+/// Long division of magnitudes: (quotient, remainder)
+fn mag_divmod(a: &[u8], b: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut quotient = vec![0; a.len()];
+    let mut rem: Vec<u8> = Vec::new();
+    for i in (0..a.len()).rev() {
+        rem.insert(0, a[i]);
+        let mut q = 0;
+        while mag_cmp(&rem, b) != Ordering::Less {
+            rem = mag_sub(&rem, b);
+            q += 1;
+        }
+        quotient[i] = q;
+    }
+    (quotient, rem)
+}
+
+fn is_zero(num: &BcNum) -> bool {
+    digit_count(&digits_of(num)) == 0
+}
+
+fn negate(num: &BcNum) -> BcNum {
+    BcNum { negative: !num.negative && !is_zero(num), ..num.clone() }
+}
+
+/// Both magnitudes at the larger of the two scales
+fn aligned(a: &BcNum, b: &BcNum) -> (Vec<u8>, Vec<u8>, usize) {
+    let (sa, sb) = (a.decimal_digits.len(), b.decimal_digits.len());
+    let scale = sa.max(sb);
+    (shifted(&digits_of(a), scale - sa), shifted(&digits_of(b), scale - sb), scale)
+}
+
+fn fits(num: BcNum) -> Option<BcNum> {
+    (digit_count(&digits_of(&num)) <= MAX_DIGITS).then_some(num)
+}
+
+/// a + b, or None past 50 digits
+fn add(a: &BcNum, b: &BcNum) -> Option<BcNum> {
+    let (da, db, scale) = aligned(a, b);
+    let result = if a.negative == b.negative {
+        from_digits(a.negative, mag_add(&da, &db), scale)
+    } else if mag_cmp(&da, &db) != Ordering::Less {
+        from_digits(a.negative, mag_sub(&da, &db), scale)
+    } else {
+        from_digits(b.negative, mag_sub(&db, &da), scale)
+    };
+    fits(result)
+}
+
+/// a * b at scale(a) + scale(b), or None past 50 digits
+fn mul(a: &BcNum, b: &BcNum) -> Option<BcNum> {
+    let scale = a.decimal_digits.len() + b.decimal_digits.len();
+    fits(from_digits(a.negative != b.negative, mag_mul(&digits_of(a), &digits_of(b)), scale))
+}
+
+/// a / b cut off at `scale` digits (rounded half up if `round`), or None
+/// for division by zero
+fn div(a: &BcNum, b: &BcNum, scale: usize, round: bool) -> Option<BcNum> {
+    if is_zero(b) {
+        return None;
+    }
+    // a * 10^(scale + scale(b) - scale(a)) / b, shifting b instead when
+    // the exponent is negative
+    let (sa, sb) = (a.decimal_digits.len(), b.decimal_digits.len());
+    let (mut da, mut db) = (digits_of(a), digits_of(b));
+    if scale + sb >= sa {
+        da = shifted(&da, scale + sb - sa);
+    } else {
+        db = shifted(&db, sa - scale - sb);
+    }
+    trim(&mut db);
+    let (mut q, rem) = mag_divmod(&da, &db);
+    if round && mag_cmp(&mag_add(&rem, &rem), &db) != Ordering::Less {
+        q = mag_add(&q, &[1]);
+    }
+    Some(from_digits(a.negative != b.negative, q, scale))
+}
+
+/// x ^ n for an integer n; a negative n gives 1 / x^|n| at `scale`
+fn pow(x: &BcNum, n: &BcNum, scale: usize) -> Option<Option<BcNum>> {
+    if n.decimal_digits.iter().any(|&d| d != 0) {
+        return None;
+    }
+    let count = to_integer(n)?;
+    let mut result = Some(BcNum::one());
+    for _ in 0..count {
+        result = result.and_then(|r| mul(&r, x));
+    }
+    if n.negative {
+        return Some(result.and_then(|r| div(&BcNum::one(), &r, scale, false)));
+    }
+    Some(result)
+}
+
+/// Square root truncated to `scale` digits
+fn sqrt(a: &BcNum, scale: usize) -> BcNum {
+    // isqrt(a * 10^(2 * scale)) by Newton's method on magnitudes
+    let sa = a.decimal_digits.len();
+    let mut n = digits_of(a);
+    if 2 * scale >= sa {
+        n = shifted(&n, 2 * scale - sa);
+    } else {
+        n.drain(..sa - 2 * scale);
+    }
+    trim(&mut n);
+    if n.is_empty() {
+        return from_digits(false, Vec::new(), scale);
+    }
+    let mut guess = n.clone();
+    loop {
+        let (q, _) = mag_divmod(&n, &guess);
+        let (mut next, _) = mag_divmod(&mag_add(&guess, &q), &[2]);
+        trim(&mut next);
+        if mag_cmp(&next, &guess) != Ordering::Less {
+            return from_digits(false, guess, scale);
+        }
+        guess = next;
+    }
+}
+
+fn compare(a: &BcNum, b: &BcNum) -> Ordering {
+    let (da, db, _) = aligned(a, b);
+    match (a.negative, b.negative) {
+        (false, true) => Ordering::Greater,
+        (true, false) => Ordering::Less,
+        (false, false) => mag_cmp(&da, &db),
+        (true, true) => mag_cmp(&db, &da),
+    }
+}
+
+fn compare_matches(op: Op, ordering: Ordering) -> bool {
+    match op {
+        Op::Eq => ordering == Ordering::Equal,
+        Op::Ne => ordering != Ordering::Equal,
+        Op::Lt => ordering == Ordering::Less,
+        Op::Le => ordering != Ordering::Greater,
+        Op::Gt => ordering == Ordering::Greater,
+        _ => ordering != Ordering::Less,
+    }
+}
+
+/// Integer part, if it fits a usize
+fn to_integer(num: &BcNum) -> Option<usize> {
+    num.integer_digits.iter().try_fold(0usize, |acc, &d| acc.checked_mul(10)?.checked_add(d as usize))
+}
+
+/// The last three integer digits mod 256, as the runtime's num_to_byte
+fn to_byte(num: &BcNum) -> u8 {
+    let digits = &num.integer_digits;
+    let low = digits[digits.len().saturating_sub(3)..].iter().fold(0u32, |acc, &d| acc * 10 + d as u32);
+    (low % 256) as u8
+}
+
+/// Significant digits, but at least the scale and at least 1
+fn length(num: &BcNum) -> usize {
+    digit_count(&digits_of(num)).max(num.decimal_digits.len()).max(1)
+}
+
+/// The ROM's decimal format: no leading zero before the point, and zero as
+/// a bare `0` whatever its scale
+fn format_decimal(num: &BcNum) -> String {
+    if is_zero(num) {
+        return "0".to_string();
+    }
+    let mut out = String::new();
+    if num.negative {
+        out.push('-');
+    }
+    if num.integer_digits != [0] {
+        out.extend(num.integer_digits.iter().map(|d| (b'0' + d) as char));
+    }
+    if !num.decimal_digits.is_empty() {
+        out.push('.');
+        out.extend(num.decimal_digits.iter().map(|d| (b'0' + d) as char));
+    }
+    out
+}
+
+/// Integer part in `base`, as the ROM prints for obase other than 10
+fn format_base(num: &BcNum, base: u8) -> String {
+    let mut value: Vec<u8> = num.integer_digits.iter().rev().copied().collect();
+    let mut digits = Vec::new();
+    loop {
+        let (q, rem) = mag_divmod(&value, &[base % 10, base / 10]);
+        let rem = rem.iter().rev().fold(0usize, |acc, &d| acc * 10 + d as usize);
+        digits.push(b"0123456789ABCDEF"[rem] as char);
+        value = q;
+        trim(&mut value);
+        if value.is_empty() {
+            break;
+        }
+    }
+    let sign = if num.negative && digits != ['0'] { "-" } else { "" };
+    format!("{}{}", sign, digits.iter().rev().collect::<String>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str) -> Vec<String> {
+        interpret(&Compiler::compile(source).unwrap()).unwrap()
+    }
+
+    /// Programs run both here and on the emulated ROM; their output must
+    /// agree line for line
+    const DIFFERENTIAL_CORPUS: &[&str] = &[
+        // Arithmetic
+        "1 + 2\n5 - 8\n-3 - -4\n6 * 7\n84 / 7\n100 - 0.5\n",
+        ".5 * .5\n1.25 * 4\n-1.5 * 2.25\n123456789 * 987654321\n",
+        "scale=4\n2/3\n-2/3\n1/8\n7 % 3\n5.5 % 2\n22/7\n",
+        "scale=10\n1/3\n2^10\n2^-3\n(1 + 2) * 3 - 4 / 2\nsqrt(2)\nsqrt(144)\nscale=2\nsqrt(2.0000)\nscale\n",
+        "x = 12\ny = x * x\ny - x\nx += 3\nx\nx *= 2\nx\nx++\nx\n--x\n",
+        "scale = 3\nscale + 1\nx = scale * 2\nx\n1/scale\n",
+        "length(12345)\nlength(.05)\nscale(1.250)\nobase=16\n255\n-26\n10.75\nobase=2\n5\n",
+        // Loops and conditionals
+        "for (i = 0; i < 5; i++) print i, \" \"\n\"\\n\"\ni\n",
+        "n = 10\ns = 0\nwhile (n > 0) { s = s + n; n = n - 1 }\ns\n",
+        "x = 3\nif (x > 2) \"big\\n\" else \"small\\n\"\nif (x == 3) x\nif (x != 3) 1\nif (!(x < 3)) 2\n",
+        "i = 0\nwhile (1) { i = i + 1; if (i == 3) continue; if (i > 5) break; i }\n",
+        "1;2\nprint 1,2; 3\n4; print 5\nprint \"\\n\"\nprint \"x\"; 5\nif (1) { 7; 8 }\n",
+        // Functions
+        "define f(n) { if (n <= 1) return 1; return n * f(n - 1) }\nf(10)\nf(20)\n",
+        "define g(a, b) { auto t; t = a * b; return t - a }\ng(3, 4)\nt = 9\ng(2, 5)\nt\n",
+        "define h(x) { auto a[]; a[1] = a[1] + x; return a[1] }\nh(5)\nh(7)\na[2] = 3\na[2] * 2\n",
+        "define p() { print \"in p\\n\" }\np()\nx = p()\nx\n",
+        "define r(n) { auto a[], t; a[0] = n; a[1] += 1; if (n > 0) t = r(n - 1); return t + a[0] + a[1] }\nr(3)\n",
+        "define f(x) { return x % 3 }\ndefine g(y) { auto z; z = 5; t = f(y); return z }\ng(7)\n",
+        "define h(n) { auto a[]; a[0] = n; return a[0] }\ns = 0\nfor (i = 0; i < 100; i++) s = s + h(i)\ns\n",
+        "define s(x) { return sqrt(x) + 2^3 }\ndefine u(y) { auto a[], z; z = 5; a[0] += s(y); return z * 10 + a[0] }\nu(16)\n",
+    ];
+
+    #[test]
+    fn test_matches_z80() {
+        use crate::emulator::Machine;
+
+        for source in DIFFERENTIAL_CORPUS {
+            let module = Compiler::compile(source).unwrap();
+            let host = interpret(&module).unwrap();
+
+            let mut machine = Machine::new(&crate::z80::generate_rom(&module));
+            assert!(machine.run(50_000_000), "ROM did not halt: {:?}", source);
+            let z80: Vec<String> = machine.output().lines().map(String::from).collect();
+            assert_eq!(host, z80, "host and ROM differ on {:?}", source);
+        }
+    }
+
+    #[test]
+    fn test_load_num_reads_constant_table() {
+        // Constants come straight from module.numbers, never from packed bytes
+        let module = Compiler::compile("123.45").unwrap();
+        assert_eq!(module.bytecode[..3], [Op::LoadNum as u8, 0, 0]);
+        let mut machine = Machine::new(&module);
+        assert!(machine.step().is_ok());
+        assert_eq!(machine.stack.len(), 1);
+        assert_eq!(machine.stack[0].integer_digits, [1, 2, 3]);
+        assert_eq!(machine.stack[0].decimal_digits, [4, 5]);
+        assert!(!machine.stack[0].negative);
+        assert_eq!(run("123.45\n-123.45\n"), ["123.45", "-123.45"]);
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(
+            run("1 + 2\n5 - 8\n6 * 7\n.5 * .5\n1.25 * 4\nscale=4\n2/3\n-2/3\n7 % 3\n2^10\n2^-3\nsqrt(2)\n"),
+            ["3", "-3", "42", ".25", "5.00", ".6666", "-.6666", ".0001", "1024", ".1250", "1.4142"]
+        );
+        let max = "9".repeat(50);
+        assert_eq!(run(&format!("{}\n{} + 1\n2\n", max, max)), [max.as_str(), "Runtime error: overflow"]);
+    }
+
+    #[test]
+    fn test_control_flow_and_functions() {
+        let source = "define f(n) { auto r; r = 1; while (n > 1) r *= n--; return r }\n\
+                      for (i = 0; i < 3; i++) print i, \" \"\n\
+                      \"\\n\"\nif (f(5) == 120) \"yes\\n\" else \"no\\n\"\nf(20)\n";
+        assert_eq!(run(source), ["0 1 2 ", "yes", "2432902008176640000"]);
+        // f's hidden % temporaries must not overwrite g's auto
+        assert_eq!(run("define f(x) { return x % 3 }\ndefine g(y) { auto z; z = 5; t = f(y); return z }\ng(7)\n"), ["5"]);
+    }
+
+    #[test]
+    fn test_arrays_bases_and_builtins() {
+        assert_eq!(
+            run("a[3] = 4\na[3] * 2\na[1]\nlength(123.45)\nscale(1.50)\nobase=16\n255\n-26\n"),
+            ["8", "0", "5", "2", "FF", "-1A"]
+        );
+        assert_eq!(run("a[256]\n"), ["Runtime error: array index out of range"]);
+        assert!(interpret(&Compiler::compile("x = read()").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_repl_session() {
+        let input = "x[2] = 4; y = 3\ndefine f(n) {\n  return n * y\n}\nf(x[2])\ny = 1 +\nf(2); 1/0\nquit\n99\n";
+        let mut output = Vec::new();
+        repl(input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "12");
+        assert!(lines[1].starts_with("Parse error at 1:"), "{}", lines[1]);
+        assert_eq!(lines[2..], ["6", "divide by zero"]);
+    }
+
+    #[test]
+    fn test_env_carries_across_snippets() {
+        let mut session = Session::new();
+        let mut eval = |source: &str| session.eval(source).unwrap();
+        assert_eq!(eval("a=5"), "");
+        assert_eq!(eval("a*2"), "10\n");
+        eval("scale=3; b[1] = 7");
+        assert_eq!(eval("1/3\nb[1] + a"), ".333\n12\n");
+        eval("ibase=16");
+        assert_eq!(eval("A + 1"), "11\n");
+        eval("obase=2");
+        assert_eq!(eval("5"), "101\n");
+
+        let mut env = Env::default();
+        interpret_in(&Compiler::compile("x = 4; scale = 2").unwrap(), &mut env).unwrap();
+        assert_eq!(interpret_in(&Compiler::compile("x / 3").unwrap(), &mut env).unwrap(), ["1.33"]);
+        assert_eq!((env.scale, format_decimal(&env.values["x"])), (2, "4".to_string()));
+    }
+
+    #[test]
+    fn test_last_in_session() {
+        let mut session = Session::new();
+        assert_eq!(session.eval("3+4").unwrap(), "7\n");
+        assert_eq!(session.eval("last+1").unwrap(), "8\n");
+        assert_eq!(session.eval(". * 2").unwrap(), "16\n");
+        // Assignments print nothing, so last keeps the printed value
+        assert_eq!(session.eval("x = 100\nlast").unwrap(), "16\n");
+        assert_eq!(format_decimal(&session.env().last), "16");
+    }
+
+    #[test]
+    fn test_function_keeps_its_ibase() {
+        let mut session = Session::new();
+        session.eval("define f() { return 10 }").unwrap();
+        session.eval("ibase=16").unwrap();
+        assert_eq!(session.eval("f()").unwrap(), "10\n");
+        session.eval("define g() { return 10 }").unwrap();
+        assert_eq!(session.eval("f() + g()").unwrap(), "26\n");
+    }
+}
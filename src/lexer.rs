@@ -90,7 +90,7 @@ impl Lexer {
 
         // Read digits (hex digits allowed if ibase > 10)
         while let Some(ch) = self.peek() {
-            if ch.is_ascii_digit() || (ch >= 'A' && ch <= 'F') || ch == '.' {
+            if ch.is_ascii_digit() || ('A'..='F').contains(&ch) || ch == '.' {
                 num.push(ch);
                 self.advance();
             } else if ch == '\\' && self.peek_ahead(1) == Some('\n') {
@@ -105,14 +105,15 @@ impl Lexer {
         num
     }
 
-    fn read_string(&mut self) -> String {
+    /// Returns None if the input ends before the closing quote
+    fn read_string(&mut self) -> Option<String> {
         let mut s = String::new();
         self.advance(); // opening "
 
         while let Some(ch) = self.peek() {
             if ch == '"' {
                 self.advance();
-                break;
+                return Some(s);
             } else if ch == '\\' {
                 self.advance();
                 if let Some(esc) = self.peek() {
@@ -134,7 +135,7 @@ impl Lexer {
             }
         }
 
-        s
+        None
     }
 
     fn read_ident(&mut self) -> String {
@@ -178,20 +179,20 @@ impl Lexer {
                     Token::Newline
                 }
 
-                '0'..='9' | '.' if ch == '.' && !self.peek_ahead(1).map_or(false, |c| c.is_ascii_digit()) => {
-                    // Just a dot, not a number
+                '0'..='9' | '.' if ch == '.' && !self.peek_ahead(1).is_some_and(|c| c.is_ascii_digit()) => {
+                    // A dot that starts no number is bc's short name for last
                     self.advance();
-                    continue; // Ignore stray dots
+                    Token::Last
                 }
                 '0'..='9' | 'A'..='F' | '.' => {
                     let num = self.read_number();
                     Token::Number(num)
                 }
 
-                '"' => {
-                    let s = self.read_string();
-                    Token::String(s)
-                }
+                '"' => match self.read_string() {
+                    Some(s) => Token::String(s),
+                    None => Token::Error("unterminated string".to_string()),
+                },
 
                 'a'..='z' | '_' | 'G'..='Z' => {
                     let ident = self.read_ident();
@@ -386,7 +387,7 @@ impl Lexer {
 
                 _ => {
                     self.advance();
-                    continue; // Skip unknown characters
+                    Token::Error(format!("illegal character '{}'", ch))
                 }
             };
 
@@ -448,4 +449,11 @@ mod tests {
         assert!(matches!(lexer.next_token().token, Token::Assign));
         assert!(matches!(lexer.next_token().token, Token::Number(n) if n == "5"));
     }
+
+    #[test]
+    fn test_dot_is_last() {
+        let mut lexer = Lexer::new(". .5");
+        assert!(matches!(lexer.next_token().token, Token::Last));
+        assert!(matches!(lexer.next_token().token, Token::Number(n) if n == ".5"));
+    }
 }
@@ -0,0 +1,229 @@
+//! bc80 - compile bc programs to Z80 ROM images
+//!
+//! `build_rom` runs the whole pipeline (lex, parse, compile, optionally
+//! optimize, generate the runtime) for tools that embed the compiler.
+
+pub mod ast;
+pub mod bcm;
+pub mod bytecode;
+pub mod compiler;
+pub mod cost;
+pub mod disasm;
+#[cfg(test)]
+mod emulator;
+pub mod error;
+pub mod format;
+pub mod interpreter;
+pub mod lexer;
+pub mod parser;
+pub mod peephole;
+pub mod simplify;
+pub mod token;
+pub mod z80;
+
+use bytecode::CompiledModule;
+use compiler::{CompileOptions, Compiler};
+use std::collections::BTreeMap;
+
+pub use error::{BuildError, Warning};
+
+/// Options for building a ROM
+#[derive(Debug, Clone)]
+pub struct Z80Config {
+    /// Run the peephole optimizer over the bytecode
+    pub optimize: bool,
+    /// Base the program's numeric literals are written in (2-16)
+    pub ibase: u32,
+    /// Round `/` half up in the last digit instead of truncating
+    pub round_division: bool,
+    /// Fail the build if the compiler reports any warning
+    pub warnings_as_errors: bool,
+    /// Source lines to compile a breakpoint (`Op::Trap`) in front of
+    pub breakpoints: Vec<usize>,
+    /// Leave bare expression results unprinted, so only `print` and strings
+    /// produce output
+    pub quiet: bool,
+    /// Fail the build if the final image is larger than this many bytes,
+    /// such as the size of the ROM chip being programmed
+    pub max_rom_size: Option<usize>,
+    /// ROM and RAM regions of the target board
+    pub layout: z80::MemoryLayout,
+    /// Extras compiled into the runtime, such as instruction tracing
+    pub runtime: z80::RuntimeOptions,
+}
+
+impl Default for Z80Config {
+    fn default() -> Self {
+        Z80Config {
+            optimize: false,
+            ibase: 10,
+            round_division: false,
+            warnings_as_errors: false,
+            breakpoints: Vec::new(),
+            quiet: false,
+            max_rom_size: None,
+            layout: z80::MemoryLayout::default(),
+            runtime: z80::RuntimeOptions::default(),
+        }
+    }
+}
+
+/// Everything a successful build produces
+#[derive(Debug)]
+pub struct RomArtifacts {
+    pub rom: Vec<u8>,
+    /// Addresses of runtime routines, sections and functions
+    pub symbols: BTreeMap<String, u16>,
+    pub module: CompiledModule,
+    /// Bytes saved by the peephole optimizer (0 if it did not run)
+    pub optimizer_saved: usize,
+}
+
+impl RomArtifacts {
+    /// One line per region of the image: runtime, padding up to the
+    /// bytecode, bytecode, constants and strings, as `0xSTART-0xEND name`
+    /// with END exclusive
+    pub fn layout_listing(&self) -> String {
+        let regions = ["padding", "bytecode", "constants", "strings"];
+        let mut bounds = vec![0];
+        bounds.extend(regions.iter().map(|name| self.symbols[*name] as usize));
+        bounds.push(self.rom.len());
+
+        let mut out = String::new();
+        for (i, name) in ["runtime"].iter().chain(&regions).enumerate() {
+            let (start, end) = (bounds[i], bounds[i + 1]);
+            out.push_str(&format!("0x{:04X}-0x{:04X} {} ({} bytes)\n", start, end, name, end - start));
+        }
+        out
+    }
+}
+
+/// Compile bc source into a Z80 ROM image
+pub fn build_rom(source: &str, config: &Z80Config) -> Result<RomArtifacts, BuildError> {
+    let options = CompileOptions {
+        ibase: config.ibase,
+        round_division: config.round_division,
+        breakpoints: config.breakpoints.clone(),
+        quiet: config.quiet,
+        ..Default::default()
+    };
+    let mut module = Compiler::compile_with(source, &options)?;
+    if config.warnings_as_errors {
+        if let Some(warning) = module.warnings.first() {
+            return Err(BuildError::compile(format!("{} (warnings are errors)", warning.message)));
+        }
+    }
+
+    let optimizer_saved = if config.optimize {
+        peephole::optimize(&mut module)
+    } else {
+        0
+    };
+
+    let (rom, symbols) = z80::generate_rom_with_layout(&module, &config.layout, &config.runtime)?;
+    if let Some(max) = config.max_rom_size.filter(|&max| rom.len() > max) {
+        return Err(BuildError::Rom {
+            msg: format!(
+                "ROM image is {} bytes, {} over the {}-byte limit",
+                rom.len(),
+                rom.len() - max,
+                max
+            ),
+        });
+    }
+
+    Ok(RomArtifacts {
+        rom,
+        symbols,
+        module,
+        optimizer_saved,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_rom() {
+        let config = Z80Config::default();
+        let artifacts = build_rom("define f(x) { return x }\nprint f(2)", &config).unwrap();
+        let bytecode = artifacts.symbols["bytecode"] as usize;
+        assert_eq!(
+            &artifacts.rom[bytecode..bytecode + artifacts.module.bytecode.len()],
+            &artifacts.module.bytecode[..]
+        );
+        assert!(artifacts.symbols["print_num"] < artifacts.symbols["bytecode"]);
+        assert!(artifacts.symbols.contains_key("fn f"));
+        assert_eq!(artifacts.optimizer_saved, 0);
+    }
+
+    #[test]
+    fn test_layout_listing() {
+        let artifacts = build_rom("x = 12\nprint \"x=\", x", &Z80Config::default()).unwrap();
+        let listing = artifacts.layout_listing();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("0x0000-"));
+        assert!(lines[1].contains("-0x2000 padding ("));
+        // Bytecode sits at BYTECODE_ORG, followed by one 53-byte constant
+        // and the length-prefixed string
+        assert!(lines[2].starts_with("0x2000-") && lines[2].contains(" bytecode "));
+        assert!(lines[3].ends_with("constants (53 bytes)"));
+        assert!(lines[4].ends_with("strings (3 bytes)"));
+        assert!(lines[4].contains(&format!("-0x{:04X} ", artifacts.rom.len())));
+    }
+
+    #[test]
+    fn test_build_rom_optimized() {
+        let config = Z80Config { optimize: true, ..Default::default() };
+        let artifacts = build_rom("if (!(1 < 2)) print 3", &config).unwrap();
+        assert_eq!(artifacts.optimizer_saved, 2);
+    }
+
+    #[test]
+    fn test_max_rom_size() {
+        let source = "print \"hello\"\n";
+        let size = build_rom(source, &Z80Config::default()).unwrap().rom.len();
+
+        let config = Z80Config { max_rom_size: Some(size), ..Default::default() };
+        assert!(build_rom(source, &config).is_ok());
+
+        let config = Z80Config { max_rom_size: Some(size - 3), ..Default::default() };
+        let err = build_rom(source, &config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("ROM error: ROM image is {} bytes, 3 over the {}-byte limit", size, size - 3)
+        );
+    }
+
+    #[test]
+    fn test_build_rom_error() {
+        let err = build_rom("break", &Z80Config::default()).unwrap_err();
+        assert!(matches!(err, BuildError::Compile { .. }));
+
+        let config = Z80Config {
+            layout: z80::MemoryLayout { rom_size: 0x2000, ram_start: 0x2000, ..Default::default() },
+            ..Default::default()
+        };
+        let err = build_rom("print 1", &config).unwrap_err();
+        assert!(matches!(err, BuildError::Rom { .. }));
+    }
+
+    #[test]
+    fn test_warnings_as_errors() {
+        let source = "define f() { return 1\n x = 2 }\nprint f()";
+        let artifacts = build_rom(source, &Z80Config::default()).unwrap();
+        assert_eq!(artifacts.module.warnings.len(), 1);
+
+        let config = Z80Config { warnings_as_errors: true, ..Default::default() };
+        let err = build_rom(source, &config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Compile error: unreachable statement after return in f(): x = 2 (warnings are errors)"
+        );
+        assert!(build_rom("print 1", &config).is_ok());
+        let err = build_rom("x = y + 0", &config).unwrap_err();
+        assert_eq!(err.to_string(), "Compile error: y + 0 simplified to y (warnings are errors)");
+    }
+}
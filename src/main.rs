@@ -1,14 +1,7 @@
-mod ast;
-mod bytecode;
-mod compiler;
-mod lexer;
-mod parser;
-mod token;
-mod z80;
-
-use compiler::Compiler;
+use kz80_bc::{bcm, build_rom, bytecode, cost, disasm, format, interpreter, lexer, parser, z80, Z80Config};
 use std::env;
 use std::fs;
+use std::io;
 use std::process;
 
 fn print_usage(program: &str) {
@@ -16,17 +9,60 @@ fn print_usage(program: &str) {
     eprintln!();
     eprintln!("Usage: {} [options] <file.bc>", program);
     eprintln!("       {} --repl FILE   Generate standalone REPL ROM", program);
+    eprintln!("       {} --host-repl   Run bc interactively on this machine", program);
     eprintln!();
     eprintln!("Options:");
     eprintln!("  --tokens     Show tokenized output");
     eprintln!("  --ast        Show parsed AST");
+    eprintln!("  --format     Print the program reformatted as canonical bc source");
     eprintln!("  --bytecode   Show compiled bytecode");
+    eprintln!("  --cost       Estimate instruction count and Z80 cycles (loops as 10 passes)");
+    eprintln!("  --dump-cfg   Print the bytecode's control-flow graph in Graphviz DOT format");
+    eprintln!("  --optimize   Run the peephole optimizer (also -O)");
+    eprintln!("  --ibase N    Read numeric literals in base N (2-16, default 10)");
+    eprintln!("  --round      Round / half up in the last digit (bc truncates)");
+    eprintln!("  --emit-constants  Show the constant table as packed BCD");
+    eprintln!("  --trace      Make the ROM print each VM PC and opcode as it runs");
+    eprintln!("  --profile    Make the ROM count each opcode it runs and print the counts at exit");
+    eprintln!("  --exit-addr ADDR  Jump to ADDR (e.g. a ROM monitor) instead of HALT at exit");
+    eprintln!("  --newline crlf|lf|cr  Line ending the ROM writes (default crlf)");
+    eprintln!("  --columns N  Break numbers wider than N columns with \\ and a newline, like bc (default: no wrapping)");
+    eprintln!("  --quiet      Do not print the values of bare expressions, only print and strings");
+    eprintln!("  --warnings-as-errors  Fail the build if the compiler reports any warning");
+    eprintln!("  --break LINE  Stop at the statement starting on LINE and dump the value stack (repeatable)");
+    eprintln!("  --max-rom-size N  Fail if the ROM image is larger than N bytes (e.g. 32768 or 0x8000)");
+    eprintln!("  --strip      Leave runtime handlers the program never uses out of the ROM");
     eprintln!("  --rom FILE   Generate Z80 ROM image");
+    eprintln!("  --dump-rom-layout  Show the byte ranges of each region of the ROM");
+    eprintln!("  --emit-vm FILE  Write the bytecode, constants, strings and functions as a BCM file");
+    eprintln!("  --annotate FILE  Write the runtime as Z80 mnemonics labelled with routine names");
     eprintln!("  --repl FILE  Generate standalone REPL ROM (no input file needed)");
+    eprintln!("  --host-repl  Read bc from stdin and run it with the host interpreter");
+    eprintln!("  --selftest FILE  Generate a ROM that checks the BCD routines on boot");
+    eprintln!("  --banner TEXT  REPL startup banner (with --repl)");
+    eprintln!("  --prompt TEXT  REPL input prompt (with --repl)");
+    eprintln!("  --repl-init TEXT  Line(s) the REPL evaluates silently at boot (repeatable)");
     eprintln!("  -o FILE      Output file (default: stdout for bytecode)");
     eprintln!("  -h, --help   Show this help");
 }
 
+/// Parse a 16-bit address given in decimal or as 0x-prefixed hex
+fn parse_addr(arg: &str) -> Option<u16> {
+    match arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+/// Parse a size in bytes given in decimal or as 0x-prefixed hex. Unlike an
+/// address it may be 0x10000, the whole address space.
+fn parse_size(arg: &str) -> Option<usize> {
+    match arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
@@ -37,9 +73,27 @@ fn main() {
 
     let mut show_tokens = false;
     let mut show_ast = false;
+    let mut show_format = false;
     let mut show_bytecode = false;
+    let mut show_cfg = false;
+    let mut show_cost = false;
+    let mut optimize = false;
+    let mut ibase = 10;
+    let mut round_division = false;
+    let mut warnings_as_errors = false;
+    let mut quiet = false;
+    let mut breakpoints = Vec::new();
+    let mut max_rom_size = None;
+    let mut show_constants = false;
+    let mut show_rom_layout = false;
+    let mut runtime = z80::RuntimeOptions::default();
     let mut rom_file: Option<String> = None;
+    let mut annotate_file: Option<String> = None;
+    let mut vm_file: Option<String> = None;
     let mut repl_file: Option<String> = None;
+    let mut repl_config = z80::ReplConfig::default();
+    let mut selftest_file: Option<String> = None;
+    let mut host_repl = false;
     let mut output_file: Option<String> = None;
     let mut input_file: Option<String> = None;
 
@@ -48,7 +102,83 @@ fn main() {
         match args[i].as_str() {
             "--tokens" => show_tokens = true,
             "--ast" => show_ast = true,
+            "--format" => show_format = true,
             "--bytecode" => show_bytecode = true,
+            "--dump-cfg" => show_cfg = true,
+            "--cost" => show_cost = true,
+            "-O" | "--optimize" => optimize = true,
+            "--emit-constants" => show_constants = true,
+            "--dump-rom-layout" => show_rom_layout = true,
+            "--round" => round_division = true,
+            "--warnings-as-errors" => warnings_as_errors = true,
+            "--quiet" => quiet = true,
+            "--ibase" => {
+                i += 1;
+                match args.get(i).and_then(|arg| arg.parse().ok()) {
+                    Some(base) if (2..=16).contains(&base) => ibase = base,
+                    _ => {
+                        eprintln!("Error: --ibase requires a base from 2 to 16");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--trace" => runtime.trace = true,
+            "--profile" => runtime.profile = true,
+            "--break" => {
+                i += 1;
+                match args.get(i).and_then(|arg| arg.parse().ok()) {
+                    Some(line) if line > 0 => breakpoints.push(line),
+                    _ => {
+                        eprintln!("Error: --break requires a line number");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--max-rom-size" => {
+                i += 1;
+                match args.get(i).and_then(|arg| parse_size(arg)) {
+                    Some(size) => max_rom_size = Some(size),
+                    None => {
+                        eprintln!("Error: --max-rom-size requires a size in bytes");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--strip" => runtime.strip = true,
+            "--exit-addr" => {
+                i += 1;
+                match args.get(i).and_then(|arg| parse_addr(arg)) {
+                    Some(addr) => runtime.exit_addr = Some(addr),
+                    None => {
+                        eprintln!("Error: --exit-addr requires an address (e.g. 0x0000)");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--columns" => {
+                i += 1;
+                match args.get(i).and_then(|arg| arg.parse::<u8>().ok()).filter(|&n| n >= 2) {
+                    Some(columns) => runtime.columns = Some(columns),
+                    None => {
+                        eprintln!("Error: --columns requires a width from 2 to 255");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--newline" => {
+                i += 1;
+                let newline = match args.get(i).map(String::as_str) {
+                    Some("crlf") => z80::Newline::CrLf,
+                    Some("lf") => z80::Newline::Lf,
+                    Some("cr") => z80::Newline::Cr,
+                    _ => {
+                        eprintln!("Error: --newline requires crlf, lf or cr");
+                        process::exit(1);
+                    }
+                };
+                runtime.newline = newline;
+                repl_config.newline = newline;
+            }
             "--rom" => {
                 i += 1;
                 if i < args.len() {
@@ -58,6 +188,24 @@ fn main() {
                     process::exit(1);
                 }
             }
+            "--emit-vm" => {
+                i += 1;
+                if i < args.len() {
+                    vm_file = Some(args[i].clone());
+                } else {
+                    eprintln!("Error: --emit-vm requires a filename");
+                    process::exit(1);
+                }
+            }
+            "--annotate" => {
+                i += 1;
+                if i < args.len() {
+                    annotate_file = Some(args[i].clone());
+                } else {
+                    eprintln!("Error: --annotate requires a filename");
+                    process::exit(1);
+                }
+            }
             "--repl" => {
                 i += 1;
                 if i < args.len() {
@@ -67,6 +215,43 @@ fn main() {
                     process::exit(1);
                 }
             }
+            "--host-repl" => host_repl = true,
+            "--selftest" => {
+                i += 1;
+                if i < args.len() {
+                    selftest_file = Some(args[i].clone());
+                } else {
+                    eprintln!("Error: --selftest requires a filename");
+                    process::exit(1);
+                }
+            }
+            "--banner" => {
+                i += 1;
+                if i < args.len() {
+                    repl_config.banner = args[i].clone();
+                } else {
+                    eprintln!("Error: --banner requires text");
+                    process::exit(1);
+                }
+            }
+            "--prompt" => {
+                i += 1;
+                if i < args.len() {
+                    repl_config.prompt = args[i].clone();
+                } else {
+                    eprintln!("Error: --prompt requires text");
+                    process::exit(1);
+                }
+            }
+            "--repl-init" => {
+                i += 1;
+                if i < args.len() {
+                    repl_config.init.extend(args[i].lines().map(str::to_string));
+                } else {
+                    eprintln!("Error: --repl-init requires text");
+                    process::exit(1);
+                }
+            }
             "-o" => {
                 i += 1;
                 if i < args.len() {
@@ -96,9 +281,24 @@ fn main() {
         i += 1;
     }
 
+    // Handle --host-repl mode (reads stdin instead of a file)
+    if host_repl {
+        if let Err(e) = interpreter::repl(io::stdin().lock(), &mut io::stdout()) {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
     // Handle --repl mode (doesn't require input file)
     if let Some(repl_path) = repl_file {
-        let rom = z80::generate_repl_rom();
+        let rom = match z80::generate_repl_rom_with(&repl_config) {
+            Ok(rom) => rom,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
         match fs::write(&repl_path, &rom) {
             Ok(_) => {
                 eprintln!("Wrote {} bytes REPL ROM to {}", rom.len(), repl_path);
@@ -111,6 +311,21 @@ fn main() {
         return;
     }
 
+    // Handle --selftest mode (doesn't require input file)
+    if let Some(selftest_path) = selftest_file {
+        let rom = z80::generate_selftest_rom();
+        match fs::write(&selftest_path, &rom) {
+            Ok(_) => {
+                eprintln!("Wrote {} bytes self-test ROM to {}", rom.len(), selftest_path);
+            }
+            Err(e) => {
+                eprintln!("Error writing self-test ROM: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
     let input_file = match input_file {
         Some(f) => f,
         None => {
@@ -127,6 +342,16 @@ fn main() {
         }
     };
 
+    // Whether anything needs the compiled module
+    let compile = show_bytecode
+        || show_cfg
+        || show_cost
+        || show_constants
+        || show_rom_layout
+        || rom_file.is_some()
+        || annotate_file.is_some()
+        || vm_file.is_some();
+
     // Tokenize
     if show_tokens {
         let mut lexer = lexer::Lexer::new(&source);
@@ -135,7 +360,7 @@ fn main() {
         for tok in &tokens {
             println!("{:4}:{:2} {:?}", tok.line, tok.col, tok.token);
         }
-        if !show_ast && !show_bytecode && rom_file.is_none() {
+        if !show_format && !show_ast && !compile {
             return;
         }
     }
@@ -145,11 +370,16 @@ fn main() {
     let program = match parser.parse() {
         Ok(p) => p,
         Err(e) => {
-            eprintln!("Parse error: {}", e);
+            eprintln!("{}", e);
             process::exit(1);
         }
     };
 
+    if show_format {
+        print!("{}", format::format_program(&program));
+        return;
+    }
+
     if show_ast {
         println!("=== AST ===");
         println!("Functions:");
@@ -160,25 +390,83 @@ fn main() {
         for stmt in &program.statements {
             println!("  {:?}", stmt);
         }
-        if !show_bytecode && rom_file.is_none() {
+        if !compile {
             return;
         }
     }
 
     // Compile
-    let module = match Compiler::compile(&source) {
-        Ok(m) => m,
+    let config = Z80Config {
+        optimize,
+        ibase,
+        round_division,
+        warnings_as_errors,
+        quiet,
+        breakpoints,
+        max_rom_size,
+        runtime,
+        ..Default::default()
+    };
+    let artifacts = match build_rom(&source, &config) {
+        Ok(a) => a,
         Err(e) => {
-            eprintln!("Compile error: {}", e);
+            eprintln!("{}", e);
             process::exit(1);
         }
     };
+    let module = &artifacts.module;
+    for warning in &module.warnings {
+        eprintln!("{}", warning);
+    }
+
+    if let Some(annotate_path) = &annotate_file {
+        let listing = disasm::annotate(&artifacts.rom, &artifacts.symbols);
+        match fs::write(annotate_path, listing) {
+            Ok(_) => eprintln!("Wrote annotated runtime to {}", annotate_path),
+            Err(e) => {
+                eprintln!("Error writing annotated runtime: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(vm_path) = &vm_file {
+        let blob = match bcm::write(module) {
+            Ok(blob) => blob,
+            Err(e) => {
+                eprintln!("Error writing BCM file: {}", e);
+                process::exit(1);
+            }
+        };
+        match fs::write(vm_path, &blob) {
+            Ok(_) => eprintln!("Wrote {} bytes BCM (version {}) to {}", blob.len(), bcm::VERSION, vm_path),
+            Err(e) => {
+                eprintln!("Error writing BCM file: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if optimize {
+        eprintln!("Optimizer: saved {} bytes", artifacts.optimizer_saved);
+    }
+
+    if show_cost {
+        let estimate = cost::estimate(module);
+        eprintln!("Estimated cost: {} instructions, ~{} cycles", estimate.instructions, estimate.cycles);
+    }
 
     if show_bytecode {
         println!("=== Bytecode ===");
         println!("Size: {} bytes", module.bytecode.len());
         println!("Numbers: {}", module.numbers.len());
         println!("Strings: {}", module.strings.len());
+        if !module.functions.is_empty() {
+            println!("Functions:");
+            for line in module.functions_listing().lines() {
+                println!("  {}", line);
+            }
+        }
         println!();
 
         let mut offset = 0;
@@ -191,29 +479,34 @@ fn main() {
 
                 // Show operands
                 match opcode {
-                    bytecode::Op::LoadNum | bytecode::Op::LoadStr | bytecode::Op::PrintStr => {
-                        if offset + 2 < module.bytecode.len() {
-                            let idx = module.bytecode[offset + 1] as u16
-                                | ((module.bytecode[offset + 2] as u16) << 8);
-                            print!(" #{}", idx);
-                            offset += 2;
-                        }
+                    bytecode::Op::LoadNum | bytecode::Op::LoadStr | bytecode::Op::PrintStr
+                        if offset + 2 < module.bytecode.len() =>
+                    {
+                        let idx = module.bytecode[offset + 1] as u16
+                            | ((module.bytecode[offset + 2] as u16) << 8);
+                        print!(" #{}", idx);
+                        offset += 2;
                     }
                     bytecode::Op::LoadVar | bytecode::Op::StoreVar |
                     bytecode::Op::LoadArray | bytecode::Op::StoreArray |
-                    bytecode::Op::Call => {
-                        if offset + 1 < module.bytecode.len() {
-                            print!(" @{}", module.bytecode[offset + 1]);
-                            offset += 1;
-                        }
+                    bytecode::Op::Call
+                        if offset + 1 < module.bytecode.len() =>
+                    {
+                        print!(" @{}", module.bytecode[offset + 1]);
+                        offset += 1;
                     }
-                    bytecode::Op::Jump | bytecode::Op::JumpIfZero | bytecode::Op::JumpIfNotZero => {
-                        if offset + 2 < module.bytecode.len() {
-                            let addr = module.bytecode[offset + 1] as u16
-                                | ((module.bytecode[offset + 2] as u16) << 8);
-                            print!(" -> {:04X}", addr);
-                            offset += 2;
-                        }
+                    bytecode::Op::Trap if offset + 2 < module.bytecode.len() => {
+                        let line = module.bytecode[offset + 1] as u16
+                            | ((module.bytecode[offset + 2] as u16) << 8);
+                        print!(" line {}", line);
+                        offset += 2;
+                    }
+                    op if op.is_jump() && offset + 2 < module.bytecode.len() =>
+                    {
+                        let addr = module.bytecode[offset + 1] as u16
+                            | ((module.bytecode[offset + 2] as u16) << 8);
+                        print!(" -> {:04X}", addr);
+                        offset += 2;
                     }
                     _ => {}
                 }
@@ -224,6 +517,29 @@ fn main() {
             offset += 1;
         }
 
+        if !show_cfg && !show_constants && !show_rom_layout && rom_file.is_none() {
+            return;
+        }
+    }
+
+    if show_cfg {
+        print!("{}", module.cfg_dot());
+        if !show_constants && !show_rom_layout && rom_file.is_none() {
+            return;
+        }
+    }
+
+    if show_constants {
+        println!("=== Constants ===");
+        print!("{}", module.constants_listing());
+        if !show_rom_layout && rom_file.is_none() {
+            return;
+        }
+    }
+
+    if show_rom_layout {
+        println!("=== ROM Layout ===");
+        print!("{}", artifacts.layout_listing());
         if rom_file.is_none() {
             return;
         }
@@ -231,9 +547,10 @@ fn main() {
 
     // Generate ROM if requested
     if let Some(rom_path) = rom_file {
-        let rom = z80::generate_rom(&module);
+        let rom = &artifacts.rom;
+        let bytecode_org = artifacts.symbols["bytecode"];
 
-        match fs::write(&rom_path, &rom) {
+        match fs::write(&rom_path, rom) {
             Ok(_) => {
                 eprintln!(
                     "Compiled: {} bytes bytecode, {} numbers, {} strings",
@@ -242,10 +559,11 @@ fn main() {
                     module.strings.len()
                 );
                 eprintln!(
-                    "Wrote {} bytes ROM to {} (runtime: {}B, bytecode at 0x1000)",
+                    "Wrote {} bytes ROM to {} (runtime: {}B, bytecode at 0x{:04X})",
                     rom.len(),
                     rom_path,
-                    0x1000
+                    bytecode_org,
+                    bytecode_org
                 );
             }
             Err(e) => {
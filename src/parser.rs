@@ -1,10 +1,26 @@
 use crate::ast::*;
+use crate::error::BuildError;
 use crate::lexer::{Lexer, TokenInfo};
 use crate::token::Token;
 
 pub struct Parser {
     tokens: Vec<TokenInfo>,
     pos: usize,
+    lines: StatementLines,
+    depth: usize,
+}
+
+/// Deepest nesting of statements and expressions accepted. Deeper input is
+/// reported as an error instead of overflowing the stack.
+pub const MAX_DEPTH: usize = 64;
+
+/// Source line each statement returned by `parse` starts on: one entry per
+/// main-program statement, and one list per function body. The AST itself
+/// carries no positions.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatementLines {
+    pub main: Vec<usize>,
+    pub functions: Vec<Vec<usize>>,
 }
 
 impl Parser {
@@ -13,28 +29,80 @@ impl Parser {
         Parser {
             tokens: lexer.tokenize(),
             pos: 0,
+            lines: StatementLines::default(),
+            depth: 0,
         }
     }
 
+    /// Lines of the top-level statements from the last `parse`
+    pub fn statement_lines(&self) -> &StatementLines {
+        &self.lines
+    }
+
     fn current(&self) -> &Token {
-        &self.tokens.get(self.pos).map(|t| &t.token).unwrap_or(&Token::Eof)
+        self.tokens.get(self.pos).map(|t| &t.token).unwrap_or(&Token::Eof)
     }
 
+    /// Step past the current token and return it. At the end of the input
+    /// this stays put and returns Eof.
     fn advance(&mut self) -> &Token {
-        let _tok = self.current().clone();
+        let consumed = self.pos;
         if self.pos < self.tokens.len() {
             self.pos += 1;
         }
-        self.tokens.get(self.pos - 1).map(|t| &t.token).unwrap_or(&Token::Eof)
+        self.tokens.get(consumed).map(|t| &t.token).unwrap_or(&Token::Eof)
     }
 
-    fn expect(&mut self, expected: Token) -> Result<(), String> {
+    fn expect(&mut self, expected: Token) -> Result<(), BuildError> {
         if self.current() == &expected {
             self.advance();
             Ok(())
         } else {
-            Err(format!("Expected {:?}, got {:?}", expected, self.current()))
+            Err(self.error(format!("Expected {:?}, got {:?}", expected, self.current())))
+        }
+    }
+
+    /// Line and column of the current token
+    fn position(&self) -> (usize, usize) {
+        self.tokens.get(self.pos).map(|t| (t.line, t.col)).unwrap_or((0, 0))
+    }
+
+    /// Run `parse` one level deeper, failing once MAX_DEPTH is reached
+    fn nested<T>(&mut self, parse: fn(&mut Self) -> Result<T, BuildError>) -> Result<T, BuildError> {
+        let depth = self.depth;
+        self.deeper()?;
+        let result = parse(self);
+        self.depth = depth;
+        result
+    }
+
+    /// Go one level deeper. Each operator in a chain such as `1+1+1` calls
+    /// this too, as it nests the AST a level; `nested` undoes it.
+    fn deeper(&mut self) -> Result<(), BuildError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("nesting is too deep"));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Parse error at the current token
+    fn error(&self, msg: impl Into<String>) -> BuildError {
+        let (line, col) = self.position();
+        BuildError::Parse { line, col, msg: msg.into() }
+    }
+
+    /// Step over a built-in function's name and the `(` after it. Using the
+    /// name as a variable is reported as such rather than as a missing `(`.
+    fn expect_builtin_call(&mut self, name: &str) -> Result<(), BuildError> {
+        let (line, col) = self.position();
+        self.advance();
+        let next = self.current();
+        if next.is_assignment_op() || matches!(next, Token::PlusPlus | Token::MinusMinus) {
+            let msg = format!("cannot assign to built-in '{}'", name);
+            return Err(BuildError::Parse { line, col, msg });
         }
+        self.expect(Token::LParen)
     }
 
     fn skip_newlines(&mut self) {
@@ -49,7 +117,19 @@ impl Parser {
         }
     }
 
-    pub fn parse(&mut self) -> Result<Program, String> {
+    /// The lexer reports bad input as Error tokens; surface the first one
+    fn check_lex_errors(&self) -> Result<(), BuildError> {
+        for t in &self.tokens {
+            if let Token::Error(msg) = &t.token {
+                return Err(BuildError::Lex { line: t.line, col: t.col, msg: msg.clone() });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parse(&mut self) -> Result<Program, BuildError> {
+        self.check_lex_errors()?;
+
         let mut functions = Vec::new();
         let mut statements = Vec::new();
 
@@ -59,9 +139,11 @@ impl Parser {
             if self.current() == &Token::Define {
                 functions.push(self.parse_function()?);
             } else {
+                let (line, _) = self.position();
                 let stmt = self.parse_statement()?;
                 if !matches!(stmt, Stmt::Empty) {
                     statements.push(stmt);
+                    self.lines.main.push(line);
                 }
             }
             self.skip_terminators();
@@ -70,7 +152,36 @@ impl Parser {
         Ok(Program { functions, statements })
     }
 
-    fn parse_function(&mut self) -> Result<Function, String> {
+    /// Parse input holding a single expression (surrounding newlines and
+    /// semicolons are allowed)
+    pub fn parse_expression(&mut self) -> Result<Expr, BuildError> {
+        self.check_lex_errors()?;
+        self.skip_terminators();
+        let expr = self.parse_expr()?;
+        self.expect_end("expression")?;
+        Ok(expr)
+    }
+
+    /// Parse input holding a single statement, such as `if (x) y = 1`
+    pub fn parse_single_statement(&mut self) -> Result<Stmt, BuildError> {
+        self.check_lex_errors()?;
+        self.skip_terminators();
+        let stmt = self.parse_statement()?;
+        self.expect_end("statement")?;
+        Ok(stmt)
+    }
+
+    /// Error unless only terminators are left
+    fn expect_end(&mut self, what: &str) -> Result<(), BuildError> {
+        self.skip_terminators();
+        if self.current() != &Token::Eof {
+            return Err(self.error(format!("Expected end of {}, got {:?}", what, self.current())));
+        }
+        Ok(())
+    }
+
+    fn parse_function(&mut self) -> Result<Function, BuildError> {
+        let (line, col) = self.position();
         self.expect(Token::Define)?;
         self.skip_newlines();
 
@@ -79,7 +190,7 @@ impl Parser {
                 self.advance();
                 n
             }
-            _ => return Err("Expected function name".to_string()),
+            _ => return Err(self.error("Expected function name")),
         };
 
         self.expect(Token::LParen)?;
@@ -99,25 +210,31 @@ impl Parser {
 
         // Parse body
         let mut body = Vec::new();
+        let mut lines = Vec::new();
         while self.current() != &Token::RBrace && self.current() != &Token::Eof {
+            let (line, _) = self.position();
             let stmt = self.parse_statement()?;
             if !matches!(stmt, Stmt::Empty) {
                 body.push(stmt);
+                lines.push(line);
             }
             self.skip_terminators();
         }
+        self.lines.functions.push(lines);
 
         self.expect(Token::RBrace)?;
 
         Ok(Function {
             name,
+            line,
+            col,
             params,
             auto_vars,
             body,
         })
     }
 
-    fn parse_param_list(&mut self) -> Result<Vec<FuncParam>, String> {
+    fn parse_param_list(&mut self) -> Result<Vec<FuncParam>, BuildError> {
         let mut params = Vec::new();
 
         if self.current() == &Token::RParen {
@@ -130,7 +247,7 @@ impl Parser {
                     self.advance();
                     n
                 }
-                _ => return Err("Expected parameter name".to_string()),
+                _ => return Err(self.error("Expected parameter name")),
             };
 
             let is_array = if self.current() == &Token::LBracket {
@@ -153,7 +270,7 @@ impl Parser {
         Ok(params)
     }
 
-    fn parse_auto(&mut self) -> Result<Vec<AutoVar>, String> {
+    fn parse_auto(&mut self) -> Result<Vec<AutoVar>, BuildError> {
         self.expect(Token::Auto)?;
         let mut vars = Vec::new();
 
@@ -163,7 +280,7 @@ impl Parser {
                     self.advance();
                     n
                 }
-                _ => return Err("Expected variable name".to_string()),
+                _ => return Err(self.error("Expected variable name")),
             };
 
             let is_array = if self.current() == &Token::LBracket {
@@ -186,7 +303,11 @@ impl Parser {
         Ok(vars)
     }
 
-    fn parse_statement(&mut self) -> Result<Stmt, String> {
+    fn parse_statement(&mut self) -> Result<Stmt, BuildError> {
+        self.nested(Self::parse_statement_inner)
+    }
+
+    fn parse_statement_inner(&mut self) -> Result<Stmt, BuildError> {
         self.skip_newlines();
 
         match self.current().clone() {
@@ -245,7 +366,7 @@ impl Parser {
         }
     }
 
-    fn parse_if(&mut self) -> Result<Stmt, String> {
+    fn parse_if(&mut self) -> Result<Stmt, BuildError> {
         self.expect(Token::If)?;
         self.expect(Token::LParen)?;
         let cond = self.parse_expr()?;
@@ -270,7 +391,7 @@ impl Parser {
         })
     }
 
-    fn parse_while(&mut self) -> Result<Stmt, String> {
+    fn parse_while(&mut self) -> Result<Stmt, BuildError> {
         self.expect(Token::While)?;
         self.expect(Token::LParen)?;
         let cond = self.parse_expr()?;
@@ -282,7 +403,7 @@ impl Parser {
         Ok(Stmt::While { cond, body })
     }
 
-    fn parse_for(&mut self) -> Result<Stmt, String> {
+    fn parse_for(&mut self) -> Result<Stmt, BuildError> {
         self.expect(Token::For)?;
         self.expect(Token::LParen)?;
 
@@ -318,7 +439,7 @@ impl Parser {
         })
     }
 
-    fn parse_return(&mut self) -> Result<Stmt, String> {
+    fn parse_return(&mut self) -> Result<Stmt, BuildError> {
         self.expect(Token::Return)?;
 
         if matches!(self.current(), Token::Newline | Token::Semicolon | Token::RBrace | Token::Eof) {
@@ -334,7 +455,7 @@ impl Parser {
         }
     }
 
-    fn parse_print(&mut self) -> Result<Stmt, String> {
+    fn parse_print(&mut self) -> Result<Stmt, BuildError> {
         self.expect(Token::Print)?;
         let mut items = Vec::new();
 
@@ -361,58 +482,59 @@ impl Parser {
         Ok(Stmt::Print(items))
     }
 
-    fn parse_expr(&mut self) -> Result<Expr, String> {
-        self.parse_assignment()
+    fn parse_expr(&mut self) -> Result<Expr, BuildError> {
+        self.nested(Self::parse_assignment)
     }
 
-    fn parse_assignment(&mut self) -> Result<Expr, String> {
+    fn parse_assignment(&mut self) -> Result<Expr, BuildError> {
         let left = self.parse_or()?;
 
         match self.current().clone() {
             Token::Assign => {
                 self.advance();
-                let right = self.parse_assignment()?;
+                let right = self.nested(Self::parse_assignment)?;
                 Ok(Expr::Assign(Box::new(left), Box::new(right)))
             }
             Token::PlusAssign => {
                 self.advance();
-                let right = self.parse_assignment()?;
+                let right = self.nested(Self::parse_assignment)?;
                 Ok(Expr::AddAssign(Box::new(left), Box::new(right)))
             }
             Token::MinusAssign => {
                 self.advance();
-                let right = self.parse_assignment()?;
+                let right = self.nested(Self::parse_assignment)?;
                 Ok(Expr::SubAssign(Box::new(left), Box::new(right)))
             }
             Token::StarAssign => {
                 self.advance();
-                let right = self.parse_assignment()?;
+                let right = self.nested(Self::parse_assignment)?;
                 Ok(Expr::MulAssign(Box::new(left), Box::new(right)))
             }
             Token::SlashAssign => {
                 self.advance();
-                let right = self.parse_assignment()?;
+                let right = self.nested(Self::parse_assignment)?;
                 Ok(Expr::DivAssign(Box::new(left), Box::new(right)))
             }
             Token::PercentAssign => {
                 self.advance();
-                let right = self.parse_assignment()?;
+                let right = self.nested(Self::parse_assignment)?;
                 Ok(Expr::ModAssign(Box::new(left), Box::new(right)))
             }
             Token::CaretAssign => {
                 self.advance();
-                let right = self.parse_assignment()?;
+                let right = self.nested(Self::parse_assignment)?;
                 Ok(Expr::PowAssign(Box::new(left), Box::new(right)))
             }
             _ => Ok(left),
         }
     }
 
-    fn parse_or(&mut self) -> Result<Expr, String> {
+    fn parse_or(&mut self) -> Result<Expr, BuildError> {
         let mut left = self.parse_and()?;
 
         while self.current() == &Token::Or {
             self.advance();
+            self.deeper()?;
             let right = self.parse_and()?;
             left = Expr::Or(Box::new(left), Box::new(right));
         }
@@ -420,11 +542,12 @@ impl Parser {
         Ok(left)
     }
 
-    fn parse_and(&mut self) -> Result<Expr, String> {
+    fn parse_and(&mut self) -> Result<Expr, BuildError> {
         let mut left = self.parse_not()?;
 
         while self.current() == &Token::And {
             self.advance();
+            self.deeper()?;
             let right = self.parse_not()?;
             left = Expr::And(Box::new(left), Box::new(right));
         }
@@ -432,66 +555,54 @@ impl Parser {
         Ok(left)
     }
 
-    fn parse_not(&mut self) -> Result<Expr, String> {
+    fn parse_not(&mut self) -> Result<Expr, BuildError> {
         if self.current() == &Token::Not {
             self.advance();
-            let expr = self.parse_not()?;
+            let expr = self.nested(Self::parse_not)?;
             Ok(Expr::Not(Box::new(expr)))
         } else {
             self.parse_comparison()
         }
     }
 
-    fn parse_comparison(&mut self) -> Result<Expr, String> {
-        let left = self.parse_additive()?;
+    /// Comparisons chain left to right as in bc: `a < b < c` is
+    /// `(a < b) < c`
+    fn parse_comparison(&mut self) -> Result<Expr, BuildError> {
+        let mut left = self.parse_additive()?;
 
-        match self.current().clone() {
-            Token::Equal => {
-                self.advance();
-                let right = self.parse_additive()?;
-                Ok(Expr::Eq(Box::new(left), Box::new(right)))
-            }
-            Token::NotEqual => {
-                self.advance();
-                let right = self.parse_additive()?;
-                Ok(Expr::Ne(Box::new(left), Box::new(right)))
-            }
-            Token::Less => {
-                self.advance();
-                let right = self.parse_additive()?;
-                Ok(Expr::Lt(Box::new(left), Box::new(right)))
-            }
-            Token::LessEqual => {
-                self.advance();
-                let right = self.parse_additive()?;
-                Ok(Expr::Le(Box::new(left), Box::new(right)))
-            }
-            Token::Greater => {
-                self.advance();
-                let right = self.parse_additive()?;
-                Ok(Expr::Gt(Box::new(left), Box::new(right)))
-            }
-            Token::GreaterEqual => {
-                self.advance();
-                let right = self.parse_additive()?;
-                Ok(Expr::Ge(Box::new(left), Box::new(right)))
-            }
-            _ => Ok(left),
+        loop {
+            let make: fn(Box<Expr>, Box<Expr>) -> Expr = match self.current() {
+                Token::Equal => Expr::Eq,
+                Token::NotEqual => Expr::Ne,
+                Token::Less => Expr::Lt,
+                Token::LessEqual => Expr::Le,
+                Token::Greater => Expr::Gt,
+                Token::GreaterEqual => Expr::Ge,
+                _ => break,
+            };
+            self.advance();
+            self.deeper()?;
+            let right = self.parse_additive()?;
+            left = make(Box::new(left), Box::new(right));
         }
+
+        Ok(left)
     }
 
-    fn parse_additive(&mut self) -> Result<Expr, String> {
+    fn parse_additive(&mut self) -> Result<Expr, BuildError> {
         let mut left = self.parse_multiplicative()?;
 
         loop {
             match self.current().clone() {
                 Token::Plus => {
                     self.advance();
+                    self.deeper()?;
                     let right = self.parse_multiplicative()?;
                     left = Expr::Add(Box::new(left), Box::new(right));
                 }
                 Token::Minus => {
                     self.advance();
+                    self.deeper()?;
                     let right = self.parse_multiplicative()?;
                     left = Expr::Sub(Box::new(left), Box::new(right));
                 }
@@ -502,23 +613,26 @@ impl Parser {
         Ok(left)
     }
 
-    fn parse_multiplicative(&mut self) -> Result<Expr, String> {
+    fn parse_multiplicative(&mut self) -> Result<Expr, BuildError> {
         let mut left = self.parse_power()?;
 
         loop {
             match self.current().clone() {
                 Token::Star => {
                     self.advance();
+                    self.deeper()?;
                     let right = self.parse_power()?;
                     left = Expr::Mul(Box::new(left), Box::new(right));
                 }
                 Token::Slash => {
                     self.advance();
+                    self.deeper()?;
                     let right = self.parse_power()?;
                     left = Expr::Div(Box::new(left), Box::new(right));
                 }
                 Token::Percent => {
                     self.advance();
+                    self.deeper()?;
                     let right = self.parse_power()?;
                     left = Expr::Mod(Box::new(left), Box::new(right));
                 }
@@ -529,25 +643,30 @@ impl Parser {
         Ok(left)
     }
 
-    fn parse_power(&mut self) -> Result<Expr, String> {
+    fn parse_power(&mut self) -> Result<Expr, BuildError> {
         let left = self.parse_unary()?;
 
         if self.current() == &Token::Caret {
             self.advance();
-            let right = self.parse_power()?; // Right associative
+            let right = self.nested(Self::parse_power)?; // Right associative
             Ok(Expr::Pow(Box::new(left), Box::new(right)))
         } else {
             Ok(left)
         }
     }
 
-    fn parse_unary(&mut self) -> Result<Expr, String> {
+    fn parse_unary(&mut self) -> Result<Expr, BuildError> {
         match self.current().clone() {
             Token::Minus => {
                 self.advance();
-                let expr = self.parse_unary()?;
+                let expr = self.nested(Self::parse_unary)?;
                 Ok(Expr::Neg(Box::new(expr)))
             }
+            // Unary plus leaves its operand as it is
+            Token::Plus => {
+                self.advance();
+                self.nested(Self::parse_unary)
+            }
             Token::PlusPlus => {
                 self.advance();
                 let expr = self.parse_postfix()?;
@@ -562,7 +681,7 @@ impl Parser {
         }
     }
 
-    fn parse_postfix(&mut self) -> Result<Expr, String> {
+    fn parse_postfix(&mut self) -> Result<Expr, BuildError> {
         let mut expr = self.parse_primary()?;
 
         loop {
@@ -592,7 +711,7 @@ impl Parser {
         Ok(expr)
     }
 
-    fn parse_primary(&mut self) -> Result<Expr, String> {
+    fn parse_primary(&mut self) -> Result<Expr, BuildError> {
         match self.current().clone() {
             Token::Number(n) => {
                 self.advance();
@@ -604,6 +723,9 @@ impl Parser {
                 Ok(Expr::String(s))
             }
 
+            // As in bc, `scale` followed by `(` is always the function, even
+            // with a space before the parenthesis; the variable can only be
+            // followed by an operator, so `scale * (x)` multiplies
             Token::Scale => {
                 self.advance();
                 if self.current() == &Token::LParen {
@@ -632,24 +754,21 @@ impl Parser {
             }
 
             Token::Length => {
-                self.advance();
-                self.expect(Token::LParen)?;
+                self.expect_builtin_call("length")?;
                 let expr = self.parse_expr()?;
                 self.expect(Token::RParen)?;
                 Ok(Expr::Length(Box::new(expr)))
             }
 
             Token::Sqrt => {
-                self.advance();
-                self.expect(Token::LParen)?;
+                self.expect_builtin_call("sqrt")?;
                 let expr = self.parse_expr()?;
                 self.expect(Token::RParen)?;
                 Ok(Expr::Sqrt(Box::new(expr)))
             }
 
             Token::Read => {
-                self.advance();
-                self.expect(Token::LParen)?;
+                self.expect_builtin_call("read")?;
                 self.expect(Token::RParen)?;
                 Ok(Expr::Read)
             }
@@ -690,7 +809,7 @@ impl Parser {
                 Ok(expr)
             }
 
-            _ => Err(format!("Unexpected token: {:?}", self.current())),
+            _ => Err(self.error(format!("Unexpected token: {:?}", self.current()))),
         }
     }
 }
@@ -727,4 +846,141 @@ mod tests {
         let program = parser.parse().unwrap();
         assert_eq!(program.statements.len(), 1);
     }
+
+    #[test]
+    fn test_comparisons_chain_left() {
+        let expr = Parser::new("1 < 2 < 3").parse_expression().unwrap();
+        let one_lt_two = Expr::Lt(Box::new(Expr::Number("1".into())), Box::new(Expr::Number("2".into())));
+        assert_eq!(expr, Expr::Lt(Box::new(one_lt_two), Box::new(Expr::Number("3".into()))));
+        assert_eq!(Parser::new("print 1 < 2 < 3\n").parse().unwrap().statements.len(), 1);
+
+        let var = |name: &str| Box::new(Expr::Var(name.into()));
+        let expr = Parser::new("a == b == c").parse_expression().unwrap();
+        assert_eq!(expr, Expr::Eq(Box::new(Expr::Eq(var("a"), var("b"))), var("c")));
+    }
+
+    #[test]
+    fn test_statement_lines() {
+        let mut parser = Parser::new("x = 1; y = 2\n\ndefine f() {\n  auto a\n  a = 1\n\n  return a\n}\nif (x) {\n  y\n}\n");
+        parser.parse().unwrap();
+        let lines = parser.statement_lines();
+        assert_eq!(lines.main, vec![1, 1, 9]);
+        assert_eq!(lines.functions, vec![vec![5, 7]]);
+    }
+
+    #[test]
+    fn test_scale_function_or_variable() {
+        let parse = |source: &str| Parser::new(source).parse_expression().unwrap();
+        let five = || Box::new(Expr::Number("5".into()));
+        assert_eq!(parse("scale(5)"), Expr::ScaleFunc(five()));
+        assert_eq!(parse("scale (5)"), Expr::ScaleFunc(five()));
+        assert_eq!(parse("scale * 5"), Expr::Mul(Box::new(Expr::Scale), five()));
+        assert_eq!(parse("scale * (5)"), Expr::Mul(Box::new(Expr::Scale), five()));
+        assert_eq!(parse("scale(5) * scale"), Expr::Mul(Box::new(Expr::ScaleFunc(five())), Box::new(Expr::Scale)));
+        assert_eq!(parse("scale = 5"), Expr::Assign(Box::new(Expr::Scale), five()));
+    }
+
+    #[test]
+    fn test_parse_single_expression_and_statement() {
+        let expr = Parser::new("1+2").parse_expression().unwrap();
+        assert!(matches!(expr, Expr::Add(..)));
+        let err = Parser::new("1+2 )").parse_expression().unwrap_err();
+        assert!(matches!(err, BuildError::Parse { line: 1, col: 5, .. }), "{:?}", err);
+
+        let stmt = Parser::new("if (x) y = 1\n").parse_single_statement().unwrap();
+        assert!(matches!(stmt, Stmt::If { .. }));
+        assert!(Parser::new("x = 1; y = 2").parse_single_statement().is_err());
+    }
+
+    #[test]
+    fn test_parse_error_position() {
+        let err = Parser::new("a = 1\nb = 2 + )").parse().unwrap_err();
+        assert!(matches!(err, BuildError::Parse { line: 2, col: 9, .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn test_assign_to_builtin() {
+        let err = Parser::new("x = 1\nlength = 3").parse().unwrap_err();
+        assert_eq!(err.to_string(), "Parse error at 2:1: cannot assign to built-in 'length'");
+        let err = Parser::new("sqrt += 2").parse().unwrap_err();
+        assert_eq!(err.to_string(), "Parse error at 1:1: cannot assign to built-in 'sqrt'");
+        assert!(Parser::new("read++").parse().is_err());
+        assert!(Parser::new("length(3) + sqrt(4)").parse().is_ok());
+    }
+
+    #[test]
+    fn test_lex_errors() {
+        let err = Parser::new("a = 1\nb = 2 @ 3").parse().unwrap_err();
+        assert_eq!(
+            err,
+            BuildError::Lex { line: 2, col: 7, msg: "illegal character '@'".to_string() }
+        );
+        let err = Parser::new("print \"abc").parse().unwrap_err();
+        assert!(matches!(err, BuildError::Lex { line: 1, col: 7, .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn test_unary_plus() {
+        let program = Parser::new("+5\na = +b").parse().unwrap();
+        assert!(matches!(&program.statements[0], Stmt::Expr(Expr::Number(n)) if n == "5"));
+        match &program.statements[1] {
+            Stmt::Expr(Expr::Assign(_, value)) => assert!(matches!(**value, Expr::Var(ref v) if v == "b")),
+            other => panic!("{:?}", other),
+        }
+        assert!(Parser::new("c = 2 * + -3").parse().is_ok());
+        assert!(Parser::new("+").parse().is_err());
+    }
+
+    #[test]
+    fn test_advance() {
+        let mut parser = Parser::new("");
+        assert_eq!(parser.advance(), &Token::Eof);
+        assert_eq!(parser.advance(), &Token::Eof);
+        assert_eq!(parser.pos, 1);
+
+        let mut parser = Parser::new("x");
+        assert_eq!(parser.advance(), &Token::Ident("x".to_string()));
+        assert_eq!(parser.advance(), &Token::Eof);
+        assert_eq!(parser.current(), &Token::Eof);
+    }
+
+    #[test]
+    fn test_malformed_input_does_not_panic() {
+        for source in ["", "/* only a comment */", "}", "{", "{{", "((((", "define", "define f(", "a[", "if (", "for (;;", "\"", "/*", "1 +", "auto", "return", "...", "--", "define f() { auto a[; }"] {
+            let _ = Parser::new(source).parse();
+        }
+        for deep in ["(", "- ", "!", "2^", "a=", "{", "if(1)"] {
+            let err = Parser::new(&deep.repeat(100_000)).parse().unwrap_err();
+            assert!(err.to_string().ends_with("nesting is too deep"), "{}: {}", deep, err);
+        }
+        // Flat chains nest the AST just as deeply
+        for op in ["+", "-", "*", "/", "%", "<", "&&", "||"] {
+            let chain = format!("1{}", format!("{}1", op).repeat(200_000));
+            let err = Parser::new(&chain).parse().unwrap_err();
+            assert!(err.to_string().ends_with("nesting is too deep"), "{}: {}", op, err);
+        }
+        assert!(Parser::new(&format!("x = 1{}", "+1".repeat(50))).parse().is_ok());
+        let nested = format!("x = {}1{}", "(".repeat(60), ")".repeat(60));
+        assert!(Parser::new(&nested).parse().is_ok());
+
+        // bc-flavoured byte soup from a fixed xorshift seed
+        let alphabet = b"abcdefxyz0123456789.+-*/%^=!<>&|()[]{};,\"\\ \n\t#@$~`?ABF";
+        let mut state: u32 = 0x2545_f491;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        for _ in 0..5000 {
+            let len = next() as usize % 40;
+            let bytes: Vec<u8> = (0..len)
+                .map(|_| {
+                    let r = next();
+                    if r % 8 == 0 { (r >> 8) as u8 } else { alphabet[(r >> 8) as usize % alphabet.len()] }
+                })
+                .collect();
+            let _ = Parser::new(&String::from_utf8_lossy(&bytes)).parse();
+        }
+    }
 }
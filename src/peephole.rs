@@ -0,0 +1,205 @@
+//! Peephole optimizer for compiled bytecode
+//!
+//! Runs over a finished `CompiledModule`, rewriting short instruction
+//! sequences into cheaper equivalents and relocating every jump target
+//! and function offset to match the new layout.
+
+use crate::bytecode::{CompiledModule, Op};
+use std::collections::{HashMap, HashSet};
+
+/// A decoded instruction: opcode plus its position in the original bytecode
+struct Insn {
+    offset: usize,
+    op: Op,
+    operands: Vec<u8>,
+}
+
+/// Apply all peephole rewrites to the module in place.
+/// Returns the number of bytes saved.
+pub fn optimize(module: &mut CompiledModule) -> usize {
+    let insns = match decode(&module.bytecode) {
+        Some(insns) => insns,
+        None => return 0, // Unknown opcode - leave the bytecode alone
+    };
+
+    let targets: HashSet<usize> = insns
+        .iter()
+        .filter(|insn| insn.op.is_jump())
+        .map(|insn| u16::from_le_bytes([insn.operands[0], insn.operands[1]]) as usize)
+        .chain(module.functions.iter().map(|f| f.bytecode_offset))
+        .collect();
+
+    let insns = fuse_compare_jumps(insns, &targets);
+
+    // Re-emit, recording where each original instruction landed
+    let mut bytecode = Vec::with_capacity(module.bytecode.len());
+    let mut relocations: HashMap<usize, usize> = HashMap::new();
+    for insn in &insns {
+        relocations.insert(insn.offset, bytecode.len());
+        bytecode.push(insn.op as u8);
+        bytecode.extend(&insn.operands);
+    }
+    relocations.insert(module.bytecode.len(), bytecode.len());
+
+    // Patch jump operands to the new addresses
+    let mut pos = 0;
+    for insn in &insns {
+        if insn.op.is_jump() {
+            let old = u16::from_le_bytes([insn.operands[0], insn.operands[1]]) as usize;
+            let new = relocations[&old] as u16;
+            bytecode[pos + 1..pos + 3].copy_from_slice(&new.to_le_bytes());
+        }
+        pos += 1 + insn.operands.len();
+    }
+
+    for func in &mut module.functions {
+        func.bytecode_offset = relocations[&func.bytecode_offset];
+    }
+
+    let saved = module.bytecode.len() - bytecode.len();
+    module.bytecode = bytecode;
+    saved
+}
+
+fn decode(bytecode: &[u8]) -> Option<Vec<Insn>> {
+    let mut insns = Vec::new();
+    let mut offset = 0;
+    while offset < bytecode.len() {
+        let op = Op::from_u8(bytecode[offset])?;
+        let end = offset + 1 + op.operand_len();
+        let operands = bytecode.get(offset + 1..end)?.to_vec();
+        insns.push(Insn { offset, op, operands });
+        offset = end;
+    }
+    Some(insns)
+}
+
+/// Fused branch taken when the comparison is true
+fn jump_if(cmp: Op) -> Option<Op> {
+    match cmp {
+        Op::Eq => Some(Op::JumpIfEq),
+        Op::Ne => Some(Op::JumpIfNe),
+        Op::Lt => Some(Op::JumpIfLt),
+        Op::Le => Some(Op::JumpIfLe),
+        Op::Gt => Some(Op::JumpIfGt),
+        Op::Ge => Some(Op::JumpIfGe),
+        _ => None,
+    }
+}
+
+/// Fused branch taken when the comparison is false
+fn jump_unless(cmp: Op) -> Option<Op> {
+    match cmp {
+        Op::Eq => Some(Op::JumpIfNe),
+        Op::Ne => Some(Op::JumpIfEq),
+        Op::Lt => Some(Op::JumpIfGe),
+        Op::Le => Some(Op::JumpIfGt),
+        Op::Gt => Some(Op::JumpIfLe),
+        Op::Ge => Some(Op::JumpIfLt),
+        _ => None,
+    }
+}
+
+/// `<cmp>; JumpIfZero L` => `JumpIf<!cmp> L`, `<cmp>; JumpIfNotZero L` => `JumpIf<cmp> L`,
+/// looking through a `Not` between them.
+/// Skipped when the conditional jump is itself a jump target, since another
+/// path could reach it with a value that did not come from the comparison.
+fn fuse_compare_jumps(insns: Vec<Insn>, targets: &HashSet<usize>) -> Vec<Insn> {
+    let mut out: Vec<Insn> = Vec::with_capacity(insns.len());
+    for mut insn in insns {
+        // `Not; JumpIfZero L` => `JumpIfNotZero L` (and vice versa), which
+        // then fuses with a comparison before the Not
+        let flipped = match insn.op {
+            Op::JumpIfZero => Some(Op::JumpIfNotZero),
+            Op::JumpIfNotZero => Some(Op::JumpIfZero),
+            _ => None,
+        };
+        if let Some(op) = flipped {
+            if out.last().is_some_and(|prev| prev.op == Op::Not) && !targets.contains(&insn.offset) {
+                let not = out.pop().unwrap();
+                insn = Insn { offset: not.offset, op, operands: insn.operands };
+            }
+        }
+        if let Some(prev) = out.last_mut() {
+            let fused = match insn.op {
+                Op::JumpIfZero => jump_unless(prev.op),
+                Op::JumpIfNotZero => jump_if(prev.op),
+                _ => None,
+            };
+            if let Some(op) = fused {
+                if !targets.contains(&insn.offset) {
+                    prev.op = op;
+                    prev.operands = insn.operands;
+                    continue;
+                }
+            }
+        }
+        out.push(insn);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+
+    const IF_HEAVY: &str = "
+        a = 5
+        b = 7
+        if (a == b) print 1
+        if (a < b) print 2
+        if (a > b) print 3
+        if (a != b) print 4
+        if (a <= b) print 5
+        if (a >= b) print 6
+        while (a < b) a = a + 1
+    ";
+
+    #[test]
+    fn test_fuses_compare_and_branch() {
+        // The compiler fuses conditions itself, so build `Lt; JumpIfZero` by hand
+        let mut module = CompiledModule::new();
+        for op in [Op::LoadZero, Op::LoadOne, Op::Lt, Op::JumpIfZero] {
+            module.emit(op);
+        }
+        module.emit_u16(7);
+        module.emit(Op::Halt);
+        optimize(&mut module);
+        assert!(module.bytecode.contains(&(Op::JumpIfGe as u8)));
+        assert!(!module.bytecode.contains(&(Op::Lt as u8)));
+        assert!(!module.bytecode.contains(&(Op::JumpIfZero as u8)));
+    }
+
+    #[test]
+    fn test_if_heavy_already_fused() {
+        // Every condition is a comparison the compiler branches on directly,
+        // which leaves the optimizer nothing to fuse
+        let mut module = Compiler::compile(IF_HEAVY).unwrap();
+        assert_eq!(optimize(&mut module), 0);
+    }
+
+    #[test]
+    fn test_fuses_through_not() {
+        let mut module = Compiler::compile("if (!(a < b)) print 1").unwrap();
+        let saved = optimize(&mut module);
+        assert_eq!(saved, 2);
+        assert!(module.bytecode.contains(&(Op::JumpIfLt as u8)));
+        assert!(!module.bytecode.contains(&(Op::Not as u8)));
+    }
+
+    #[test]
+    fn test_relocates_jumps() {
+        let mut module = Compiler::compile("while (a < 3) { if (a == 1) print 9; a = a + 1 }").unwrap();
+        optimize(&mut module);
+        let insns = decode(&module.bytecode).unwrap();
+        let starts: HashSet<usize> = insns.iter().map(|i| i.offset).collect();
+        for insn in insns.iter().filter(|i| i.op.is_jump()) {
+            let target = u16::from_le_bytes([insn.operands[0], insn.operands[1]]) as usize;
+            assert!(starts.contains(&target) || target == module.bytecode.len());
+        }
+        // Back-edge goes to the start of the loop
+        let back = insns.iter().rfind(|i| i.op == Op::Jump).unwrap();
+        assert_eq!(back.operands, vec![0, 0]);
+    }
+}
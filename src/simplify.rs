@@ -0,0 +1,267 @@
+//! AST simplifier for identity operations
+//!
+//! Runs before compilation and rewrites `x + 0`, `x - 0`, `x * 1`, `x ^ 1`
+//! to `x` and `x * 0` to `0`, saving a BCD operation each. Only integer
+//! literals count, since `x + 0.00` can raise the scale of the result.
+//! `x * 0` is kept when `x` has side effects or can stop with a runtime
+//! error, and nothing is rewritten to a bare assignment, which would stop
+//! an expression statement printing. Each rewrite is reported as a
+//! warning on the line where its top-level statement starts.
+
+use crate::ast::*;
+use crate::error::Warning;
+use crate::format;
+use crate::parser::StatementLines;
+
+/// Simplify every expression in the program in place, with a warning for
+/// each rewrite
+pub fn simplify_program(program: &mut Program, lines: &StatementLines) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    for (func, lines) in program.functions.iter_mut().zip(&lines.functions) {
+        for (stmt, &line) in func.body.iter_mut().zip(lines) {
+            simplify_stmt(stmt, line, &mut warnings);
+        }
+    }
+    for (stmt, &line) in program.statements.iter_mut().zip(&lines.main) {
+        simplify_stmt(stmt, line, &mut warnings);
+    }
+    warnings
+}
+
+/// Simplify a single expression in place, with a warning for each rewrite
+pub fn simplify_expression(expr: &mut Expr) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    simplify_expr(expr, 0, &mut warnings);
+    warnings
+}
+
+fn simplify_stmt(stmt: &mut Stmt, line: usize, warnings: &mut Vec<Warning>) {
+    match stmt {
+        Stmt::Expr(expr) => simplify_expr(expr, line, warnings),
+        Stmt::Print(items) => {
+            for item in items {
+                if let PrintItem::Expr(expr) = item {
+                    simplify_expr(expr, line, warnings);
+                }
+            }
+        }
+        Stmt::Block(stmts) => stmts.iter_mut().for_each(|stmt| simplify_stmt(stmt, line, warnings)),
+        Stmt::If { cond, then_branch, else_branch } => {
+            simplify_expr(cond, line, warnings);
+            simplify_stmt(then_branch, line, warnings);
+            if let Some(else_branch) = else_branch {
+                simplify_stmt(else_branch, line, warnings);
+            }
+        }
+        Stmt::While { cond, body } => {
+            simplify_expr(cond, line, warnings);
+            simplify_stmt(body, line, warnings);
+        }
+        Stmt::For { init, cond, update, body } => {
+            for expr in [init, cond, update].into_iter().flatten() {
+                simplify_expr(expr, line, warnings);
+            }
+            simplify_stmt(body, line, warnings);
+        }
+        Stmt::Return(Some(expr)) => simplify_expr(expr, line, warnings),
+        _ => {}
+    }
+}
+
+fn simplify_expr(expr: &mut Expr, line: usize, warnings: &mut Vec<Warning>) {
+    // Children first, so nested identities collapse bottom-up
+    match expr {
+        Expr::ArrayElement(_, a)
+        | Expr::Not(a)
+        | Expr::Neg(a)
+        | Expr::PreInc(a)
+        | Expr::PreDec(a)
+        | Expr::PostInc(a)
+        | Expr::PostDec(a)
+        | Expr::Length(a)
+        | Expr::ScaleFunc(a)
+        | Expr::Sqrt(a) => simplify_expr(a, line, warnings),
+        Expr::Add(a, b)
+        | Expr::Sub(a, b)
+        | Expr::Mul(a, b)
+        | Expr::Div(a, b)
+        | Expr::Mod(a, b)
+        | Expr::Pow(a, b)
+        | Expr::Eq(a, b)
+        | Expr::Ne(a, b)
+        | Expr::Lt(a, b)
+        | Expr::Le(a, b)
+        | Expr::Gt(a, b)
+        | Expr::Ge(a, b)
+        | Expr::And(a, b)
+        | Expr::Or(a, b)
+        | Expr::Assign(a, b)
+        | Expr::AddAssign(a, b)
+        | Expr::SubAssign(a, b)
+        | Expr::MulAssign(a, b)
+        | Expr::DivAssign(a, b)
+        | Expr::ModAssign(a, b)
+        | Expr::PowAssign(a, b) => {
+            simplify_expr(a, line, warnings);
+            simplify_expr(b, line, warnings);
+        }
+        Expr::Call(_, args) => args.iter_mut().for_each(|arg| simplify_expr(arg, line, warnings)),
+        _ => {}
+    }
+
+    let replacement = match expr {
+        Expr::Add(x, k) | Expr::Sub(x, k) if is_int(k, "0") => keep(x),
+        Expr::Add(k, x) if is_int(k, "0") => keep(x),
+        Expr::Mul(x, k) | Expr::Mul(k, x) if is_int(k, "1") => keep(x),
+        Expr::Pow(x, k) if is_int(k, "1") => keep(x),
+        Expr::Mul(x, k) | Expr::Mul(k, x) if is_int(k, "0") && !has_side_effects(x) && has_zero_scale(x) => {
+            Some(Expr::Number("0".to_string()))
+        }
+        _ => None,
+    };
+    if let Some(replacement) = replacement {
+        warnings.push(Warning {
+            line,
+            col: 0,
+            message: format!("{} simplified to {}", format::format_expr(expr), format::format_expr(&replacement)),
+        });
+        *expr = replacement;
+    }
+}
+
+/// `x` itself, unless that would turn the expression into an assignment or
+/// a bare string (which prints instead of being a type error)
+fn keep(x: &Expr) -> Option<Expr> {
+    if is_assignment(x) || matches!(x, Expr::String(_)) {
+        None
+    } else {
+        Some(x.clone())
+    }
+}
+
+/// Integer literal with the given value (leading zeros allowed)
+fn is_int(expr: &Expr, value: &str) -> bool {
+    match expr {
+        Expr::Number(s) => {
+            let digits = s.trim_start_matches('0');
+            !s.contains('.') && (digits == value || (digits.is_empty() && value == "0"))
+        }
+        _ => false,
+    }
+}
+
+/// Whether the value always has scale 0. `x * 0` keeps the scale of `x`
+/// (up to `scale`), so only these fold to a plain 0.
+fn has_zero_scale(expr: &Expr) -> bool {
+    match expr {
+        Expr::Number(s) => !s.contains('.'),
+        Expr::Neg(a) => has_zero_scale(a),
+        Expr::Length(_) | Expr::ScaleFunc(_) | Expr::Not(_) => true,
+        Expr::Eq(..) | Expr::Ne(..) | Expr::Lt(..) | Expr::Le(..) | Expr::Gt(..) | Expr::Ge(..) => true,
+        Expr::And(..) | Expr::Or(..) => true,
+        _ => false,
+    }
+}
+
+fn is_assignment(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::Assign(..)
+            | Expr::AddAssign(..)
+            | Expr::SubAssign(..)
+            | Expr::MulAssign(..)
+            | Expr::DivAssign(..)
+            | Expr::ModAssign(..)
+            | Expr::PowAssign(..)
+            | Expr::PreInc(_)
+            | Expr::PreDec(_)
+            | Expr::PostInc(_)
+            | Expr::PostDec(_)
+    )
+}
+
+/// Whether evaluating the expression can change state, read input or stop
+/// with a runtime error. Function calls are assumed to. Division, `%`, `^`
+/// and `sqrt` can fail on their operands, array elements on their index,
+/// and `+`, `-` and `*` by overflowing. Strings count too, so that
+/// dropping one does not hide the compiler's type error.
+fn has_side_effects(expr: &Expr) -> bool {
+    match expr {
+        Expr::Call(..) | Expr::Read | Expr::String(_) => true,
+        e if is_assignment(e) => true,
+        Expr::ArrayElement(..) | Expr::Sqrt(_) => true,
+        Expr::Add(..) | Expr::Sub(..) | Expr::Mul(..) | Expr::Div(..) | Expr::Mod(..) | Expr::Pow(..) => true,
+        Expr::Not(a) | Expr::Neg(a) | Expr::Length(a) | Expr::ScaleFunc(a) => has_side_effects(a),
+        Expr::Eq(a, b)
+        | Expr::Ne(a, b)
+        | Expr::Lt(a, b)
+        | Expr::Le(a, b)
+        | Expr::Gt(a, b)
+        | Expr::Ge(a, b)
+        | Expr::And(a, b)
+        | Expr::Or(a, b) => has_side_effects(a) || has_side_effects(b),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::Op;
+    use crate::compiler::Compiler;
+    use crate::interpreter::interpret;
+
+    fn ops(source: &str) -> Vec<u8> {
+        Compiler::compile(source).unwrap().bytecode
+    }
+
+    #[test]
+    fn test_identities_removed() {
+        let bytecode = ops("x * 1");
+        assert_eq!(bytecode[0], Op::LoadVar as u8);
+        assert!(!bytecode.contains(&(Op::Mul as u8)));
+        assert_eq!(ops("x * 1"), ops("x"));
+        assert_eq!(ops("0 + x - 0"), ops("x"));
+        assert_eq!(ops("(x + 0) ^ 1"), ops("x"));
+        assert_eq!(ops("y = 5 * 0"), ops("y = 0"));
+    }
+
+    #[test]
+    fn test_side_effects_and_scale_kept() {
+        // The call must still happen
+        let bytecode = ops("define f() { return 2 }\nf() * 0");
+        assert!(bytecode.contains(&(Op::Call as u8)));
+        assert!(bytecode.contains(&(Op::Mul as u8)));
+        // 0.0 is not an integer zero: it can change the result's scale
+        assert!(ops("x + 0.0").contains(&(Op::Add as u8)));
+        // Rewriting to a bare assignment would stop the value printing
+        assert!(ops("(x = 5) + 0").contains(&(Op::PrintNewline as u8)));
+        // Operands that can stop with a runtime error are still evaluated
+        for source in ["(1/a)*0", "0 * (x % a)", "(2^0.5)*0", "sqrt(-4)*0", "a[-1]*0", "(x + y) * 0"] {
+            assert!(ops(source).contains(&(Op::Mul as u8)), "{} was simplified", source);
+        }
+        assert_eq!(ops("y = (-x == 1) * 0"), ops("y = 0"));
+    }
+
+    #[test]
+    fn test_mul_by_zero_keeps_scale() {
+        assert!(ops("x * 0").contains(&(Op::Mul as u8)));
+        assert!(ops("0 * 1.25").contains(&(Op::Mul as u8)));
+        let module = Compiler::compile("x = 1.25\nscale(x * 0)\nscale(0 * 1.25)").unwrap();
+        assert_eq!(interpret(&module).unwrap(), ["2", "2"]);
+    }
+
+    #[test]
+    fn test_rewrites_are_warned() {
+        let module = Compiler::compile("y = 2\nwhile (y < 3) {\n y = y * 1 + 0 }\ndefine f(n) { return n ^ 1 }").unwrap();
+        let warnings: Vec<String> = module.warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            [
+                "warning at line 4: n ^ 1 simplified to n",
+                "warning at line 2: y * 1 simplified to y",
+                "warning at line 2: y + 0 simplified to y",
+            ]
+        );
+        assert!(Compiler::compile("y = x * 2 + 1").unwrap().warnings.is_empty());
+    }
+}
@@ -75,11 +75,11 @@ pub enum Token {
     Newline,            // Significant in bc
 
     // Special
+    Error(String),      // Illegal input, reported by the parser
     Eof,
 }
 
 impl Token {
-    #[allow(dead_code)]
     pub fn is_assignment_op(&self) -> bool {
         matches!(self,
             Token::Assign | Token::PlusAssign | Token::MinusAssign |
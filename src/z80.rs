@@ -1,15 +1,17 @@
-/// Z80 code generator for bc with arbitrary-precision BCD arithmetic
-///
-/// BCD Number Format in memory (compact):
-/// - Byte 0: Flags (bit 7 = sign: 0=positive, 1=negative)
-/// - Byte 1: Total digit count (max 100)
-/// - Byte 2: Scale (digits after decimal point)
-/// - Byte 3+: Packed BCD digits (2 per byte, high nibble first)
-///
-/// Maximum precision: 100 digits (50 bytes of BCD data + 3 header = 53 bytes max)
-/// Numbers are stored with implicit decimal point based on scale.
-
-use crate::bytecode::{CompiledModule, Op};
+//! Z80 code generator for bc with arbitrary-precision BCD arithmetic
+//!
+//! BCD Number Format in memory (compact):
+//! - Byte 0: Flags (bit 7 = sign: 0=positive, 1=negative)
+//! - Byte 1: Total digit count (max 100)
+//! - Byte 2: Scale (digits after decimal point)
+//! - Byte 3+: Packed BCD digits (2 per byte, high nibble first)
+//!
+//! Maximum precision: 100 digits (50 bytes of BCD data + 3 header = 53 bytes max)
+//! Numbers are stored with implicit decimal point based on scale.
+
+use crate::bytecode::{BcNum, CompiledModule, Op};
+use crate::error::BuildError;
+use std::collections::BTreeMap;
 
 // Z80 opcodes
 #[allow(dead_code)]
@@ -291,29 +293,77 @@ const STACK_TOP: u16 = 0xFFFF;        // Z80 hardware stack
 
 // VM state in RAM at 0x8000+
 const VM_STATE_BASE: u16 = 0x8000;
-const VM_PC: u16 = VM_STATE_BASE;           // VM program counter (2 bytes)
+// The VM program counter lives in HL', not RAM
+const VM_READ_DIGIT: u16 = VM_STATE_BASE;   // read(): current digit as a number (2 bytes)
 const VM_SP: u16 = VM_STATE_BASE + 2;       // VM value stack pointer (2 bytes)
 const VM_SCALE: u16 = VM_STATE_BASE + 4;    // Current scale (1 byte)
 const VM_IBASE: u16 = VM_STATE_BASE + 5;    // Input base (1 byte)
 const VM_OBASE: u16 = VM_STATE_BASE + 6;    // Output base (1 byte)
+const VM_COLUMN: u16 = VM_STATE_BASE + 7;   // Characters of a number on this line, with --columns (1 byte)
 const VM_HEAP: u16 = VM_STATE_BASE + 8;     // Heap pointer (2 bytes)
-#[allow(dead_code)]
-const VM_TEMP: u16 = VM_STATE_BASE + 10;    // Temp pointer (2 bytes)
+const VM_TEMP: u16 = VM_STATE_BASE + 10;    // Sign of a mul/div result (1 byte)
+const VM_ROUND: u16 = VM_STATE_BASE + 11;   // Nonzero while a DivRound runs (1 byte)
+const VM_READ_ACC: u16 = VM_STATE_BASE + 12;  // read(): value so far (2 bytes)
+const VM_READ_BASE: u16 = VM_STATE_BASE + 14; // read(): ibase as a number (2 bytes)
 
 // Pre-allocated constants in RAM (each needs 28 bytes: 3 header + 25 packed)
 const CONST_ZERO: u16 = VM_STATE_BASE + 0x10;  // Zero constant (0x8010-0x802B)
 const CONST_ONE: u16 = VM_STATE_BASE + 0x2C;   // One constant (0x802C-0x8047)
 
-// Variable storage (26 vars * 2 bytes = 52 bytes for pointers)
+// Variable storage (26 vars * 2 bytes = 52 bytes for pointers); the
+// compiler stops at MAX_VAR_SLOTS
 const VARS_BASE: u16 = VM_STATE_BASE + 0x48;   // (0x8048-0x807B)
 
-// Value stack (pointers to numbers, 64 entries * 2 bytes = 128 bytes)
+// Value stack (pointers to numbers, 64 entries * 2 bytes = 128 bytes).
+// Deeper stacks configured in MemoryLayout go at HEAP_START instead and
+// push the heap up.
 const VSTACK_BASE: u16 = VM_STATE_BASE + 0x7C; // (0x807C-0x80FB)
-#[allow(dead_code)]
 const VSTACK_SIZE: u16 = 128;
 
-// Heap for BCD numbers starts after value stack
-const HEAP_START: u16 = VM_STATE_BASE + 0xFC;  // (0x80FC+)
+// Callee details copied from the function table by Call (0x8100-0x8105):
+// bytecode address, address of its first variable slot, parameter count
+// and parameter + auto count
+const VM_CALL: u16 = VM_STATE_BASE + 0x100;
+
+// Heap below this address may be reachable from a global variable or
+// array, so Return never reclaims it (2 bytes at 0x8106)
+const VM_PINNED: u16 = VM_STATE_BASE + 0x106;
+
+// Heap for BCD numbers starts after the mul/div scratch at REPL_TEMP
+// (0x8700-0x875F), which would otherwise overwrite live numbers
+const HEAP_START: u16 = VM_STATE_BASE + 0x800; // (0x8800+)
+
+// Elements per array. An array's slot points to a heap block of this many
+// number pointers, allocated and zeroed on first use; a slot pointing
+// below the heap (unset, or an auto reset to zero by Call) has none yet.
+// num_to_index accepts exactly the indices 0-255.
+const ARRAY_SIZE: u16 = 256;
+
+// Line buffer for read(), just below the Z80 stack
+const READ_BUF: u16 = 0xFD00;                  // (0xFD00-0xFDFF)
+const READ_LEN: u16 = 0xFE00;                  // Length of last line read
+const READ_POS: u16 = 0xFE01;                  // Parse position (unused by read())
+
+// Scratch for printing in a non-decimal obase, just below the read() buffer
+const BASE_WORK: u16 = 0xFC00;                 // Unpacked integer digits (0xFC00-0xFC31)
+const BASE_LEN: u16 = 0xFC32;                  // Integer digit count
+const BASE_RADIX: u16 = 0xFC33;                // Output base
+const BASE_OUT_END: u16 = 0xFD00;              // Digit chars are built downward from here
+
+// Execution count per opcode value with --profile, 16 bits each and
+// saturating, below the obase scratch (0xFA00-0xFBFF)
+const PROFILE_COUNTS: u16 = 0xFA00;
+
+// The heap grows up to the lowest of the buffers above that the ROM uses
+// (BASE_WORK, or PROFILE_COUNTS with --profile); allocating past it stops
+// with "Runtime error: out of memory"
+fn heap_end(options: &RuntimeOptions) -> u16 {
+    if options.profile {
+        PROFILE_COUNTS
+    } else {
+        BASE_WORK
+    }
+}
 
 // Number format constants
 #[allow(dead_code)]
@@ -322,41 +372,217 @@ const NUM_HEADER_SIZE: u8 = 3;        // sign + len + scale
 const MAX_DIGITS: u8 = 100;           // Max digits per number
 const MAX_NUM_SIZE: u8 = 53;          // 3 + 50 packed bytes
 
+/// Where ROM and RAM live on the target board. ROM always starts at
+/// 0x0000 and holds the runtime, bytecode, constants and strings; VM state,
+/// variables, the value stack and the heap are placed at `ram_start`. The
+/// read() and obase buffers and the Z80 stack stay at the top of memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryLayout {
+    pub rom_size: u16,
+    pub ram_start: u16,
+    /// Value stack entries; more than 64 moves the stack to the start of
+    /// the heap area and the heap up behind it
+    pub vstack_depth: u16,
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        MemoryLayout {
+            rom_size: 0x8000,
+            ram_start: VM_STATE_BASE,
+            vstack_depth: VSTACK_SIZE / 2,
+        }
+    }
+}
+
+impl MemoryLayout {
+    /// Relocate an address from the default RAM map (based at 0x8000)
+    fn ram(&self, addr: u16) -> u16 {
+        addr - VM_STATE_BASE + self.ram_start
+    }
+
+    fn vstack_outgrows_default(&self) -> bool {
+        self.vstack_depth > VSTACK_SIZE / 2
+    }
+
+    fn vstack_base(&self) -> u16 {
+        if self.vstack_outgrows_default() {
+            self.ram(HEAP_START)
+        } else {
+            self.ram(VSTACK_BASE)
+        }
+    }
+
+    /// First address past the last value stack entry
+    fn vstack_end(&self) -> u16 {
+        self.vstack_base() + self.vstack_depth * 2
+    }
+
+    fn heap_start(&self) -> u16 {
+        if self.vstack_outgrows_default() {
+            self.vstack_end()
+        } else {
+            self.ram(HEAP_START)
+        }
+    }
+
+    fn validate(&self) -> Result<(), BuildError> {
+        if self.rom_size < RUNTIME_SIZE || self.rom_size > self.ram_start {
+            return Err(BuildError::Rom {
+                msg: format!(
+                    "ROM size 0x{:04X} must be at least 0x{:04X} and end at or below RAM (0x{:04X})",
+                    self.rom_size, RUNTIME_SIZE, self.ram_start
+                ),
+            });
+        }
+        if self.vstack_depth == 0 {
+            return Err(BuildError::Rom { msg: "value stack depth must be at least 1".to_string() });
+        }
+        // VM state, the value stack, the heap and the mul/div scratch at
+        // +0x700 must all fit below the buffers at the top of memory
+        let extra = if self.vstack_outgrows_default() { self.vstack_depth as u32 * 2 } else { 0 };
+        if self.ram_start as u32 + (REPL_HEAP - VM_STATE_BASE) as u32 + extra > PROFILE_COUNTS as u32 {
+            return Err(BuildError::Rom {
+                msg: format!(
+                    "RAM at 0x{:04X} with a {}-entry value stack leaves no room for the heap",
+                    self.ram_start, self.vstack_depth
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Line ending written for PrintNewline and for "\n" in strings
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Newline {
+    /// CR LF, for serial terminals
+    #[default]
+    CrLf,
+    Lf,
+    Cr,
+}
+
+impl Newline {
+    fn bytes(self) -> &'static [u8] {
+        match self {
+            Newline::CrLf => b"\r\n",
+            Newline::Lf => b"\n",
+            Newline::Cr => b"\r",
+        }
+    }
+}
+
+/// Optional extras compiled into the VM runtime
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RuntimeOptions {
+    /// Print the VM PC and opcode (as hex) before executing each instruction
+    pub trace: bool,
+    /// Jump here (e.g. to a ROM monitor) when the program ends instead of
+    /// halting the CPU
+    pub exit_addr: Option<u16>,
+    pub newline: Newline,
+    /// Leave out handlers and routines the program's opcodes never reach
+    pub strip: bool,
+    /// Count how often each opcode runs and print the counts when the
+    /// program halts or quits
+    pub profile: bool,
+    /// Break decimal numbers wider than this many columns with `\` and a
+    /// newline, as bc does at 70. At least 2.
+    pub columns: Option<u8>,
+}
+
 pub fn generate_rom(module: &CompiledModule) -> Vec<u8> {
+    generate_rom_with_symbols(module).0
+}
+
+/// Generate the ROM along with the addresses of runtime routines, the
+/// bytecode/constant/string sections and each compiled function
+pub fn generate_rom_with_symbols(module: &CompiledModule) -> (Vec<u8>, BTreeMap<String, u16>) {
+    emit_rom(module, &MemoryLayout::default(), &RuntimeOptions::default())
+}
+
+/// Generate the ROM and symbols for a specific memory layout and runtime
+/// options, failing if the image does not fit in the ROM region
+pub fn generate_rom_with_layout(
+    module: &CompiledModule,
+    layout: &MemoryLayout,
+    options: &RuntimeOptions,
+) -> Result<(Vec<u8>, BTreeMap<String, u16>), BuildError> {
+    layout.validate()?;
+    let (code, symbols) = emit_rom(module, layout, options);
+    if code.len() > layout.rom_size as usize {
+        return Err(BuildError::Rom {
+            msg: format!(
+                "ROM image is {} bytes but the ROM region holds {}",
+                code.len(),
+                layout.rom_size
+            ),
+        });
+    }
+    Ok((code, symbols))
+}
+
+fn emit_rom(
+    module: &CompiledModule,
+    layout: &MemoryLayout,
+    options: &RuntimeOptions,
+) -> (Vec<u8>, BTreeMap<String, u16>) {
     let mut code = Vec::new();
+    let mut symbols = BTreeMap::new();
 
     // Generate Z80 runtime with all opcode handlers
-    generate_runtime(&mut code, module);
+    generate_runtime(&mut code, layout, options, module, &mut symbols);
 
     // Pad to BYTECODE_ORG
+    symbols.insert("padding".to_string(), code.len() as u16);
     while code.len() < RUNTIME_SIZE as usize {
         code.push(NOP);
     }
 
     // Append bytecode
+    symbols.insert("bytecode".to_string(), BYTECODE_ORG);
+    for func in &module.functions {
+        symbols.insert(format!("fn {}", func.name), BYTECODE_ORG + func.bytecode_offset as u16);
+    }
     code.extend(&module.bytecode);
 
     // Append number constants in packed format, padded to fixed size
     // Each number is padded to MAX_NUM_SIZE bytes for simple indexing
+    symbols.insert("constants".to_string(), code.len() as u16);
     for num in &module.numbers {
         let packed = num.to_packed();
         code.extend(&packed);
         // Pad to MAX_NUM_SIZE
-        for _ in packed.len()..MAX_NUM_SIZE as usize {
-            code.push(0);
-        }
+        code.resize(code.len() + MAX_NUM_SIZE as usize - packed.len(), 0);
     }
 
     // Append strings (length-prefixed)
+    symbols.insert("strings".to_string(), code.len() as u16);
     for s in &module.strings {
         code.push(s.len() as u8);
         code.extend(s.as_bytes());
     }
 
-    code
+    (code, symbols)
 }
 
-fn generate_runtime(code: &mut Vec<u8>, module: &CompiledModule) {
+fn generate_runtime(
+    code: &mut Vec<u8>,
+    layout: &MemoryLayout,
+    options: &RuntimeOptions,
+    module: &CompiledModule,
+    symbols: &mut BTreeMap<String, u16>,
+) {
+    // With --strip, only the handlers for opcodes the program uses and the
+    // routines they reach are emitted
+    let used = module.used_ops();
+    let wants = |ops: &[Op]| !options.strip || ops.iter().any(|op| used.contains(op));
+    let routines = needed_routines(&used, options);
+    let keep = |name: &str| !options.strip || routines.contains(&name);
+    // Slots below this are globals; the ones above belong to call frames
+    let frame_base = module.functions.iter().map(|func| func.first_slot).min();
+
     // =====================================================
     // Entry point at 0x0000
     // =====================================================
@@ -369,10 +595,23 @@ fn generate_runtime(code: &mut Vec<u8>, module: &CompiledModule) {
     emit_u16(code, STACK_TOP);
 
     // Initialize VM state
-    init_vm_state(code);
+    init_vm_state(code, layout);
 
     // Initialize constants in RAM
-    init_constants(code);
+    init_constants(code, layout);
+
+    if options.profile {
+        // Zero the opcode counters
+        code.push(LD_HL_NN);
+        emit_u16(code, PROFILE_COUNTS);
+        code.push(LD_DE_NN);
+        emit_u16(code, PROFILE_COUNTS + 1);
+        code.push(LD_BC_NN);
+        emit_u16(code, 511);
+        code.push(XOR_A);
+        code.push(LD_HL_A);
+        emit_ldir(code);
+    }
 
     // Jump to main interpreter loop
     code.push(JP_NN);
@@ -385,88 +624,312 @@ fn generate_runtime(code: &mut Vec<u8>, module: &CompiledModule) {
 
     // --- ACIA output routine (address stored for reference) ---
     let acia_out = code.len() as u16;
-    emit_acia_out(code);
+    if keep("acia_out") {
+        emit_acia_out(code);
+    }
 
     // --- ACIA wait for TX ready ---
     let _acia_wait = code.len() as u16;
     emit_acia_wait(code);
 
+    // --- ACIA input routine ---
+    let acia_in = code.len() as u16;
+    if keep("acia_in") {
+        emit_acia_in(code);
+    }
+
+    // --- Read line into READ_BUF ---
+    let getline = code.len() as u16;
+    if keep("getline") {
+        emit_getline(code, acia_in, acia_out, READ_BUF, READ_LEN, READ_POS, None);
+    }
+
+    // --- Parse digits into a BCD number (shared with the REPL) ---
+    let parse_num = code.len() as u16;
+    if keep("parse_num") {
+        emit_parse_num(code);
+    }
+
+    // --- Output that wraps long numbers (only with --columns) ---
+    let print_wrap = match options.columns {
+        Some(columns) if keep("print_num") => {
+            let print_wrap = code.len() as u16;
+            emit_print_wrap(code, acia_out, layout.ram(VM_COLUMN), columns, options.newline);
+            symbols.insert("print_wrap".to_string(), print_wrap);
+            Some((print_wrap, layout.ram(VM_COLUMN)))
+        }
+        _ => None,
+    };
+
     // --- Print BCD number subroutine ---
     let print_num = code.len() as u16;
-    emit_print_bcd_number(code, acia_out);
+    if keep("print_num") {
+        emit_print_bcd_number(code, acia_out, print_wrap);
+    }
+
+    // --- Print integer part in another base ---
+    let print_base_n = code.len() as u16;
+    if keep("print_base_n") {
+        emit_print_base_n(code, acia_out);
+    }
 
     // --- Print newline ---
     let print_newline = code.len() as u16;
-    emit_print_crlf(code, acia_out);
+    if keep("print_newline") {
+        emit_print_crlf(code, acia_out, options.newline);
+    }
+
+    // --- Runtime errors: a sum carrying out of the top digit (the Add and
+    // Sub handlers check bcd_add's carry), a full value stack, a bad array
+    // index and a full heap ---
+    let overflow = if keep("overflow") {
+        emit_runtime_error(code, acia_out, print_newline, "overflow")
+    } else {
+        0
+    };
+    let vstack_full = if keep("vstack_full") {
+        emit_runtime_error(code, acia_out, print_newline, "value stack full")
+    } else {
+        0
+    };
+    let bad_index = if keep("bad_index") {
+        emit_runtime_error(code, acia_out, print_newline, "array index out of range")
+    } else {
+        0
+    };
+    let out_of_memory = if keep("out_of_memory") {
+        emit_runtime_error(code, acia_out, print_newline, "out of memory")
+    } else {
+        0
+    };
+
+    // --- Convert number to byte ---
+    let num_to_byte = code.len() as u16;
+    if keep("num_to_byte") {
+        emit_num_to_byte(code);
+    }
+
+    // --- Convert an array index to a byte ---
+    let num_to_index = code.len() as u16;
+    if keep("num_to_index") {
+        emit_num_to_index(code);
+    }
+
+    // --- Convert byte to number ---
+    let byte_to_num = code.len() as u16;
+    if keep("byte_to_num") {
+        emit_byte_to_num(code);
+    }
 
     // --- Allocate number on heap ---
     let alloc_num = code.len() as u16;
-    emit_alloc_number(code);
+    if keep("alloc_num") {
+        emit_alloc_number(code, layout, heap_end(options), out_of_memory);
+    }
+
+    // --- Address of an array element's pointer ---
+    let array_elem = code.len() as u16;
+    if keep("array_elem") {
+        emit_array_elem(code, layout, heap_end(options), frame_base, num_to_index, bad_index, out_of_memory);
+    }
 
     // --- Copy number ---
     let copy_num = code.len() as u16;
-    emit_copy_number(code);
+    if keep("copy_num") {
+        emit_copy_number(code);
+    }
 
     // --- BCD Add subroutine ---
     let bcd_add_sub = code.len() as u16;
-    emit_bcd_add_routine(code);
+    if keep("bcd_add") {
+        emit_bcd_add_routine(code);
+    }
+
+    // --- Ten's complement negate subroutine ---
+    let tens_complement = code.len() as u16;
+    if keep("tens_complement") {
+        emit_tens_complement_routine(code);
+    }
 
     // --- BCD Subtract subroutine ---
     let bcd_sub_sub = code.len() as u16;
-    emit_bcd_sub_routine(code);
-
-    // --- BCD Multiply subroutine ---
-    let bcd_mul_sub = code.len() as u16;
-    emit_bcd_mul_routine(code, bcd_add_sub);
+    if keep("bcd_sub") {
+        emit_bcd_sub_routine(code, tens_complement);
+    }
 
     // --- BCD Multiply by 10 subroutine ---
     let bcd_mul10_sub = code.len() as u16;
-    emit_bcd_mul10_routine(code);
+    if keep("bcd_mul10") {
+        emit_bcd_mul10_routine(code);
+    }
+
+    // --- BCD Multiply subroutine ---
+    let bcd_mul_sub = code.len() as u16;
+    if keep("bcd_mul") {
+        emit_bcd_mul_routine(code, layout, bcd_add_sub, bcd_mul10_sub);
+    }
 
     // --- BCD Compare subroutine ---
     let bcd_cmp_sub = code.len() as u16;
-    emit_bcd_cmp_routine(code);
+    if keep("bcd_cmp") {
+        emit_bcd_cmp_routine(code);
+    }
+
+    // --- Shifted copy of a number (x * 10^n, scale + n) ---
+    let shift_copy = code.len() as u16;
+    if keep("shift_copy") {
+        emit_shift_copy_routine(code, alloc_num, copy_num, bcd_mul10_sub);
+    }
+
+    // --- Bring two operands to the same scale ---
+    let align_scales = code.len() as u16;
+    if keep("align_scales") {
+        emit_align_scales_routine(code, shift_copy);
+    }
+
+    // --- Signed compare for the comparison opcodes ---
+    let bcd_cmp_signed = code.len() as u16;
+    if keep("bcd_cmp_signed") {
+        emit_bcd_cmp_signed_routine(code, align_scales, bcd_cmp_sub);
+    }
 
     // --- BCD Divide subroutine ---
     let bcd_div_sub = code.len() as u16;
-    emit_bcd_div_routine(code, bcd_sub_sub, bcd_cmp_sub, bcd_mul10_sub);
+    if keep("bcd_div") {
+        emit_bcd_div_routine(code, layout, bcd_sub_sub, bcd_cmp_sub, bcd_mul10_sub);
+    }
 
     // --- BCD Negate subroutine ---
     let bcd_neg_sub = code.len() as u16;
-    emit_bcd_neg_routine(code);
+    if keep("bcd_neg") {
+        emit_bcd_neg_routine(code);
+    }
+
+    // --- Zero test subroutine ---
+    let is_zero_sub = code.len() as u16;
+    if keep("is_zero") {
+        emit_is_zero_routine(code);
+    }
 
     // --- Push value stack ---
     let push_vstack = code.len() as u16;
-    emit_push_vstack(code);
+    if keep("push_vstack") {
+        emit_push_vstack(code, layout, vstack_full);
+    }
 
     // --- Pop value stack ---
     let pop_vstack = code.len() as u16;
-    emit_pop_vstack(code);
+    if keep("pop_vstack") {
+        emit_pop_vstack(code, layout);
+    }
+
+    // --- Read and overwrite the top entry, for handlers that pop their
+    // last operand and push a result in its place ---
+    let peek_vstack = code.len() as u16;
+    if keep("peek_vstack") {
+        emit_peek_vstack(code, layout);
+    }
+    let set_vstack_top = code.len() as u16;
+    if keep("set_vstack_top") {
+        emit_set_vstack_top(code, layout);
+    }
+
+    for (name, addr) in [
+        ("acia_out", acia_out),
+        ("acia_in", acia_in),
+        ("getline", getline),
+        ("parse_num", parse_num),
+        ("print_num", print_num),
+        ("print_base_n", print_base_n),
+        ("print_newline", print_newline),
+        ("overflow", overflow),
+        ("vstack_full", vstack_full),
+        ("bad_index", bad_index),
+        ("out_of_memory", out_of_memory),
+        ("num_to_byte", num_to_byte),
+        ("num_to_index", num_to_index),
+        ("byte_to_num", byte_to_num),
+        ("alloc_num", alloc_num),
+        ("array_elem", array_elem),
+        ("copy_num", copy_num),
+        ("bcd_add", bcd_add_sub),
+        ("tens_complement", tens_complement),
+        ("bcd_sub", bcd_sub_sub),
+        ("bcd_mul", bcd_mul_sub),
+        ("bcd_mul10", bcd_mul10_sub),
+        ("bcd_cmp", bcd_cmp_sub),
+        ("shift_copy", shift_copy),
+        ("align_scales", align_scales),
+        ("bcd_cmp_signed", bcd_cmp_signed),
+        ("bcd_div", bcd_div_sub),
+        ("bcd_neg", bcd_neg_sub),
+        ("is_zero", is_zero_sub),
+        ("push_vstack", push_vstack),
+        ("pop_vstack", pop_vstack),
+        ("peek_vstack", peek_vstack),
+        ("set_vstack_top", set_vstack_top),
+    ] {
+        if keep(name) {
+            symbols.insert(name.to_string(), addr);
+        }
+    }
+
+    // --- Instruction trace (only in traced ROMs) ---
+    let print_hex = if options.trace || options.profile {
+        let print_hex = code.len() as u16;
+        emit_print_hex(code, acia_out);
+        print_hex
+    } else {
+        0
+    };
+    let trace_op = if options.trace {
+        let trace_op = emit_trace_routine(code, acia_out, print_hex, print_newline);
+        symbols.insert("trace_op".to_string(), trace_op);
+        Some(trace_op)
+    } else {
+        None
+    };
+
+    // --- Opcode counters (only in profiled ROMs) ---
+    let profile = if options.profile {
+        let profile_op = code.len() as u16;
+        emit_profile_op(code);
+        let profile_dump = code.len() as u16;
+        emit_profile_dump(code, acia_out, print_hex, print_newline);
+        symbols.insert("profile_op".to_string(), profile_op);
+        symbols.insert("profile_dump".to_string(), profile_dump);
+        Some((profile_op, profile_dump))
+    } else {
+        None
+    };
 
     // =====================================================
     // Main interpreter loop
     // =====================================================
     let vm_loop = code.len() as u16;
+    symbols.insert("vm_loop".to_string(), vm_loop);
 
     // Patch the initial jump
     code[vm_loop_patch] = (vm_loop & 0xFF) as u8;
     code[vm_loop_patch + 1] = (vm_loop >> 8) as u8;
 
-    // Fetch opcode: LD HL, (VM_PC)
-    code.push(LD_HL_NN_IND);
-    emit_u16(code, VM_PC);
-
-    // LD A, (HL) - fetch opcode
-    code.push(LD_A_HL);
-
-    // INC HL, store back to VM_PC
-    code.push(INC_HL);
-    code.push(LD_NN_HL);
-    emit_u16(code, VM_PC);
+    // Fetch opcode from the VM PC, which lives in the shadow HL'
+    emit_fetch_operand_byte(code);
 
     // Save opcode in B for later
     code.push(LD_B_A);
 
+    if let Some(trace_op) = trace_op {
+        // The trace routine prints HL - 1
+        emit_get_pc(code);
+        code.push(CALL_NN);
+        emit_u16(code, trace_op);
+    }
+    if let Some((profile_op, _)) = profile {
+        code.push(CALL_NN);
+        emit_u16(code, profile_op);
+    }
+
     // =====================================================
     // Opcode dispatch
     // =====================================================
@@ -474,288 +937,739 @@ fn generate_runtime(code: &mut Vec<u8>, module: &CompiledModule) {
     // HALT (0x00)
     code.push(OR_A);
     let skip_halt = jr_placeholder(code, JR_NZ_N);
+    if let Some((_, profile_dump)) = profile {
+        code.push(CALL_NN);
+        emit_u16(code, profile_dump);
+    }
     code.push(HALT);
     patch_jr(code, skip_halt);
 
-    // LoadZero (0x10)
-    code.push(LD_A_B);
+    // Quit (0x05): back to the monitor if there is one
     code.push(CP_N);
-    code.push(Op::LoadZero as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    // Push pointer to CONST_ZERO
-    code.push(LD_HL_NN);
-    emit_u16(code, CONST_ZERO);
-    code.push(CALL_NN);
-    emit_u16(code, push_vstack);
-    code.push(JP_NN);
-    emit_u16(code, vm_loop);
-    patch_jr(code, skip);
+    code.push(Op::Quit as u8);
+    let skip_quit = jr_placeholder(code, JR_NZ_N);
+    if let Some((_, profile_dump)) = profile {
+        code.push(CALL_NN);
+        emit_u16(code, profile_dump);
+    }
+    match options.exit_addr {
+        Some(addr) => {
+            code.push(JP_NN);
+            emit_u16(code, addr);
+        }
+        None => code.push(HALT),
+    }
+    patch_jr(code, skip_quit);
+
+    if wants(&[Op::Trap]) {
+        // Trap (0x06)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Trap as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_trap_handler(code, layout, acia_out, print_num, print_newline, vm_loop);
+        patch_jr(code, skip);
+    }
 
-    // LoadOne (0x11)
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::LoadOne as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    code.push(LD_HL_NN);
-    emit_u16(code, CONST_ONE);
-    code.push(CALL_NN);
-    emit_u16(code, push_vstack);
-    code.push(JP_NN);
-    emit_u16(code, vm_loop);
-    patch_jr(code, skip);
+    if wants(&[Op::LoadZero]) {
+        // LoadZero (0x10)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::LoadZero as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        // Push pointer to CONST_ZERO
+        code.push(LD_HL_NN);
+        emit_u16(code, layout.ram(CONST_ZERO));
+        code.push(CALL_NN);
+        emit_u16(code, push_vstack);
+        code.push(JP_NN);
+        emit_u16(code, vm_loop);
+        patch_jr(code, skip);
+    }
 
-    // LoadNum (0x12) - load from constant table
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::LoadNum as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    emit_load_num_handler(code, module, push_vstack, vm_loop);
-    patch_jr(code, skip);
+    if wants(&[Op::LoadOne]) {
+        // LoadOne (0x11)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::LoadOne as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        code.push(LD_HL_NN);
+        emit_u16(code, layout.ram(CONST_ONE));
+        code.push(CALL_NN);
+        emit_u16(code, push_vstack);
+        code.push(JP_NN);
+        emit_u16(code, vm_loop);
+        patch_jr(code, skip);
+    }
 
-    // LoadVar (0x20)
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::LoadVar as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    emit_load_var_handler(code, push_vstack, vm_loop);
-    patch_jr(code, skip);
+    if wants(&[Op::LoadNum]) {
+        // LoadNum (0x12) - load from constant table
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::LoadNum as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_load_num_handler(code, module, push_vstack, vm_loop);
+        patch_jr(code, skip);
+    }
 
-    // StoreVar (0x21)
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::StoreVar as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    emit_store_var_handler(code, pop_vstack, vm_loop);
-    patch_jr(code, skip);
+    if wants(&[Op::LoadVar]) {
+        // LoadVar (0x20)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::LoadVar as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_load_var_handler(code, layout, push_vstack, vm_loop);
+        patch_jr(code, skip);
+    }
 
-    // Add (0x30) - signed addition with proper sign handling
-    // Use absolute jump (JP NZ) since handler is >127 bytes
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::Add as u8);
-    let skip = jp_nz_placeholder(code);
-    emit_add_op_handler(code, pop_vstack, push_vstack, bcd_add_sub, bcd_sub_sub, bcd_cmp_sub, alloc_num, vm_loop);
-    patch_jp(code, skip);
+    if wants(&[Op::StoreVar]) {
+        // StoreVar (0x21)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::StoreVar as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_store_var_handler(code, layout, frame_base, pop_vstack, vm_loop);
+        patch_jr(code, skip);
+    }
 
-    // Sub (0x31) - signed subtraction with proper sign handling
-    // Use absolute jump (JP NZ) since handler is >127 bytes
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::Sub as u8);
-    let skip = jp_nz_placeholder(code);
-    emit_sub_op_handler(code, pop_vstack, push_vstack, bcd_add_sub, bcd_sub_sub, bcd_cmp_sub, alloc_num, vm_loop);
-    patch_jp(code, skip);
+    if wants(&[Op::LoadArray]) {
+        // LoadArray (0x22)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::LoadArray as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_load_array_handler(code, layout, pop_vstack, push_vstack, array_elem, vm_loop);
+        patch_jr(code, skip);
+    }
 
-    // Mul (0x32)
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::Mul as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    emit_binary_op_handler(code, pop_vstack, push_vstack, bcd_mul_sub, alloc_num, vm_loop);
-    patch_jr(code, skip);
+    if wants(&[Op::StoreArray]) {
+        // StoreArray (0x23)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::StoreArray as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_store_array_handler(code, layout, frame_base, pop_vstack, array_elem, vm_loop);
+        patch_jr(code, skip);
+    }
 
-    // Div (0x33) - with scale support
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::Div as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    emit_div_op_handler(code, pop_vstack, push_vstack, bcd_div_sub, bcd_mul10_sub, alloc_num, vm_loop);
-    patch_jr(code, skip);
+    let mut add_handler = 0;
+    if wants(&[Op::Add, Op::Inc]) {
+        // Add (0x30) - signed addition with proper sign handling
+        // Use absolute jump (JP NZ) since handler is >127 bytes
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Add as u8);
+        let skip = jp_nz_placeholder(code);
+        add_handler = code.len() as u16;
+        emit_add_op_handler(code, pop_vstack, peek_vstack, set_vstack_top, bcd_add_sub, bcd_sub_sub, bcd_cmp_sub, alloc_num, align_scales, overflow, vm_loop);
+        patch_jp(code, skip);
+    }
 
-    // Neg (0x36)
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::Neg as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    emit_unary_op_handler(code, pop_vstack, push_vstack, bcd_neg_sub, copy_num, alloc_num, vm_loop);
-    patch_jr(code, skip);
+    let mut sub_handler = 0;
+    if wants(&[Op::Sub, Op::Dec]) {
+        // Sub (0x31) - signed subtraction with proper sign handling
+        // Use absolute jump (JP NZ) since handler is >127 bytes
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Sub as u8);
+        let skip = jp_nz_placeholder(code);
+        sub_handler = code.len() as u16;
+        emit_sub_op_handler(code, pop_vstack, peek_vstack, set_vstack_top, bcd_add_sub, bcd_sub_sub, bcd_cmp_sub, alloc_num, align_scales, overflow, vm_loop);
+        patch_jp(code, skip);
+    }
 
-    // Eq (0x40) - comparison
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::Eq as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    emit_cmp_handler(code, pop_vstack, push_vstack, bcd_cmp_sub, 0, vm_loop); // 0 = equal
-    patch_jr(code, skip);
+    if wants(&[Op::Inc]) {
+        // Inc (0x50) - push 1 and reuse the Add handler
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Inc as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        code.push(LD_HL_NN);
+        emit_u16(code, layout.ram(CONST_ONE));
+        code.push(CALL_NN);
+        emit_u16(code, push_vstack);
+        code.push(JP_NN);
+        emit_u16(code, add_handler);
+        patch_jr(code, skip);
+    }
 
-    // Lt (0x42)
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::Lt as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    emit_cmp_handler(code, pop_vstack, push_vstack, bcd_cmp_sub, 0xFF, vm_loop); // -1 = less
-    patch_jr(code, skip);
+    if wants(&[Op::Dec]) {
+        // Dec (0x51) - push 1 and reuse the Sub handler
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Dec as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        code.push(LD_HL_NN);
+        emit_u16(code, layout.ram(CONST_ONE));
+        code.push(CALL_NN);
+        emit_u16(code, push_vstack);
+        code.push(JP_NN);
+        emit_u16(code, sub_handler);
+        patch_jr(code, skip);
+    }
 
-    // Gt (0x44)
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::Gt as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    emit_cmp_handler(code, pop_vstack, push_vstack, bcd_cmp_sub, 1, vm_loop); // 1 = greater
-    patch_jr(code, skip);
+    if wants(&[Op::Mul]) {
+        // Mul (0x32)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Mul as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_binary_op_handler(code, layout, pop_vstack, peek_vstack, set_vstack_top, bcd_mul_sub, alloc_num, is_zero_sub, vm_loop);
+        patch_jr(code, skip);
+    }
 
-    // Pop (0x02)
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::Pop as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    code.push(CALL_NN);
-    emit_u16(code, pop_vstack);
-    code.push(JP_NN);
-    emit_u16(code, vm_loop);
-    patch_jr(code, skip);
+    if wants(&[Op::Div, Op::DivRound]) {
+        // Div (0x33) - with scale support
+        // Use absolute jump (JP NZ) since handler is >127 bytes
+        // DivRound (0x37) shares the handler; VM_ROUND tells them apart
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::DivRound as u8);
+        let round = jr_placeholder(code, JR_Z_N);  // A = 0x37, nonzero
+        code.push(CP_N);
+        code.push(Op::Div as u8);
+        let skip = jp_nz_placeholder(code);
+        code.push(XOR_A);
+        patch_jr(code, round);
+        code.push(LD_NN_A);
+        emit_u16(code, layout.ram(VM_ROUND));
+        emit_div_op_handler(
+            code,
+            layout,
+            pop_vstack,
+            peek_vstack,
+            set_vstack_top,
+            bcd_div_sub,
+            bcd_mul10_sub,
+            bcd_add_sub,
+            bcd_cmp_sub,
+            alloc_num,
+            shift_copy,
+            is_zero_sub,
+            vm_loop,
+        );
+        patch_jp(code, skip);
+    }
 
-    // Dup (0x03)
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::Dup as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    // Get top of stack, push it again
-    // VM_SP points past top entry, so: high byte at VM_SP-1, low byte at VM_SP-2
-    code.push(LD_HL_NN_IND);
-    emit_u16(code, VM_SP);
-    code.push(DEC_HL);       // HL = high byte address
-    code.push(LD_D_HL);      // D = high byte
-    code.push(DEC_HL);       // HL = low byte address
-    code.push(LD_E_HL);      // E = low byte
-    code.push(INC_HL);
-    code.push(INC_HL);       // Restore HL to VM_SP position (not strictly needed)
-    // DE = top value, push it
-    code.push(EX_DE_HL);
-    code.push(CALL_NN);
-    emit_u16(code, push_vstack);
-    code.push(JP_NN);
-    emit_u16(code, vm_loop);
-    patch_jr(code, skip);
+    if wants(&[Op::Neg]) {
+        // Neg (0x36)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Neg as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_unary_op_handler(code, peek_vstack, set_vstack_top, bcd_neg_sub, copy_num, alloc_num, vm_loop);
+        patch_jr(code, skip);
+    }
 
-    // Print (0x90)
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::Print as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    code.push(CALL_NN);
-    emit_u16(code, pop_vstack);
-    // HL = pointer to number
-    code.push(CALL_NN);
-    emit_u16(code, print_num);
-    code.push(JP_NN);
-    emit_u16(code, vm_loop);
-    patch_jr(code, skip);
+    if wants(&[Op::Eq]) {
+        // Eq (0x40) - comparison
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Eq as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_cmp_handler(code, pop_vstack, peek_vstack, set_vstack_top, bcd_cmp_signed, alloc_num, byte_to_num, 0, vm_loop); // 0 = equal
+        patch_jr(code, skip);
+    }
 
-    // PrintNewline (0x92)
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::PrintNewline as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    code.push(CALL_NN);
-    emit_u16(code, print_newline);
-    code.push(JP_NN);
-    emit_u16(code, vm_loop);
-    patch_jr(code, skip);
+    if wants(&[Op::Lt]) {
+        // Lt (0x42)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Lt as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_cmp_handler(code, pop_vstack, peek_vstack, set_vstack_top, bcd_cmp_signed, alloc_num, byte_to_num, 0xFF, vm_loop); // -1 = less
+        patch_jr(code, skip);
+    }
 
-    // Jump (0x60)
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::Jump as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    emit_jump_handler(code, vm_loop);
-    patch_jr(code, skip);
+    if wants(&[Op::Gt]) {
+        // Gt (0x44)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Gt as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_cmp_handler(code, pop_vstack, peek_vstack, set_vstack_top, bcd_cmp_signed, alloc_num, byte_to_num, 1, vm_loop); // 1 = greater
+        patch_jr(code, skip);
+    }
 
-    // JumpIfZero (0x61)
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::JumpIfZero as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    emit_jump_if_zero_handler(code, pop_vstack, vm_loop);
-    patch_jr(code, skip);
+    if wants(&[Op::Not]) {
+        // Not (0x4A) - also completes the lowered Ne/Le/Ge
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Not as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_not_handler(code, peek_vstack, set_vstack_top, is_zero_sub, alloc_num, byte_to_num, vm_loop);
+        patch_jr(code, skip);
+    }
 
-    // JumpIfNotZero (0x62)
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::JumpIfNotZero as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    emit_jump_if_not_zero_handler(code, pop_vstack, vm_loop);
-    patch_jr(code, skip);
+    if wants(&[Op::Pop]) {
+        // Pop (0x02)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Pop as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        code.push(CALL_NN);
+        emit_u16(code, pop_vstack);
+        code.push(JP_NN);
+        emit_u16(code, vm_loop);
+        patch_jr(code, skip);
+    }
 
-    // StoreScale (0x29) - pop value and store as scale
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::StoreScale as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    // Pop number from stack, get its value (0-99), store in VM_SCALE
-    // Number format: [sign][len=50][scale][25 packed bytes]
-    // For small numbers, value is in the last 2 digits (positions 48-49)
-    // Last packed byte is at offset 27 (3 header + 24 = 27)
-    code.push(CALL_NN);
-    emit_u16(code, pop_vstack);
-    // HL = pointer to BCD number
-    // Skip to last packed byte (offset 27 = 3 header + 24 data bytes)
-    code.push(LD_DE_NN);
-    emit_u16(code, 27);
-    code.push(ADD_HL_DE);
-    code.push(LD_A_HL);  // Get last packed byte (digits 48-49)
-    // Extract both nibbles: high nibble * 10 + low nibble
-    code.push(LD_B_A);   // B = packed byte
-    code.push(AND_N);
-    code.push(0xF0);     // High nibble
-    code.push(RRA);
-    code.push(RRA);
-    code.push(RRA);
-    code.push(RRA);      // A = tens digit (0-9)
-    code.push(LD_C_A);   // C = tens digit
-    // Multiply tens by 10: A = C * 10
-    code.push(ADD_A_A);  // A = C * 2
-    code.push(ADD_A_A);  // A = C * 4
-    code.push(ADD_A_C);  // A = C * 5
-    code.push(ADD_A_A);  // A = C * 10
-    // Add ones digit
-    code.push(LD_C_A);   // C = tens * 10
-    code.push(LD_A_B);   // A = packed byte
-    code.push(AND_N);
-    code.push(0x0F);     // A = ones digit
-    code.push(ADD_A_C);  // A = tens*10 + ones
-    code.push(LD_NN_A);
-    emit_u16(code, VM_SCALE);
-    code.push(JP_NN);
-    emit_u16(code, vm_loop);
-    patch_jr(code, skip);
+    if wants(&[Op::Dup]) {
+        // Dup (0x03)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Dup as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        // Get top of stack, push it again
+        // VM_SP points past top entry, so: high byte at VM_SP-1, low byte at VM_SP-2
+        code.push(LD_HL_NN_IND);
+        emit_u16(code, layout.ram(VM_SP));
+        code.push(DEC_HL);       // HL = high byte address
+        code.push(LD_D_HL);      // D = high byte
+        code.push(DEC_HL);       // HL = low byte address
+        code.push(LD_E_HL);      // E = low byte
+        code.push(INC_HL);
+        code.push(INC_HL);       // Restore HL to VM_SP position (not strictly needed)
+        // DE = top value, push it
+        code.push(EX_DE_HL);
+        code.push(CALL_NN);
+        emit_u16(code, push_vstack);
+        code.push(JP_NN);
+        emit_u16(code, vm_loop);
+        patch_jr(code, skip);
+    }
 
-    // Nop (0x01) - do nothing
-    code.push(LD_A_B);
-    code.push(CP_N);
-    code.push(Op::Nop as u8);
-    let skip = jr_placeholder(code, JR_NZ_N);
-    code.push(JP_NN);
-    emit_u16(code, vm_loop);
-    patch_jr(code, skip);
+    if wants(&[Op::Swap]) {
+        // Swap (0x04)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Swap as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        // Exchange the two entries below VM_SP in place
+        code.push(LD_HL_NN_IND);
+        emit_u16(code, layout.ram(VM_SP));
+        code.push(DEC_HL);
+        code.push(LD_D_HL);
+        code.push(DEC_HL);
+        code.push(LD_E_HL);      // DE = top
+        code.push(DEC_HL);
+        code.push(LD_B_HL);
+        code.push(DEC_HL);
+        code.push(LD_C_HL);      // BC = second
+        code.push(LD_HL_E);
+        code.push(INC_HL);
+        code.push(LD_HL_D);      // Second slot = old top
+        code.push(INC_HL);
+        code.push(LD_HL_C);
+        code.push(INC_HL);
+        code.push(LD_HL_B);      // Top slot = old second
+        code.push(JP_NN);
+        emit_u16(code, vm_loop);
+        patch_jr(code, skip);
+    }
 
-    // Unknown opcode - just loop (ignoring unknown opcodes)
-    code.push(JP_NN);
-    emit_u16(code, vm_loop);
-}
+    if wants(&[Op::Print]) {
+        // Print (0x90)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Print as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        code.push(CALL_NN);
+        emit_u16(code, pop_vstack);
+        // HL = pointer to number
+        code.push(LD_A_NN_IND);
+        emit_u16(code, layout.ram(VM_OBASE));
+        code.push(CP_N);
+        code.push(10);
+        let decimal = jr_placeholder(code, JR_Z_N);
+        code.push(CALL_NN);
+        emit_u16(code, print_base_n);
+        code.push(JP_NN);
+        emit_u16(code, vm_loop);
+        patch_jr(code, decimal);
+        code.push(CALL_NN);
+        emit_u16(code, print_num);
+        code.push(JP_NN);
+        emit_u16(code, vm_loop);
+        patch_jr(code, skip);
+    }
 
-// =====================================================
-// Helper functions
-// =====================================================
+    if wants(&[Op::PrintStr]) {
+        // PrintStr (0x91)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::PrintStr as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_print_str_handler(code, module, acia_out, options.newline, vm_loop);
+        patch_jr(code, skip);
+    }
 
-fn emit_u16(code: &mut Vec<u8>, val: u16) {
-    code.push((val & 0xFF) as u8);
-    code.push((val >> 8) as u8);
-}
+    if wants(&[Op::PrintNewline]) {
+        // PrintNewline (0x92)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::PrintNewline as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        code.push(CALL_NN);
+        emit_u16(code, print_newline);
+        code.push(JP_NN);
+        emit_u16(code, vm_loop);
+        patch_jr(code, skip);
+    }
 
-fn jr_placeholder(code: &mut Vec<u8>, opcode: u8) -> usize {
-    code.push(opcode);
-    let pos = code.len();
-    code.push(0); // Placeholder
-    pos
-}
+    let mut jump_handler = 0;
+    if wants(&[Op::Jump, Op::JumpIfEq, Op::JumpIfNe, Op::JumpIfLt, Op::JumpIfGe, Op::JumpIfGt, Op::JumpIfLe]) {
+        // Jump (0x60)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Jump as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        jump_handler = code.len() as u16;
+        emit_jump_handler(code, vm_loop);
+        patch_jr(code, skip);
+    }
 
-fn patch_jr(code: &mut Vec<u8>, pos: usize) {
-    let offset = (code.len() - pos - 1) as i8;
-    code[pos] = offset as u8;
-}
+    if wants(&[Op::JumpIfZero]) {
+        // JumpIfZero (0x61)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::JumpIfZero as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_jump_if_zero_handler(code, pop_vstack, is_zero_sub, vm_loop);
+        patch_jr(code, skip);
+    }
 
-// Absolute jump helpers for long jumps (>127 bytes)
+    if wants(&[Op::JumpIfNotZero]) {
+        // JumpIfNotZero (0x62)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::JumpIfNotZero as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_jump_if_not_zero_handler(code, pop_vstack, is_zero_sub, vm_loop);
+        patch_jr(code, skip);
+    }
+
+    // Fused compare-and-branch (0x63-0x68), emitted by the peephole pass.
+    // Each pairs a bcd_cmp result with whether a match means "jump".
+    for (op, expected, jump_on_match) in [
+        (Op::JumpIfEq, 0, true),
+        (Op::JumpIfNe, 0, false),
+        (Op::JumpIfLt, 0xFF, true),
+        (Op::JumpIfGe, 0xFF, false),
+        (Op::JumpIfGt, 1, true),
+        (Op::JumpIfLe, 1, false),
+    ] {
+        if !wants(&[op]) {
+            continue;
+        }
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(op as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_cmp_jump_handler(code, pop_vstack, bcd_cmp_signed, expected, jump_on_match, jump_handler, vm_loop);
+        patch_jr(code, skip);
+    }
+
+    if wants(&[Op::LoadScale]) {
+        // LoadScale (0x28) - push the current scale
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::LoadScale as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        emit_load_byte_handler(code, layout.ram(VM_SCALE), alloc_num, byte_to_num, push_vstack, vm_loop);
+        patch_jr(code, skip);
+    }
+
+    if wants(&[Op::StoreScale]) {
+        // StoreScale (0x29) - pop value and store as scale
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::StoreScale as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        code.push(CALL_NN);
+        emit_u16(code, pop_vstack);
+        code.push(CALL_NN);
+        emit_u16(code, num_to_byte);
+        code.push(LD_NN_A);
+        emit_u16(code, layout.ram(VM_SCALE));
+        code.push(JP_NN);
+        emit_u16(code, vm_loop);
+        patch_jr(code, skip);
+    }
+
+    if wants(&[Op::ScaleOf]) {
+        // ScaleOf (0x81) - push the scale byte of the popped number
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::ScaleOf as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        code.push(CALL_NN);
+        emit_u16(code, pop_vstack);
+        code.push(INC_HL);
+        code.push(INC_HL);
+        code.push(LD_A_HL);
+        code.push(PUSH_AF);
+        code.push(CALL_NN);
+        emit_u16(code, alloc_num);
+        code.push(POP_AF);
+        code.push(CALL_NN);
+        emit_u16(code, byte_to_num);
+        code.push(CALL_NN);
+        emit_u16(code, push_vstack);
+        code.push(JP_NN);
+        emit_u16(code, vm_loop);
+        patch_jr(code, skip);
+    }
+
+    if wants(&[Op::Length]) {
+        // Length (0x80) - significant digits: from the first non-zero digit to
+        // the end, but at least the scale and at least 1
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Length as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        code.push(CALL_NN);
+        emit_u16(code, pop_vstack);
+        code.push(INC_HL);
+        code.push(INC_HL);
+        code.push(LD_C_HL);      // C = scale
+        code.push(INC_HL);
+        code.push(LD_B_N);
+        code.push(25);
+        code.push(LD_D_N);
+        code.push(50);           // D = digits after the leading zeros
+        let zero_loop = code.len();
+        code.push(LD_A_HL);
+        code.push(OR_A);
+        let found = jr_placeholder(code, JR_NZ_N);
+        code.push(DEC_D);
+        code.push(DEC_D);
+        code.push(INC_HL);
+        code.push(DJNZ_N);
+        code.push((zero_loop as i16 - code.len() as i16 - 1) as u8);
+        let counted = jr_placeholder(code, JR_N);
+        patch_jr(code, found);
+        code.push(AND_N);
+        code.push(0xF0);
+        let high_digit = jr_placeholder(code, JR_NZ_N);
+        code.push(DEC_D);
+        patch_jr(code, counted);
+        patch_jr(code, high_digit);
+        code.push(LD_A_D);
+        code.push(CP_C);
+        let at_least_scale = jr_placeholder(code, JR_NC_N);
+        code.push(LD_A_C);
+        patch_jr(code, at_least_scale);
+        code.push(OR_A);
+        let nonzero = jr_placeholder(code, JR_NZ_N);
+        code.push(INC_A);
+        patch_jr(code, nonzero);
+        code.push(PUSH_AF);
+        code.push(CALL_NN);
+        emit_u16(code, alloc_num);
+        code.push(POP_AF);
+        code.push(CALL_NN);
+        emit_u16(code, byte_to_num);
+        code.push(CALL_NN);
+        emit_u16(code, push_vstack);
+        code.push(JP_NN);
+        emit_u16(code, vm_loop);
+        patch_jr(code, skip);
+    }
+
+    // Base registers (0x2A-0x2D) - bases are clamped to 2-16 like bc does
+    for (load_op, store_op, addr) in [
+        (Op::LoadIbase, Op::StoreIbase, layout.ram(VM_IBASE)),
+        (Op::LoadObase, Op::StoreObase, layout.ram(VM_OBASE)),
+    ] {
+        if wants(&[load_op]) {
+            code.push(LD_A_B);
+            code.push(CP_N);
+            code.push(load_op as u8);
+            let skip = jr_placeholder(code, JR_NZ_N);
+            emit_load_byte_handler(code, addr, alloc_num, byte_to_num, push_vstack, vm_loop);
+            patch_jr(code, skip);
+        }
+
+        if wants(&[store_op]) {
+            code.push(LD_A_B);
+            code.push(CP_N);
+            code.push(store_op as u8);
+            let skip = jr_placeholder(code, JR_NZ_N);
+            emit_store_base_handler(code, addr, pop_vstack, num_to_byte, vm_loop);
+            patch_jr(code, skip);
+        }
+    }
+
+    if wants(&[Op::Read]) {
+        // Read (0x93) - read a line from the ACIA and push it as a number
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Read as u8);
+        let skip = jp_nz_placeholder(code);
+        emit_read_handler(
+            code,
+            layout,
+            getline,
+            parse_num,
+            alloc_num,
+            byte_to_num,
+            bcd_mul_sub,
+            bcd_add_sub,
+            push_vstack,
+            print_newline,
+            vm_loop,
+        );
+        patch_jp(code, skip);
+    }
+
+    if wants(&[Op::Call]) {
+        // Call (0x70)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Call as u8);
+        let skip = jp_nz_placeholder(code);
+        emit_call_handler(code, layout, module, pop_vstack, vm_loop);
+        patch_jp(code, skip);
+    }
+
+    if wants(&[Op::Return, Op::ReturnValue]) {
+        // Return (0x71) / ReturnValue (0x72)
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Return as u8);
+        let not_return = jr_placeholder(code, JR_NZ_N);
+        code.push(LD_HL_NN);
+        emit_u16(code, layout.ram(CONST_ZERO));
+        let to_return = jr_placeholder(code, JR_N);
+        patch_jr(code, not_return);
+        code.push(CP_N);
+        code.push(Op::ReturnValue as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        code.push(CALL_NN);
+        emit_u16(code, pop_vstack);
+        patch_jr(code, to_return);
+        emit_return_handler(code, layout, push_vstack, vm_loop);
+        patch_jr(code, skip);
+    }
+
+    if wants(&[Op::Nop]) {
+        // Nop (0x01) - do nothing
+        code.push(LD_A_B);
+        code.push(CP_N);
+        code.push(Op::Nop as u8);
+        let skip = jr_placeholder(code, JR_NZ_N);
+        code.push(JP_NN);
+        emit_u16(code, vm_loop);
+        patch_jr(code, skip);
+    }
+
+    // Unknown opcode - just loop (ignoring unknown opcodes)
+    code.push(JP_NN);
+    emit_u16(code, vm_loop);
+}
+
+/// Runtime routines an opcode's handler calls directly
+fn handler_routines(op: Op) -> &'static [&'static str] {
+    match op {
+        Op::LoadZero | Op::LoadOne | Op::LoadNum | Op::LoadVar | Op::Dup | Op::Swap => &["push_vstack"],
+        Op::StoreVar | Op::Pop | Op::Call => &["pop_vstack"],
+        Op::LoadArray => &["pop_vstack", "push_vstack", "array_elem"],
+        Op::StoreArray => &["pop_vstack", "array_elem"],
+        Op::Add | Op::Sub => &[
+            "pop_vstack", "peek_vstack", "set_vstack_top", "bcd_add", "bcd_sub", "bcd_cmp", "alloc_num",
+            "align_scales", "overflow",
+        ],
+        // Push a 1 for the Add/Sub handler
+        Op::Inc | Op::Dec => &["push_vstack"],
+        Op::Mul => &["pop_vstack", "peek_vstack", "set_vstack_top", "bcd_mul", "alloc_num", "is_zero"],
+        Op::Div | Op::DivRound => &[
+            "pop_vstack", "peek_vstack", "set_vstack_top", "bcd_div", "bcd_mul10", "bcd_add", "bcd_cmp",
+            "alloc_num", "shift_copy", "is_zero",
+        ],
+        Op::Neg => &["peek_vstack", "set_vstack_top", "bcd_neg", "copy_num", "alloc_num"],
+        Op::Eq | Op::Lt | Op::Gt => {
+            &["pop_vstack", "peek_vstack", "set_vstack_top", "bcd_cmp_signed", "alloc_num", "byte_to_num"]
+        }
+        Op::Not => &["peek_vstack", "set_vstack_top", "is_zero", "alloc_num", "byte_to_num"],
+        Op::Print => &["pop_vstack", "print_num", "print_base_n"],
+        Op::PrintStr => &["acia_out"],
+        Op::PrintNewline => &["print_newline"],
+        Op::JumpIfZero | Op::JumpIfNotZero => &["pop_vstack", "is_zero"],
+        Op::JumpIfEq | Op::JumpIfNe | Op::JumpIfLt | Op::JumpIfLe | Op::JumpIfGt | Op::JumpIfGe => {
+            &["pop_vstack", "bcd_cmp_signed"]
+        }
+        Op::LoadScale | Op::LoadIbase | Op::LoadObase | Op::ScaleOf | Op::Length => {
+            &["pop_vstack", "push_vstack", "alloc_num", "byte_to_num"]
+        }
+        Op::StoreScale | Op::StoreIbase | Op::StoreObase => &["pop_vstack", "num_to_byte"],
+        Op::Read => &[
+            "getline", "parse_num", "alloc_num", "byte_to_num", "bcd_mul", "bcd_add", "push_vstack", "print_newline",
+        ],
+        Op::Return | Op::ReturnValue => &["pop_vstack", "push_vstack"],
+        Op::Trap => &["acia_out", "print_num", "print_newline"],
+        _ => &[],
+    }
+}
+
+/// Runtime routines another routine calls
+fn routine_deps(name: &str) -> &'static [&'static str] {
+    match name {
+        "getline" => &["acia_in", "acia_out"],
+        "print_num" | "print_base_n" | "print_newline" => &["acia_out"],
+        "overflow" | "vstack_full" | "bad_index" | "out_of_memory" => &["acia_out", "print_newline"],
+        "alloc_num" => &["out_of_memory"],
+        "array_elem" => &["num_to_index", "bad_index", "out_of_memory"],
+        "bcd_sub" => &["tens_complement"],
+        "bcd_mul" => &["bcd_add", "bcd_mul10"],
+        "shift_copy" => &["alloc_num", "copy_num", "bcd_mul10"],
+        "align_scales" => &["shift_copy"],
+        "bcd_cmp_signed" => &["align_scales", "bcd_cmp"],
+        "bcd_div" => &["bcd_sub", "bcd_cmp", "bcd_mul10"],
+        "push_vstack" => &["vstack_full"],
+        _ => &[],
+    }
+}
+
+/// Every runtime routine reachable from the given opcodes' handlers
+fn needed_routines(used: &[Op], options: &RuntimeOptions) -> Vec<&'static str> {
+    let mut pending: Vec<&'static str> = used.iter().flat_map(|&op| handler_routines(op).iter().copied()).collect();
+    if options.trace || options.profile {
+        pending.extend(["acia_out", "print_newline"]);
+    }
+    let mut needed = Vec::new();
+    while let Some(name) = pending.pop() {
+        if !needed.contains(&name) {
+            needed.push(name);
+            pending.extend(routine_deps(name));
+        }
+    }
+    needed
+}
+
+// =====================================================
+// Helper functions
+// =====================================================
+
+fn emit_u16(code: &mut Vec<u8>, val: u16) {
+    code.push((val & 0xFF) as u8);
+    code.push((val >> 8) as u8);
+}
+
+fn jr_placeholder(code: &mut Vec<u8>, opcode: u8) -> usize {
+    code.push(opcode);
+    let pos = code.len();
+    code.push(0); // Placeholder
+    pos
+}
+
+fn patch_jr(code: &mut [u8], pos: usize) {
+    let distance = code.len() - pos - 1;
+    assert!(distance <= 127, "JR at 0x{:04X} cannot reach {} bytes ahead", pos - 1, distance);
+    code[pos] = distance as u8;
+}
+
+// Absolute jump helpers for long jumps (>127 bytes)
 fn jp_z_placeholder(code: &mut Vec<u8>) -> usize {
     code.push(JP_Z_NN);
     let pos = code.len();
@@ -771,7 +1685,7 @@ fn jp_placeholder(code: &mut Vec<u8>) -> usize {
     pos
 }
 
-fn patch_jp(code: &mut Vec<u8>, pos: usize) {
+fn patch_jp(code: &mut [u8], pos: usize) {
     let addr = code.len() as u16;
     code[pos] = (addr & 0xFF) as u8;
     code[pos + 1] = (addr >> 8) as u8;
@@ -850,7 +1764,6 @@ fn emit_sbc_hl_de(code: &mut Vec<u8>) {
     code.push(SBC_HL_DE_OP);
 }
 
-#[allow(dead_code)]
 fn emit_sbc_hl_bc(code: &mut Vec<u8>) {
     code.push(ED_PREFIX);
     code.push(SBC_HL_BC_OP);
@@ -893,49 +1806,50 @@ fn emit_bit_0_c(code: &mut Vec<u8>) {
     code.push(BIT_0_C_OP);
 }
 
-fn init_vm_state(code: &mut Vec<u8>) {
-    // VM_PC = BYTECODE_ORG
+fn init_vm_state(code: &mut Vec<u8>, layout: &MemoryLayout) {
+    // VM PC (HL') = BYTECODE_ORG
     code.push(LD_HL_NN);
     emit_u16(code, BYTECODE_ORG);
-    code.push(LD_NN_HL);
-    emit_u16(code, VM_PC);
+    code.push(EXX);
 
-    // VM_SP = VSTACK_BASE
+    // VM_SP = bottom of the value stack
     code.push(LD_HL_NN);
-    emit_u16(code, VSTACK_BASE);
+    emit_u16(code, layout.vstack_base());
     code.push(LD_NN_HL);
-    emit_u16(code, VM_SP);
+    emit_u16(code, layout.ram(VM_SP));
 
     // VM_SCALE = 0
     code.push(XOR_A);
     code.push(LD_NN_A);
-    emit_u16(code, VM_SCALE);
+    emit_u16(code, layout.ram(VM_SCALE));
 
     // VM_IBASE = 10
     code.push(LD_A_N);
     code.push(10);
     code.push(LD_NN_A);
-    emit_u16(code, VM_IBASE);
+    emit_u16(code, layout.ram(VM_IBASE));
 
     // VM_OBASE = 10
     code.push(LD_NN_A);
-    emit_u16(code, VM_OBASE);
+    emit_u16(code, layout.ram(VM_OBASE));
 
-    // VM_HEAP = HEAP_START
+    // VM_HEAP = VM_PINNED = start of the heap
     code.push(LD_HL_NN);
-    emit_u16(code, HEAP_START);
+    emit_u16(code, layout.heap_start());
     code.push(LD_NN_HL);
-    emit_u16(code, VM_HEAP);
+    emit_u16(code, layout.ram(VM_HEAP));
+    code.push(LD_NN_HL);
+    emit_u16(code, layout.ram(VM_PINNED));
 }
 
-fn init_constants(code: &mut Vec<u8>) {
+fn init_constants(code: &mut Vec<u8>, layout: &MemoryLayout) {
     // Constants use fixed 50-digit format (25 packed bytes) for proper BCD alignment
     const FIXED_DIGIT_COUNT: u8 = 50;
     const FIXED_PACKED_BYTES: u8 = 25;
 
     // CONST_ZERO: sign=0, len=50, scale=0, 25 bytes of 0x00
     code.push(LD_HL_NN);
-    emit_u16(code, CONST_ZERO);
+    emit_u16(code, layout.ram(CONST_ZERO));
     code.push(XOR_A);           // A = 0
     code.push(LD_HL_A);         // sign = 0
     code.push(INC_HL);
@@ -959,7 +1873,7 @@ fn init_constants(code: &mut Vec<u8>) {
 
     // CONST_ONE: sign=0, len=50, scale=0, 24 bytes of 0x00 then 0x01
     code.push(LD_HL_NN);
-    emit_u16(code, CONST_ONE);
+    emit_u16(code, layout.ram(CONST_ONE));
     code.push(XOR_A);
     code.push(LD_HL_A);         // sign = 0
     code.push(INC_HL);
@@ -987,6 +1901,11 @@ fn init_constants(code: &mut Vec<u8>) {
 }
 
 // ACIA ports (matching kz80_lisp implementation)
+/// Port the Trap handler writes to, with DE = source line, after dumping
+/// the value stack. An emulator can stop there; on a board with nothing at
+/// this port the write does nothing and the program carries on.
+pub const TRAP_PORT: u8 = 0xFE;
+
 const ACIA_STATUS_PORT: u8 = 0x80;
 const ACIA_DATA_PORT: u8 = 0x81;
 const ACIA_TX_READY: u8 = 0x02;  // Bit 1 = TX ready
@@ -1023,24 +1942,252 @@ fn emit_acia_out(code: &mut Vec<u8>) {
     code.push(RET);
 }
 
-fn emit_print_crlf(code: &mut Vec<u8>, acia_out: u16) {
+/// Print A as two hex digits
+fn emit_print_hex(code: &mut Vec<u8>, acia_out: u16) {
+    let print_hex = code.len() as u16;
+    code.push(PUSH_AF);
+    for _ in 0..4 {
+        code.push(RRCA);
+    }
+    code.push(CALL_NN);
+    emit_u16(code, print_hex + 9);
+    code.push(POP_AF);
+    // Low nibble of A (print_hex + 9)
+    code.push(AND_N);
+    code.push(0x0F);
+    code.push(CP_N);
+    code.push(10);
+    code.push(JR_C_N);
+    code.push(2);
+    code.push(ADD_A_N);
+    code.push(b'A' - b'0' - 10);
+    code.push(ADD_A_N);
+    code.push(b'0');
+    code.push(JP_NN);
+    emit_u16(code, acia_out);
+}
+
+/// Emit the trace routine and return its entry point. It prints "PPPP OO"
+/// and a newline for the instruction being executed; called from the fetch
+/// with HL = VM PC + 1 and A = B = opcode, it preserves all of them.
+fn emit_trace_routine(code: &mut Vec<u8>, acia_out: u16, print_hex: u16, print_newline: u16) -> u16 {
+    let entry = code.len() as u16;
+    code.push(PUSH_AF);
+    code.push(PUSH_HL);
+    code.push(PUSH_BC);
+    code.push(DEC_HL);
+    code.push(LD_A_H);
+    code.push(CALL_NN);
+    emit_u16(code, print_hex);
+    code.push(LD_A_L);
+    code.push(CALL_NN);
+    emit_u16(code, print_hex);
+    code.push(LD_A_N);
+    code.push(b' ');
+    code.push(CALL_NN);
+    emit_u16(code, acia_out);
+    code.push(LD_A_B);
+    code.push(CALL_NN);
+    emit_u16(code, print_hex);
+    code.push(CALL_NN);
+    emit_u16(code, print_newline);
+    code.push(POP_BC);
+    code.push(POP_HL);
+    code.push(POP_AF);
+    code.push(RET);
+    entry
+}
+
+/// Print A, first breaking the line with `\` and a newline if `columns - 1`
+/// characters are already on it, as bc does
+fn emit_print_wrap(code: &mut Vec<u8>, acia_out: u16, column: u16, columns: u8, newline: Newline) {
+    code.push(PUSH_AF);
+    code.push(LD_A_NN_IND);
+    emit_u16(code, column);
+    code.push(CP_N);
+    code.push(columns - 1);
+    let room = jr_placeholder(code, JR_C_N);
     code.push(LD_A_N);
-    code.push(0x0D); // CR
+    code.push(b'\\');
     code.push(CALL_NN);
     emit_u16(code, acia_out);
+    for &byte in newline.bytes() {
+        code.push(LD_A_N);
+        code.push(byte);
+        code.push(CALL_NN);
+        emit_u16(code, acia_out);
+    }
+    code.push(XOR_A);
+    patch_jr(code, room);
+    code.push(INC_A);
+    code.push(LD_NN_A);
+    emit_u16(code, column);
+    code.push(POP_AF);
+    code.push(JP_NN);
+    emit_u16(code, acia_out);
+}
+
+/// Count one run of the opcode in A (= B). Called from the fetch, it
+/// preserves A, B, DE and HL.
+fn emit_profile_op(code: &mut Vec<u8>) {
+    code.push(PUSH_HL);
+    code.push(PUSH_DE);
+    code.push(LD_L_A);
+    code.push(LD_H_N);
+    code.push(0);
+    code.push(ADD_HL_HL);
+    code.push(LD_DE_NN);
+    emit_u16(code, PROFILE_COUNTS);
+    code.push(ADD_HL_DE);
+    code.push(LD_E_HL);
+    code.push(INC_HL);
+    code.push(LD_D_HL);
+    code.push(INC_DE);
+    code.push(LD_A_D);
+    code.push(OR_E);
+    let saturated = jr_placeholder(code, JR_Z_N);  // Stay at 65535
+    code.push(LD_HL_D);
+    code.push(DEC_HL);
+    code.push(LD_HL_E);
+    patch_jr(code, saturated);
+    code.push(POP_DE);
+    code.push(POP_HL);
+    code.push(LD_A_B);
+    code.push(RET);
+}
+
+/// Print "profile", then "OO count" (opcode in hex, count in decimal) for
+/// every opcode that ran, one per line
+fn emit_profile_dump(code: &mut Vec<u8>, acia_out: u16, print_hex: u16, print_newline: u16) {
+    for &c in b"profile" {
+        code.push(LD_A_N);
+        code.push(c);
+        code.push(CALL_NN);
+        emit_u16(code, acia_out);
+    }
+    code.push(CALL_NN);
+    emit_u16(code, print_newline);
+
+    code.push(LD_HL_NN);
+    emit_u16(code, PROFILE_COUNTS);
+    code.push(LD_B_N);
+    code.push(0);                // Opcode
+    let op_loop = code.len();
+    code.push(LD_E_HL);
+    code.push(INC_HL);
+    code.push(LD_D_HL);
+    code.push(INC_HL);
+    code.push(LD_A_D);
+    code.push(OR_E);
+    let unused = jp_z_placeholder(code);
+    code.push(PUSH_HL);
+    code.push(PUSH_BC);
+    code.push(LD_A_B);
+    code.push(CALL_NN);
+    emit_u16(code, print_hex);
     code.push(LD_A_N);
-    code.push(0x0A); // LF
+    code.push(b' ');
+    code.push(CALL_NN);
+    emit_u16(code, acia_out);
+
+    // Count in decimal: subtract each power of ten while it fits, leaving
+    // out leading zeros (C = nonzero once a digit is printed)
+    code.push(EX_DE_HL);
+    code.push(LD_C_N);
+    code.push(0);
+    for power in [10000u16, 1000, 100, 10] {
+        code.push(PUSH_BC);
+        code.push(LD_BC_NN);
+        emit_u16(code, power.wrapping_neg());
+        code.push(LD_A_N);
+        code.push(b'0' - 1);
+        let digit_loop = code.len();
+        code.push(INC_A);
+        code.push(ADD_HL_BC);
+        code.push(JR_C_N);
+        code.push((digit_loop as i16 - code.len() as i16 - 1) as u8);
+        emit_sbc_hl_bc(code);    // Undo the subtraction that went below 0
+        code.push(POP_BC);
+        code.push(CP_N);
+        code.push(b'0');
+        let nonzero = jr_placeholder(code, JR_NZ_N);
+        code.push(INC_C);
+        code.push(DEC_C);
+        let leading = jr_placeholder(code, JR_Z_N);
+        patch_jr(code, nonzero);
+        code.push(LD_C_A);
+        code.push(CALL_NN);
+        emit_u16(code, acia_out);
+        patch_jr(code, leading);
+    }
+    code.push(LD_A_L);
+    code.push(ADD_A_N);
+    code.push(b'0');
     code.push(CALL_NN);
     emit_u16(code, acia_out);
+    code.push(CALL_NN);
+    emit_u16(code, print_newline);
+    code.push(POP_BC);
+    code.push(POP_HL);
+
+    patch_jp(code, unused);
+    code.push(INC_B);
+    code.push(JP_NZ_NN);
+    emit_u16(code, op_loop as u16);
+    code.push(RET);
+}
+
+fn emit_print_crlf(code: &mut Vec<u8>, acia_out: u16, newline: Newline) {
+    for &byte in newline.bytes() {
+        code.push(LD_A_N);
+        code.push(byte);
+        code.push(CALL_NN);
+        emit_u16(code, acia_out);
+    }
     code.push(RET);
 }
 
-fn emit_print_bcd_number(code: &mut Vec<u8>, acia_out: u16) {
+/// Print the number at HL in decimal. With `wrap`, a (routine, column
+/// counter) pair from `emit_print_wrap`, characters go through the routine
+/// and the count starts over for each number.
+fn emit_print_bcd_number(code: &mut Vec<u8>, acia_out: u16, wrap: Option<(u16, u16)>) {
     // Input: HL = pointer to BCD number
     // Format: [sign][len][scale][packed digits...]
     // E = 0 initially (flag: have we printed any digit yet?)
     // C = scale (number of decimal places)
 
+    let out = match wrap {
+        Some((print_wrap, column)) => {
+            code.push(XOR_A);
+            code.push(LD_NN_A);
+            emit_u16(code, column);
+            print_wrap
+        }
+        None => acia_out,
+    };
+
+    // Zero prints as "0" whatever its sign or scale, like bc
+    code.push(PUSH_HL);
+    code.push(INC_HL);
+    code.push(INC_HL);
+    code.push(INC_HL);
+    code.push(LD_B_N);
+    code.push(25);
+    code.push(XOR_A);
+    let zero_loop = code.len() as u16;
+    code.push(OR_HL);
+    code.push(INC_HL);
+    code.push(DJNZ_N);
+    let back = (zero_loop as i16 - code.len() as i16 - 1) as i8;
+    code.push(back as u8);
+    code.push(POP_HL);
+    let nonzero = jr_placeholder(code, JR_NZ_N);
+    code.push(LD_A_N);
+    code.push(b'0');
+    code.push(JP_NN);
+    emit_u16(code, out);
+    patch_jr(code, nonzero);
+
     code.push(PUSH_HL);
     code.push(LD_E_N);
     code.push(0);        // E = 0 (haven't printed any digit yet)
@@ -1055,7 +2202,7 @@ fn emit_print_bcd_number(code: &mut Vec<u8>, acia_out: u16) {
     code.push(LD_A_N);
     code.push(b'-');
     code.push(CALL_NN);
-    emit_u16(code, acia_out);
+    emit_u16(code, out);
 
     patch_jr(code, skip_minus);
 
@@ -1063,13 +2210,33 @@ fn emit_print_bcd_number(code: &mut Vec<u8>, acia_out: u16) {
     code.push(INC_HL);
 
     // Get length
-    code.push(LD_B_HL);  // B = digit count (50)
+    code.push(LD_B_HL);  // B = significant digit count (up to 50)
     code.push(INC_HL);
 
     // Get scale for decimal point placement
     code.push(LD_C_HL);  // C = scale (number of decimal places)
     code.push(INC_HL);
 
+    // Digits are right-aligned in the 25 packed bytes. Round the count up
+    // to whole bytes (the extra high nibble is a suppressed leading zero)
+    // and skip the padding bytes in front of them.
+    code.push(LD_A_B);
+    code.push(INC_A);
+    code.push(AND_N);
+    code.push(0xFE);
+    code.push(LD_B_A);   // B = digits rounded up to even
+    emit_srl_a(code);
+    code.push(LD_D_A);
+    code.push(LD_A_N);
+    code.push(25);
+    code.push(SUB_D);
+    code.push(LD_E_A);
+    code.push(LD_D_N);
+    code.push(0);
+    code.push(ADD_HL_DE); // HL = first byte holding significant digits
+    code.push(LD_E_N);
+    code.push(0);        // E = 0 again (clobbered above)
+
     // HL now points to first packed byte
     // B = remaining digit count
     // C = scale (when B == C, print decimal point)
@@ -1134,7 +2301,7 @@ fn emit_print_bcd_number(code: &mut Vec<u8>, acia_out: u16) {
     code.push(LD_A_N);
     code.push(b'.');
     code.push(CALL_NN);
-    emit_u16(code, acia_out);
+    emit_u16(code, out);
     // Mark that we've "started" so leading zeros in fraction are printed
     code.push(LD_E_N);
     code.push(1);
@@ -1153,7 +2320,7 @@ fn emit_print_bcd_number(code: &mut Vec<u8>, acia_out: u16) {
     code.push(ADD_A_N);
     code.push(b'0');
     code.push(CALL_NN);
-    emit_u16(code, acia_out);
+    emit_u16(code, out);
     code.push(LD_E_N);
     code.push(1);        // E = 1 (we've printed a digit)
 
@@ -1208,7 +2375,7 @@ fn emit_print_bcd_number(code: &mut Vec<u8>, acia_out: u16) {
     code.push(LD_A_N);
     code.push(b'.');
     code.push(CALL_NN);
-    emit_u16(code, acia_out);
+    emit_u16(code, out);
     // Mark that we've "started" so leading zeros in fraction are printed
     code.push(LD_E_N);
     code.push(1);
@@ -1223,7 +2390,7 @@ fn emit_print_bcd_number(code: &mut Vec<u8>, acia_out: u16) {
     code.push(ADD_A_N);
     code.push(b'0');
     code.push(CALL_NN);
-    emit_u16(code, acia_out);
+    emit_u16(code, out);
     code.push(LD_E_N);
     code.push(1);        // E = 1 (we've printed a digit)
 
@@ -1242,29 +2409,378 @@ fn emit_print_bcd_number(code: &mut Vec<u8>, acia_out: u16) {
     emit_u16(code, print_loop);
 }
 
-fn emit_alloc_number(code: &mut Vec<u8>) {
-    // Allocate space for a number on heap
-    // Returns HL = pointer to new number
-    // Advances heap by MAX_NUM_SIZE
+fn emit_print_base_n(code: &mut Vec<u8>, acia_out: u16) {
+    // Print the integer part of a number in base 2-16
+    // Input: HL = pointer to number, A = base
+    // Unpacks the integer digits into BASE_WORK, then repeatedly divides
+    // them by the base; each remainder becomes one output digit, built
+    // right to left below BASE_OUT_END. The fraction is not printed.
+    code.push(LD_NN_A);
+    emit_u16(code, BASE_RADIX);
 
-    code.push(LD_HL_NN_IND);
-    emit_u16(code, VM_HEAP);
-    code.push(PUSH_HL);  // Save result
+    // Keep the sign byte until the digits are known
+    code.push(LD_A_HL);
+    code.push(PUSH_AF);          // [stack: sign]
 
-    // Advance heap
+    // Integer digit count = 50 - scale (0 if scale >= 50)
+    code.push(INC_HL);
+    code.push(INC_HL);
+    code.push(LD_C_HL);          // C = scale
+    code.push(INC_HL);           // HL = first packed byte
+    code.push(LD_A_N);
+    code.push(50);
+    code.push(SUB_C);
+    let in_range = jr_placeholder(code, JR_NC_N);
+    code.push(XOR_A);
+    patch_jr(code, in_range);
+    code.push(LD_NN_A);
+    emit_u16(code, BASE_LEN);
+
+    // Unpack all 25 bytes to one digit per byte; only BASE_LEN are used
     code.push(LD_DE_NN);
-    emit_u16(code, MAX_NUM_SIZE as u16);
-    code.push(ADD_HL_DE);
-    code.push(LD_NN_HL);
-    emit_u16(code, VM_HEAP);
+    emit_u16(code, BASE_WORK);
+    code.push(LD_B_N);
+    code.push(25);
+    let unpack_loop = code.len() as u16;
+    code.push(LD_A_HL);
+    code.push(RRCA);
+    code.push(RRCA);
+    code.push(RRCA);
+    code.push(RRCA);
+    code.push(AND_N);
+    code.push(0x0F);
+    code.push(LD_DE_A);
+    code.push(INC_DE);
+    code.push(LD_A_HL);
+    code.push(AND_N);
+    code.push(0x0F);
+    code.push(LD_DE_A);
+    code.push(INC_DE);
+    code.push(INC_HL);
+    code.push(DJNZ_N);
+    let back = (unpack_loop as i16 - code.len() as i16 - 1) as i8;
+    code.push(back as u8);
 
-    code.push(POP_HL);   // Return allocated address
-    code.push(RET);
-}
+    code.push(LD_HL_NN);
+    emit_u16(code, BASE_OUT_END);
+    code.push(PUSH_HL);          // [stack: output pointer]
 
-fn emit_copy_number(code: &mut Vec<u8>) {
-    // Copy number from DE to HL
-    // Both point to BCD number structures
+    // One pass: BASE_WORK /= base, C = remainder, E = OR of quotient digits
+    let div_pass = code.len() as u16;
+    code.push(LD_HL_NN);
+    emit_u16(code, BASE_WORK);
+    code.push(LD_A_NN_IND);
+    emit_u16(code, BASE_RADIX);
+    code.push(LD_D_A);           // D = base
+    code.push(LD_A_NN_IND);
+    emit_u16(code, BASE_LEN);
+    code.push(LD_B_A);           // B = digit count
+    code.push(LD_C_N);
+    code.push(0);                // C = remainder
+    code.push(LD_E_N);
+    code.push(0);                // E = quotient non-zero flag
+    code.push(OR_A);
+    let no_digits = jr_placeholder(code, JR_Z_N);
+
+    let digit_loop = code.len() as u16;
+    // A = remainder * 10 + digit (at most 159)
+    code.push(LD_A_C);
+    code.push(ADD_A_A);
+    code.push(LD_C_A);           // C = rem * 2
+    code.push(ADD_A_A);
+    code.push(ADD_A_A);          // A = rem * 8
+    code.push(ADD_A_C);          // A = rem * 10
+    code.push(ADD_A_HL);
+    // C = A / base, A = A % base
+    code.push(LD_C_N);
+    code.push(0);
+    let div_loop = code.len() as u16;
+    code.push(CP_D);
+    let div_done = jr_placeholder(code, JR_C_N);
+    code.push(SUB_D);
+    code.push(INC_C);
+    code.push(JR_N);
+    let back = (div_loop as i16 - code.len() as i16 - 1) as i8;
+    code.push(back as u8);
+    patch_jr(code, div_done);
+    code.push(LD_HL_C);          // Store quotient digit
+    code.push(LD_C_A);           // C = remainder
+    code.push(LD_A_HL);
+    code.push(OR_E);
+    code.push(LD_E_A);
+    code.push(INC_HL);
+    code.push(DJNZ_N);
+    let back = (digit_loop as i16 - code.len() as i16 - 1) as i8;
+    code.push(back as u8);
+
+    patch_jr(code, no_digits);
+
+    // Look up the remainder's digit character (B = 0 here)
+    code.push(LD_HL_NN);
+    let table_patch = code.len();
+    emit_u16(code, 0);
+    code.push(ADD_HL_BC);
+    code.push(LD_A_HL);
+    code.push(POP_HL);
+    code.push(DEC_HL);
+    code.push(LD_HL_A);
+    code.push(PUSH_HL);
+
+    // Keep dividing until the quotient is zero
+    code.push(LD_A_E);
+    code.push(OR_A);
+    code.push(JP_NZ_NN);
+    emit_u16(code, div_pass);
+
+    // A minus goes before the digits, unless all there is to print is a
+    // lone 0 (a negative number whose integer part is zero)
+    code.push(POP_HL);
+    code.push(POP_AF);
+    code.push(AND_N);
+    code.push(0x80);
+    let positive = jr_placeholder(code, JR_Z_N);
+    code.push(LD_A_L);
+    code.push(CP_N);
+    code.push(0xFF);
+    let minus = jr_placeholder(code, JR_NZ_N);
+    code.push(LD_A_HL);
+    code.push(CP_N);
+    code.push(b'0');
+    let zero = jr_placeholder(code, JR_Z_N);
+    patch_jr(code, minus);
+    code.push(LD_A_N);
+    code.push(b'-');
+    code.push(CALL_NN);
+    emit_u16(code, acia_out);
+    patch_jr(code, positive);
+    patch_jr(code, zero);
+
+    // Print the digits; BASE_OUT_END is page aligned, so stop when L wraps
+    let print_loop = code.len() as u16;
+    code.push(LD_A_HL);
+    code.push(CALL_NN);
+    emit_u16(code, acia_out);
+    code.push(INC_HL);
+    code.push(LD_A_L);
+    code.push(OR_A);
+    code.push(JR_NZ_N);
+    let back = (print_loop as i16 - code.len() as i16 - 1) as i8;
+    code.push(back as u8);
+    code.push(RET);
+
+    let table = code.len() as u16;
+    code[table_patch..table_patch + 2].copy_from_slice(&table.to_le_bytes());
+    code.extend_from_slice(b"0123456789ABCDEF");
+}
+
+fn emit_num_to_byte(code: &mut Vec<u8>) {
+    // Convert the last three digits of a number to a byte (mod 256)
+    // Input: HL = pointer to number
+    // Output: A = hundreds * 100 + tens * 10 + ones
+    code.push(LD_DE_NN);
+    emit_u16(code, 26);          // Byte 26: hundreds in low nibble
+    code.push(ADD_HL_DE);
+    code.push(LD_A_HL);
+    code.push(AND_N);
+    code.push(0x0F);
+    code.push(INC_HL);           // Byte 27: tens | ones
+    for digit_shift in [true, false] {
+        // A = A * 10
+        code.push(LD_C_A);
+        code.push(ADD_A_A);
+        code.push(ADD_A_A);
+        code.push(ADD_A_C);
+        code.push(ADD_A_A);
+        code.push(LD_C_A);
+        code.push(LD_A_HL);
+        if digit_shift {
+            code.push(RRCA);
+            code.push(RRCA);
+            code.push(RRCA);
+            code.push(RRCA);
+        }
+        code.push(AND_N);
+        code.push(0x0F);
+        code.push(ADD_A_C);
+    }
+    code.push(RET);
+}
+
+fn emit_num_to_index(code: &mut Vec<u8>) {
+    // Convert an array index to a byte, dropping any fraction (as bc does)
+    // Input: HL = pointer to number
+    // Output: A = integer part; carry set if negative or above 255
+    code.push(LD_A_HL);
+    code.push(AND_N);
+    code.push(0x80);
+    let negative = jr_placeholder(code, JR_NZ_N);
+    code.push(INC_HL);
+    code.push(INC_HL);
+    code.push(LD_C_HL);
+    code.push(LD_A_N);
+    code.push(50);
+    code.push(SUB_C);            // A = integer digit count
+    let all_fraction = jr_placeholder(code, JR_C_N);
+    let empty = jr_placeholder(code, JR_Z_N);
+    code.push(LD_B_A);
+    code.push(INC_HL);           // HL = first packed byte
+    code.push(LD_DE_NN);
+    emit_u16(code, 0);           // DE = integer part so far
+    code.push(LD_C_N);
+    code.push(0);                // Bit 0 of C: low nibble next
+
+    let digit_loop = code.len() as u16;
+    code.push(LD_A_HL);
+    emit_bit_0_c(code);
+    let low = jr_placeholder(code, JR_NZ_N);
+    code.push(RRCA);
+    code.push(RRCA);
+    code.push(RRCA);
+    code.push(RRCA);
+    let got = jr_placeholder(code, JR_N);
+    patch_jr(code, low);
+    code.push(INC_HL);           // Both digits of this byte done
+    patch_jr(code, got);
+    code.push(AND_N);
+    code.push(0x0F);
+
+    // DE = DE * 10 + digit, stopping once it passes a byte
+    code.push(PUSH_HL);
+    code.push(LD_H_D);
+    code.push(LD_L_E);
+    code.push(ADD_HL_HL);
+    code.push(ADD_HL_HL);
+    code.push(ADD_HL_DE);
+    code.push(ADD_HL_HL);
+    code.push(LD_E_A);
+    code.push(LD_D_N);
+    code.push(0);
+    code.push(ADD_HL_DE);
+    code.push(EX_DE_HL);
+    code.push(POP_HL);
+    code.push(LD_A_D);
+    code.push(OR_A);
+    let too_big = jr_placeholder(code, JR_NZ_N);
+    code.push(INC_C);
+    code.push(DJNZ_N);
+    code.push((digit_loop as i16 - code.len() as i16 - 1) as u8);
+    code.push(LD_A_E);           // OR A above left carry clear
+    code.push(RET);
+
+    patch_jr(code, all_fraction);
+    patch_jr(code, empty);
+    code.push(XOR_A);
+    code.push(RET);
+
+    patch_jr(code, negative);
+    patch_jr(code, too_big);
+    code.push(SCF);
+    code.push(RET);
+}
+
+fn emit_byte_to_num(code: &mut Vec<u8>) {
+    // Store a byte as an integer number
+    // Input: A = value, HL = pointer to number (preserved)
+    code.push(PUSH_HL);
+    code.push(LD_C_A);           // C = value
+
+    // Header: sign=0, len=50, scale=0
+    code.push(XOR_A);
+    code.push(LD_HL_A);
+    code.push(INC_HL);
+    code.push(LD_A_N);
+    code.push(50);
+    code.push(LD_HL_A);
+    code.push(INC_HL);
+    code.push(XOR_A);
+    code.push(LD_HL_A);
+    code.push(INC_HL);
+
+    // Clear the 25 digit bytes
+    code.push(LD_B_N);
+    code.push(25);
+    let clear_loop = code.len() as u16;
+    code.push(LD_HL_A);
+    code.push(INC_HL);
+    code.push(DJNZ_N);
+    let back = (clear_loop as i16 - code.len() as i16 - 1) as i8;
+    code.push(back as u8);
+    code.push(DEC_HL);           // HL = byte 27
+
+    // D = hundreds, E = tens, A = ones
+    code.push(LD_A_C);
+    code.push(LD_D_N);
+    code.push(0);
+    let hundreds_loop = code.len() as u16;
+    code.push(CP_N);
+    code.push(100);
+    let hundreds_done = jr_placeholder(code, JR_C_N);
+    code.push(SUB_N);
+    code.push(100);
+    code.push(INC_D);
+    code.push(JR_N);
+    let back = (hundreds_loop as i16 - code.len() as i16 - 1) as i8;
+    code.push(back as u8);
+    patch_jr(code, hundreds_done);
+    code.push(LD_E_N);
+    code.push(0);
+    let tens_loop = code.len() as u16;
+    code.push(CP_N);
+    code.push(10);
+    let tens_done = jr_placeholder(code, JR_C_N);
+    code.push(SUB_N);
+    code.push(10);
+    code.push(INC_E);
+    code.push(JR_N);
+    let back = (tens_loop as i16 - code.len() as i16 - 1) as i8;
+    code.push(back as u8);
+    patch_jr(code, tens_done);
+
+    // Byte 27 = tens | ones, byte 26 = hundreds
+    code.push(LD_C_A);
+    code.push(LD_A_E);
+    code.push(ADD_A_A);
+    code.push(ADD_A_A);
+    code.push(ADD_A_A);
+    code.push(ADD_A_A);
+    code.push(OR_C);
+    code.push(LD_HL_A);
+    code.push(DEC_HL);
+    code.push(LD_HL_D);
+
+    code.push(POP_HL);
+    code.push(RET);
+}
+
+fn emit_alloc_number(code: &mut Vec<u8>, layout: &MemoryLayout, heap_end: u16, out_of_memory: u16) {
+    // Allocate space for a number on heap
+    // Returns HL = pointer to new number
+    // Advances heap by MAX_NUM_SIZE, or stops at out_of_memory past heap_end
+
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, layout.ram(VM_HEAP));
+    code.push(PUSH_HL);  // Save result
+
+    // Advance heap
+    code.push(LD_DE_NN);
+    emit_u16(code, MAX_NUM_SIZE as u16);
+    code.push(ADD_HL_DE);
+    code.push(LD_NN_HL);
+    emit_u16(code, layout.ram(VM_HEAP));
+    code.push(LD_DE_NN);
+    emit_u16(code, heap_end + 1);
+    code.push(OR_A);
+    emit_sbc_hl_de(code);
+    code.push(JP_NC_NN);
+    emit_u16(code, out_of_memory);
+
+    code.push(POP_HL);   // Return allocated address
+    code.push(RET);
+}
+
+fn emit_copy_number(code: &mut Vec<u8>) {
+    // Copy number from DE to HL
+    // Both point to BCD number structures
 
     code.push(PUSH_HL);
     code.push(PUSH_DE);
@@ -1284,11 +2800,19 @@ fn emit_bcd_add_routine(code: &mut Vec<u8>) {
     // BCD Addition: (HL) = (DE) + (HL)
     // Uses DAA for decimal correction
     // Input: DE = first operand, HL = result (copy of second operand)
+    // Output: carry set if the sum overflowed the 50 digits
     // Process RIGHT TO LEFT for proper carry propagation
 
     code.push(PUSH_HL);
     code.push(PUSH_DE);
 
+    // The sum can outgrow either operand's digit count - mark it full width
+    code.push(INC_HL);
+    code.push(LD_A_N);
+    code.push(50);
+    code.push(LD_HL_A);
+    code.push(DEC_HL);
+
     // Skip to END of packed data (header 3 bytes + 24 bytes = offset 27 = last byte)
     // HL += 27, DE += 27
     code.push(LD_BC_NN);
@@ -1319,21 +2843,89 @@ fn emit_bcd_add_routine(code: &mut Vec<u8>) {
     let offset = (add_loop as i16 - code.len() as i16 - 1) as i8;
     code.push(offset as u8);
 
+    // DJNZ, DEC rr and POP leave the final carry alone
     code.push(POP_DE);
     code.push(POP_HL);
     code.push(RET);
 }
 
-fn emit_bcd_sub_routine(code: &mut Vec<u8>) {
+/// Emit a routine that prints "Runtime error: <msg>" and halts, for
+/// failures detected inside the runtime rather than by compiled checks
+fn emit_runtime_error(code: &mut Vec<u8>, acia_out: u16, print_newline: u16, msg: &str) -> u16 {
+    let entry = code.len() as u16;
+    code.push(LD_HL_NN);
+    let msg_patch = code.len();
+    emit_u16(code, 0);
+    let print_loop = code.len();
+    code.push(LD_A_HL);
+    code.push(OR_A);
+    let done = jr_placeholder(code, JR_Z_N);
+    code.push(CALL_NN);
+    emit_u16(code, acia_out);
+    code.push(INC_HL);
+    code.push(JR_N);
+    code.push((print_loop as i16 - code.len() as i16 - 1) as u8);
+    patch_jr(code, done);
+    code.push(CALL_NN);
+    emit_u16(code, print_newline);
+    code.push(HALT);
+
+    let text = code.len() as u16;
+    code[msg_patch] = (text & 0xFF) as u8;
+    code[msg_patch + 1] = (text >> 8) as u8;
+    code.extend_from_slice(format!("Runtime error: {}\0", msg).as_bytes());
+    entry
+}
+
+fn emit_tens_complement_routine(code: &mut Vec<u8>) {
+    // Negate the digit field in place: (HL) = 0 - (HL)
+    // Turns the ten's complement left by an underflowing subtraction
+    // (e.g. 99...93 for 2 - 9) back into a magnitude (7)
+    // Input: HL = number (preserved), sign byte untouched
+
+    code.push(PUSH_HL);
+    code.push(LD_BC_NN);
+    emit_u16(code, 27);
+    code.push(ADD_HL_BC);    // HL = last packed byte
+
+    code.push(LD_B_N);
+    code.push(25);
+    code.push(OR_A);         // Clear borrow
+
+    let loop_start = code.len() as u16;
+    code.push(LD_A_N);
+    code.push(0);            // LD keeps the borrow flag intact
+    code.push(SBC_A_HL);
+    code.push(DAA);
+    code.push(LD_HL_A);
+    code.push(DEC_HL);
+    code.push(DJNZ_N);
+    let offset = (loop_start as i16 - code.len() as i16 - 1) as i8;
+    code.push(offset as u8);
+
+    code.push(POP_HL);
+    code.push(RET);
+}
+
+fn emit_bcd_sub_routine(code: &mut Vec<u8>, tens_complement: u16) {
     // BCD Subtraction: (HL) = (HL) - (DE)
     // HL = result (copy of first operand)
     // DE = second operand
     // Uses DAA for decimal correction after SBC
     // Process RIGHT TO LEFT for proper borrow propagation
+    // If (DE) > (HL) the field is converted back to a magnitude and the
+    // result's sign flipped; carry is set on return in that case
 
     code.push(PUSH_HL);
     code.push(PUSH_DE);
 
+    // The difference can outgrow either operand's digit count - mark it full width
+    code.push(INC_HL);
+    code.push(LD_A_N);
+    code.push(50);
+    code.push(LD_HL_A);
+    code.push(DEC_HL);
+
     // Skip to END of packed data (header 3 bytes + 24 bytes = offset 27 = last byte)
     // HL += 27, DE += 27
     code.push(LD_BC_NN);
@@ -1368,143 +2960,65 @@ fn emit_bcd_sub_routine(code: &mut Vec<u8>) {
 
     code.push(POP_DE);
     code.push(POP_HL);
+    code.push(RET_NC);           // No borrow out: result is a magnitude
+
+    code.push(CALL_NN);
+    emit_u16(code, tens_complement);
+    code.push(LD_A_HL);
+    code.push(XOR_N);
+    code.push(0x80);             // Flip sign
+    code.push(LD_HL_A);
+    code.push(SCF);
     code.push(RET);
 }
 
-fn emit_bcd_mul_routine(code: &mut Vec<u8>, bcd_add: u16) {
-    // BCD Multiplication using repeated addition
+fn emit_bcd_mul_routine(code: &mut Vec<u8>, layout: &MemoryLayout, bcd_add: u16, bcd_mul10: u16) {
+    // BCD Multiplication, schoolbook style
     // Input: DE = multiplier ptr, HL = result ptr (contains multiplicand copy)
-    // Output: result in HL
+    // Output: result in HL, scale = multiplicand scale + multiplier scale
     //
-    // Algorithm: result = 0; loop multiplier times: result += multiplicand
-    // Uses REPL_TEMP (0x8700) to save multiplicand
-    // Supports multipliers 0-9999 (4 BCD digits)
+    // Algorithm: result = 0; for each multiplier digit d, most significant
+    // first: result = result * 10 + d * multiplicand
+    // Uses REPL_TEMP (0x8700) to save the multiplicand, REPL_TEMP+28 for
+    // the scale and REPL_TEMP+30 for the result ptr
 
-    // Save result ptr and multiplier ptr
-    code.push(PUSH_HL);          // [stack: result]
-    code.push(PUSH_DE);          // [stack: multiplier, result]
+    code.push(PUSH_DE);          // [stack: multiplier]
+    code.push(LD_NN_HL);
+    emit_u16(code, layout.ram(REPL_TEMP + 30));
 
     // Copy multiplicand (from HL) to REPL_TEMP
     code.push(LD_DE_NN);
-    emit_u16(code, REPL_TEMP);
+    emit_u16(code, layout.ram(REPL_TEMP));
     code.push(LD_BC_NN);
     emit_u16(code, 28);
-    emit_ldir(code);             // Copy multiplicand to REPL_TEMP
-
-    // Save multiplicand scale (now at REPL_TEMP+2) to REPL_TEMP+28
-    code.push(LD_HL_NN);
-    emit_u16(code, REPL_TEMP + 2);
-    code.push(LD_A_HL);          // A = multiplicand scale
-    code.push(LD_HL_NN);
-    emit_u16(code, REPL_TEMP + 28);
-    code.push(LD_HL_A);          // REPL_TEMP+28 = multiplicand scale
-
-    // Get multiplier value from last 2 packed bytes (up to 4 BCD digits = 0-9999)
-    code.push(POP_HL);           // HL = multiplier ptr
+    emit_ldir(code);
 
-    // Add multiplier scale to saved multiplicand scale
-    code.push(PUSH_HL);          // Save multiplier ptr
+    // Combined scale = multiplicand scale + multiplier scale
+    code.push(LD_A_NN_IND);
+    emit_u16(code, layout.ram(REPL_TEMP + 2));
+    code.push(LD_B_A);
+    code.push(POP_HL);           // HL = multiplier
+    code.push(PUSH_HL);
     code.push(INC_HL);
-    code.push(INC_HL);           // HL = multiplier + 2 (scale offset)
-    code.push(LD_B_HL);          // B = multiplier scale
-    code.push(LD_HL_NN);
-    emit_u16(code, REPL_TEMP + 28);
-    code.push(LD_A_HL);          // A = multiplicand scale
-    code.push(ADD_A_B);          // A = multiplicand_scale + multiplier_scale
-    code.push(LD_HL_A);          // Save combined scale to REPL_TEMP+28
-    code.push(POP_HL);           // Restore multiplier ptr
-    code.push(LD_BC_NN);
-    emit_u16(code, 26);
-    code.push(ADD_HL_BC);        // HL = multiplier + 26 (byte 26)
-
-    // Read byte 26 (high 2 digits) and byte 27 (low 2 digits)
-    code.push(LD_D_HL);          // D = byte 26 (packed BCD)
     code.push(INC_HL);
-    code.push(LD_E_HL);          // E = byte 27 (packed BCD)
-    // Save these for later
-    code.push(PUSH_DE);          // [stack: packed bytes, result]
-
-    // Convert E (byte 27, low 2 digits) to binary (0-99)
-    code.push(LD_A_E);
-    code.push(LD_B_A);           // B = save packed byte
-    code.push(AND_N);
-    code.push(0x0F);             // A = low digit
-    code.push(LD_C_A);           // C = low digit
-    code.push(LD_A_B);           // A = packed byte
-    code.push(RRCA);
-    code.push(RRCA);
-    code.push(RRCA);
-    code.push(RRCA);
-    code.push(AND_N);
-    code.push(0x0F);             // A = high digit
-    code.push(LD_B_A);           // B = high digit
-    code.push(ADD_A_A);          // A = 2 * high
-    code.push(ADD_A_A);          // A = 4 * high
-    code.push(ADD_A_B);          // A = 5 * high
-    code.push(ADD_A_A);          // A = 10 * high
-    code.push(ADD_A_C);          // A = 10 * high + low (0-99)
-    code.push(LD_E_A);           // E = byte27 binary value
-
-    // Convert D (byte 26, high 2 digits) to binary (0-99)
-    code.push(POP_HL);           // H = byte26, L = byte27 (packed)
-    code.push(PUSH_DE);          // Save E (low value) [stack: E, result]
-    code.push(LD_A_H);
-    code.push(LD_B_A);           // B = save packed byte
-    code.push(AND_N);
-    code.push(0x0F);             // A = low digit
-    code.push(LD_C_A);           // C = low digit
-    code.push(LD_A_B);           // A = packed byte
-    code.push(RRCA);
-    code.push(RRCA);
-    code.push(RRCA);
-    code.push(RRCA);
-    code.push(AND_N);
-    code.push(0x0F);             // A = high digit
-    code.push(LD_B_A);           // B = high digit
-    code.push(ADD_A_A);          // A = 2 * high
-    code.push(ADD_A_A);          // A = 4 * high
-    code.push(ADD_A_B);          // A = 5 * high
-    code.push(ADD_A_A);          // A = 10 * high
-    code.push(ADD_A_C);          // A = 10 * high + low (0-99)
-    // A = byte26 binary value (0-99), need to multiply by 100
-
-    // Compute A * 100: 100 = 64 + 32 + 4
-    // Result in HL (16-bit)
-    code.push(LD_L_A);
-    code.push(LD_H_N);
-    code.push(0);                // HL = A (0-99)
-
-    code.push(ADD_HL_HL);        // HL = A * 2
-    code.push(ADD_HL_HL);        // HL = A * 4
-    code.push(PUSH_HL);          // Save A * 4
-    code.push(ADD_HL_HL);        // HL = A * 8
-    code.push(ADD_HL_HL);        // HL = A * 16
-    code.push(ADD_HL_HL);        // HL = A * 32
-    code.push(PUSH_HL);          // Save A * 32
-    code.push(ADD_HL_HL);        // HL = A * 64
-    code.push(POP_BC);           // BC = A * 32
-    code.push(ADD_HL_BC);        // HL = A * 96
-    code.push(POP_BC);           // BC = A * 4
-    code.push(ADD_HL_BC);        // HL = A * 100
-
-    // Add low byte (E) to get total: HL = high*100 + low
-    code.push(POP_DE);           // E = low value [stack: result]
-    code.push(LD_D_N);
-    code.push(0);                // DE = low value (0-99)
-    code.push(ADD_HL_DE);        // HL = total (0-9999)
-
-    // BC = 16-bit loop counter
-    code.push(LD_B_H);
-    code.push(LD_C_L);
-
-    // Zero the result buffer
-    code.push(POP_HL);           // HL = result ptr [stack: empty]
-    code.push(PUSH_HL);          // [stack: result]
-    code.push(PUSH_BC);          // [stack: counter, result]
+    code.push(LD_A_HL);
+    code.push(ADD_A_B);
+    code.push(LD_NN_A);
+    emit_u16(code, layout.ram(REPL_TEMP + 28));
 
+    // Result = 0 at the combined scale
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, layout.ram(REPL_TEMP + 30));
+    code.push(LD_C_A);
+    code.push(XOR_A);
+    code.push(LD_HL_A);          // sign = 0
     code.push(INC_HL);
+    code.push(LD_A_N);
+    code.push(50);
+    code.push(LD_HL_A);          // len = 50
+    code.push(INC_HL);
+    code.push(LD_HL_C);          // scale
     code.push(INC_HL);
-    code.push(INC_HL);           // Skip header
     code.push(LD_B_N);
     code.push(25);
     code.push(XOR_A);
@@ -1512,58 +3026,68 @@ fn emit_bcd_mul_routine(code: &mut Vec<u8>, bcd_add: u16) {
     code.push(LD_HL_A);
     code.push(INC_HL);
     code.push(DJNZ_N);
-    let back = (zero_loop as i16 - code.len() as i16 - 1) as i8;
-    code.push(back as u8);
+    code.push((zero_loop as i16 - code.len() as i16 - 1) as u8);
 
-    // Set up result header
-    code.push(POP_BC);           // BC = counter
-    code.push(POP_HL);           // HL = result ptr
-    code.push(PUSH_HL);          // [stack: result]
-    code.push(XOR_A);
-    code.push(LD_HL_A);          // sign = 0
+    // Walk the 25 packed multiplier bytes, high digit then low digit
+    code.push(POP_HL);           // HL = multiplier
     code.push(INC_HL);
-    code.push(LD_A_N);
-    code.push(50);
-    code.push(LD_HL_A);          // len = 50
     code.push(INC_HL);
-    // Set scale = multiplicand_scale + multiplier_scale (saved at REPL_TEMP+28)
-    code.push(PUSH_HL);          // Save result+2 pointer
-    code.push(LD_HL_NN);
-    emit_u16(code, REPL_TEMP + 28);
-    code.push(LD_A_HL);          // A = combined scale
-    code.push(POP_HL);           // HL = result+2
-    code.push(LD_HL_A);          // scale = combined scale
-
-    // Check if counter is 0
-    code.push(LD_A_B);
-    code.push(OR_C);
-    let mul_done = jr_placeholder(code, JR_Z_N);
-
-    // Loop: add multiplicand to result BC times (16-bit counter)
-    let mul_loop = code.len() as u16;
-
-    code.push(POP_HL);           // HL = result
+    code.push(INC_HL);           // Skip header
+    code.push(LD_B_N);
+    code.push(25);
+    let byte_loop = code.len() as u16;
+    code.push(PUSH_BC);
+    code.push(PUSH_HL);          // [stack: byte ptr, counter]
+    code.push(LD_A_HL);
+    code.push(RRCA);
+    code.push(RRCA);
+    code.push(RRCA);
+    code.push(RRCA);
+    code.push(CALL_NN);
+    let high_call = code.len();
+    emit_u16(code, 0);
+    code.push(POP_HL);
     code.push(PUSH_HL);
-    code.push(PUSH_BC);          // Save counter
+    code.push(LD_A_HL);
+    code.push(CALL_NN);
+    let low_call = code.len();
+    emit_u16(code, 0);
+    code.push(POP_HL);
+    code.push(INC_HL);
+    code.push(POP_BC);
+    code.push(DJNZ_N);
+    code.push((byte_loop as i16 - code.len() as i16 - 1) as u8);
 
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, layout.ram(REPL_TEMP + 30));
+    code.push(RET);
+
+    // One digit (low nibble of A): result = result * 10 + digit * multiplicand
+    let digit_step = code.len() as u16;
+    for call in [high_call, low_call] {
+        code[call..call + 2].copy_from_slice(&digit_step.to_le_bytes());
+    }
+    code.push(AND_N);
+    code.push(0x0F);
+    code.push(PUSH_AF);
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, layout.ram(REPL_TEMP + 30));
+    code.push(CALL_NN);
+    emit_u16(code, bcd_mul10);
+    code.push(POP_AF);
+    code.push(RET_Z);
+    code.push(LD_B_A);
+    let add_loop = code.len() as u16;
+    code.push(PUSH_BC);
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, layout.ram(REPL_TEMP + 30));
     code.push(LD_DE_NN);
-    emit_u16(code, REPL_TEMP);
+    emit_u16(code, layout.ram(REPL_TEMP));
     code.push(CALL_NN);
     emit_u16(code, bcd_add);
-
-    code.push(POP_BC);           // Restore counter
-
-    // Decrement BC (16-bit)
-    code.push(DEC_BC);
-    code.push(LD_A_B);
-    code.push(OR_C);
-    code.push(JR_NZ_N);
-    let back2 = (mul_loop as i16 - code.len() as i16 - 1) as i8;
-    code.push(back2 as u8);
-
-    patch_jr(code, mul_done);
-
-    code.push(POP_HL);           // Return result ptr
+    code.push(POP_BC);
+    code.push(DJNZ_N);
+    code.push((add_loop as i16 - code.len() as i16 - 1) as u8);
     code.push(RET);
 }
 
@@ -1617,7 +3141,7 @@ fn emit_bcd_mul10_routine(code: &mut Vec<u8>) {
     code.push(RET);
 }
 
-fn emit_bcd_div_routine(code: &mut Vec<u8>, bcd_sub: u16, bcd_cmp: u16, bcd_mul10: u16) {
+fn emit_bcd_div_routine(code: &mut Vec<u8>, layout: &MemoryLayout, bcd_sub: u16, bcd_cmp: u16, bcd_mul10: u16) {
     // BCD Long Division - proper arbitrary precision
     // Input: DE = divisor ptr, HL = result ptr (holds dividend copy)
     // Result: quotient in HL
@@ -1637,20 +3161,20 @@ fn emit_bcd_div_routine(code: &mut Vec<u8>, bcd_sub: u16, bcd_cmp: u16, bcd_mul1
     // - Result (HL): quotient
 
     // Save divisor pointer to a fixed location
-    emit_ld_nn_de(code, REPL_TEMP + 56);  // Save divisor ptr at REPL_TEMP+56
+    emit_ld_nn_de(code, layout.ram(REPL_TEMP + 56));  // Save divisor ptr at REPL_TEMP+56
 
     code.push(PUSH_HL);              // Save result ptr [stack: result]
 
     // Copy dividend (HL) to REPL_TEMP2
     code.push(LD_DE_NN);
-    emit_u16(code, REPL_TEMP2);
+    emit_u16(code, layout.ram(REPL_TEMP2));
     code.push(LD_BC_NN);
     emit_u16(code, 28);
     emit_ldir(code);                 // REPL_TEMP2 = dividend
 
     // Zero REPL_TEMP (remainder)
     code.push(LD_HL_NN);
-    emit_u16(code, REPL_TEMP);
+    emit_u16(code, layout.ram(REPL_TEMP));
     code.push(LD_B_N);
     code.push(28);
     code.push(XOR_A);
@@ -1663,7 +3187,7 @@ fn emit_bcd_div_routine(code: &mut Vec<u8>, bcd_sub: u16, bcd_cmp: u16, bcd_mul1
 
     // Set remainder header (sign=0, len=50, scale=0)
     code.push(LD_HL_NN);
-    emit_u16(code, REPL_TEMP);
+    emit_u16(code, layout.ram(REPL_TEMP));
     code.push(XOR_A);
     code.push(LD_HL_A);              // sign = 0
     code.push(INC_HL);
@@ -1704,20 +3228,20 @@ fn emit_bcd_div_routine(code: &mut Vec<u8>, bcd_sub: u16, bcd_cmp: u16, bcd_mul1
     // Use REPL_TEMP+58 to store current digit position (0-49)
     code.push(XOR_A);
     code.push(LD_NN_A);
-    emit_u16(code, REPL_TEMP + 58);  // digit_pos = 0
+    emit_u16(code, layout.ram(REPL_TEMP + 58));  // digit_pos = 0
 
     let digit_loop = code.len() as u16;
 
     // === Step a: remainder = remainder * 10 ===
     code.push(LD_HL_NN);
-    emit_u16(code, REPL_TEMP);
+    emit_u16(code, layout.ram(REPL_TEMP));
     code.push(CALL_NN);
     emit_u16(code, bcd_mul10);
 
     // === Step b: Get dividend digit[i] and add to remainder ===
     // Digit position i: byte = 3 + i/2, nibble = high if i even, low if i odd
     code.push(LD_A_NN_IND);
-    emit_u16(code, REPL_TEMP + 58);  // A = digit position
+    emit_u16(code, layout.ram(REPL_TEMP + 58));  // A = digit position
     code.push(LD_C_A);               // C = position (for later)
     emit_srl_a(code);                // A = position / 2
     code.push(ADD_A_N);
@@ -1726,7 +3250,7 @@ fn emit_bcd_div_routine(code: &mut Vec<u8>, bcd_sub: u16, bcd_cmp: u16, bcd_mul1
     code.push(LD_D_N);
     code.push(0);                    // DE = byte offset
     code.push(LD_HL_NN);
-    emit_u16(code, REPL_TEMP2);      // HL = dividend
+    emit_u16(code, layout.ram(REPL_TEMP2));      // HL = dividend
     code.push(ADD_HL_DE);            // HL = dividend + byte offset
     code.push(LD_A_HL);              // A = packed byte from dividend
 
@@ -1751,7 +3275,7 @@ fn emit_bcd_div_routine(code: &mut Vec<u8>, bcd_sub: u16, bcd_cmp: u16, bcd_mul1
     // Add digit to remainder at LSB (position 49 = byte 27, low nibble)
     code.push(LD_B_A);               // B = digit to add
     code.push(LD_HL_NN);
-    emit_u16(code, REPL_TEMP + 27);  // HL = remainder LSB byte
+    emit_u16(code, layout.ram(REPL_TEMP + 27));  // HL = remainder LSB byte
     code.push(LD_A_HL);
     code.push(OR_B);                 // OR in the digit (low nibble was 0 after mul10)
     code.push(LD_HL_A);
@@ -1763,9 +3287,9 @@ fn emit_bcd_div_routine(code: &mut Vec<u8>, bcd_sub: u16, bcd_cmp: u16, bcd_mul1
     let sub_loop = code.len() as u16;
 
     // Compare: is remainder >= divisor?
-    emit_ld_de_nn_ind(code, REPL_TEMP + 56);  // DE = divisor ptr
+    emit_ld_de_nn_ind(code, layout.ram(REPL_TEMP + 56));  // DE = divisor ptr
     code.push(LD_HL_NN);
-    emit_u16(code, REPL_TEMP);       // HL = remainder
+    emit_u16(code, layout.ram(REPL_TEMP));       // HL = remainder
     code.push(PUSH_BC);              // Save quotient digit
     code.push(CALL_NN);
     emit_u16(code, bcd_cmp);         // A = -1 if divisor < remainder, 0 if equal, 1 if divisor > remainder
@@ -1777,9 +3301,9 @@ fn emit_bcd_div_routine(code: &mut Vec<u8>, bcd_sub: u16, bcd_cmp: u16, bcd_mul1
     let sub_done = jr_placeholder(code, JR_Z_N);
 
     // remainder >= divisor, so subtract and increment q
-    emit_ld_de_nn_ind(code, REPL_TEMP + 56);  // DE = divisor
+    emit_ld_de_nn_ind(code, layout.ram(REPL_TEMP + 56));  // DE = divisor
     code.push(LD_HL_NN);
-    emit_u16(code, REPL_TEMP);       // HL = remainder
+    emit_u16(code, layout.ram(REPL_TEMP));       // HL = remainder
     code.push(PUSH_BC);
     code.push(CALL_NN);
     emit_u16(code, bcd_sub);         // remainder -= divisor
@@ -1808,7 +3332,7 @@ fn emit_bcd_div_routine(code: &mut Vec<u8>, bcd_sub: u16, bcd_cmp: u16, bcd_mul1
     // C = quotient digit (0-9)
     // Position i stored at REPL_TEMP+58
     code.push(LD_A_NN_IND);
-    emit_u16(code, REPL_TEMP + 58);  // A = digit position
+    emit_u16(code, layout.ram(REPL_TEMP + 58));  // A = digit position
     code.push(LD_B_A);               // B = position (for even/odd check)
     emit_srl_a(code);                // A = position / 2
     code.push(ADD_A_N);
@@ -1850,10 +3374,10 @@ fn emit_bcd_div_routine(code: &mut Vec<u8>, bcd_sub: u16, bcd_cmp: u16, bcd_mul1
 
     // === Increment digit position and loop ===
     code.push(LD_A_NN_IND);
-    emit_u16(code, REPL_TEMP + 58);
+    emit_u16(code, layout.ram(REPL_TEMP + 58));
     code.push(INC_A);
     code.push(LD_NN_A);
-    emit_u16(code, REPL_TEMP + 58);
+    emit_u16(code, layout.ram(REPL_TEMP + 58));
     code.push(CP_N);
     code.push(50);                   // Done all 50 digits?
     code.push(JP_NZ_NN);
@@ -1919,6 +3443,155 @@ fn emit_bcd_cmp_routine(code: &mut Vec<u8>) {
     code.push(RET);
 }
 
+fn emit_shift_copy_routine(code: &mut Vec<u8>, alloc_num: u16, copy_num: u16, bcd_mul10: u16) {
+    // Copy a number shifted left by B digits, adding B to its scale so the
+    // value is unchanged (digits shifted past the top are lost)
+    // Input: HL = number, B = digit count (1-255)
+    // Output: HL = new number; DE preserved
+
+    code.push(PUSH_DE);
+    code.push(PUSH_BC);
+    code.push(PUSH_HL);
+    code.push(CALL_NN);
+    emit_u16(code, alloc_num);
+    code.push(POP_DE);
+    code.push(CALL_NN);
+    emit_u16(code, copy_num);    // HL = copy of the number
+    code.push(POP_BC);
+
+    // len = 50 like other runtime results, scale += B
+    code.push(INC_HL);
+    code.push(LD_A_N);
+    code.push(50);
+    code.push(LD_HL_A);
+    code.push(INC_HL);
+    code.push(LD_A_B);
+    code.push(ADD_A_HL);
+    code.push(LD_HL_A);
+    code.push(DEC_HL);
+    code.push(DEC_HL);
+
+    let shift_loop = code.len() as u16;
+    code.push(PUSH_BC);
+    code.push(CALL_NN);
+    emit_u16(code, bcd_mul10);
+    code.push(POP_BC);
+    code.push(DJNZ_N);
+    let back = (shift_loop as i16 - code.len() as i16 - 1) as i8;
+    code.push(back as u8);
+
+    code.push(POP_DE);
+    code.push(RET);
+}
+
+fn emit_align_scales_routine(code: &mut Vec<u8>, shift_copy: u16) {
+    // Give two numbers the same scale so their digits line up
+    // Input: HL, DE = numbers
+    // Output: HL, DE = the same numbers, or a shifted copy of the one with
+    // the smaller scale
+
+    code.push(PUSH_HL);
+    code.push(INC_HL);
+    code.push(INC_HL);
+    code.push(LD_A_HL);          // A = scale(HL)
+    code.push(EX_DE_HL);
+    code.push(INC_HL);
+    code.push(INC_HL);
+    code.push(LD_C_HL);          // C = scale(DE)
+    code.push(DEC_HL);
+    code.push(DEC_HL);
+    code.push(EX_DE_HL);
+    code.push(POP_HL);
+    code.push(CP_C);
+    code.push(RET_Z);
+    let hl_smaller = jr_placeholder(code, JR_C_N);
+
+    // DE has the smaller scale: shift it by A - C digits
+    code.push(SUB_C);
+    code.push(LD_B_A);
+    code.push(EX_DE_HL);
+    code.push(CALL_NN);
+    emit_u16(code, shift_copy);
+    code.push(EX_DE_HL);
+    code.push(RET);
+
+    // HL has the smaller scale: shift it by C - A digits
+    patch_jr(code, hl_smaller);
+    code.push(LD_B_A);
+    code.push(LD_A_C);
+    code.push(SUB_B);
+    code.push(LD_B_A);
+    code.push(JP_NN);
+    emit_u16(code, shift_copy);
+}
+
+fn emit_bcd_cmp_signed_routine(code: &mut Vec<u8>, align_scales: u16, bcd_cmp: u16) {
+    // Compare two signed BCD numbers; bcd_cmp only compares magnitudes
+    // Input: DE = first, HL = second
+    // Output: A = -1 if DE < HL, 0 if equal, 1 if DE > HL
+
+    code.push(CALL_NN);
+    emit_u16(code, align_scales);
+
+    code.push(LD_A_DE);
+    code.push(XOR_HL);
+    code.push(AND_N);
+    code.push(0x80);
+    let signs_differ = jr_placeholder(code, JR_NZ_N);
+
+    // Same sign: positive numbers compare by magnitude
+    code.push(LD_A_DE);
+    code.push(AND_N);
+    code.push(0x80);
+    code.push(JP_Z_NN);
+    emit_u16(code, bcd_cmp);
+
+    // Both negative: the larger magnitude is the smaller number
+    code.push(CALL_NN);
+    emit_u16(code, bcd_cmp);
+    code.push(ED_PREFIX);
+    code.push(NEG);
+    code.push(RET);
+
+    // Signs differ: equal only if both are zero (-0 and 0)
+    patch_jr(code, signs_differ);
+    code.push(CALL_NN);
+    emit_u16(code, bcd_cmp);
+    code.push(OR_A);
+    let by_sign = jr_placeholder(code, JR_NZ_N);
+    code.push(PUSH_DE);
+    code.push(PUSH_HL);
+    code.push(LD_H_D);
+    code.push(LD_L_E);
+    code.push(INC_HL);
+    code.push(INC_HL);
+    code.push(INC_HL);
+    code.push(LD_B_N);
+    code.push(25);
+    let zero_loop = code.len() as u16;
+    code.push(OR_HL);
+    code.push(INC_HL);
+    code.push(DJNZ_N);
+    let offset = (zero_loop as i16 - code.len() as i16 - 1) as i8;
+    code.push(offset as u8);
+    code.push(POP_HL);
+    code.push(POP_DE);
+    code.push(OR_A);
+    code.push(RET_Z);
+
+    // Otherwise the negative one is smaller
+    patch_jr(code, by_sign);
+    code.push(LD_A_DE);
+    code.push(AND_N);
+    code.push(0x80);
+    code.push(LD_A_N);
+    code.push(1);
+    code.push(RET_Z);
+    code.push(LD_A_N);
+    code.push(0xFF);
+    code.push(RET);
+}
+
 fn emit_bcd_neg_routine(code: &mut Vec<u8>) {
     // Negate a BCD number (flip sign bit)
     // Input: HL = pointer to number
@@ -1930,13 +3603,48 @@ fn emit_bcd_neg_routine(code: &mut Vec<u8>) {
     code.push(RET);
 }
 
-fn emit_push_vstack(code: &mut Vec<u8>) {
+fn emit_is_zero_routine(code: &mut Vec<u8>) {
+    // Test whether a BCD number is zero (all digit bytes clear)
+    // Input: HL = pointer to number
+    // Output: Z flag set if zero
+    const FIXED_PACKED_BYTES: u8 = 25;
+
+    code.push(INC_HL);
+    code.push(INC_HL);
+    code.push(INC_HL);  // Skip sign, len, scale
+    code.push(LD_B_N);
+    code.push(FIXED_PACKED_BYTES);
+    code.push(XOR_A);
+    let loop_start = code.len() as u16;
+    code.push(OR_HL);
+    code.push(INC_HL);
+    code.push(DJNZ_N);
+    let offset = (loop_start as i16 - code.len() as i16 - 1) as i8;
+    code.push(offset as u8);
+    code.push(OR_A);
+    code.push(RET);
+}
+
+fn emit_push_vstack(code: &mut Vec<u8>, layout: &MemoryLayout, vstack_full: u16) {
     // Push HL onto value stack
     code.push(PUSH_DE);
     code.push(EX_DE_HL);  // DE = value to push
 
     code.push(LD_HL_NN_IND);
-    emit_u16(code, VM_SP);
+    emit_u16(code, layout.ram(VM_SP));
+
+    // Stop before writing past the last entry
+    let end = layout.vstack_end();
+    code.push(LD_A_L);
+    code.push(CP_N);
+    code.push((end & 0xFF) as u8);
+    let room = jr_placeholder(code, JR_NZ_N);
+    code.push(LD_A_H);
+    code.push(CP_N);
+    code.push((end >> 8) as u8);
+    code.push(JP_Z_NN);
+    emit_u16(code, vstack_full);
+    patch_jr(code, room);
 
     code.push(LD_A_E);
     code.push(LD_HL_A);
@@ -1946,16 +3654,16 @@ fn emit_push_vstack(code: &mut Vec<u8>) {
     code.push(INC_HL);
 
     code.push(LD_NN_HL);
-    emit_u16(code, VM_SP);
+    emit_u16(code, layout.ram(VM_SP));
 
     code.push(POP_DE);
     code.push(RET);
 }
 
-fn emit_pop_vstack(code: &mut Vec<u8>) {
+fn emit_pop_vstack(code: &mut Vec<u8>, layout: &MemoryLayout) {
     // Pop from value stack into HL
     code.push(LD_HL_NN_IND);
-    emit_u16(code, VM_SP);
+    emit_u16(code, layout.ram(VM_SP));
 
     code.push(DEC_HL);
     code.push(LD_D_HL);
@@ -1963,30 +3671,291 @@ fn emit_pop_vstack(code: &mut Vec<u8>) {
     code.push(LD_E_HL);
 
     code.push(LD_NN_HL);
-    emit_u16(code, VM_SP);
+    emit_u16(code, layout.ram(VM_SP));
 
     code.push(EX_DE_HL);  // HL = popped value
     code.push(RET);
 }
 
-fn emit_load_num_handler(code: &mut Vec<u8>, module: &CompiledModule, push_vstack: u16, vm_loop: u16) {
-    // Read 16-bit index from bytecode
-    code.push(LD_HL_NN_IND);
-    emit_u16(code, VM_PC);
+/// A = next bytecode byte. The VM PC is kept in HL' for the whole run
+/// (nothing else uses the shadow registers), so this avoids the memory
+/// round trip of a PC variable.
+fn emit_fetch_operand_byte(code: &mut Vec<u8>) {
+    code.push(EXX);
+    code.push(LD_A_HL);
+    code.push(INC_HL);
+    code.push(EXX);
+}
+
+/// DE = next two bytecode bytes (little endian)
+fn emit_fetch_operand_word(code: &mut Vec<u8>) {
+    code.push(EXX);
     code.push(LD_E_HL);
     code.push(INC_HL);
     code.push(LD_D_HL);
     code.push(INC_HL);
-    code.push(LD_NN_HL);
-    emit_u16(code, VM_PC);
+    code.push(PUSH_DE);
+    code.push(EXX);
+    code.push(POP_DE);
+}
 
-    // DE = index, calculate address in constant table
-    // Constants start after bytecode at BYTECODE_ORG + bytecode.len()
-    // Each constant is padded to MAX_NUM_SIZE (53) bytes
-    let nums_base = BYTECODE_ORG + module.bytecode.len() as u16;
+/// HL = VM PC
+fn emit_get_pc(code: &mut Vec<u8>) {
+    code.push(EXX);
+    code.push(PUSH_HL);
+    code.push(EXX);
+    code.push(POP_HL);
+}
 
-    // Multiply index by MAX_NUM_SIZE (53 = 32 + 16 + 4 + 1)
-    // Use shifts and adds: index * 53 = index * 64 - index * 8 - index * 2 - index
+/// HL = top entry of the value stack, left in place (DE is clobbered)
+fn emit_peek_vstack(code: &mut Vec<u8>, layout: &MemoryLayout) {
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, layout.ram(VM_SP));
+    code.push(DEC_HL);
+    code.push(LD_D_HL);
+    code.push(DEC_HL);
+    code.push(LD_E_HL);
+    code.push(EX_DE_HL);
+    code.push(RET);
+}
+
+/// Overwrite the top entry of the value stack with HL. Cheaper than a pop
+/// and push: VM_SP is not written and the stack cannot overflow.
+fn emit_set_vstack_top(code: &mut Vec<u8>, layout: &MemoryLayout) {
+    code.push(PUSH_DE);
+    code.push(EX_DE_HL);
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, layout.ram(VM_SP));
+    code.push(DEC_HL);
+    code.push(LD_HL_D);
+    code.push(DEC_HL);
+    code.push(LD_HL_E);
+    code.push(EX_DE_HL);
+    code.push(POP_DE);
+    code.push(RET);
+}
+
+/// HL += A (A is clobbered)
+fn emit_add_hl_a(code: &mut Vec<u8>) {
+    code.push(ADD_A_L);
+    code.push(LD_L_A);
+    code.push(LD_A_H);
+    code.push(ADC_A_N);
+    code.push(0);
+    code.push(LD_H_A);
+}
+
+/// Call a function. The frame lives on the Z80 stack: the caller's values
+/// of the callee's parameter and auto slots, then the slot address and
+/// count, then the heap pointer at the call, then the return VM PC on top.
+/// Arguments are popped into the parameter slots and autos start at zero,
+/// which for an auto array means its first element access allocates
+/// storage for this call alone; Return frees it with the rest of the
+/// call's heap. The function table (bytecode address, slot address,
+/// parameter count, slot count) follows the code.
+fn emit_call_handler(
+    code: &mut Vec<u8>,
+    layout: &MemoryLayout,
+    module: &CompiledModule,
+    pop_vstack: u16,
+    vm_loop: u16,
+) {
+    let call = layout.ram(VM_CALL);
+    let (call_slots, call_params, call_count) = (call + 2, call + 4, call + 5);
+
+    // A = function index; the VM PC is left at the return address
+    emit_fetch_operand_byte(code);
+
+    // Copy the 6-byte table entry to VM_CALL
+    code.push(LD_L_A);
+    code.push(LD_H_N);
+    code.push(0);
+    code.push(ADD_HL_HL);
+    code.push(LD_D_H);
+    code.push(LD_E_L);
+    code.push(ADD_HL_HL);
+    code.push(ADD_HL_DE);
+    code.push(LD_DE_NN);
+    let table_patch = code.len();
+    emit_u16(code, 0);
+    code.push(ADD_HL_DE);
+    code.push(LD_DE_NN);
+    emit_u16(code, call);
+    code.push(LD_BC_NN);
+    emit_u16(code, 6);
+    code.push(ED_PREFIX);
+    code.push(LDIR_OP);
+
+    // Save the caller's slot values
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, call_slots);
+    code.push(LD_A_NN_IND);
+    emit_u16(code, call_count);
+    code.push(OR_A);
+    let no_slots = jr_placeholder(code, JR_Z_N);
+    code.push(LD_B_A);
+    let save_loop = code.len();
+    code.push(LD_E_HL);
+    code.push(INC_HL);
+    code.push(LD_D_HL);
+    code.push(INC_HL);
+    code.push(PUSH_DE);
+    code.push(DJNZ_N);
+    code.push((save_loop as i16 - code.len() as i16 - 1) as u8);
+    patch_jr(code, no_slots);
+
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, call_slots);
+    code.push(PUSH_HL);
+    code.push(LD_A_NN_IND);
+    emit_u16(code, call_count);
+    code.push(PUSH_AF);
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, layout.ram(VM_HEAP));
+    code.push(PUSH_HL);
+    emit_get_pc(code);
+    code.push(PUSH_HL);
+
+    // Pop arguments into the parameter slots, last one first
+    code.push(LD_A_NN_IND);
+    emit_u16(code, call_params);
+    code.push(OR_A);
+    let no_args = jr_placeholder(code, JR_Z_N);
+    code.push(LD_B_A);
+    let arg_loop = code.len();
+    code.push(CALL_NN);
+    emit_u16(code, pop_vstack);
+    code.push(EX_DE_HL);
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, call_slots);
+    code.push(LD_A_B);
+    code.push(DEC_A);
+    code.push(ADD_A_A);
+    emit_add_hl_a(code);
+    code.push(LD_HL_E);
+    code.push(INC_HL);
+    code.push(LD_HL_D);
+    code.push(DJNZ_N);
+    code.push((arg_loop as i16 - code.len() as i16 - 1) as u8);
+    patch_jr(code, no_args);
+
+    // Autos start at zero
+    code.push(LD_HL_NN);
+    emit_u16(code, call_params);
+    code.push(LD_A_NN_IND);
+    emit_u16(code, call_count);
+    code.push(SUB_HL);
+    let no_autos = jr_placeholder(code, JR_Z_N);
+    code.push(LD_B_A);
+    code.push(LD_A_HL);
+    code.push(ADD_A_A);
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, call_slots);
+    emit_add_hl_a(code);
+    code.push(LD_DE_NN);
+    emit_u16(code, layout.ram(CONST_ZERO));
+    let auto_loop = code.len();
+    code.push(LD_HL_E);
+    code.push(INC_HL);
+    code.push(LD_HL_D);
+    code.push(INC_HL);
+    code.push(DJNZ_N);
+    code.push((auto_loop as i16 - code.len() as i16 - 1) as u8);
+    patch_jr(code, no_autos);
+
+    code.push(EXX);
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, call);
+    code.push(EXX);
+    code.push(JP_NN);
+    emit_u16(code, vm_loop);
+
+    let table = code.len() as u16;
+    code[table_patch] = (table & 0xFF) as u8;
+    code[table_patch + 1] = (table >> 8) as u8;
+    for func in &module.functions {
+        emit_u16(code, BYTECODE_ORG + func.bytecode_offset as u16);
+        emit_u16(code, layout.ram(VARS_BASE) + 2 * func.first_slot as u16);
+        code.push(func.param_count as u8);
+        code.push((func.param_count + func.local_count) as u8);
+    }
+}
+
+/// Unwind the frame built by Call and push the return value (HL). The
+/// heap goes back to where it was at the call, or to VM_PINNED if a global
+/// took a number allocated since; a return value above that point is
+/// moved down to the new top first.
+fn emit_return_handler(code: &mut Vec<u8>, layout: &MemoryLayout, push_vstack: u16, vm_loop: u16) {
+    // The table entry copy is no longer needed; keep the value there
+    code.push(LD_NN_HL);
+    emit_u16(code, layout.ram(VM_CALL));
+
+    code.push(EXX);
+    code.push(POP_HL);
+    code.push(EXX);
+
+    // DE = max(heap at the call, VM_PINNED)
+    code.push(POP_DE);
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, layout.ram(VM_PINNED));
+    code.push(OR_A);
+    emit_sbc_hl_de(code);
+    let below_mark = jr_placeholder(code, JR_C_N);
+    code.push(ADD_HL_DE);
+    code.push(EX_DE_HL);
+    patch_jr(code, below_mark);
+
+    // Move the return value down if it lies in the freed part
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, layout.ram(VM_CALL));
+    code.push(OR_A);
+    emit_sbc_hl_de(code);
+    let kept = jr_placeholder(code, JR_C_N);
+    code.push(ADD_HL_DE);
+    emit_ld_nn_de(code, layout.ram(VM_CALL));
+    code.push(LD_BC_NN);
+    emit_u16(code, MAX_NUM_SIZE as u16);
+    emit_ldir(code);
+    patch_jr(code, kept);
+    emit_ld_nn_de(code, layout.ram(VM_HEAP));
+    code.push(POP_AF);
+    code.push(POP_HL);
+
+    // Restore the caller's slot values, last slot first
+    code.push(OR_A);
+    let no_slots = jr_placeholder(code, JR_Z_N);
+    code.push(LD_B_A);
+    code.push(ADD_A_A);
+    emit_add_hl_a(code);
+    let restore_loop = code.len();
+    code.push(POP_DE);
+    code.push(DEC_HL);
+    code.push(LD_HL_D);
+    code.push(DEC_HL);
+    code.push(LD_HL_E);
+    code.push(DJNZ_N);
+    code.push((restore_loop as i16 - code.len() as i16 - 1) as u8);
+    patch_jr(code, no_slots);
+
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, layout.ram(VM_CALL));
+    code.push(CALL_NN);
+    emit_u16(code, push_vstack);
+    code.push(JP_NN);
+    emit_u16(code, vm_loop);
+}
+
+fn emit_load_num_handler(code: &mut Vec<u8>, module: &CompiledModule, push_vstack: u16, vm_loop: u16) {
+    // Read 16-bit index from bytecode
+    emit_fetch_operand_word(code);
+
+    // DE = index, calculate address in constant table
+    // Constants start after bytecode at BYTECODE_ORG + bytecode.len()
+    // Each constant is padded to MAX_NUM_SIZE (53) bytes
+    let nums_base = BYTECODE_ORG + module.bytecode.len() as u16;
+
+    // Multiply index by MAX_NUM_SIZE (53 = 32 + 16 + 4 + 1)
+    // Use shifts and adds: index * 53 = index * 64 - index * 8 - index * 2 - index
     // Or simpler: just add MAX_NUM_SIZE times (slow but works for small indices)
     // For efficiency, we'll use: index * 53 = index * 48 + index * 5 = index * (32+16) + index * (4+1)
 
@@ -2043,14 +4012,127 @@ fn emit_load_num_handler(code: &mut Vec<u8>, module: &CompiledModule, push_vstac
     emit_u16(code, vm_loop);
 }
 
-fn emit_load_var_handler(code: &mut Vec<u8>, push_vstack: u16, vm_loop: u16) {
-    // Read variable index from bytecode
-    code.push(LD_HL_NN_IND);
-    emit_u16(code, VM_PC);
+/// Print each value on the stack, bottom first, after a space, then a
+/// newline, and signal TRAP_PORT with DE = the source line operand
+fn emit_trap_handler(
+    code: &mut Vec<u8>,
+    layout: &MemoryLayout,
+    acia_out: u16,
+    print_num: u16,
+    print_newline: u16,
+    vm_loop: u16,
+) {
+    emit_fetch_operand_word(code);
+    code.push(PUSH_DE);
+    code.push(LD_HL_NN);
+    emit_u16(code, layout.vstack_base());
+
+    // Stop when HL reaches the stack pointer. ADD HL,DE keeps the Z flag
+    // from the subtraction.
+    let next = code.len();
+    emit_ld_de_nn_ind(code, layout.ram(VM_SP));
+    code.push(OR_A);
+    emit_sbc_hl_de(code);
+    code.push(ADD_HL_DE);
+    let done = jr_placeholder(code, JR_Z_N);
+    code.push(LD_A_N);
+    code.push(b' ');
+    code.push(CALL_NN);
+    emit_u16(code, acia_out);
+    code.push(LD_E_HL);
+    code.push(INC_HL);
+    code.push(LD_D_HL);
+    code.push(INC_HL);
+    code.push(PUSH_HL);
+    code.push(EX_DE_HL);
+    code.push(CALL_NN);
+    emit_u16(code, print_num);
+    code.push(POP_HL);
+    code.push(JR_N);
+    let back = (next as i16 - code.len() as i16 - 1) as i8;
+    code.push(back as u8);
+    patch_jr(code, done);
+
+    code.push(CALL_NN);
+    emit_u16(code, print_newline);
+    code.push(POP_DE);
+    code.push(OUT_N_A);
+    code.push(TRAP_PORT);
+    code.push(JP_NN);
+    emit_u16(code, vm_loop);
+}
+
+fn emit_print_str_handler(
+    code: &mut Vec<u8>,
+    module: &CompiledModule,
+    acia_out: u16,
+    newline: Newline,
+    vm_loop: u16,
+) {
+    // Read 16-bit string index from bytecode
+    emit_fetch_operand_word(code);
+
+    // Strings follow the constant table, each prefixed with its length
+    let strs_base = BYTECODE_ORG
+        + module.bytecode.len() as u16
+        + module.numbers.len() as u16 * MAX_NUM_SIZE as u16;
+    code.push(LD_HL_NN);
+    emit_u16(code, strs_base);
+
+    // Skip DE strings
+    let skip_loop = code.len() as u16;
+    code.push(LD_A_D);
+    code.push(OR_E);
+    let found = jr_placeholder(code, JR_Z_N);
+    code.push(LD_C_HL);
+    code.push(LD_B_N);
+    code.push(0);
+    code.push(ADD_HL_BC);
+    code.push(INC_HL);      // HL += length + 1
+    code.push(DEC_DE);
+    code.push(JR_N);
+    let back = (skip_loop as i16 - code.len() as i16 - 1) as i8;
+    code.push(back as u8);
+    patch_jr(code, found);
+
+    // B = length, HL = first character
+    code.push(LD_B_HL);
+    code.push(INC_HL);
+    code.push(LD_A_B);
+    code.push(OR_A);
+    let empty = jr_placeholder(code, JR_Z_N);
+
+    // Print each character, turning \n into the newline PrintNewline writes
+    let (last, rest) = newline.bytes().split_last().unwrap();
+    let char_loop = code.len() as u16;
     code.push(LD_A_HL);
+    code.push(CP_N);
+    code.push(b'\n');
+    let not_lf = jr_placeholder(code, JR_NZ_N);
+    for &byte in rest {
+        code.push(LD_A_N);
+        code.push(byte);
+        code.push(CALL_NN);
+        emit_u16(code, acia_out);
+    }
+    code.push(LD_A_N);
+    code.push(*last);
+    patch_jr(code, not_lf);
+    code.push(CALL_NN);
+    emit_u16(code, acia_out);
     code.push(INC_HL);
-    code.push(LD_NN_HL);
-    emit_u16(code, VM_PC);
+    code.push(DJNZ_N);
+    let back = (char_loop as i16 - code.len() as i16 - 1) as i8;
+    code.push(back as u8);
+    patch_jr(code, empty);
+
+    code.push(JP_NN);
+    emit_u16(code, vm_loop);
+}
+
+fn emit_load_var_handler(code: &mut Vec<u8>, layout: &MemoryLayout, push_vstack: u16, vm_loop: u16) {
+    // Read variable index from bytecode
+    emit_fetch_operand_byte(code);
 
     // A = var index, get pointer from VARS_BASE + index * 2
     code.push(LD_L_A);
@@ -2058,7 +4140,7 @@ fn emit_load_var_handler(code: &mut Vec<u8>, push_vstack: u16, vm_loop: u16) {
     code.push(0);
     code.push(ADD_HL_HL);  // HL = index * 2
     code.push(LD_DE_NN);
-    emit_u16(code, VARS_BASE);
+    emit_u16(code, layout.ram(VARS_BASE));
     code.push(ADD_HL_DE);
 
     // HL points to variable slot, load pointer
@@ -2072,7 +4154,7 @@ fn emit_load_var_handler(code: &mut Vec<u8>, push_vstack: u16, vm_loop: u16) {
     code.push(OR_L);
     let not_zero = jr_placeholder(code, JR_NZ_N);
     code.push(LD_HL_NN);
-    emit_u16(code, CONST_ZERO);
+    emit_u16(code, layout.ram(CONST_ZERO));
     patch_jr(code, not_zero);
 
     code.push(CALL_NN);
@@ -2082,19 +4164,43 @@ fn emit_load_var_handler(code: &mut Vec<u8>, push_vstack: u16, vm_loop: u16) {
     emit_u16(code, vm_loop);
 }
 
-fn emit_store_var_handler(code: &mut Vec<u8>, pop_vstack: u16, vm_loop: u16) {
+/// With functions in the program, set VM_PINNED to the heap pointer plus
+/// `extra` if slot A is a global, which keeps Return from freeing what the
+/// global may now point to. Clobbers HL and DE.
+fn emit_pin_global(code: &mut Vec<u8>, layout: &MemoryLayout, frame_base: Option<u8>, extra: u16) {
+    let Some(frame_base) = frame_base else {
+        return;
+    };
+    code.push(CP_N);
+    code.push(frame_base);
+    let local = jr_placeholder(code, JR_NC_N);
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, layout.ram(VM_HEAP));
+    if extra != 0 {
+        code.push(LD_DE_NN);
+        emit_u16(code, extra);
+        code.push(ADD_HL_DE);
+    }
+    code.push(LD_NN_HL);
+    emit_u16(code, layout.ram(VM_PINNED));
+    patch_jr(code, local);
+}
+
+fn emit_store_var_handler(
+    code: &mut Vec<u8>,
+    layout: &MemoryLayout,
+    frame_base: Option<u8>,
+    pop_vstack: u16,
+    vm_loop: u16,
+) {
     // Pop value
     code.push(CALL_NN);
     emit_u16(code, pop_vstack);
     code.push(PUSH_HL);  // Save value pointer
 
     // Read variable index
-    code.push(LD_HL_NN_IND);
-    emit_u16(code, VM_PC);
-    code.push(LD_A_HL);
-    code.push(INC_HL);
-    code.push(LD_NN_HL);
-    emit_u16(code, VM_PC);
+    emit_fetch_operand_byte(code);
+    emit_pin_global(code, layout, frame_base, 0);
 
     // Calculate var slot address
     code.push(LD_L_A);
@@ -2102,7 +4208,7 @@ fn emit_store_var_handler(code: &mut Vec<u8>, pop_vstack: u16, vm_loop: u16) {
     code.push(0);
     code.push(ADD_HL_HL);
     code.push(LD_DE_NN);
-    emit_u16(code, VARS_BASE);
+    emit_u16(code, layout.ram(VARS_BASE));
     code.push(ADD_HL_DE);
 
     // Store pointer
@@ -2117,26 +4223,216 @@ fn emit_store_var_handler(code: &mut Vec<u8>, pop_vstack: u16, vm_loop: u16) {
     emit_u16(code, vm_loop);
 }
 
-fn emit_binary_op_handler(
+#[allow(clippy::too_many_arguments)]
+fn emit_array_elem(
     code: &mut Vec<u8>,
-    pop_vstack: u16,
-    push_vstack: u16,
-    op_routine: u16,
-    alloc_num: u16,
-    vm_loop: u16,
+    layout: &MemoryLayout,
+    heap_end: u16,
+    frame_base: Option<u8>,
+    num_to_index: u16,
+    bad_index: u16,
+    out_of_memory: u16,
 ) {
-    // Pop two operands (last pushed = first popped)
-    // For "a + b", bytecode pushes a then b, so we pop b first, then a
-    code.push(CALL_NN);
-    emit_u16(code, pop_vstack);
-    code.push(PUSH_HL);  // Stack: [second operand (b)]
-
-    code.push(CALL_NN);
-    emit_u16(code, pop_vstack);
-    code.push(PUSH_HL);  // Stack: [first operand (a), second operand (b)]
+    // Find an array element, giving the array its storage if it has none
+    // Input: A = array slot, HL = index number (jumps to bad_index unless
+    // its integer part is 0 to ARRAY_SIZE - 1, and to out_of_memory if the
+    // storage does not fit below heap_end)
+    // Output: HL = address of the element's number pointer (0 if unset)
+    code.push(PUSH_HL);              // [index]
+    code.push(LD_L_A);
+    code.push(LD_H_N);
+    code.push(0);
+    code.push(ADD_HL_HL);
+    code.push(LD_DE_NN);
+    emit_u16(code, layout.ram(VARS_BASE));
+    code.push(ADD_HL_DE);            // HL = slot
+    code.push(LD_E_HL);
+    code.push(INC_HL);
+    code.push(LD_D_HL);
+    code.push(PUSH_HL);              // [slot + 1, index]
+    code.push(EX_DE_HL);             // HL = array block
+    code.push(LD_DE_NN);
+    emit_u16(code, layout.heap_start());
+    code.push(OR_A);
+    emit_sbc_hl_de(code);
+    code.push(ADD_HL_DE);
+    let allocated = jr_placeholder(code, JR_NC_N);
 
-    // Allocate result number on heap
-    code.push(CALL_NN);
+    // Allocate a zeroed block and point the slot at it
+    emit_pin_global(code, layout, frame_base, 2 * ARRAY_SIZE);
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, layout.ram(VM_HEAP));
+    code.push(PUSH_HL);              // [block, slot + 1, index]
+    code.push(LD_DE_NN);
+    emit_u16(code, 2 * ARRAY_SIZE);
+    code.push(ADD_HL_DE);
+    code.push(LD_DE_NN);
+    emit_u16(code, heap_end + 1);
+    code.push(OR_A);
+    emit_sbc_hl_de(code);
+    code.push(JP_NC_NN);
+    emit_u16(code, out_of_memory);
+    code.push(POP_HL);
+    code.push(PUSH_HL);
+    code.push(LD_D_H);
+    code.push(LD_E_L);
+    code.push(INC_DE);
+    code.push(XOR_A);
+    code.push(LD_HL_A);
+    code.push(LD_BC_NN);
+    emit_u16(code, 2 * ARRAY_SIZE - 1);
+    emit_ldir(code);                 // DE = end of block
+    emit_ld_nn_de(code, layout.ram(VM_HEAP));
+    code.push(POP_DE);               // DE = block
+    code.push(POP_HL);               // HL = slot + 1
+    code.push(LD_HL_D);
+    code.push(DEC_HL);
+    code.push(LD_HL_E);
+    code.push(PUSH_HL);              // Keep the stack shape
+    code.push(EX_DE_HL);             // HL = block
+    patch_jr(code, allocated);
+
+    code.push(POP_DE);               // [index]
+    code.push(EX_SP_HL);             // HL = index, [block]
+    code.push(CALL_NN);
+    emit_u16(code, num_to_index);
+    code.push(JP_C_NN);
+    emit_u16(code, bad_index);
+    code.push(POP_HL);               // HL = block
+    code.push(LD_E_A);
+    code.push(LD_D_N);
+    code.push(0);
+    code.push(ADD_HL_DE);
+    code.push(ADD_HL_DE);
+    code.push(RET);
+}
+
+fn emit_load_array_handler(
+    code: &mut Vec<u8>,
+    layout: &MemoryLayout,
+    pop_vstack: u16,
+    push_vstack: u16,
+    array_elem: u16,
+    vm_loop: u16,
+) {
+    // Pop the index, then read the array slot from bytecode
+    code.push(CALL_NN);
+    emit_u16(code, pop_vstack);
+    emit_fetch_operand_byte(code);
+    code.push(CALL_NN);
+    emit_u16(code, array_elem);
+
+    // Load the element's pointer; an unset element is zero
+    code.push(LD_E_HL);
+    code.push(INC_HL);
+    code.push(LD_D_HL);
+    code.push(EX_DE_HL);
+    code.push(LD_A_H);
+    code.push(OR_L);
+    let not_zero = jr_placeholder(code, JR_NZ_N);
+    code.push(LD_HL_NN);
+    emit_u16(code, layout.ram(CONST_ZERO));
+    patch_jr(code, not_zero);
+
+    code.push(CALL_NN);
+    emit_u16(code, push_vstack);
+    code.push(JP_NN);
+    emit_u16(code, vm_loop);
+}
+
+fn emit_store_array_handler(
+    code: &mut Vec<u8>,
+    layout: &MemoryLayout,
+    frame_base: Option<u8>,
+    pop_vstack: u16,
+    array_elem: u16,
+    vm_loop: u16,
+) {
+    // Pop the index (pushed after the value), then read the array slot
+    code.push(CALL_NN);
+    emit_u16(code, pop_vstack);
+    emit_fetch_operand_byte(code);
+    code.push(PUSH_HL);
+    emit_pin_global(code, layout, frame_base, 0);
+    code.push(POP_HL);
+    code.push(CALL_NN);
+    emit_u16(code, array_elem);
+    code.push(PUSH_HL);  // Save element address
+
+    // Store the value's pointer
+    code.push(CALL_NN);
+    emit_u16(code, pop_vstack);
+    code.push(EX_DE_HL);
+    code.push(POP_HL);
+    code.push(LD_HL_E);
+    code.push(INC_HL);
+    code.push(LD_HL_D);
+
+    code.push(JP_NN);
+    emit_u16(code, vm_loop);
+}
+
+// VM_TEMP = sign of the operand at HL (first operand of a mul/div)
+fn emit_record_sign(code: &mut Vec<u8>, layout: &MemoryLayout) {
+    code.push(LD_A_HL);
+    code.push(AND_N);
+    code.push(0x80);
+    code.push(LD_NN_A);
+    emit_u16(code, layout.ram(VM_TEMP));
+}
+
+// VM_TEMP ^= sign of the operand at HL (second operand of a mul/div)
+fn emit_combine_sign(code: &mut Vec<u8>, layout: &MemoryLayout) {
+    code.push(LD_A_NN_IND);
+    emit_u16(code, layout.ram(VM_TEMP));
+    code.push(XOR_HL);
+    code.push(AND_N);
+    code.push(0x80);
+    code.push(LD_NN_A);
+    emit_u16(code, layout.ram(VM_TEMP));
+}
+
+// Give the result at HL the sign in VM_TEMP, or + if it is zero
+fn emit_apply_sign(code: &mut Vec<u8>, layout: &MemoryLayout, is_zero: u16) {
+    code.push(PUSH_HL);
+    code.push(CALL_NN);
+    emit_u16(code, is_zero);
+    code.push(POP_HL);
+    code.push(LD_A_NN_IND);
+    emit_u16(code, layout.ram(VM_TEMP));
+    code.push(JR_NZ_N);
+    code.push(1);
+    code.push(XOR_A);
+    code.push(LD_HL_A);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn emit_binary_op_handler(
+    code: &mut Vec<u8>,
+    layout: &MemoryLayout,
+    pop_vstack: u16,
+    peek_vstack: u16,
+    set_vstack_top: u16,
+    op_routine: u16,
+    alloc_num: u16,
+    is_zero: u16,
+    vm_loop: u16,
+) {
+    // Magnitude operation (multiply); the sign is applied afterwards
+    // Pop two operands (last pushed = first popped)
+    // For "a * b", bytecode pushes a then b, so we pop b first, then a
+    code.push(CALL_NN);
+    emit_u16(code, pop_vstack);
+    emit_record_sign(code, layout);
+    code.push(PUSH_HL);  // Stack: [second operand (b)]
+
+    code.push(CALL_NN);
+    emit_u16(code, peek_vstack);
+    emit_combine_sign(code, layout);
+    code.push(PUSH_HL);  // Stack: [first operand (a), second operand (b)]
+
+    // Allocate result number on heap
+    code.push(CALL_NN);
     emit_u16(code, alloc_num);
     // HL = result pointer
     code.push(PUSH_HL);  // Stack: [result, first, second]
@@ -2173,23 +4469,28 @@ fn emit_binary_op_handler(
     // Clean up stack and push result
     code.push(POP_DE);   // Discard second operand
     code.push(POP_HL);   // HL = result
+    emit_apply_sign(code, layout, is_zero);
 
     // Push result onto value stack
     code.push(CALL_NN);
-    emit_u16(code, push_vstack);
+    emit_u16(code, set_vstack_top);
 
     code.push(JP_NN);
     emit_u16(code, vm_loop);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn emit_sub_op_handler(
     code: &mut Vec<u8>,
     pop_vstack: u16,
-    push_vstack: u16,
+    peek_vstack: u16,
+    set_vstack_top: u16,
     bcd_add: u16,
     bcd_sub: u16,
     bcd_cmp: u16,
     alloc_num: u16,
+    align_scales: u16,
+    overflow: u16,
     vm_loop: u16,
 ) {
     // Signed subtraction: a - b
@@ -2208,7 +4509,13 @@ fn emit_sub_op_handler(
 
     // Pop a
     code.push(CALL_NN);
-    emit_u16(code, pop_vstack);
+    emit_u16(code, peek_vstack);
+
+    // Line up the decimal points
+    code.push(POP_DE);
+    code.push(CALL_NN);
+    emit_u16(code, align_scales);
+    code.push(PUSH_DE);
     code.push(PUSH_HL);  // Z80 stack: [a, b]
 
     // Get sign of a into D, sign of b into E
@@ -2268,6 +4575,8 @@ fn emit_sub_op_handler(
     code.push(PUSH_HL);  // Keep result
     code.push(CALL_NN);
     emit_u16(code, bcd_add);  // result = a + b
+    code.push(JP_C_NN);
+    emit_u16(code, overflow);
 
     // Restore b's sign
     code.push(POP_HL);   // HL = result
@@ -2282,7 +4591,7 @@ fn emit_sub_op_handler(
     // Push result to value stack
     code.push(POP_HL);   // HL = result
     code.push(CALL_NN);
-    emit_u16(code, push_vstack);
+    emit_u16(code, set_vstack_top);
     code.push(JP_NN);
     emit_u16(code, vm_loop);
 
@@ -2356,7 +4665,7 @@ fn emit_sub_op_handler(
     code.push(LD_HL_A);  // Store flipped sign
 
     code.push(CALL_NN);
-    emit_u16(code, push_vstack);
+    emit_u16(code, set_vstack_top);
     code.push(JP_NN);
     emit_u16(code, vm_loop);
 
@@ -2398,19 +4707,23 @@ fn emit_sub_op_handler(
     // Sign is already correct (copied from a)
     code.push(POP_HL);   // HL = result
     code.push(CALL_NN);
-    emit_u16(code, push_vstack);
+    emit_u16(code, set_vstack_top);
     code.push(JP_NN);
     emit_u16(code, vm_loop);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn emit_add_op_handler(
     code: &mut Vec<u8>,
     pop_vstack: u16,
-    push_vstack: u16,
+    peek_vstack: u16,
+    set_vstack_top: u16,
     bcd_add: u16,
     bcd_sub: u16,
     bcd_cmp: u16,
     alloc_num: u16,
+    align_scales: u16,
+    overflow: u16,
     vm_loop: u16,
 ) {
     // Signed addition: a + b
@@ -2427,7 +4740,13 @@ fn emit_add_op_handler(
 
     // Pop a
     code.push(CALL_NN);
-    emit_u16(code, pop_vstack);
+    emit_u16(code, peek_vstack);
+
+    // Line up the decimal points
+    code.push(POP_DE);
+    code.push(CALL_NN);
+    emit_u16(code, align_scales);
+    code.push(PUSH_DE);
     code.push(PUSH_HL);  // Z80 stack: [a, b]
 
     // Get sign of a into D, sign of b into E
@@ -2480,11 +4799,13 @@ fn emit_add_op_handler(
     code.push(PUSH_HL);  // Save result
     code.push(CALL_NN);
     emit_u16(code, bcd_add);  // result = a + b
+    code.push(JP_C_NN);
+    emit_u16(code, overflow);
 
     // Sign is already correct (copied from a, which equals sign of b)
     code.push(POP_HL);   // HL = result
     code.push(CALL_NN);
-    emit_u16(code, push_vstack);
+    emit_u16(code, set_vstack_top);
     code.push(JP_NN);
     emit_u16(code, vm_loop);
 
@@ -2555,7 +4876,7 @@ fn emit_add_op_handler(
     code.push(LD_HL_A);  // Store b's sign in result
 
     code.push(CALL_NN);
-    emit_u16(code, push_vstack);
+    emit_u16(code, set_vstack_top);
     code.push(JP_NN);
     emit_u16(code, vm_loop);
 
@@ -2604,22 +4925,31 @@ fn emit_add_op_handler(
     code.push(LD_HL_A);  // Store a's sign in result
 
     code.push(CALL_NN);
-    emit_u16(code, push_vstack);
+    emit_u16(code, set_vstack_top);
     code.push(JP_NN);
     emit_u16(code, vm_loop);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn emit_div_op_handler(
     code: &mut Vec<u8>,
+    layout: &MemoryLayout,
     pop_vstack: u16,
-    push_vstack: u16,
+    peek_vstack: u16,
+    set_vstack_top: u16,
     div_routine: u16,
     mul10_routine: u16,
+    bcd_add: u16,
+    bcd_cmp: u16,
     alloc_num: u16,
+    shift_copy: u16,
+    is_zero: u16,
     vm_loop: u16,
 ) {
     // Division with scale: result = (dividend * 10^scale) / divisor
-    // Result's scale is set to VM_SCALE
+    // Result's scale is set to VM_SCALE. The quotient is truncated as in
+    // bc, unless VM_ROUND is set: then it is rounded half up (away from
+    // zero) by comparing twice the remainder with the divisor.
 
     // Pop two operands and save their scales
     code.push(CALL_NN);
@@ -2629,21 +4959,23 @@ fn emit_div_op_handler(
     code.push(INC_HL);
     code.push(LD_A_HL);
     code.push(LD_NN_A);
-    emit_u16(code, REPL_TEMP + 59);
+    emit_u16(code, layout.ram(REPL_TEMP + 59));
     code.push(DEC_HL);
     code.push(DEC_HL);
+    emit_record_sign(code, layout);
     code.push(PUSH_HL);  // Stack: [divisor]
 
     code.push(CALL_NN);
-    emit_u16(code, pop_vstack);
+    emit_u16(code, peek_vstack);
     // Save dividend scale to REPL_TEMP+58
     code.push(INC_HL);
     code.push(INC_HL);
     code.push(LD_A_HL);
     code.push(LD_NN_A);
-    emit_u16(code, REPL_TEMP + 58);
+    emit_u16(code, layout.ram(REPL_TEMP + 58));
     code.push(DEC_HL);
     code.push(DEC_HL);
+    emit_combine_sign(code, layout);
     code.push(PUSH_HL);  // Stack: [dividend, divisor]
 
     // Allocate result number on heap
@@ -2670,21 +5002,33 @@ fn emit_div_op_handler(
     // Multiply result by 10^effective_count
     // effective_count = VM_SCALE + divisor_scale - dividend_scale
     code.push(LD_A_NN_IND);
-    emit_u16(code, VM_SCALE);        // A = VM_SCALE
+    emit_u16(code, layout.ram(VM_SCALE));        // A = VM_SCALE
     code.push(LD_B_A);               // B = VM_SCALE
     code.push(LD_A_NN_IND);
-    emit_u16(code, REPL_TEMP + 59);  // A = divisor_scale
+    emit_u16(code, layout.ram(REPL_TEMP + 59));  // A = divisor_scale
     code.push(ADD_A_B);              // A = VM_SCALE + divisor_scale
     code.push(LD_B_A);               // B = VM_SCALE + divisor_scale
     code.push(LD_A_NN_IND);
-    emit_u16(code, REPL_TEMP + 58);  // A = dividend_scale
+    emit_u16(code, layout.ram(REPL_TEMP + 58));  // A = dividend_scale
     code.push(LD_C_A);               // C = dividend_scale
     code.push(LD_A_B);               // A = VM_SCALE + divisor_scale
     code.push(SUB_C);                // A = VM_SCALE + divisor_scale - dividend_scale
-    // If result is negative (carry set), set to 0
-    code.push(JR_NC_N);
-    code.push(1);                    // Skip 1 byte (XOR_A)
-    code.push(XOR_A);                // A = 0 if negative
+    let non_negative = jr_placeholder(code, JR_NC_N);
+
+    // The dividend has more decimals than the quotient needs: shift a copy
+    // of the divisor up instead, so the extra digits are truncated
+    code.push(ED_PREFIX);
+    code.push(NEG);
+    code.push(LD_B_A);   // B = digits to shift the divisor
+    code.push(POP_HL);   // HL = result
+    code.push(POP_DE);   // DE = divisor
+    code.push(EX_DE_HL);
+    code.push(CALL_NN);
+    emit_u16(code, shift_copy);  // HL = shifted divisor, DE = result
+    code.push(PUSH_HL);
+    code.push(PUSH_DE);  // Stack: [result, divisor]
+    let shifted = jr_placeholder(code, JR_N);
+    patch_jr(code, non_negative);
 
     code.push(OR_A);     // Check if effective count is 0
     let skip_mul10 = jr_placeholder(code, JR_Z_N);
@@ -2703,6 +5047,7 @@ fn emit_div_op_handler(
     code.push(offset as u8);
 
     patch_jr(code, skip_mul10);
+    patch_jr(code, shifted);
 
     // Stack: [result, divisor]
     // Now do the division: result = result / divisor
@@ -2712,19 +5057,49 @@ fn emit_div_op_handler(
     code.push(CALL_NN);
     emit_u16(code, div_routine);
 
+    // Round: the remainder is left at REPL_TEMP, the divisor's address at
+    // REPL_TEMP+56
+    code.push(LD_A_NN_IND);
+    emit_u16(code, layout.ram(VM_ROUND));
+    code.push(OR_A);
+    let no_round = jr_placeholder(code, JR_Z_N);
+    code.push(LD_HL_NN);
+    emit_u16(code, layout.ram(REPL_TEMP));
+    code.push(LD_D_H);
+    code.push(LD_E_L);
+    code.push(CALL_NN);
+    emit_u16(code, bcd_add);         // remainder *= 2
+    let round_up = jr_placeholder(code, JR_C_N);  // Past 50 digits: above any divisor
+    emit_ld_de_nn_ind(code, layout.ram(REPL_TEMP + 56));
+    code.push(CALL_NN);
+    emit_u16(code, bcd_cmp);         // A = 1 if divisor > 2 * remainder
+    code.push(CP_N);
+    code.push(1);
+    let round_down = jr_placeholder(code, JR_Z_N);
+    patch_jr(code, round_up);
+    code.push(POP_HL);
+    code.push(PUSH_HL);              // HL = result
+    code.push(LD_DE_NN);
+    emit_u16(code, layout.ram(CONST_ONE));
+    code.push(CALL_NN);
+    emit_u16(code, bcd_add);         // Add one in the last place
+    patch_jr(code, no_round);
+    patch_jr(code, round_down);
+
     // Set result's scale to VM_SCALE
     code.push(POP_HL);   // HL = result
     code.push(INC_HL);
     code.push(INC_HL);   // HL points to scale byte
     code.push(LD_A_NN_IND);
-    emit_u16(code, VM_SCALE);
+    emit_u16(code, layout.ram(VM_SCALE));
     code.push(LD_HL_A);  // Set scale
     code.push(DEC_HL);
     code.push(DEC_HL);   // HL = result again
+    emit_apply_sign(code, layout, is_zero);
 
     // Push result onto value stack
     code.push(CALL_NN);
-    emit_u16(code, push_vstack);
+    emit_u16(code, set_vstack_top);
 
     code.push(JP_NN);
     emit_u16(code, vm_loop);
@@ -2732,8 +5107,8 @@ fn emit_div_op_handler(
 
 fn emit_unary_op_handler(
     code: &mut Vec<u8>,
-    pop_vstack: u16,
-    push_vstack: u16,
+    peek_vstack: u16,
+    set_vstack_top: u16,
     op_routine: u16,
     copy_num: u16,
     alloc_num: u16,
@@ -2741,38 +5116,72 @@ fn emit_unary_op_handler(
 ) {
     // Pop operand
     code.push(CALL_NN);
-    emit_u16(code, pop_vstack);
+    emit_u16(code, peek_vstack);
     code.push(PUSH_HL);
 
     // Allocate result
     code.push(CALL_NN);
     emit_u16(code, alloc_num);
-    code.push(EX_DE_HL);  // DE = result
-    code.push(POP_HL);    // HL = operand
-    code.push(PUSH_DE);   // Save result
+    code.push(POP_DE);    // DE = operand, HL = result
 
-    // Copy operand to result
+    // Copy operand to result (copy_num copies DE to HL), so the operand
+    // itself - possibly a shared constant or a variable - is untouched
     code.push(CALL_NN);
     emit_u16(code, copy_num);
 
     // Apply operation to result
-    code.push(POP_HL);    // HL = result
     code.push(CALL_NN);
     emit_u16(code, op_routine);
 
     // Push result
     code.push(CALL_NN);
-    emit_u16(code, push_vstack);
+    emit_u16(code, set_vstack_top);
+
+    code.push(JP_NN);
+    emit_u16(code, vm_loop);
+}
+
+/// Push a fresh 1 if the popped value is zero, otherwise 0
+fn emit_not_handler(
+    code: &mut Vec<u8>,
+    peek_vstack: u16,
+    set_vstack_top: u16,
+    is_zero: u16,
+    alloc_num: u16,
+    byte_to_num: u16,
+    vm_loop: u16,
+) {
+    code.push(CALL_NN);
+    emit_u16(code, peek_vstack);
+    code.push(CALL_NN);
+    emit_u16(code, is_zero);
+    code.push(LD_A_N);
+    code.push(0);
+    let nonzero = jr_placeholder(code, JR_NZ_N);
+    code.push(INC_A);
+    patch_jr(code, nonzero);
 
+    code.push(PUSH_AF);
+    code.push(CALL_NN);
+    emit_u16(code, alloc_num);
+    code.push(POP_AF);
+    code.push(CALL_NN);
+    emit_u16(code, byte_to_num);
+    code.push(CALL_NN);
+    emit_u16(code, set_vstack_top);
     code.push(JP_NN);
     emit_u16(code, vm_loop);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn emit_cmp_handler(
     code: &mut Vec<u8>,
     pop_vstack: u16,
-    push_vstack: u16,
+    peek_vstack: u16,
+    set_vstack_top: u16,
     cmp_routine: u16,
+    alloc_num: u16,
+    byte_to_num: u16,
     expected: u8,
     vm_loop: u16,
 ) {
@@ -2782,7 +5191,7 @@ fn emit_cmp_handler(
     code.push(PUSH_HL);
 
     code.push(CALL_NN);
-    emit_u16(code, pop_vstack);
+    emit_u16(code, peek_vstack);
     code.push(POP_DE);
 
     // HL = first, DE = second
@@ -2792,132 +5201,338 @@ fn emit_cmp_handler(
     code.push(CALL_NN);
     emit_u16(code, cmp_routine);
 
-    // A = comparison result
+    // A = 1 if the result matches, 0 otherwise
     code.push(CP_N);
     code.push(expected);
+    code.push(LD_A_N);
+    code.push(0);
+    let no_match = jr_placeholder(code, JR_NZ_N);
+    code.push(INC_A);
+    patch_jr(code, no_match);
 
-    // Push 1 if match, 0 otherwise
-    let match_case = jr_placeholder(code, JR_Z_N);
-    code.push(LD_HL_NN);
-    emit_u16(code, CONST_ZERO);
-    let done = code.len();
-    code.push(JP_NN);
-    emit_u16(code, 0); // Placeholder
-
-    patch_jr(code, match_case);
-    code.push(LD_HL_NN);
-    emit_u16(code, CONST_ONE);
-
-    let here = code.len() as u16;
-    code[done + 1] = (here & 0xFF) as u8;
-    code[done + 2] = (here >> 8) as u8;
+    // Push a fresh number rather than CONST_ZERO/CONST_ONE, so the
+    // result can be modified without touching the shared constants
+    code.push(PUSH_AF);
+    code.push(CALL_NN);
+    emit_u16(code, alloc_num);
+    code.push(POP_AF);
+    code.push(CALL_NN);
+    emit_u16(code, byte_to_num);
 
     code.push(CALL_NN);
-    emit_u16(code, push_vstack);
+    emit_u16(code, set_vstack_top);
 
     code.push(JP_NN);
     emit_u16(code, vm_loop);
 }
 
-fn emit_jump_handler(code: &mut Vec<u8>, vm_loop: u16) {
-    // Read 16-bit address and set VM_PC
-    code.push(LD_HL_NN_IND);
-    emit_u16(code, VM_PC);
-    code.push(LD_E_HL);
-    code.push(INC_HL);
-    code.push(LD_D_HL);
+#[allow(clippy::too_many_arguments)]
+fn emit_read_handler(
+    code: &mut Vec<u8>,
+    layout: &MemoryLayout,
+    getline: u16,
+    parse_num: u16,
+    alloc_num: u16,
+    byte_to_num: u16,
+    bcd_mul: u16,
+    bcd_add: u16,
+    push_vstack: u16,
+    print_newline: u16,
+    vm_loop: u16,
+) {
+    // Read an integer line (optional leading '-') in the current ibase
+    // and push it
+    code.push(CALL_NN);
+    emit_u16(code, getline);
+    code.push(CALL_NN);
+    emit_u16(code, print_newline);  // Echo the line ending
+
+    code.push(LD_A_NN_IND);
+    emit_u16(code, layout.ram(VM_IBASE));
+    code.push(CP_N);
+    code.push(10);
+    let other_base = jp_nz_placeholder(code);
 
-    // DE = jump target (relative to bytecode start)
+    // Decimal: pack the digits directly
+    code.push(CALL_NN);
+    emit_u16(code, alloc_num);
+    code.push(EX_DE_HL);            // DE = destination
     code.push(LD_HL_NN);
-    emit_u16(code, BYTECODE_ORG);
-    code.push(ADD_HL_DE);
+    emit_u16(code, READ_BUF);
 
-    code.push(LD_NN_HL);
-    emit_u16(code, VM_PC);
+    // Leading minus sign
+    code.push(LD_A_HL);
+    code.push(CP_N);
+    code.push(b'-');
+    code.push(PUSH_AF);             // Z = negative
+    let positive = jr_placeholder(code, JR_NZ_N);
+    code.push(INC_HL);
+    patch_jr(code, positive);
 
+    code.push(CALL_NN);
+    emit_u16(code, parse_num);
+
+    // DE = number, Z on the stack if it was negative
+    let apply_sign = code.len() as u16;
+    code.push(POP_AF);
+    let no_sign = jr_placeholder(code, JR_NZ_N);
+    code.push(LD_A_N);
+    code.push(0x80);
+    code.push(LD_DE_A);             // Set sign bit
+    patch_jr(code, no_sign);
+
+    code.push(EX_DE_HL);            // HL = number
+    code.push(CALL_NN);
+    emit_u16(code, push_vstack);
     code.push(JP_NN);
     emit_u16(code, vm_loop);
-}
 
-fn emit_jump_if_zero_handler(code: &mut Vec<u8>, pop_vstack: u16, vm_loop: u16) {
-    // Pop condition
+    // Other bases: value = value * ibase + digit for each digit 0-9, A-F
+    patch_jp(code, other_base);
     code.push(CALL_NN);
-    emit_u16(code, pop_vstack);
+    emit_u16(code, alloc_num);
+    code.push(LD_NN_HL);
+    emit_u16(code, layout.ram(VM_READ_BASE));
+    code.push(LD_A_NN_IND);
+    emit_u16(code, layout.ram(VM_IBASE));
+    code.push(CALL_NN);
+    emit_u16(code, byte_to_num);
+    code.push(CALL_NN);
+    emit_u16(code, alloc_num);
+    code.push(LD_NN_HL);
+    emit_u16(code, layout.ram(VM_READ_DIGIT));
+    code.push(CALL_NN);
+    emit_u16(code, alloc_num);
+    code.push(LD_NN_HL);
+    emit_u16(code, layout.ram(VM_READ_ACC));
+    code.push(XOR_A);
+    code.push(CALL_NN);
+    emit_u16(code, byte_to_num);
 
-    // Check if zero (compare first digit byte)
-    code.push(INC_HL);
-    code.push(INC_HL);
-    code.push(INC_HL);
+    code.push(LD_HL_NN);
+    emit_u16(code, READ_BUF);
     code.push(LD_A_HL);
-    code.push(OR_A);
+    code.push(CP_N);
+    code.push(b'-');
+    code.push(PUSH_AF);             // Z = negative
+    let positive = jr_placeholder(code, JR_NZ_N);
+    code.push(INC_HL);
+    patch_jr(code, positive);
 
-    let not_zero = jr_placeholder(code, JR_NZ_N);
+    let digit_loop = code.len();
+    code.push(LD_A_HL);
+    code.push(SUB_N);
+    code.push(b'0');
+    let done = jr_placeholder(code, JR_C_N);
+    code.push(CP_N);
+    code.push(10);
+    let decimal_digit = jr_placeholder(code, JR_C_N);
+    code.push(SUB_N);
+    code.push(b'A' - b'0');
+    let done2 = jr_placeholder(code, JR_C_N);
+    code.push(CP_N);
+    code.push(6);
+    let done3 = jr_placeholder(code, JR_NC_N);
+    code.push(ADD_A_N);
+    code.push(10);
+    patch_jr(code, decimal_digit);
 
-    // Is zero - do the jump
+    code.push(INC_HL);
+    code.push(PUSH_HL);             // Text position
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, layout.ram(VM_READ_DIGIT));
+    code.push(CALL_NN);
+    emit_u16(code, byte_to_num);
+    code.push(LD_HL_NN_IND);
+    emit_u16(code, layout.ram(VM_READ_ACC));
+    emit_ld_de_nn_ind(code, layout.ram(VM_READ_BASE));
+    code.push(CALL_NN);
+    emit_u16(code, bcd_mul);
     code.push(LD_HL_NN_IND);
-    emit_u16(code, VM_PC);
+    emit_u16(code, layout.ram(VM_READ_ACC));
+    emit_ld_de_nn_ind(code, layout.ram(VM_READ_DIGIT));
+    code.push(CALL_NN);
+    emit_u16(code, bcd_add);
+    code.push(POP_HL);
+    code.push(JR_N);
+    code.push((digit_loop as i16 - code.len() as i16 - 1) as u8);
+
+    patch_jr(code, done);
+    patch_jr(code, done2);
+    patch_jr(code, done3);
+    emit_ld_de_nn_ind(code, layout.ram(VM_READ_ACC));
+    code.push(JP_NN);
+    emit_u16(code, apply_sign);
+}
+
+fn emit_load_byte_handler(
+    code: &mut Vec<u8>,
+    addr: u16,
+    alloc_num: u16,
+    byte_to_num: u16,
+    push_vstack: u16,
+    vm_loop: u16,
+) {
+    // Push the byte at addr as a number
+    code.push(CALL_NN);
+    emit_u16(code, alloc_num);
+    code.push(LD_A_NN_IND);
+    emit_u16(code, addr);
+    code.push(CALL_NN);
+    emit_u16(code, byte_to_num);
+    code.push(CALL_NN);
+    emit_u16(code, push_vstack);
+    code.push(JP_NN);
+    emit_u16(code, vm_loop);
+}
+
+fn emit_store_base_handler(code: &mut Vec<u8>, addr: u16, pop_vstack: u16, num_to_byte: u16, vm_loop: u16) {
+    // Pop a number and store it at addr as a base, clamped to 2-16
+    code.push(CALL_NN);
+    emit_u16(code, pop_vstack);
+    code.push(CALL_NN);
+    emit_u16(code, num_to_byte);
+    code.push(CP_N);
+    code.push(2);
+    let not_low = jr_placeholder(code, JR_NC_N);
+    code.push(LD_A_N);
+    code.push(2);
+    patch_jr(code, not_low);
+    code.push(CP_N);
+    code.push(17);
+    let not_high = jr_placeholder(code, JR_C_N);
+    code.push(LD_A_N);
+    code.push(16);
+    patch_jr(code, not_high);
+    code.push(LD_NN_A);
+    emit_u16(code, addr);
+    code.push(JP_NN);
+    emit_u16(code, vm_loop);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn emit_cmp_jump_handler(
+    code: &mut Vec<u8>,
+    pop_vstack: u16,
+    cmp_routine: u16,
+    expected: u8,
+    jump_on_match: bool,
+    jump_handler: u16,
+    vm_loop: u16,
+) {
+    // Pop two operands and compare, as in emit_cmp_handler
+    code.push(CALL_NN);
+    emit_u16(code, pop_vstack);
+    code.push(PUSH_HL);
+
+    code.push(CALL_NN);
+    emit_u16(code, pop_vstack);
+    code.push(POP_DE);
+
+    code.push(EX_DE_HL);
+
+    code.push(CALL_NN);
+    emit_u16(code, cmp_routine);
+
+    code.push(CP_N);
+    code.push(expected);
+
+    // Taken - the Jump handler reads the target at the VM PC
+    code.push(if jump_on_match { JP_Z_NN } else { JP_NZ_NN });
+    emit_u16(code, jump_handler);
+
+    // Not taken - skip the jump address
+    code.push(EXX);
+    code.push(INC_HL);
+    code.push(INC_HL);
+    code.push(EXX);
+
+    code.push(JP_NN);
+    emit_u16(code, vm_loop);
+}
+
+fn emit_jump_handler(code: &mut Vec<u8>, vm_loop: u16) {
+    // Read 16-bit address and set the VM PC, all in the shadow registers
+    code.push(EXX);
     code.push(LD_E_HL);
     code.push(INC_HL);
     code.push(LD_D_HL);
+
+    // DE' = jump target (relative to bytecode start)
     code.push(LD_HL_NN);
     emit_u16(code, BYTECODE_ORG);
     code.push(ADD_HL_DE);
-    code.push(LD_NN_HL);
-    emit_u16(code, VM_PC);
+    code.push(EXX);
+
+    code.push(JP_NN);
+    emit_u16(code, vm_loop);
+}
+
+fn emit_jump_if_zero_handler(code: &mut Vec<u8>, pop_vstack: u16, is_zero: u16, vm_loop: u16) {
+    // Pop condition
+    code.push(CALL_NN);
+    emit_u16(code, pop_vstack);
+
+    // Check if zero (all digit bytes, not just the leading one)
+    code.push(CALL_NN);
+    emit_u16(code, is_zero);
+
+    let not_zero = jr_placeholder(code, JR_NZ_N);
+
+    // Is zero - do the jump
+    code.push(EXX);
+    code.push(LD_E_HL);
+    code.push(INC_HL);
+    code.push(LD_D_HL);
+    code.push(LD_HL_NN);
+    emit_u16(code, BYTECODE_ORG);
+    code.push(ADD_HL_DE);
+    code.push(EXX);
     code.push(JP_NN);
     emit_u16(code, vm_loop);
 
     patch_jr(code, not_zero);
 
     // Not zero - skip the jump address
-    code.push(LD_HL_NN_IND);
-    emit_u16(code, VM_PC);
+    code.push(EXX);
     code.push(INC_HL);
     code.push(INC_HL);
-    code.push(LD_NN_HL);
-    emit_u16(code, VM_PC);
+    code.push(EXX);
 
     code.push(JP_NN);
     emit_u16(code, vm_loop);
 }
 
-fn emit_jump_if_not_zero_handler(code: &mut Vec<u8>, pop_vstack: u16, vm_loop: u16) {
+fn emit_jump_if_not_zero_handler(code: &mut Vec<u8>, pop_vstack: u16, is_zero: u16, vm_loop: u16) {
     // Pop condition
     code.push(CALL_NN);
     emit_u16(code, pop_vstack);
 
-    // Check if zero
-    code.push(INC_HL);
-    code.push(INC_HL);
-    code.push(INC_HL);
-    code.push(LD_A_HL);
-    code.push(OR_A);
+    // Check if zero (all digit bytes, not just the leading one)
+    code.push(CALL_NN);
+    emit_u16(code, is_zero);
 
     let is_zero = jr_placeholder(code, JR_Z_N);
 
     // Not zero - do the jump
-    code.push(LD_HL_NN_IND);
-    emit_u16(code, VM_PC);
+    code.push(EXX);
     code.push(LD_E_HL);
     code.push(INC_HL);
     code.push(LD_D_HL);
     code.push(LD_HL_NN);
     emit_u16(code, BYTECODE_ORG);
     code.push(ADD_HL_DE);
-    code.push(LD_NN_HL);
-    emit_u16(code, VM_PC);
+    code.push(EXX);
     code.push(JP_NN);
     emit_u16(code, vm_loop);
 
     patch_jr(code, is_zero);
 
     // Is zero - skip the jump address
-    code.push(LD_HL_NN_IND);
-    emit_u16(code, VM_PC);
+    code.push(EXX);
     code.push(INC_HL);
     code.push(INC_HL);
-    code.push(LD_NN_HL);
-    emit_u16(code, VM_PC);
+    code.push(EXX);
 
     code.push(JP_NN);
     emit_u16(code, vm_loop);
@@ -2944,14 +5559,20 @@ const REPL_SCALE_BCD: u16 = 0x8400 + 26 * 28;  // Scale as BCD (slot 26, same fo
 const REPL_TEMP: u16 = 0x8700;           // Temp BCD buffer (28 bytes)
 const REPL_TEMP2: u16 = 0x871C;          // Second temp buffer
 const REPL_SCALE: u16 = 0x8740;          // Scale setting (1 byte)
+const REPL_IBASE: u16 = 0x8741;          // Input base, 2-16 (1 byte)
+const REPL_IBASE_BCD: u16 = 0x8744;      // ibase as a number, assigned like a variable (28 bytes)
+const REPL_DIGIT: u16 = 0x8760;          // Digit being added while parsing in another base (28 bytes)
 const REPL_HEAP: u16 = 0x8800;           // Heap start
 const REPL_HEAP_PTR: u16 = 0x87FC;       // Current heap pointer
+const REPL_HISTORY: u16 = 0xFB00;        // Last input line: length, then the characters
+const REPL_LINE_MAX: usize = 250;        // Longest line getline accepts
 
 // Token types for REPL
 const TOK_EOF: u8 = 0x00;
 const TOK_NUMBER: u8 = 0x01;      // Followed by 2-byte pointer to BCD
 const TOK_VARIABLE: u8 = 0x02;    // Followed by variable index (0-25)
 const TOK_SCALE: u8 = 0x03;       // Special 'scale' variable
+const TOK_IBASE: u8 = 0x04;       // 'ibase'
 const TOK_PLUS: u8 = 0x10;
 const TOK_MINUS: u8 = 0x11;
 const TOK_STAR: u8 = 0x12;
@@ -2964,10 +5585,53 @@ const TOK_LPAREN: u8 = 0x20;
 const TOK_RPAREN: u8 = 0x21;
 const TOK_ASSIGN: u8 = 0x30;
 
+/// Customisable text embedded in the REPL ROM
+#[derive(Debug, Clone)]
+pub struct ReplConfig {
+    /// Printed once at startup, followed by a newline
+    pub banner: String,
+    /// Printed before each input line
+    pub prompt: String,
+    pub newline: Newline,
+    /// Lines evaluated silently after the banner, before the first prompt
+    pub init: Vec<String>,
+}
+
+impl Default for ReplConfig {
+    fn default() -> Self {
+        ReplConfig {
+            banner: "bc80 REPL v1.0".to_string(),
+            prompt: "> ".to_string(),
+            newline: Newline::default(),
+            init: Vec::new(),
+        }
+    }
+}
+
 /// Generate a standalone REPL ROM that runs entirely on the Z80
 pub fn generate_repl_rom() -> Vec<u8> {
+    generate_repl_rom_with(&ReplConfig::default()).expect("default REPL strings fit")
+}
+
+/// Generate a REPL ROM with a custom banner, prompt and startup lines.
+/// Fails if a string contains NUL (the terminator), an init line would
+/// not fit the input buffer, or the ROM outgrows the 8KB ROM area.
+pub fn generate_repl_rom_with(config: &ReplConfig) -> Result<Vec<u8>, BuildError> {
     use opcodes::*;
 
+    for (name, text) in [("banner", &config.banner), ("prompt", &config.prompt)] {
+        if text.contains('\0') {
+            return Err(BuildError::Rom { msg: format!("REPL {} contains a NUL byte", name) });
+        }
+    }
+    for line in &config.init {
+        if line.contains(['\0', '\r', '\n']) || line.len() > REPL_LINE_MAX {
+            return Err(BuildError::Rom {
+                msg: format!("REPL init line {:?} must be one line of at most {} characters", line, REPL_LINE_MAX),
+            });
+        }
+    }
+
     let mut code = Vec::new();
 
     // Jump to init
@@ -2988,7 +5652,7 @@ pub fn generate_repl_rom() -> Vec<u8> {
 
     // ACIA input character (returns char in A)
     let acia_in = code.len() as u16;
-    emit_repl_acia_in(&mut code);
+    emit_acia_in(&mut code);
 
     // Print string (HL = null-terminated string)
     let print_str = code.len() as u16;
@@ -2996,24 +5660,24 @@ pub fn generate_repl_rom() -> Vec<u8> {
 
     // Print CRLF
     let print_crlf = code.len() as u16;
-    emit_repl_print_crlf(&mut code, acia_out);
+    emit_repl_print_crlf(&mut code, acia_out, config.newline);
 
     // Get line from input (fills REPL_INPUT_BUF)
     let getline = code.len() as u16;
-    emit_repl_getline(&mut code, acia_in, acia_out);
+    emit_getline(
+        &mut code,
+        acia_in,
+        acia_out,
+        REPL_INPUT_BUF,
+        REPL_INPUT_LEN,
+        REPL_INPUT_POS,
+        Some(REPL_HISTORY),
+    );
 
     // Allocate BCD number on heap (returns HL = pointer)
     let alloc_num = code.len() as u16;
     emit_repl_alloc_num(&mut code);
 
-    // Parse number from input buffer (returns HL = BCD pointer)
-    let parse_num = code.len() as u16;
-    emit_repl_parse_num(&mut code, alloc_num);
-
-    // Tokenize input buffer
-    let tokenize = code.len() as u16;
-    emit_repl_tokenize(&mut code, parse_num);
-
     // Push value onto value stack
     let val_push = code.len() as u16;
     emit_repl_val_push(&mut code);
@@ -3046,22 +5710,40 @@ pub fn generate_repl_rom() -> Vec<u8> {
     let bcd_add = code.len() as u16;
     emit_bcd_add_routine(&mut code);
 
+    let tens_complement = code.len() as u16;
+    emit_tens_complement_routine(&mut code);
     let bcd_sub = code.len() as u16;
-    emit_bcd_sub_routine(&mut code);
-
-    let bcd_mul = code.len() as u16;
-    emit_bcd_mul_routine(&mut code, bcd_add);
+    emit_bcd_sub_routine(&mut code, tens_complement);
 
     // Multiply BCD by 10 (shift digits left)
     let bcd_mul10 = code.len() as u16;
     emit_bcd_mul10_routine(&mut code);
 
+    let bcd_mul = code.len() as u16;
+    emit_bcd_mul_routine(&mut code, &MemoryLayout::default(), bcd_add, bcd_mul10);
+
     // BCD Compare routine
     let bcd_cmp = code.len() as u16;
     emit_bcd_cmp_routine(&mut code);
 
     let bcd_div = code.len() as u16;
-    emit_bcd_div_routine(&mut code, bcd_sub, bcd_cmp, bcd_mul10);
+    emit_bcd_div_routine(&mut code, &MemoryLayout::default(), bcd_sub, bcd_cmp, bcd_mul10);
+
+    // Store A as a number at HL (shared with the VM)
+    let byte_to_num = code.len() as u16;
+    emit_byte_to_num(&mut code);
+
+    // Parse digits into a BCD number (shared with the VM's read())
+    let parse_num = code.len() as u16;
+    emit_parse_num(&mut code);
+
+    // Parse number from input buffer (returns HL = BCD pointer)
+    let repl_parse_num = code.len() as u16;
+    emit_repl_parse_num(&mut code, alloc_num, parse_num, byte_to_num, bcd_mul, bcd_add);
+
+    // Tokenize input buffer
+    let tokenize = code.len() as u16;
+    emit_repl_tokenize(&mut code, repl_parse_num);
 
     // Copy BCD number (HL = dest, DE = source) - use REPL 28-byte version
     let bcd_copy = code.len() as u16;
@@ -3077,7 +5759,7 @@ pub fn generate_repl_rom() -> Vec<u8> {
 
     // Apply binary operator (A = op, pops 2 vals, pushes result)
     let apply_op = code.len() as u16;
-    emit_repl_apply_op(&mut code, val_pop, val_push, alloc_num, bcd_add, bcd_sub, bcd_mul, bcd_div, bcd_mul10, bcd_copy, scale_bcd_to_byte);
+    emit_repl_apply_op(&mut code, val_pop, val_push, alloc_num, bcd_add, bcd_sub, bcd_mul, bcd_div, bcd_mul10, bcd_copy, byte_to_num, scale_bcd_to_byte);
 
     // Evaluate expression from token buffer
     let evaluate = code.len() as u16;
@@ -3085,7 +5767,7 @@ pub fn generate_repl_rom() -> Vec<u8> {
 
     // Print BCD number (use the working VM version)
     let print_num = code.len() as u16;
-    emit_print_bcd_number(&mut code, acia_out);
+    emit_print_bcd_number(&mut code, acia_out, None);
 
     // === Initialization ===
     let init_addr = code.len() as u16;
@@ -3093,37 +5775,47 @@ pub fn generate_repl_rom() -> Vec<u8> {
     code[init_patch] = (init_addr & 0xFF) as u8;
     code[init_patch + 1] = (init_addr >> 8) as u8;
 
-    emit_repl_init(&mut code);
+    let banner_patch = emit_repl_init(&mut code, print_str, byte_to_num);
+    let init_patches: Vec<usize> = config
+        .init
+        .iter()
+        .map(|line| emit_repl_init_line(&mut code, line.len() as u8, tokenize, evaluate, val_pop))
+        .collect();
 
     // === Main REPL loop ===
     let repl_loop = code.len() as u16;
-    emit_repl_main_loop(&mut code, print_str, print_crlf, getline, tokenize, evaluate, val_pop, print_num, repl_loop);
+    let (prompt_patch, error_patch) = emit_repl_main_loop(&mut code, print_str, print_crlf, getline, tokenize, evaluate, val_pop, print_num, repl_loop);
 
     // === String constants ===
-    let banner_str = code.len() as u16;
-    for b in b"bc80 REPL v1.0\r\n" {
-        code.push(*b);
-    }
+    patch_jp(&mut code, banner_patch);
+    code.extend(config.banner.as_bytes());
+    code.extend(config.newline.bytes());
     code.push(0);
 
-    let prompt_str = code.len() as u16;
-    for b in b"> " {
-        code.push(*b);
-    }
+    patch_jp(&mut code, prompt_patch);
+    code.extend(config.prompt.as_bytes());
     code.push(0);
 
-    let error_str = code.len() as u16;
-    for b in b"Error\r\n" {
-        code.push(*b);
-    }
+    patch_jp(&mut code, error_patch);
+    code.extend(b"Error");
+    code.extend(config.newline.bytes());
     code.push(0);
 
-    // Patch string addresses in init
-    patch_repl_strings(&mut code, init_addr, banner_str, prompt_str, error_str, print_str, repl_loop);
+    for (patch, line) in init_patches.into_iter().zip(&config.init) {
+        patch_jp(&mut code, patch);
+        code.extend(line.as_bytes());
+        code.push(0);
+    }
+
+    if code.len() > RUNTIME_SIZE as usize {
+        return Err(BuildError::Rom {
+            msg: format!("REPL ROM is {} bytes, limit is {}", code.len(), RUNTIME_SIZE),
+        });
+    }
 
     eprintln!("REPL code size: {} bytes", code.len());
 
-    code
+    Ok(code)
 }
 
 fn emit_repl_acia_out(code: &mut Vec<u8>) {
@@ -3144,7 +5836,7 @@ fn emit_repl_acia_out(code: &mut Vec<u8>) {
     code.push(RET);
 }
 
-fn emit_repl_acia_in(code: &mut Vec<u8>) {
+fn emit_acia_in(code: &mut Vec<u8>) {
     use opcodes::*;
     // Wait for RX ready, then read to A
     let wait_loop = code.len() as u16;
@@ -3175,24 +5867,33 @@ fn emit_repl_print_str(code: &mut Vec<u8>, acia_out: u16) {
     code.push(offset as u8);
 }
 
-fn emit_repl_print_crlf(code: &mut Vec<u8>, acia_out: u16) {
+fn emit_repl_print_crlf(code: &mut Vec<u8>, acia_out: u16, newline: Newline) {
     use opcodes::*;
-    code.push(LD_A_N);
-    code.push(0x0D);  // CR
-    code.push(CALL_NN);
-    emit_u16(code, acia_out);
-    code.push(LD_A_N);
-    code.push(0x0A);  // LF
-    code.push(CALL_NN);
-    emit_u16(code, acia_out);
+    for &byte in newline.bytes() {
+        code.push(LD_A_N);
+        code.push(byte);
+        code.push(CALL_NN);
+        emit_u16(code, acia_out);
+    }
     code.push(RET);
 }
 
-fn emit_repl_getline(code: &mut Vec<u8>, acia_in: u16, acia_out: u16) {
+fn emit_getline(
+    code: &mut Vec<u8>,
+    acia_in: u16,
+    acia_out: u16,
+    buf: u16,
+    len_addr: u16,
+    pos_addr: u16,
+    history: Option<u16>,
+) {
     use opcodes::*;
-    // Read line into REPL_INPUT_BUF, handle backspace
+    // Read a null-terminated line into buf (256 bytes), handle backspace.
+    // Stores the length at len_addr and resets the parse position at pos_addr.
+    // With a history buffer, up-arrow (ESC [ A) recalls the last non-empty
+    // line and down-arrow (ESC [ B) clears the line being typed.
     code.push(LD_HL_NN);
-    emit_u16(code, REPL_INPUT_BUF);
+    emit_u16(code, buf);
     code.push(LD_B_N);
     code.push(0);  // Character count
 
@@ -3200,6 +5901,13 @@ fn emit_repl_getline(code: &mut Vec<u8>, acia_in: u16, acia_out: u16) {
     code.push(CALL_NN);
     emit_u16(code, acia_in);
 
+    // Escape sequences are handled out of line, after the RET
+    let esc = history.map(|_| {
+        code.push(CP_N);
+        code.push(0x1B);
+        jp_z_placeholder(code)
+    });
+
     // Check for CR
     code.push(CP_N);
     code.push(13);
@@ -3220,7 +5928,16 @@ fn emit_repl_getline(code: &mut Vec<u8>, acia_in: u16, acia_out: u16) {
     code.push(OR_A);
     let no_del = jr_placeholder(code, JR_Z_N);  // Nothing to delete
     code.push(DEC_B);
-    code.push(DEC_HL);
+    // Recompute HL = buf + B so the write position can never leave the
+    // buffer, whatever mix of input and backspaces came before
+    code.push(PUSH_DE);
+    code.push(LD_HL_NN);
+    emit_u16(code, buf);
+    code.push(LD_E_B);
+    code.push(LD_D_N);
+    code.push(0);
+    code.push(ADD_HL_DE);
+    code.push(POP_DE);
     // Echo: BS, space, BS
     code.push(LD_A_N);
     code.push(8);
@@ -3244,8 +5961,13 @@ fn emit_repl_getline(code: &mut Vec<u8>, acia_in: u16, acia_out: u16) {
     code.push(LD_C_A);  // Save char
     code.push(LD_A_B);
     code.push(CP_N);
-    code.push(250);
+    code.push(REPL_LINE_MAX as u8);
     let not_full = jr_placeholder(code, JR_C_N);
+    // Full: ring the bell instead of silently dropping the character
+    code.push(LD_A_N);
+    code.push(0x07);
+    code.push(CALL_NN);
+    emit_u16(code, acia_out);
     code.push(JR_N);
     let back_to_loop2 = (loop_start as i16 - code.len() as i16 - 1) as i8;
     code.push(back_to_loop2 as u8);
@@ -3269,11 +5991,106 @@ fn emit_repl_getline(code: &mut Vec<u8>, acia_in: u16, acia_out: u16) {
     code.push(LD_HL_A);  // Null terminate
     code.push(LD_A_B);
     code.push(LD_NN_A);
-    emit_u16(code, REPL_INPUT_LEN);
+    emit_u16(code, len_addr);
     code.push(XOR_A);
     code.push(LD_NN_A);
-    emit_u16(code, REPL_INPUT_POS);
+    emit_u16(code, pos_addr);
+
+    let (Some(hist), Some(esc)) = (history, esc) else {
+        code.push(RET);
+        return;
+    };
+
+    // Remember a non-empty line: length at hist, characters after it
+    code.push(LD_A_B);
+    code.push(OR_A);
+    code.push(RET_Z);
+    code.push(LD_NN_A);
+    emit_u16(code, hist);
+    code.push(PUSH_HL);
+    code.push(PUSH_DE);
+    code.push(PUSH_BC);
+    code.push(LD_HL_NN);
+    emit_u16(code, buf);
+    code.push(LD_DE_NN);
+    emit_u16(code, hist + 1);
+    code.push(LD_C_B);
+    code.push(LD_B_N);
+    code.push(0);
+    emit_ldir(code);
+    code.push(POP_BC);
+    code.push(POP_DE);
+    code.push(POP_HL);
     code.push(RET);
+
+    // ESC: only "[A" and "[B" mean anything, other sequences are dropped
+    patch_jp(code, esc);
+    code.push(CALL_NN);
+    emit_u16(code, acia_in);
+    code.push(CP_N);
+    code.push(b'[');
+    code.push(JP_NZ_NN);
+    emit_u16(code, loop_start);
+    code.push(CALL_NN);
+    emit_u16(code, acia_in);
+    code.push(CP_N);
+    code.push(b'A');
+    let is_arrow = jr_placeholder(code, JR_Z_N);
+    code.push(CP_N);
+    code.push(b'B');
+    code.push(JP_NZ_NN);
+    emit_u16(code, loop_start);
+    patch_jr(code, is_arrow);
+    code.push(LD_C_A);  // Save which arrow
+
+    // Erase the line on screen: BS, space, BS for each character
+    code.push(LD_A_B);
+    code.push(OR_A);
+    let erased = jr_placeholder(code, JR_Z_N);
+    let erase_loop = code.len();
+    for ch in [8, b' ', 8] {
+        code.push(LD_A_N);
+        code.push(ch);
+        code.push(CALL_NN);
+        emit_u16(code, acia_out);
+    }
+    code.push(DJNZ_N);
+    code.push((erase_loop as i16 - code.len() as i16 - 1) as u8);
+    patch_jr(code, erased);
+    code.push(LD_HL_NN);
+    emit_u16(code, buf);
+
+    // Down-arrow stops here with an empty line
+    code.push(LD_A_C);
+    code.push(CP_N);
+    code.push(b'A');
+    code.push(JP_NZ_NN);
+    emit_u16(code, loop_start);
+
+    // Up-arrow: copy the remembered line into buf, echoing it
+    code.push(LD_A_NN_IND);
+    emit_u16(code, hist);
+    code.push(OR_A);
+    code.push(JP_Z_NN);
+    emit_u16(code, loop_start);
+    code.push(LD_C_A);
+    code.push(PUSH_DE);
+    code.push(LD_DE_NN);
+    emit_u16(code, hist + 1);
+    let recall_loop = code.len();
+    code.push(LD_A_DE);
+    code.push(LD_HL_A);
+    code.push(INC_HL);
+    code.push(INC_DE);
+    code.push(INC_B);
+    code.push(CALL_NN);
+    emit_u16(code, acia_out);
+    code.push(DEC_C);
+    code.push(JR_NZ_N);
+    code.push((recall_loop as i16 - code.len() as i16 - 1) as u8);
+    code.push(POP_DE);
+    code.push(JP_NN);
+    emit_u16(code, loop_start);
 }
 
 fn emit_repl_alloc_num(code: &mut Vec<u8>) {
@@ -3511,49 +6328,39 @@ fn emit_scale_bcd_to_byte(code: &mut Vec<u8>) {
     code.push(RET);
 }
 
-fn emit_repl_parse_num(code: &mut Vec<u8>, alloc_num: u16) {
+/// Shared number parser used by the REPL tokenizer and the VM's `read()`.
+/// Register-based with no fixed RAM addresses, so both ROMs embed the same code.
+fn emit_parse_num(code: &mut Vec<u8>) {
     use opcodes::*;
-    // Parse number from input at REPL_INPUT_POS
-    // Returns HL = pointer to BCD number in fixed 50-digit packed format
+    // Input: HL = pointer to text, DE = 28-byte destination
+    // Output: HL = one past the last digit, DE = destination (preserved)
     // Format: [sign][len=50][scale][25 packed bytes]
     // Numbers are right-aligned: single digit goes in low nibble of byte 27
 
-    // Allocate space (28 bytes)
-    code.push(CALL_NN);
-    emit_u16(code, alloc_num);
-    code.push(PUSH_HL);  // Save BCD pointer [stack: bcd]
+    code.push(PUSH_DE);  // [stack: dest]
 
     // Initialize header: sign=0, len=50, scale=0
     code.push(XOR_A);
-    code.push(LD_HL_A);  // sign = 0
-    code.push(INC_HL);
+    code.push(LD_DE_A);  // sign = 0
+    code.push(INC_DE);
     code.push(LD_A_N);
     code.push(50);       // Fixed 50 digits
-    code.push(LD_HL_A);  // len = 50
-    code.push(INC_HL);
+    code.push(LD_DE_A);  // len = 50
+    code.push(INC_DE);
     code.push(XOR_A);
-    code.push(LD_HL_A);  // scale = 0
-    code.push(INC_HL);
+    code.push(LD_DE_A);  // scale = 0
+    code.push(INC_DE);
 
     // Zero out all 25 packed bytes
     code.push(LD_B_N);
     code.push(25);
     let zero_loop = code.len() as u16;
-    code.push(LD_HL_A);  // Store 0
-    code.push(INC_HL);
+    code.push(LD_DE_A);  // Store 0
+    code.push(INC_DE);
     code.push(DJNZ_N);
     let offset = (zero_loop as i16 - code.len() as i16 - 1) as i8;
     code.push(offset as u8);
-
-    // Get input position, HL = input pointer
-    code.push(LD_A_NN_IND);
-    emit_u16(code, REPL_INPUT_POS);
-    code.push(LD_E_A);
-    code.push(LD_D_N);
-    code.push(0);
-    code.push(LD_HL_NN);
-    emit_u16(code, REPL_INPUT_BUF);
-    code.push(ADD_HL_DE);
+    code.push(DEC_DE);   // DE = last packed byte (digits 49-50)
 
     // Count digits and find end position
     code.push(LD_B_N);
@@ -3577,106 +6384,180 @@ fn emit_repl_parse_num(code: &mut Vec<u8>, alloc_num: u16) {
     patch_jr(code, count_done2);
     // HL = one past last digit, B = digit count
 
-    // Update input position
-    code.push(PUSH_HL);
-    code.push(LD_DE_NN);
-    emit_u16(code, REPL_INPUT_BUF);
-    code.push(OR_A);
-    emit_sbc_hl_de(code);
-    code.push(LD_A_L);
-    code.push(LD_NN_A);
-    emit_u16(code, REPL_INPUT_POS);
-    code.push(POP_HL);  // HL = one past last digit
-
-    // If no digits, return zero
+    // If no digits, the number stays zero
     code.push(LD_A_B);
     code.push(OR_A);
-    let has_digits = jr_placeholder(code, JR_NZ_N);
-    code.push(POP_HL);  // Return BCD pointer
-    code.push(RET);
-
-    patch_jr(code, has_digits);
-
-    // Get BCD pointer, calculate position for last packed byte (offset 27)
-    code.push(POP_DE);   // DE = BCD pointer [stack: empty]
-    code.push(PUSH_DE);  // Save for return [stack: bcd]
-    code.push(LD_A_N);
-    code.push(27);
-    code.push(ADD_A_E);
-    code.push(LD_E_A);
-    let no_carry = jr_placeholder(code, JR_NC_N);
-    code.push(INC_D);
-    patch_jr(code, no_carry);
-    // DE = pointer to last packed byte (byte 27 = digits 49-50)
-
-    // HL = one past last digit, B = count, go back to last digit
-    code.push(DEC_HL);
+    let no_digits = jr_placeholder(code, JR_Z_N);
 
-    // Save original count's parity to temp location
-    // Position = (original_count - B), if even -> low nibble, if odd -> high nibble
-    // (original_count XOR B) has same parity as (original_count - B)
-    code.push(LD_A_B);
-    code.push(AND_N);
-    code.push(1);
-    code.push(LD_NN_A);
-    emit_u16(code, REPL_TEMP);  // Save parity of original count
+    code.push(PUSH_HL);  // [stack: dest, end]
+    code.push(DEC_HL);   // Back to last digit
 
-    // Pack digits from right to left
+    // Pack digits from right to left, two per byte (destination is zeroed)
     let pack_loop = code.len() as u16;
+    // Low nibble
     code.push(LD_A_HL);
     code.push(SUB_N);
     code.push(b'0');
-    code.push(LD_C_A);   // C = digit (0-9)
-
-    // Check position parity: (original_parity XOR B) & 1
-    // If 0 -> low nibble (even position from right)
-    // If 1 -> high nibble (odd position from right)
-    code.push(LD_A_NN_IND);
-    emit_u16(code, REPL_TEMP);
-    code.push(XOR_B);
-    code.push(AND_N);
-    code.push(1);
-    let is_high_nibble = jr_placeholder(code, JR_NZ_N);
-
-    // Even count remaining: store in LOW nibble (rightmost digit position)
-    code.push(LD_A_DE);
-    code.push(AND_N);
-    code.push(0xF0);     // Keep high nibble
-    code.push(OR_C);     // Add low nibble
     code.push(LD_DE_A);
-    let done_digit = jr_placeholder(code, JR_N);
-
-    patch_jr(code, is_high_nibble);
-    // Odd count remaining: store in HIGH nibble
-    code.push(LD_A_C);
-    code.push(RLA);
-    code.push(RLA);
-    code.push(RLA);
-    code.push(RLA);
+    code.push(DEC_B);
+    let pack_done = jr_placeholder(code, JR_Z_N);
+    code.push(DEC_HL);
+    // High nibble
+    code.push(LD_A_HL);
+    code.push(SUB_N);
+    code.push(b'0');
+    code.push(RLCA);
+    code.push(RLCA);
+    code.push(RLCA);
+    code.push(RLCA);
     code.push(LD_C_A);
     code.push(LD_A_DE);
-    code.push(AND_N);
-    code.push(0x0F);     // Keep low nibble
-    code.push(OR_C);     // Add high nibble
+    code.push(OR_C);
     code.push(LD_DE_A);
     code.push(DEC_DE);   // Move to previous packed byte
-
-    patch_jr(code, done_digit);
     code.push(DEC_B);
-    let pack_done = jr_placeholder(code, JR_Z_N);
+    let pack_done2 = jr_placeholder(code, JR_Z_N);
     code.push(DEC_HL);
     code.push(JR_N);
     let back2 = (pack_loop as i16 - code.len() as i16 - 1) as i8;
     code.push(back2 as u8);
 
     patch_jr(code, pack_done);
-    code.push(POP_HL);   // Return BCD pointer
+    patch_jr(code, pack_done2);
+    code.push(POP_HL);   // HL = one past last digit
+
+    patch_jr(code, no_digits);
+    code.push(POP_DE);   // DE = destination
+    code.push(RET);
+}
+
+/// Parse a number from REPL_INPUT_POS into a new BCD number, returned in
+/// HL, and advance REPL_INPUT_POS. In ibase 10 this is `emit_parse_num`;
+/// otherwise (or for a number starting A-F) each digit 0-9, A-F does
+/// value = value * ibase + digit. Returns with carry set for a digit not
+/// below ibase, or if there is no digit at all.
+fn emit_repl_parse_num(code: &mut Vec<u8>, alloc_num: u16, parse_num: u16, byte_to_num: u16, bcd_mul: u16, bcd_add: u16) {
+    use opcodes::*;
+
+    // Allocate space (28 bytes)
+    code.push(CALL_NN);
+    emit_u16(code, alloc_num);
+    code.push(EX_DE_HL);  // DE = destination
+
+    // HL = input pointer
+    code.push(LD_A_NN_IND);
+    emit_u16(code, REPL_INPUT_POS);
+    code.push(LD_L_A);
+    code.push(LD_H_N);
+    code.push(0);
+    code.push(LD_BC_NN);
+    emit_u16(code, REPL_INPUT_BUF);
+    code.push(ADD_HL_BC);
+
+    code.push(LD_A_NN_IND);
+    emit_u16(code, REPL_IBASE);
+    code.push(CP_N);
+    code.push(10);
+    let other_base = jr_placeholder(code, JR_NZ_N);
+    code.push(LD_A_HL);
+    code.push(CP_N);
+    code.push(b'A');
+    let letter = jr_placeholder(code, JR_NC_N);  // A-F is then rejected as >= ibase
+
+    code.push(CALL_NN);
+    emit_u16(code, parse_num);
+    let parsed = jr_placeholder(code, JR_N);
+
+    // value = 0, then value = value * ibase + digit
+    patch_jr(code, other_base);
+    patch_jr(code, letter);
+    code.push(PUSH_HL);
+    code.push(EX_DE_HL);
+    code.push(XOR_A);
+    code.push(CALL_NN);
+    emit_u16(code, byte_to_num);  // Clobbers DE
+    code.push(EX_DE_HL);          // DE = destination
+    code.push(POP_HL);
+
+    let digit_loop = code.len();
+    code.push(LD_A_HL);
+    code.push(SUB_N);
+    code.push(b'0');
+    let done = jr_placeholder(code, JR_C_N);
+    code.push(CP_N);
+    code.push(10);
+    let decimal_digit = jr_placeholder(code, JR_C_N);
+    code.push(SUB_N);
+    code.push(b'A' - b'0');
+    let done2 = jr_placeholder(code, JR_C_N);
+    code.push(CP_N);
+    code.push(6);
+    let done3 = jr_placeholder(code, JR_NC_N);
+    code.push(ADD_A_N);
+    code.push(10);
+    patch_jr(code, decimal_digit);
+
+    code.push(LD_B_A);
+    code.push(LD_A_NN_IND);
+    emit_u16(code, REPL_IBASE);
+    code.push(DEC_A);
+    code.push(CP_B);
+    code.push(RET_C);             // Digit >= ibase
+    code.push(LD_A_B);
+
+    code.push(INC_HL);
+    code.push(PUSH_HL);           // Text position
+    code.push(PUSH_DE);           // Destination
+    code.push(LD_HL_NN);
+    emit_u16(code, REPL_DIGIT);
+    code.push(CALL_NN);
+    emit_u16(code, byte_to_num);
+    code.push(POP_HL);
+    code.push(PUSH_HL);
+    code.push(LD_DE_NN);
+    emit_u16(code, REPL_IBASE_BCD);
+    code.push(CALL_NN);
+    emit_u16(code, bcd_mul);
+    code.push(POP_HL);
+    code.push(PUSH_HL);
+    code.push(LD_DE_NN);
+    emit_u16(code, REPL_DIGIT);
+    code.push(CALL_NN);
+    emit_u16(code, bcd_add);
+    code.push(POP_DE);
+    code.push(POP_HL);
+    code.push(JR_N);
+    code.push((digit_loop as i16 - code.len() as i16 - 1) as u8);
+
+    patch_jr(code, done);
+    patch_jr(code, done2);
+    patch_jr(code, done3);
+    patch_jr(code, parsed);
+
+    // HL = one past the number. Fail if nothing was read (e.g. a lone '.'),
+    // else update the input position
+    code.push(LD_BC_NN);
+    emit_u16(code, REPL_INPUT_BUF);
+    code.push(OR_A);
+    emit_sbc_hl_bc(code);
+    code.push(LD_A_NN_IND);
+    emit_u16(code, REPL_INPUT_POS);
+    code.push(CP_L);
+    code.push(SCF);
+    code.push(RET_Z);
+    code.push(LD_A_L);
+    code.push(LD_NN_A);
+    emit_u16(code, REPL_INPUT_POS);
+
+    code.push(EX_DE_HL);  // Return BCD pointer
+    code.push(AND_A);     // Clear carry: parsed
     code.push(RET);
 }
 
+/// Tokenize REPL_INPUT_BUF into REPL_TOKEN_BUF. Returns with carry set,
+/// and the token buffer unfinished, at a character it has no token for.
 fn emit_repl_tokenize(code: &mut Vec<u8>, parse_num: u16) {
     use opcodes::*;
-    // Tokenize REPL_INPUT_BUF into REPL_TOKEN_BUF
 
     // Reset token count
     code.push(XOR_A);
@@ -3698,7 +6579,11 @@ fn emit_repl_tokenize(code: &mut Vec<u8>, parse_num: u16) {
     // Skip whitespace
     code.push(CP_N);
     code.push(b' ');
+    let space = jr_placeholder(code, JR_Z_N);
+    code.push(CP_N);
+    code.push(b'\t');
     let not_space = jr_placeholder(code, JR_NZ_N);
+    patch_jr(code, space);
     code.push(INC_HL);
     // Update input pos
     code.push(LD_A_NN_IND);
@@ -3722,12 +6607,18 @@ fn emit_repl_tokenize(code: &mut Vec<u8>, parse_num: u16) {
     let is_digit = jr_placeholder(code, JR_C_N);
 
     patch_jr(code, not_digit);
-    // Check for decimal point starting a number
+    // Check for decimal point or a digit A-F starting a number
     code.push(LD_A_HL);
     code.push(CP_N);
     code.push(b'.');
-    let not_num = jr_placeholder(code, JR_NZ_N);
+    let is_point = jr_placeholder(code, JR_Z_N);
+    code.push(SUB_N);
+    code.push(b'A');
+    code.push(CP_N);
+    code.push(6);
+    let not_num = jr_placeholder(code, JR_NC_N);
 
+    patch_jr(code, is_point);
     patch_jr(code, is_digit);
     // Parse number
     code.push(PUSH_HL);
@@ -3745,6 +6636,11 @@ fn emit_repl_tokenize(code: &mut Vec<u8>, parse_num: u16) {
     code.push(LD_B_H);
     code.push(LD_C_L);  // BC = BCD pointer
     code.push(POP_DE);
+    let parsed = jr_placeholder(code, JR_NC_N);
+    code.push(POP_AF);  // Discard old HL
+    code.push(SCF);     // Bad digit for ibase, or no digits
+    code.push(RET);
+    patch_jr(code, parsed);
     // Store token
     code.push(LD_A_N);
     code.push(TOK_NUMBER);
@@ -3830,15 +6726,54 @@ fn emit_repl_tokenize(code: &mut Vec<u8>, parse_num: u16) {
     let is_var = jr_placeholder(code, JR_C_N);
 
     patch_jr(code, not_var);
-    // Unknown character - skip it
-    code.push(INC_HL);
-    // Use JP instead of JR - too far for relative jump
-    code.push(JP_NN);
-    emit_u16(code, tok_loop);
+    // Unknown character, such as an operator the REPL lacks
+    code.push(SCF);
+    code.push(RET);
 
     // Store variable token
     patch_jr(code, is_var);
     // A = (char - 'a') = variable index (0-25)
+    // Check for "ibase" first
+    code.push(CP_N);
+    code.push(b'i' - b'a');
+    let not_ibase = jr_placeholder(code, JR_NZ_N);
+    code.push(PUSH_HL);
+    let mut not_ibase_rest = Vec::new();
+    for &c in b"base" {
+        code.push(INC_HL);
+        code.push(LD_A_HL);
+        code.push(CP_N);
+        code.push(c);
+        not_ibase_rest.push(jr_placeholder(code, JR_NZ_N));
+    }
+    code.push(POP_AF);           // Discard saved HL; HL is at 'e'
+    code.push(LD_A_N);
+    code.push(TOK_IBASE);
+    code.push(LD_DE_A);
+    code.push(INC_DE);
+    code.push(XOR_A);
+    code.push(LD_DE_A);
+    code.push(INC_DE);
+    code.push(LD_DE_A);
+    code.push(INC_DE);
+    code.push(LD_DE_A);
+    code.push(INC_DE);
+    code.push(LD_A_NN_IND);
+    emit_u16(code, REPL_TOKEN_CNT);
+    code.push(INC_A);
+    code.push(LD_NN_A);
+    emit_u16(code, REPL_TOKEN_CNT);
+    code.push(INC_HL);
+    code.push(JP_NN);
+    emit_u16(code, tok_loop);
+    for patch in not_ibase_rest {
+        patch_jr(code, patch);
+    }
+    code.push(POP_HL);           // Restore position: the variable 'i'
+    code.push(LD_A_N);
+    code.push(b'i' - b'a');
+    patch_jr(code, not_ibase);
+
     // But first check if this is "scale" keyword
     code.push(CP_N);
     code.push(b's' - b'a');      // Is it 's'?
@@ -3965,6 +6900,7 @@ fn emit_repl_tokenize(code: &mut Vec<u8>, parse_num: u16) {
     code.push(LD_A_N);
     code.push(TOK_EOF);
     code.push(LD_DE_A);
+    code.push(AND_A);  // Clear carry: tokenized
     code.push(RET);
 }
 
@@ -4088,9 +7024,10 @@ fn emit_repl_get_prec(code: &mut Vec<u8>) {
     code.push(RET);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn emit_repl_apply_op(code: &mut Vec<u8>, val_pop: u16, val_push: u16, alloc_num: u16,
                       bcd_add: u16, bcd_sub: u16, bcd_mul: u16, bcd_div: u16, bcd_mul10: u16, bcd_copy: u16,
-                      _scale_bcd_to_byte: u16) {
+                      byte_to_num: u16, _scale_bcd_to_byte: u16) {
     use opcodes::*;
     // Apply operator in A to top two values on stack
     // Strategy: copy left to result, then apply operation with right
@@ -4170,7 +7107,61 @@ fn emit_repl_apply_op(code: &mut Vec<u8>, val_pop: u16, val_push: u16, alloc_num
 
     patch_jr(code, not_scale);
     patch_jr(code, not_scale2);
-    // Either path: stack has [left]
+
+    // Likewise ibase, clamped to 2-16 in both the byte and the number
+    code.push(POP_HL);           // HL = left [stack: empty]
+    code.push(PUSH_HL);          // Re-save [stack: left]
+    code.push(LD_DE_NN);
+    emit_u16(code, REPL_IBASE_BCD);
+    code.push(LD_A_L);
+    code.push(XOR_E);
+    let not_ibase = jr_placeholder(code, JR_NZ_N);
+    code.push(LD_A_H);
+    code.push(XOR_D);
+    let not_ibase2 = jr_placeholder(code, JR_NZ_N);
+    code.push(LD_BC_NN);
+    emit_u16(code, 27);
+    code.push(ADD_HL_BC);
+    code.push(LD_A_HL);          // Last packed byte: value mod 100
+    code.push(LD_B_A);
+    code.push(AND_N);
+    code.push(0x0F);
+    code.push(LD_C_A);
+    code.push(LD_A_B);
+    code.push(RRCA);
+    code.push(RRCA);
+    code.push(RRCA);
+    code.push(RRCA);
+    code.push(AND_N);
+    code.push(0x0F);
+    code.push(LD_B_A);
+    code.push(ADD_A_A);
+    code.push(ADD_A_A);
+    code.push(ADD_A_B);
+    code.push(ADD_A_A);
+    code.push(ADD_A_C);          // A = 10 * high + low
+    code.push(CP_N);
+    code.push(2);
+    let not_low = jr_placeholder(code, JR_NC_N);
+    code.push(LD_A_N);
+    code.push(2);
+    patch_jr(code, not_low);
+    code.push(CP_N);
+    code.push(17);
+    let not_high = jr_placeholder(code, JR_C_N);
+    code.push(LD_A_N);
+    code.push(16);
+    patch_jr(code, not_high);
+    code.push(LD_NN_A);
+    emit_u16(code, REPL_IBASE);
+    code.push(LD_HL_NN);
+    emit_u16(code, REPL_IBASE_BCD);
+    code.push(CALL_NN);
+    emit_u16(code, byte_to_num);
+
+    patch_jr(code, not_ibase);
+    patch_jr(code, not_ibase2);
+    // Every path: stack has [left]
 
     // Push result (left, which now contains right's value)
     code.push(POP_HL);   // HL = left [stack: empty]
@@ -4298,6 +7289,7 @@ fn emit_repl_apply_op(code: &mut Vec<u8>, val_pop: u16, val_push: u16, alloc_num
     code.push(RET);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn emit_repl_evaluate(code: &mut Vec<u8>, val_push: u16, _val_pop: u16, op_push: u16, op_pop: u16, op_empty: u16, op_peek: u16, get_prec: u16, apply_op: u16, _byte_to_scale_bcd: u16, _alloc_num: u16, _bcd_copy: u16) {
     use opcodes::*;
     // Shunting-yard expression evaluator
@@ -4379,6 +7371,21 @@ fn emit_repl_evaluate(code: &mut Vec<u8>, val_push: u16, _val_pop: u16, op_push:
     emit_u16(code, eval_loop);
 
     patch_jr(code, not_var);
+    // Check IBASE
+    code.push(CP_N);
+    code.push(TOK_IBASE);
+    let not_ibase = jr_placeholder(code, JR_NZ_N);
+    code.push(LD_HL_NN);
+    emit_u16(code, REPL_IBASE_BCD);
+    code.push(CALL_NN);
+    emit_u16(code, val_push);
+    code.push(LD_BC_NN);
+    emit_u16(code, 4);
+    emit_add_ix_bc(code);
+    code.push(JP_NN);
+    emit_u16(code, eval_loop);
+
+    patch_jr(code, not_ibase);
     // Check SCALE - treat it like variable index 26
     code.push(CP_N);
     code.push(TOK_SCALE);
@@ -4588,7 +7595,9 @@ fn emit_repl_print_num(code: &mut Vec<u8>, acia_out: u16) {
     code.push(back2 as u8);
 }
 
-fn emit_repl_init(code: &mut Vec<u8>) {
+/// Returns the position of the banner address operand, patched once the
+/// string is emitted
+fn emit_repl_init(code: &mut Vec<u8>, print_str: u16, byte_to_num: u16) -> usize {
     use opcodes::*;
 
     // Disable interrupts, set stack
@@ -4606,21 +7615,71 @@ fn emit_repl_init(code: &mut Vec<u8>) {
     code.push(XOR_A);
     code.push(LD_NN_A);
     emit_u16(code, REPL_SCALE);
+    code.push(LD_NN_A);
+    emit_u16(code, REPL_HISTORY);  // No line to recall yet
 
     // NOTE: Scale (slot 26) is NOT pre-initialized like other variables
 
+    // ibase = 10
+    code.push(LD_A_N);
+    code.push(10);
+    code.push(LD_NN_A);
+    emit_u16(code, REPL_IBASE);
+    code.push(LD_HL_NN);
+    emit_u16(code, REPL_IBASE_BCD);
+    code.push(CALL_NN);
+    emit_u16(code, byte_to_num);
+
     // Print banner (address will be patched)
     code.push(LD_HL_NN);
+    let banner_patch = code.len();
     emit_u16(code, 0);  // Placeholder for banner address
     code.push(CALL_NN);
-    emit_u16(code, 0);  // Placeholder for print_str
+    emit_u16(code, print_str);
+
+    banner_patch
+}
+
+/// Copy a startup line into the input buffer and evaluate it as if typed,
+/// dropping the result. Returns the position of the line's address
+/// operand, patched once the string is emitted.
+fn emit_repl_init_line(code: &mut Vec<u8>, len: u8, tokenize: u16, evaluate: u16, val_pop: u16) -> usize {
+    use opcodes::*;
+
+    code.push(LD_HL_NN);
+    let line_patch = code.len();
+    emit_u16(code, 0);  // Placeholder for the line's address
+    code.push(LD_DE_NN);
+    emit_u16(code, REPL_INPUT_BUF);
+    code.push(LD_BC_NN);
+    emit_u16(code, len as u16 + 1);  // Including the NUL
+    emit_ldir(code);
+    code.push(LD_A_N);
+    code.push(len);
+    code.push(LD_NN_A);
+    emit_u16(code, REPL_INPUT_LEN);
+
+    code.push(CALL_NN);
+    emit_u16(code, tokenize);
+    let bad_line = jr_placeholder(code, JR_C_N);
+    code.push(CALL_NN);
+    emit_u16(code, evaluate);
+    code.push(CALL_NN);
+    emit_u16(code, val_pop);
+    patch_jr(code, bad_line);
+
+    line_patch
 }
 
-fn emit_repl_main_loop(code: &mut Vec<u8>, print_str: u16, print_crlf: u16, getline: u16, tokenize: u16, evaluate: u16, val_pop: u16, print_num: u16, repl_loop: u16) {
+/// Returns the positions of the prompt and error-message address operands,
+/// patched once the strings are emitted
+#[allow(clippy::too_many_arguments)]
+fn emit_repl_main_loop(code: &mut Vec<u8>, print_str: u16, print_crlf: u16, getline: u16, tokenize: u16, evaluate: u16, val_pop: u16, print_num: u16, repl_loop: u16) -> (usize, usize) {
     use opcodes::*;
 
     // Print prompt
     code.push(LD_HL_NN);
+    let prompt_patch = code.len();
     emit_u16(code, 0);  // Placeholder for prompt address
     code.push(CALL_NN);
     emit_u16(code, print_str);
@@ -4639,6 +7698,15 @@ fn emit_repl_main_loop(code: &mut Vec<u8>, print_str: u16, print_crlf: u16, getl
     // Tokenize
     code.push(CALL_NN);
     emit_u16(code, tokenize);
+    let tokenized = jr_placeholder(code, JR_NC_N);
+    code.push(LD_HL_NN);
+    let error_patch = code.len();
+    emit_u16(code, 0);  // Placeholder for the error message's address
+    code.push(CALL_NN);
+    emit_u16(code, print_str);
+    code.push(JP_NN);
+    emit_u16(code, repl_loop);
+    patch_jr(code, tokenized);
 
     // Evaluate
     code.push(CALL_NN);
@@ -4659,47 +7727,446 @@ fn emit_repl_main_loop(code: &mut Vec<u8>, print_str: u16, print_crlf: u16, getl
     // Loop
     code.push(JP_NN);
     emit_u16(code, repl_loop);
+
+    (prompt_patch, error_patch)
 }
 
-fn patch_repl_strings(code: &mut Vec<u8>, init_addr: u16, banner_str: u16, prompt_str: u16, _error_str: u16, print_str: u16, repl_loop: u16) {
-    // Find and patch string addresses in init code
-    // The init code has:
-    //   LD HL, banner_addr
-    //   CALL print_str
-    // and the main loop has:
-    //   LD HL, prompt_addr
-    //   CALL print_str
+// =====================================================
+// Self-test ROM
+// =====================================================
 
-    // Init code structure:
-    // DI; LD SP,nn; LD HL,heap; LD (heap_ptr),HL; XOR A; LD (scale),A
-    // That's: 1 + 3 + 3 + 3 + 1 + 3 = 14 bytes
-    // Then: LD HL,nn (banner) = 3 bytes, CALL nn (print_str) = 3 bytes
+// Self-test RAM
+const SELFTEST_WORK: u16 = 0x8000;       // Operation result (28 bytes)
+const SELFTEST_FAILED: u16 = 0x8020;     // Non-zero once any case fails
+
+/// Cases run by the self-test ROM: a <op> b == expected
+const SELFTEST_CASES: &[(&str, Op, &str, &str)] = &[
+    ("12", Op::Add, "30", "42"),
+    ("999", Op::Add, "1", "1000"),
+    ("9", Op::Sub, "2", "7"),
+    ("1000", Op::Sub, "1", "999"),
+    ("12", Op::Mul, "34", "408"),
+    ("84", Op::Div, "7", "12"),
+];
+
+/// Generate a ROM that checks the BCD routines against known answers
+/// and prints `PASS` or `FAIL n` (1-based case number) for hardware bring-up
+pub fn generate_selftest_rom() -> Vec<u8> {
+    emit_selftest(SELFTEST_CASES)
+}
 
-    let banner_patch = init_addr as usize + 14 + 1;  // +1 for LD HL opcode
-    code[banner_patch] = (banner_str & 0xFF) as u8;
-    code[banner_patch + 1] = (banner_str >> 8) as u8;
+fn emit_selftest(cases: &[(&str, Op, &str, &str)]) -> Vec<u8> {
+    use opcodes::*;
 
-    let print_str_patch = init_addr as usize + 14 + 3 + 1;  // +1 for CALL opcode
-    code[print_str_patch] = (print_str & 0xFF) as u8;
-    code[print_str_patch + 1] = (print_str >> 8) as u8;
+    let mut code = Vec::new();
+    code.push(DI);
+    code.push(LD_SP_NN);
+    emit_u16(&mut code, STACK_TOP);
+    let start = jp_placeholder(&mut code);
 
-    // Repl loop is at repl_loop
-    // LD HL, prompt (3 bytes)
-    let prompt_patch = repl_loop as usize + 1;
-    code[prompt_patch] = (prompt_str & 0xFF) as u8;
-    code[prompt_patch + 1] = (prompt_str >> 8) as u8;
+    // === Subroutines ===
+    let acia_out = code.len() as u16;
+    emit_acia_out(&mut code);
+    let print_str = code.len() as u16;
+    emit_repl_print_str(&mut code, acia_out);
+    let bcd_add = code.len() as u16;
+    emit_bcd_add_routine(&mut code);
+    let tens_complement = code.len() as u16;
+    emit_tens_complement_routine(&mut code);
+    let bcd_sub = code.len() as u16;
+    emit_bcd_sub_routine(&mut code, tens_complement);
+    let bcd_mul10 = code.len() as u16;
+    emit_bcd_mul10_routine(&mut code);
+    let bcd_mul = code.len() as u16;
+    emit_bcd_mul_routine(&mut code, &MemoryLayout::default(), bcd_add, bcd_mul10);
+    let bcd_cmp = code.len() as u16;
+    emit_bcd_cmp_routine(&mut code);
+    let bcd_div = code.len() as u16;
+    emit_bcd_div_routine(&mut code, &MemoryLayout::default(), bcd_sub, bcd_cmp, bcd_mul10);
+
+    // Numbers and messages go after the code. Each LD operand that refers
+    // to them is recorded as (operand position, offset into data).
+    let mut data: Vec<u8> = Vec::new();
+    let mut data_patches: Vec<(usize, usize)> = Vec::new();
+    let mut ld_data = |code: &mut Vec<u8>, opcode: u8, bytes: &[u8]| {
+        code.push(opcode);
+        data_patches.push((code.len(), data.len()));
+        emit_u16(code, 0);
+        data.extend_from_slice(bytes);
+    };
+
+    // === Test battery ===
+    patch_jp(&mut code, start);
+    code.push(XOR_A);
+    code.push(LD_NN_A);
+    emit_u16(&mut code, SELFTEST_FAILED);
+
+    for (n, &(a, op, b, expected)) in cases.iter().enumerate() {
+        // work = a
+        ld_data(&mut code, LD_HL_NN, &BcNum::parse(a).to_packed());
+        code.push(LD_DE_NN);
+        emit_u16(&mut code, SELFTEST_WORK);
+        code.push(LD_BC_NN);
+        emit_u16(&mut code, 28);
+        emit_ldir(&mut code);
+
+        // work = work <op> b
+        code.push(LD_HL_NN);
+        emit_u16(&mut code, SELFTEST_WORK);
+        ld_data(&mut code, LD_DE_NN, &BcNum::parse(b).to_packed());
+        let routine = match op {
+            Op::Add => bcd_add,
+            Op::Sub => bcd_sub,
+            Op::Mul => bcd_mul,
+            Op::Div => bcd_div,
+            _ => panic!("self-test op {:?} not supported", op),
+        };
+        code.push(CALL_NN);
+        emit_u16(&mut code, routine);
+
+        // Compare against the expected value
+        code.push(LD_DE_NN);
+        emit_u16(&mut code, SELFTEST_WORK);
+        ld_data(&mut code, LD_HL_NN, &BcNum::parse(expected).to_packed());
+        code.push(CALL_NN);
+        emit_u16(&mut code, bcd_cmp);
+        code.push(OR_A);
+        let ok = jr_placeholder(&mut code, JR_Z_N);
+
+        // Report this case and remember the failure
+        ld_data(&mut code, LD_HL_NN, format!("FAIL {}\r\n\0", n + 1).as_bytes());
+        code.push(CALL_NN);
+        emit_u16(&mut code, print_str);
+        code.push(LD_A_N);
+        code.push(1);
+        code.push(LD_NN_A);
+        emit_u16(&mut code, SELFTEST_FAILED);
+        patch_jr(&mut code, ok);
+    }
+
+    // PASS only if every case matched
+    code.push(LD_A_NN_IND);
+    emit_u16(&mut code, SELFTEST_FAILED);
+    code.push(OR_A);
+    let failed = jr_placeholder(&mut code, JR_NZ_N);
+    ld_data(&mut code, LD_HL_NN, b"PASS\r\n\0");
+    code.push(CALL_NN);
+    emit_u16(&mut code, print_str);
+    patch_jr(&mut code, failed);
+    code.push(HALT);
+
+    let data_org = code.len();
+    for (pos, offset) in data_patches {
+        let addr = (data_org + offset) as u16;
+        code[pos..pos + 2].copy_from_slice(&addr.to_le_bytes());
+    }
+    code.extend(data);
+    code
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::compiler::{CompileOptions, Compiler};
+    use crate::emulator::Machine;
+
+    /// Compile a program, run its ROM to HALT and return the serial output
+    fn run_source(source: &str) -> String {
+        let module = Compiler::compile(source).unwrap();
+        let rom = generate_rom(&module);
+        let mut machine = Machine::new(&rom);
+        assert!(machine.run(50_000_000), "ROM did not halt: {:?}", source);
+        machine.output()
+    }
+
+    #[test]
+    fn test_split_memory_layout() {
+        // 16K ROM at 0x0000, 48K RAM from 0x4000
+        let layout = MemoryLayout { rom_size: 0x4000, ram_start: 0x4000, ..Default::default() };
+        let module = Compiler::compile("x = 6 * 7\ny = x / 2\nx\ny - 1\n(x > y)\n").unwrap();
+        let (rom, _) = generate_rom_with_layout(&module, &layout, &RuntimeOptions::default()).unwrap();
+        assert!(rom.len() <= 0x4000);
+
+        let mut machine = Machine::new(&rom);
+        assert!(machine.run(50_000_000));
+        assert_eq!(machine.output(), "42\r\n20\r\n1\r\n");
+
+        // VM state and heap moved into the new RAM; the old map is untouched
+        let heap_ptr = layout.ram(VM_HEAP);
+        let heap = machine.peek(heap_ptr) as u16 | (machine.peek(heap_ptr + 1) as u16) << 8;
+        assert!(heap > layout.ram(HEAP_START) && heap < 0x8000);
+        assert!((0x8000..0x8800).all(|addr| machine.peek(addr) == 0));
+    }
+
+    #[test]
+    fn test_vstack_depth() {
+        // Push 100 ones, then add them up
+        let mut module = CompiledModule::new();
+        for _ in 0..100 {
+            module.emit(Op::LoadOne);
+        }
+        for _ in 0..99 {
+            module.emit(Op::Add);
+        }
+        module.emit(Op::Print);
+        module.emit(Op::PrintNewline);
+        module.emit(Op::Halt);
+        let mut machine = Machine::new(&generate_rom(&module));
+        assert!(machine.run(50_000_000));
+        assert_eq!(machine.output(), "Runtime error: value stack full\r\n");
+
+        let layout = MemoryLayout { vstack_depth: 200, ..Default::default() };
+        let (rom, _) = generate_rom_with_layout(&module, &layout, &RuntimeOptions::default()).unwrap();
+        let mut machine = Machine::new(&rom);
+        assert!(machine.run(50_000_000));
+        assert_eq!(machine.output(), "100\r\n");
+
+        // The stack took the start of the heap area and the heap moved past it
+        assert_eq!(layout.vstack_base(), HEAP_START);
+        assert_eq!(layout.heap_start(), HEAP_START + 400);
+        let heap = machine.peek(VM_HEAP) as u16 | (machine.peek(VM_HEAP + 1) as u16) << 8;
+        assert!(heap > HEAP_START + 400);
+
+        let too_deep = MemoryLayout { vstack_depth: 0x4000, ..Default::default() };
+        assert!(generate_rom_with_layout(&module, &too_deep, &RuntimeOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_out_of_memory() {
+        // Every result takes a fresh heap number; once the heap would reach
+        // the buffers at the top of memory the program stops instead of
+        // running on into them
+        assert_eq!(
+            run_source("s = 0\nfor (i = 0; i < 300; i++) s = s + i\ns\n"),
+            "Runtime error: out of memory\r\n"
+        );
+        // 278 passes leave room for another number but not for an array
+        let fill = "for (i = 0; i < 278; i++) x = x + 1\n";
+        assert_eq!(run_source(&format!("{}y = 1\n7\n", fill)), "7\r\n");
+        assert_eq!(run_source(&format!("{}a[0] = 1\n7\n", fill)), "Runtime error: out of memory\r\n");
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut module = CompiledModule::new();
+        module.emit(Op::LoadZero);
+        module.emit(Op::Pop);
+        module.emit(Op::Halt);
+        let options = RuntimeOptions { trace: true, ..Default::default() };
+        let (rom, symbols) =
+            generate_rom_with_layout(&module, &MemoryLayout::default(), &options).unwrap();
+        assert!(symbols.contains_key("trace_op"));
+
+        let mut machine = Machine::new(&rom);
+        assert!(machine.run(1_000_000));
+        assert_eq!(machine.output(), "2000 10\r\n2001 02\r\n2002 00\r\n");
+
+        // Untraced ROMs carry no trace code
+        assert!(!generate_rom_with_symbols(&module).1.contains_key("trace_op"));
+    }
+
+    #[test]
+    fn test_profile_counts() {
+        let module = Compiler::compile("for (i = 0; i < 100; i++) x = x + 2\nx\n").unwrap();
+        let options = RuntimeOptions { profile: true, ..Default::default() };
+        let (rom, symbols) =
+            generate_rom_with_layout(&module, &MemoryLayout::default(), &options).unwrap();
+        assert!(symbols.contains_key("profile_dump"));
+        let mut machine = Machine::new(&rom);
+        assert!(machine.run(50_000_000));
+        let output = machine.output();
+        let (printed, dump) = output.split_once("profile\r\n").unwrap();
+        assert_eq!(printed, "200\r\n");
+
+        let counts: BTreeMap<u8, u32> = dump
+            .lines()
+            .map(|line| {
+                let (op, count) = line.split_once(' ').unwrap();
+                (u8::from_str_radix(op, 16).unwrap(), count.parse().unwrap())
+            })
+            .collect();
+        // The loop body's Add runs once per pass, the final print and Quit once
+        assert_eq!(counts[&(Op::Add as u8)], 100);
+        assert_eq!(counts[&(Op::Print as u8)], 1);
+        assert_eq!(counts[&(Op::Quit as u8)], 1);
+        assert!(counts.values().all(|&count| count >= 1));
+        assert!(!counts.contains_key(&(Op::Mul as u8)));
+    }
+
+    #[test]
+    fn test_exit_addr() {
+        let module = Compiler::compile("quit").unwrap();
+        let options = RuntimeOptions { exit_addr: Some(0x1234), ..Default::default() };
+        let (rom, symbols) =
+            generate_rom_with_layout(&module, &MemoryLayout::default(), &options).unwrap();
+        // The Quit dispatch right after the fetch jumps to the monitor,
+        // while Halt still stops the CPU
+        let vm_loop = symbols["vm_loop"] as usize;
+        let dispatch = &rom[vm_loop..vm_loop + 24];
+        assert!(dispatch.windows(3).any(|w| w == [JP_NN, 0x34, 0x12]));
+        assert_eq!(dispatch.iter().filter(|&&b| b == HALT).count(), 1);
+
+        let (rom, symbols) = generate_rom_with_symbols(&module);
+        let vm_loop = symbols["vm_loop"] as usize;
+        let dispatch = &rom[vm_loop..vm_loop + 24];
+        assert!(!dispatch.windows(3).any(|w| w == [JP_NN, 0x34, 0x12]));
+        assert_eq!(dispatch.iter().filter(|&&b| b == HALT).count(), 2);
+    }
+
+    #[test]
+    fn test_strip_runtime() {
+        let strip = RuntimeOptions { strip: true, ..Default::default() };
+        let build = |source: &str, options: &RuntimeOptions| {
+            let module = Compiler::compile(source).unwrap();
+            generate_rom_with_layout(&module, &MemoryLayout::default(), options).unwrap()
+        };
+        let run = |rom: &[u8]| {
+            let mut machine = Machine::new(rom);
+            assert!(machine.run(50_000_000));
+            machine.output()
+        };
+
+        let (add_rom, add_symbols) = build("x = 2\nx + 3\n", &strip);
+        assert_eq!(run(&add_rom), "5\r\n");
+        assert!(!add_symbols.contains_key("bcd_div"));
+        assert!(add_symbols.contains_key("bcd_add") && !add_symbols.contains_key("bcd_mul"));
+
+        let everything = "scale = 3\nobase = 16\n255\nobase = 10\ndefine f(n) { auto a[]; a[n] = n; return a[n] * 2 }\n\
+            x = f(4) / 3\nx\nx % 2\n-x\nx++\nx--\n(x < 2) + (x == 1) + (x != 0)\n\
+            length(x) + scale(x)\nif (x >= 1) print \"big\\n\"\nwhile (x > 1) x = x - 1\nsqrt(2)\n";
+        let (all_rom, all_symbols) = build(everything, &strip);
+        assert!(add_symbols["padding"] < all_symbols["padding"]);
+        let (full_rom, full_symbols) = build(everything, &RuntimeOptions::default());
+        assert!(all_symbols["padding"] <= full_symbols["padding"]);
+        assert_eq!(run(&all_rom), run(&full_rom));
+    }
+
+    #[test]
+    fn test_strip_dependency_closure() {
+        // Div calls bcd_div, which needs bcd_sub, which needs tens_complement
+        let routines = needed_routines(&[Op::Div], &RuntimeOptions::default());
+        for name in ["bcd_div", "bcd_sub", "tens_complement", "bcd_cmp", "shift_copy", "copy_num"] {
+            assert!(routines.contains(&name), "{} missing", name);
+        }
+        // Div leaves its result in its operand's slot, so it never pushes
+        // and cannot hit the stack-full error
+        assert!(!routines.contains(&"push_vstack") && !routines.contains(&"bcd_mul"));
+
+        let module = Compiler::compile("7 / 2\n").unwrap();
+        let options = RuntimeOptions { strip: true, ..Default::default() };
+        let (rom, symbols) = generate_rom_with_layout(&module, &MemoryLayout::default(), &options).unwrap();
+        for name in ["bcd_div", "bcd_sub", "tens_complement", "acia_out", "print_num", "print_newline"] {
+            assert!(symbols.contains_key(name), "{} missing", name);
+        }
+        assert!(!symbols.contains_key("bcd_mul") && !symbols.contains_key("getline"));
+        let mut machine = Machine::new(&rom);
+        assert!(machine.run(10_000_000));
+        assert_eq!(machine.output(), "3\r\n");
+    }
+
+    #[test]
+    fn test_fetch_uses_shadow_pc() {
+        // The VM PC stays in HL', so fetching an opcode touches no RAM
+        // variable: EXX; LD A,(HL); INC HL; EXX
+        let (rom, symbols) = generate_rom_with_symbols(&Compiler::compile("x = 1\nwhile (x < 4) x = x + 1\nx\n").unwrap());
+        let vm_loop = symbols["vm_loop"] as usize;
+        assert_eq!(&rom[vm_loop..vm_loop + 5], &[EXX, LD_A_HL, INC_HL, EXX, LD_B_A]);
+
+        let mut machine = Machine::new(&rom);
+        assert!(machine.run(10_000_000));
+        assert_eq!(machine.output(), "4\r\n");
+
+        // This loop took 1906833 T-states with the PC in RAM, and 1848414
+        // once it moved to HL'
+        let source = "s = 0\nfor (i = 0; i < 100; i++) s = s + i\ns\n";
+        let mut machine = Machine::new(&generate_rom(&Compiler::compile(source).unwrap()));
+        assert!(machine.run(10_000_000));
+        assert_eq!(machine.output(), "4950\r\n");
+        assert!(machine.cycles <= 1_848_414, "{} T-states", machine.cycles);
+    }
+
+    #[test]
+    fn test_ops_replace_stack_top() {
+        // Binary and unary ops overwrite their last operand's slot instead
+        // of popping it and pushing the result. This program wrote 15285
+        // bytes when they went through pop_vstack and push_vstack.
+        let source = "x = 3\ny = 7\nz = (x * y - x / y + -x) * (y - x)\nz\n(z > 50) + (z == 72)\n";
+        let mut machine = Machine::new(&generate_rom(&Compiler::compile(source).unwrap()));
+        assert!(machine.run(10_000_000));
+        assert_eq!(machine.output(), "72\r\n2\r\n");
+        assert!(machine.writes < 15_285, "{} bytes written", machine.writes);
+
+        // A 100-pass summing loop wrote 43111 bytes before, 41907 after
+        let source = "s = 0\nfor (i = 0; i < 100; i++) s = s + i\ns\n";
+        let mut machine = Machine::new(&generate_rom(&Compiler::compile(source).unwrap()));
+        assert!(machine.run(10_000_000));
+        assert_eq!(machine.output(), "4950\r\n");
+        assert!(machine.writes <= 41_907, "{} bytes written", machine.writes);
+    }
+
+    #[test]
+    fn test_newline_option() {
+        let module = Compiler::compile("print 1, \"\\n\"\n2\n").unwrap();
+        let options = RuntimeOptions { newline: Newline::Lf, ..Default::default() };
+        let (rom, symbols) =
+            generate_rom_with_layout(&module, &MemoryLayout::default(), &options).unwrap();
+        // print_newline writes just LF
+        let print_newline = symbols["print_newline"] as usize;
+        let [lo, hi] = symbols["acia_out"].to_le_bytes();
+        assert_eq!(&rom[print_newline..print_newline + 6], &[LD_A_N, 0x0A, CALL_NN, lo, hi, RET]);
+
+        let mut machine = Machine::new(&rom);
+        assert!(machine.run(1_000_000));
+        assert_eq!(machine.output(), "1\n2\n");
+
+        let config = ReplConfig { newline: Newline::Lf, ..ReplConfig::default() };
+        let mut machine = Machine::new(&generate_repl_rom_with(&config).unwrap());
+        machine.run(1_000_000);
+        assert!(machine.output().starts_with("bc80 REPL v1.0\n> "), "{:?}", machine.output());
+    }
+
+    #[test]
+    fn test_columns_option() {
+        // Each line of a number holds at most 9 characters and a `\`; the
+        // count starts over for every number
+        let module = Compiler::compile(
+            "x = 12345678901234567890123\nx\n-x\n123456789\n1234567890\n.5\n",
+        )
+        .unwrap();
+        let options = RuntimeOptions { columns: Some(10), ..Default::default() };
+        let (rom, symbols) =
+            generate_rom_with_layout(&module, &MemoryLayout::default(), &options).unwrap();
+        assert!(symbols.contains_key("print_wrap"));
+        let mut machine = Machine::new(&rom);
+        assert!(machine.run(5_000_000));
+        assert_eq!(
+            machine.output(),
+            "123456789\\\r\n012345678\\\r\n90123\r\n\
+             -12345678\\\r\n901234567\\\r\n890123\r\n\
+             123456789\r\n123456789\\\r\n0\r\n.5\r\n"
+        );
+
+        let (_, symbols) = generate_rom_with_symbols(&module);
+        assert!(!symbols.contains_key("print_wrap"));
+    }
+
+    #[test]
+    fn test_layout_errors() {
+        let module = Compiler::compile("print \"hello\"").unwrap();
+        let too_small = MemoryLayout { rom_size: 0x2004, ram_start: 0x4000, ..Default::default() };
+        assert!(matches!(generate_rom_with_layout(&module, &too_small, &RuntimeOptions::default()), Err(BuildError::Rom { .. })));
+        let overlapping = MemoryLayout { rom_size: 0x8000, ram_start: 0x4000, ..Default::default() };
+        assert!(generate_rom_with_layout(&module, &overlapping, &RuntimeOptions::default()).is_err());
+        let no_heap = MemoryLayout { rom_size: 0x8000, ram_start: 0xF800, ..Default::default() };
+        assert!(generate_rom_with_layout(&module, &no_heap, &RuntimeOptions::default()).is_err());
+    }
 
     #[test]
     fn test_generate_runtime() {
         let module = CompiledModule::new();
         let mut code = Vec::new();
-        generate_runtime(&mut code, &module);
-        assert!(code.len() > 0);
+        generate_runtime(&mut code, &MemoryLayout::default(), &RuntimeOptions::default(), &module, &mut BTreeMap::new());
+        assert!(!code.is_empty());
         assert!(code.len() < RUNTIME_SIZE as usize);
         println!("Runtime size: {} bytes", code.len());
     }
@@ -4723,10 +8190,726 @@ mod tests {
     fn test_bcnum_packed() {
         let num = BcNum::parse("12");
         let packed = num.to_packed();
-        // Header: sign(0) + len(2) + scale(0) + packed(0x12)
+        // Header: sign(0) + len(2) + scale(0), digits right-aligned
+        assert_eq!(packed.len(), 28);
         assert_eq!(packed[0], 0x00);  // positive
-        assert_eq!(packed[1], 2);     // 2 total digits
+        assert_eq!(packed[1], 2);     // significant digits only
         assert_eq!(packed[2], 0);     // scale = 0 (no decimal digits)
-        assert_eq!(packed[3], 0x12);  // packed digits
+        assert_eq!(packed[27], 0x12); // packed digits (right-aligned)
+    }
+
+    #[test]
+    fn test_print_uses_significant_digit_count() {
+        // Odd and even digit counts, fractions with leading zeros, zero itself,
+        // and a sum that grows past both operands' digit counts
+        assert_eq!(
+            run_source("123\n12\n.05\n0\n1.5\n99+1\n"),
+            "123\r\n12\r\n.05\r\n0\r\n1.5\r\n100\r\n"
+        );
+    }
+
+    #[test]
+    fn test_obase_prints_integer_part() {
+        assert_eq!(run_source("obase = 2\n5\n0\n"), "101\r\n0\r\n");
+        assert_eq!(run_source("obase = 16\n255\n26\n3.75\n"), "FF\r\n1A\r\n3\r\n");
+    }
+
+    #[test]
+    fn test_obase_negative() {
+        // The minus sign comes before the converted digits
+        assert_eq!(run_source("obase = 16\n-255\n-26\n"), "-FF\r\n-1A\r\n");
+        assert_eq!(run_source("obase = 2\n-5\nx = -1\nx\n"), "-101\r\n-1\r\n");
+        // Only the integer part is printed, and -0 is just 0
+        assert_eq!(run_source("obase = 16\n-.5\n-16.5\n"), "0\r\n-10\r\n");
+    }
+
+    #[test]
+    fn test_print_statement_uses_obase() {
+        // print shares Op::Print, and so the base-aware formatter, with
+        // auto-printed expressions
+        assert_eq!(
+            run_source("obase = 16\nprint 255, \" \", 26\nprint \"\\n\"\n255\n"),
+            "FF 1A\r\nFF\r\n"
+        );
+        assert_eq!(run_source("obase = 2\nprint 5; print 0\n"), "1010");
+    }
+
+    #[test]
+    fn test_base_registers() {
+        // obase itself prints in the new base, as in bc
+        assert_eq!(run_source("obase = 16\nobase\n"), "10\r\n");
+        assert_eq!(run_source("obase = 16\nx = obase\nobase = 10\nx\n"), "16\r\n");
+        assert_eq!(run_source("ibase = 16\nibase\nobase\n"), "16\r\n10\r\n");
+        // Out-of-range bases are clamped to 2-16
+        assert_eq!(run_source("obase = 1\nx = obase\nobase = 10\nx\n"), "2\r\n");
+        assert_eq!(run_source("ibase = 200\nibase\n"), "16\r\n");
+    }
+
+    #[test]
+    fn test_for_without_condition_breaks() {
+        assert_eq!(run_source("for(;;) break\n7"), "7\r\n");
+    }
+
+    #[test]
+    fn test_for_without_condition_counts() {
+        assert_eq!(run_source("for(i=0;;i++){if(i>2)break}\ni"), "3\r\n");
+    }
+
+    #[test]
+    fn test_for_continue_runs_update() {
+        assert_eq!(run_source("for(i=0;i<3;i++){if(i==1)continue; print i}"), "02");
+    }
+
+    #[test]
+    fn test_fused_compare_jumps() {
+        let source = "a = 5; b = 7
+            if (a == b) print 1
+            if (a < b) print 2
+            if (a > b) print 3
+            if (a != b) print 4
+            if (a <= b) print 5
+            if (a >= b) print 6
+            while (a < b) a = a + 1
+            a";
+        let module = Compiler::compile(source).unwrap();
+        let ops = module.used_ops();
+        assert!([Op::JumpIfNe, Op::JumpIfGe, Op::JumpIfLe, Op::JumpIfEq, Op::JumpIfGt, Op::JumpIfLt]
+            .iter()
+            .all(|op| ops.contains(op)));
+        let mut machine = Machine::new(&generate_rom(&module));
+        assert!(machine.run(50_000_000));
+        assert_eq!(machine.output(), "2457\r\n");
+    }
+
+    #[test]
+    fn test_parse_num_shared_by_repl_and_read() {
+        let mut routine = Vec::new();
+        emit_parse_num(&mut routine);
+        let contains = |rom: &[u8]| rom.windows(routine.len()).any(|w| w == routine.as_slice());
+        assert!(contains(&generate_repl_rom()));
+        let module = Compiler::compile("x = read()").unwrap();
+        assert!(contains(&generate_rom(&module)));
+    }
+
+    #[test]
+    fn test_read_number() {
+        let module = Compiler::compile("x = read()\nx * 2\nread() + read()").unwrap();
+        let mut machine = Machine::new(&generate_rom(&module));
+        machine.send("21\r-5\r12\r");
+        assert!(machine.run(50_000_000));
+        assert_eq!(machine.output(), "21\r\n42\r\n-5\r\n12\r\n7\r\n");
+    }
+
+    #[test]
+    fn test_read_uses_ibase() {
+        let module = Compiler::compile("ibase = 16\nx = read()\nx\nread()\nibase = 2\nread()\nread()").unwrap();
+        let mut machine = Machine::new(&generate_rom(&module));
+        machine.send("FF\r-1A\r1101\r0\r");
+        assert!(machine.run(50_000_000));
+        assert_eq!(machine.output(), "FF\r\n255\r\n-1A\r\n-26\r\n1101\r\n13\r\n0\r\n0\r\n");
+    }
+
+    #[test]
+    fn test_repl_parses_numbers() {
+        let mut machine = Machine::new(&generate_repl_rom());
+        machine.send("12*34\r12*3\r");
+        machine.run(40_000_000);
+        assert!(machine.output().contains("408\r\n"));
+        assert!(machine.output().contains("36\r\n"));
+    }
+
+    #[test]
+    fn test_repl_unsupported_operator() {
+        // `%` has no REPL token: the line is an error, not `5 3` run
+        // together. As with results, the message follows the unechoed Enter.
+        let mut machine = Machine::new(&generate_repl_rom());
+        machine.send("5 % 3\r1 +\t1\r");
+        machine.run(20_000_000);
+        let output = machine.output();
+        let lines: Vec<&str> = output.split("> ").skip(1).collect();
+        assert_eq!(lines[0], "5 % 3Error\r\n", "{:?}", output);
+        assert_eq!(lines[1], "1 +\t12\r\n", "{:?}", output);
+    }
+
+    #[test]
+    fn test_repl_ibase() {
+        // Numbers are read in ibase; `ibase=A` is ten whatever the base, a
+        // digit not below ibase is an error, and ibase is clamped to 2-16
+        let mut machine = Machine::new(&generate_repl_rom());
+        machine.send("ibase\rF\ribase=16\r1F\rFF+1\r1G\r19\ribase\ribase=A\r10\ribase=2\r101\r2\ribase=1\r.\r");
+        machine.run(100_000_000);
+        let output = machine.output();
+        let lines: Vec<&str> = output.split("> ").skip(1).collect();
+        let expected = [
+            "ibase10\r\n", "FError\r\n", "ibase=1616\r\n", "1F31\r\n", "FF+1256\r\n",
+            "1GError\r\n", "1925\r\n", "ibase16\r\n", "ibase=A10\r\n", "1010\r\n",
+            "ibase=22\r\n", "1015\r\n", "2Error\r\n", "ibase=12\r\n", ".Error\r\n",
+        ];
+        assert_eq!(lines[..expected.len()], expected, "{:?}", output);
+    }
+
+    #[test]
+    fn test_getline_full_buffer_beeps() {
+        let mut machine = Machine::new(&generate_repl_rom());
+        machine.send(&"1".repeat(253));
+        machine.run(10_000_000);
+        // 250 characters are echoed after the prompt, the other three ring the bell
+        let output = machine.output();
+        let echoed = output.split_once("> ").unwrap().1;
+        assert_eq!(echoed, format!("{}\x07\x07\x07", "1".repeat(250)));
+    }
+
+    #[test]
+    fn test_getline_backspace_at_start() {
+        // Backspaces on an empty line are ignored and echo nothing; the line
+        // reads "43", whose result follows the unechoed Enter
+        let mut machine = Machine::new(&generate_repl_rom());
+        machine.send("\x08\x08\x0812\x08\x08\x08\x0845\x083\r");
+        machine.run(10_000_000);
+        let output = machine.output();
+        let echoed = output.split_once("> ").unwrap().1;
+        assert!(
+            echoed.starts_with("12\x08 \x08\x08 \x0845\x08 \x08343\r\n"),
+            "{:?}",
+            echoed
+        );
+    }
+
+    #[test]
+    fn test_getline_arrow_keys() {
+        // Up-arrow recalls "12+3" (and an empty line keeps it remembered);
+        // down-arrow erases "9" and the stray "ESC [ C" is dropped
+        let mut machine = Machine::new(&generate_repl_rom());
+        machine.send("12+3\r\r\x1b[A\r9\x1b[B\x1b[C7\r");
+        machine.run(20_000_000);
+        let output = machine.output();
+        let lines: Vec<&str> = output.split("> ").skip(1).collect();
+        assert!(lines[0].starts_with("12+315\r\n"), "{:?}", output);
+        assert!(lines[2].starts_with("12+315\r\n"), "{:?}", output);
+        assert!(lines[3].starts_with("9\x08 \x0877\r\n"), "{:?}", output);
+    }
+
+    #[test]
+    fn test_repl_custom_strings() {
+        let config = ReplConfig {
+            banner: "Acme Calc".to_string(),
+            prompt: "calc? ".to_string(),
+            ..ReplConfig::default()
+        };
+        let rom = generate_repl_rom_with(&config).unwrap();
+        assert!(rom.windows(11).any(|w| w == b"Acme Calc\r\n"));
+
+        let mut machine = Machine::new(&rom);
+        machine.send("1+1\r");
+        machine.run(10_000_000);
+        assert!(machine.output().starts_with("Acme Calc\r\ncalc? 1+1"), "{:?}", machine.output());
+
+        let too_long = ReplConfig { banner: "x".repeat(8192), ..ReplConfig::default() };
+        assert!(matches!(generate_repl_rom_with(&too_long), Err(BuildError::Rom { .. })));
+        let nul = ReplConfig { prompt: "a\0b".to_string(), ..ReplConfig::default() };
+        assert!(matches!(generate_repl_rom_with(&nul), Err(BuildError::Rom { .. })));
+    }
+
+    #[test]
+    fn test_repl_init_lines() {
+        let config = ReplConfig { init: vec!["x=10".to_string(), "y=x*2".to_string()], ..ReplConfig::default() };
+        let mut machine = Machine::new(&generate_repl_rom_with(&config).unwrap());
+        machine.send("x+y\r");
+        machine.run(40_000_000);
+        // The init lines print nothing; x and y are set at the first prompt
+        assert_eq!(machine.output(), "bc80 REPL v1.0\r\n> x+y30\r\n> ");
+
+        let two_lines = ReplConfig { init: vec!["x=1\nx=2".to_string()], ..ReplConfig::default() };
+        assert!(matches!(generate_repl_rom_with(&two_lines), Err(BuildError::Rom { .. })));
+    }
+
+    #[test]
+    fn test_repl_string_operands() {
+        // The banner and prompt addresses must be the operands of LD HL,nn
+        let rom = generate_repl_rom();
+        for text in [&b"bc80 REPL"[..], &b"> \0"[..]] {
+            let addr = rom.windows(text.len()).rposition(|w| w == text).unwrap() as u16;
+            let [lo, hi] = addr.to_le_bytes();
+            assert!(
+                rom.windows(3).any(|w| w == [opcodes::LD_HL_NN, lo, hi]),
+                "no LD HL,{:04X}",
+                addr
+            );
+        }
+    }
+
+    #[test]
+    fn test_selftest_rom() {
+        let mut machine = Machine::new(&generate_selftest_rom());
+        assert!(machine.run(20_000_000));
+        assert_eq!(machine.output(), "PASS\r\n");
+
+        // A wrong expectation is reported by its case number
+        let cases = [("1", Op::Add, "1", "2"), ("2", Op::Mul, "3", "7")];
+        let mut machine = Machine::new(&emit_selftest(&cases));
+        assert!(machine.run(20_000_000));
+        assert_eq!(machine.output(), "FAIL 2\r\n");
+    }
+
+    #[test]
+    fn test_comparison_result_is_fresh() {
+        // Reusing a comparison result must not disturb the constants behind 0 and 1
+        assert_eq!(
+            run_source("x = (1<2)\nx = x + 5\nx\n(1<2)\n(2<1)\ny = (2<1)\ny++\ny\n(2<1)\n"),
+            "6\r\n1\r\n0\r\n1\r\n0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_mutating_ops_leave_constants_alone() {
+        // ++ on an unset variable starts from the shared zero constant,
+        // and negation must work on a copy of its operand
+        assert_eq!(
+            run_source("++a\n++b\na\nb\nc\nx = 26\n-x\nx\n"),
+            "1\r\n1\r\n0\r\n-26\r\n26\r\n"
+        );
+    }
+
+    #[test]
+    fn test_sqrt() {
+        assert_eq!(
+            run_source("scale=6\nsqrt(2)\nsqrt(0)\nsqrt(1)\nsqrt(0.25)\nscale=0\nsqrt(99)\n"),
+            "1.414213\r\n0\r\n1.000000\r\n.500000\r\n9\r\n"
+        );
+        assert_eq!(
+            run_source("sqrt(4)\nsqrt(0-1)\n5\n"),
+            "2\r\nRuntime error: square root of a negative number\r\n"
+        );
+        // The root keeps max(scale, scale(x)) digits; scale itself is unchanged
+        assert_eq!(
+            run_source("sqrt(2.00)\nscale=4\nsqrt(2.000000)\nx = sqrt(.0625)\nx\nscale\n"),
+            "1.41\r\n1.414213\r\n.2500\r\n4\r\n"
+        );
+    }
+
+    #[test]
+    fn test_signed_comparisons() {
+        assert_eq!(
+            run_source("x = 0-1\n(x < 0)\n(0 < x)\n(x == 1)\n(0-5 > 0-6)\nif (x < 0) 7\n"),
+            "1\r\n0\r\n0\r\n1\r\n7\r\n"
+        );
+    }
+
+    #[test]
+    fn test_negated_comparisons() {
+        assert_eq!(
+            run_source("(3 <= 3)\n(4 <= 3)\n(3 >= 4)\n(0-1 >= 0-1)\n(2 != 2)\n(2 != 3)\n!0\n!5\n"),
+            "1\r\n0\r\n0\r\n1\r\n0\r\n1\r\n1\r\n0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_chained_comparisons() {
+        // (1 == 1) == 1, (3 > 2) > 1 and (2 == 3) < 1
+        assert_eq!(run_source("1 == 1 == 1\n3 > 2 > 1\n2 == 3 < 1\n"), "1\r\n0\r\n1\r\n");
+    }
+
+    #[test]
+    fn test_add_overflow() {
+        let max = "9".repeat(50);
+        // The largest value still fits; one more carries out of the field
+        assert_eq!(
+            run_source(&format!("x = {}\nx - 1 + 1\nx + 1\n2\n", max)),
+            format!("{}\r\nRuntime error: overflow\r\n", max)
+        );
+        // Subtracting a negative adds magnitudes too
+        assert_eq!(
+            run_source(&format!("x = {}\nx - -x\n", max)),
+            "Runtime error: overflow\r\n"
+        );
+    }
+
+    #[test]
+    fn test_read_variable_from_ram() {
+        let module = Compiler::compile("x = -12.5\ny = x * 4\n").unwrap();
+        let mut machine = Machine::new(&generate_rom(&module));
+        assert!(machine.run(50_000_000));
+
+        let value = |slot: u16| {
+            let ptr = machine.peek(VARS_BASE + slot * 2) as u16 | (machine.peek(VARS_BASE + slot * 2 + 1) as u16) << 8;
+            let bytes: Vec<u8> = (0..MAX_NUM_SIZE as u16).map(|i| machine.peek(ptr + i)).collect();
+            BcNum::from_packed(&bytes).to_string()
+        };
+        assert_eq!(value(0), "-12.5");
+        assert_eq!(value(1), "-50.0");
+    }
+
+    #[test]
+    fn test_arrays() {
+        // a and a[] are separate; unset elements read as zero
+        assert_eq!(
+            run_source("a = 1\na[2] = 5\na[0] = a[2] * 3\na\na[0]\na[2]\na[3]\n"),
+            "1\r\n15\r\n5\r\n0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_array_increment() {
+        // The index of a[i++]++ is evaluated once
+        assert_eq!(
+            run_source("a[i++]++\ni\na[0]\nx = a[0]++\nx\na[0]\nx = --a[i]\nx\n"),
+            "1\r\n1\r\n1\r\n2\r\n-1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_compound_assign_index_once() {
+        // f() runs once per assignment, and the a[0]++ inside the value
+        // does not disturb the outer store
+        let source = "define f() { c = c + 1; return 0 }\na[f()] += 5\na[f()] *= 2\nc\na[0]\na[1] += a[0]++\na[1]\na[0]\n";
+        assert_eq!(run_source(source), "2\r\n10\r\n10\r\n11\r\n");
+    }
+
+    #[test]
+    fn test_array_index_checked() {
+        // Fractions are dropped, as in bc
+        assert_eq!(run_source("a[2] = 5\na[2]\na[2.9]\na[255] = 1\na[255]\n"), "5\r\n5\r\n1\r\n");
+        for index in ["256", "-1", "1000"] {
+            assert_eq!(
+                run_source(&format!("a[1] = 1\na[{}]\n2\n", index)),
+                "Runtime error: array index out of range\r\n"
+            );
+        }
+        assert_eq!(run_source("a[-1] = 3\n"), "Runtime error: array index out of range\r\n");
+    }
+
+    #[test]
+    fn test_auto_array_zeroed_per_call() {
+        // The local array starts over each call; the caller's is restored
+        let source = "define f(x) {\n auto a[]\n a[1] = a[1] + x\n return a[1]\n}\n\
+                      a[1] = 9\nf(5)\nf(7)\na[1]\n";
+        assert_eq!(run_source(source), "5\r\n7\r\n9\r\n");
+    }
+
+    #[test]
+    fn test_auto_array_per_frame() {
+        // Each recursive call gets its own array: the inner calls' writes
+        // must not show through in the outer frames
+        let source = "define f(n) {\n auto a[], t\n a[0] = n; a[1] = a[1] + 1\n\
+                      if (n > 0) t = f(n - 1)\n print a[0], \" \", a[1], \" \", t, \"\\n\"\n return t + a[0]\n}\n\
+                      f(3)\n";
+        assert_eq!(run_source(source), "0 1 0\r\n1 1 0\r\n2 1 1\r\n3 1 3\r\n6\r\n");
+    }
+
+    #[test]
+    fn test_return_frees_call_heap() {
+        // Each call's 512-byte auto array used to stay allocated, so about
+        // 50 calls filled the heap
+        let source = "define h(n) { auto a[]; a[0] = n; return a[0] }\n\
+                      s = 0\nfor (i = 0; i < 100; i++) s = s + h(i)\ns\n";
+        assert_eq!(run_source(source), "4950\r\n");
+
+        // Numbers and arrays a function hands to globals are kept
+        let source = "define g(n) { q = n * 2; w[n] = n + 1; return n * 3 }\n\
+                      for (i = 0; i < 5; i++) x = g(i)\nx\nq\nw[3]\nw[4]\n";
+        assert_eq!(run_source(source), "12\r\n8\r\n4\r\n5\r\n");
+
+        // Calls made inside a function give back everything but the result,
+        // even when main uses the same hidden temporaries for %
+        let source = "define r(n) { return n % 7 + sqrt(n) }\nx = 9 % 4\n\
+                      define t() { auto i, s; for (i = 0; i < 40; i++) s = s + r(i); return s }\nt()\n";
+        let mut machine = Machine::new(&generate_rom(&Compiler::compile(source).unwrap()));
+        assert!(machine.run(500_000_000));
+        assert_eq!(machine.output(), "264\r\n");
+        let heap = machine.peek(VM_HEAP) as u16 | (machine.peek(VM_HEAP + 1) as u16) << 8;
+        assert!(heap < HEAP_START + 0x200, "heap at 0x{:04X}", heap);
+    }
+
+    #[test]
+    fn test_load_scale() {
+        assert_eq!(run_source("scale\nscale = 3\nscale + 1\nx = scale * 2\nx\n1/scale\n"), "0\r\n4\r\n6\r\n.333\r\n");
+    }
+
+    #[test]
+    fn test_mul_scale() {
+        // The product keeps every digit: its scale is the operands' sum,
+        // whatever `scale` is set to
+        assert_eq!(
+            run_source(".5 * .5\n3 * .25\n-1.5 * 2.25\n2.50 * 2\nscale=10\n1.25 * 1.25\nx = 0.1 * 0.1\nscale(x)\n"),
+            ".25\r\n.75\r\n-3.375\r\n5.00\r\n1.5625\r\n2\r\n"
+        );
+    }
+
+    #[test]
+    fn test_mul_fraction_digits() {
+        // Every digit of both operands takes part, fractional ones included
+        assert_eq!(
+            run_source("1.25 * 4\n0.1 * 0.1\n12345 * 1.2345\n123456789 * 987654321\n.000123 * -45.6\n"),
+            "5.00\r\n.01\r\n15239.9025\r\n121932631112635269\r\n-.0056088\r\n"
+        );
+    }
+
+    #[test]
+    fn test_mul_multiplier_boundaries() {
+        // Around the old 0-9999 repeated-addition limit and its x100 step
+        assert_eq!(
+            run_source("1234 * 100\n1234 * 999\n1234 * 1000\n1234 * 9999\n1234 * 10000\n99 * 101\n"),
+            "123400\r\n1232766\r\n1234000\r\n12338766\r\n12340000\r\n9999\r\n"
+        );
+    }
+
+    #[test]
+    fn test_round_division() {
+        let run = |source: &str, round_division: bool| {
+            let config = crate::Z80Config { round_division, ..Default::default() };
+            let artifacts = crate::build_rom(source, &config).unwrap();
+            let mut machine = Machine::new(&artifacts.rom);
+            assert!(machine.run(50_000_000), "ROM did not halt: {:?}", source);
+            machine.output()
+        };
+        let source = "scale=4\n2/3\n-2/3\n1/8\n1/4\n7%3\n";
+        // bc truncates; half up rounds away from zero, .12500 -> .1250
+        assert_eq!(run(source, false), ".6666\r\n-.6666\r\n.1250\r\n.2500\r\n.0001\r\n");
+        assert_eq!(run(source, true), ".6667\r\n-.6667\r\n.1250\r\n.2500\r\n.0001\r\n");
+        assert_eq!(run("scale=0\n5/2\n4/3\nx=7\nx/=2\nx\n", true), "3\r\n1\r\n4\r\n");
+    }
+
+    #[test]
+    fn test_pow() {
+        assert_eq!(
+            run_source("2^10\n(0-3)^3\n5^0\nscale=4\n2^-3\nx=2\nx^=-1\nx\nscale(1.25)\n"),
+            "1024\r\n-27\r\n1\r\n.1250\r\n.5000\r\n2\r\n"
+        );
+        assert_eq!(
+            run_source("2^1.5\n1\n"),
+            "Runtime error: non-zero scale in exponent\r\n"
+        );
+    }
+
+    #[test]
+    fn test_newline_placement() {
+        // Expression statements end with CR LF; print and assignments add none
+        assert_eq!(run_source("1 + 2\nx = 5\nx\n"), "3\r\n5\r\n");
+        assert_eq!(run_source("print 3\nprint 4, \"\\n\"\nprint 5, 6\n"), "34\r\n56");
+        assert_eq!(run_source("if (1) { 7; print 8 }\n9\n"), "7\r\n89\r\n");
+    }
+
+    #[test]
+    fn test_function_calls() {
+        // Recursion, and autos that leave the caller's variable alone
+        assert_eq!(
+            run_source("define f(n) { if (n < 2) return 1; return n * f(n - 1) }\nf(5)\n"),
+            "120\r\n"
+        );
+        assert_eq!(
+            run_source("define g(a) { auto t; t = a + 1; return t }\nt = 9\ng(1)\nt\n"),
+            "2\r\n9\r\n"
+        );
+        assert_eq!(run_source("define p() { print 7 }\np()\n"), "70\r\n");
+    }
+
+    #[test]
+    fn test_hidden_temps_in_calls() {
+        // f's hidden % temporaries sit in the slots where g keeps z
+        let source = "define f(x) { return x % 3 }\n\
+            define g(y) { auto z; z = 5; t = f(y); return z }\ng(7)\n";
+        assert_eq!(run_source(source), "5\r\n");
+    }
+
+    #[test]
+    fn test_lowered_ops_called_from_functions() {
+        // Each callee's temporaries for %, sqrt, ^ and updates overlap the
+        // caller's autos; all of them must survive the call
+        let source = "define s(x) { auto b[]; b[0] += x; b[0] %= 7; return sqrt(x) + x^2 + b[0] }\n\
+            define h(y) { auto z, w, v; z = 5; w = 6; v = 7; t = s(y); return z * 100 + w * 10 + v + t }\n\
+            h(4)\nh(9)\n";
+        assert_eq!(run_source(source), "589\r\n653\r\n");
+    }
+
+    #[test]
+    fn test_functions_share_globals() {
+        // n and h[] appear only inside functions, after get's parameters
+        // would have been allocated; s's auto n shadows the global
+        let source = "define inc(d) { n = n + d; h[n] = d; return n }\n\
+            define get(a, b) { return n * 100 + h[5] }\n\
+            define s() { auto n; n = 9; return n }\n\
+            x = inc(2)\nx = inc(3)\nx = s()\nget(0, 0)\nn\n";
+        assert_eq!(run_source(source), "503\r\n5\r\n");
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let source = "define f(x) {\n  y = x * 2\n  return y + 1\n}\na = 5\n1 + f(a)\n";
+        let options = CompileOptions { breakpoints: vec![3], ..Default::default() };
+        let module = Compiler::compile_with(source, &options).unwrap();
+        let mut machine = Machine::new(&generate_rom(&module));
+
+        // Stopped inside f, with the 1 of `1 + f(a)` waiting on the stack
+        assert!(!machine.run(50_000_000));
+        assert_eq!(machine.take_trap(), Some(3));
+        assert_eq!(machine.output(), "break at line 3: 1\r\n");
+
+        assert!(machine.run(50_000_000));
+        assert_eq!(machine.take_trap(), None);
+        assert_eq!(machine.output(), "break at line 3: 1\r\n12\r\n");
+
+        let options = CompileOptions { breakpoints: vec![4], ..Default::default() };
+        let err = Compiler::compile_with(source, &options).unwrap_err();
+        assert_eq!(err, BuildError::compile("breakpoint at line 4: no statement starts there"));
+    }
+
+    #[test]
+    fn test_functions_calling_builtins() {
+        assert_eq!(
+            run_source("define h(a, b) { return sqrt(a*a + b*b) }\nh(3, 4)\nscale=3\nh(1, 1)\n"),
+            "5\r\n1.414\r\n"
+        );
+        assert_eq!(
+            run_source("define l(x) { return length(x) }\nl(12345)\nl(.05)\nl(0)\nl(99999 * 1000)\n"),
+            "5\r\n2\r\n1\r\n8\r\n"
+        );
+    }
+
+    #[test]
+    fn test_builtins_on_expressions() {
+        // The whole argument is evaluated first, and the result replaces it
+        // without disturbing values already on the stack
+        assert_eq!(
+            run_source("length(2*50)\nscale=4\nscale(1/3)\nlength(1/3)\n1 + length(10^3) * 10\nscale(2*50) + 1\nx = 7\nlength(x++ * 20)\nx\n"),
+            "3\r\n4\r\n4\r\n41\r\n1\r\n3\r\n8\r\n"
+        );
+    }
+
+    #[test]
+    fn test_print_str() {
+        assert_eq!(run_source("print \"a\", \"bc\\n\"\n"), "abc\r\n");
+        assert_eq!(run_source("\"x = \"\n5\n"), "x = 5\r\n");
+    }
+
+    #[test]
+    fn test_empty_program() {
+        // Nothing to run compiles to a lone Quit, whose ROM boots and halts
+        // without output
+        for source in ["", "\n  \t\n", "/* nothing */\n# here\n"] {
+            let module = Compiler::compile(source).unwrap();
+            assert_eq!(module.bytecode, [Op::Quit as u8], "{:?}", source);
+            assert_eq!(run_source(source), "");
+        }
+
+        // With no input at all the REPL still greets and prompts
+        let mut machine = Machine::new(&generate_repl_rom());
+        machine.run(5_000_000);
+        assert_eq!(machine.output(), "bc80 REPL v1.0\r\n> ");
+    }
+
+    #[test]
+    fn test_statement_newlines() {
+        // Each expression statement prints its value on its own line, `;`
+        // separated or not; print adds no newline between or after items
+        assert_eq!(run_source("1;2\n"), "1\r\n2\r\n");
+        assert_eq!(run_source("print 1,2\n"), "12");
+        assert_eq!(run_source("print 1,2; 3\n4; print 5\n"), "123\r\n4\r\n5");
+        // bc prints `x5` then a newline: the string's line runs on into
+        // the value's
+        assert_eq!(run_source("print \"x\"; 5\n"), "x5\r\n");
+        assert_eq!(run_source("x = 1; x; x = 2\nx\n"), "1\r\n2\r\n");
+        assert_eq!(run_source("if (1) { 7; 8 }\n"), "7\r\n8\r\n");
+    }
+
+    #[test]
+    fn test_mixed_scale_arithmetic() {
+        assert_eq!(
+            run_source("1.5 + 2\n0-3.5+1.25\n3 - 1.25\n(1.0 == 1)\nx = 2/3\n(x < 1)\n"),
+            "3.5\r\n-2.25\r\n1.75\r\n1\r\n1\r\n"
+        );
+        assert_eq!(
+            run_source("(0-2)*3\n(0-2)*(0-3)\n7/(0-2)\n(0-2)*0\n5.5/2\nscale=1\n5.57/2\n"),
+            "-6\r\n6\r\n-3\r\n0\r\n2\r\n2.7\r\n"
+        );
+    }
+
+    #[test]
+    fn test_mod() {
+        // a % b = a - (a / b) * b, dividing at the current scale
+        assert_eq!(
+            run_source("5.5 % 2\nscale=2\n5.5 % 2\n(5.5 % 2) + 1\nscale=1\n5.57 % 2\n7 % 3\n"),
+            "1.5\r\n0\r\n1.00\r\n.17\r\n.1\r\n"
+        );
+        assert_eq!(run_source("(0-7) % 3\nx = 17\nx %= 5\nx\n"), "-1\r\n2\r\n");
+    }
+
+    #[test]
+    fn test_mod_remainder_bounds() {
+        // At scale 0 the remainder takes the sign of a and 0 <= |a % b| < |b|
+        let pairs: Vec<(i32, i32)> = [-23, -10, -1, 0, 1, 4, 5, 10, 23, 99, 1000]
+            .iter()
+            .flat_map(|&a| [-7, -5, 1, 2, 3, 5, 10, 11].iter().map(move |&b| (a, b)))
+            .collect();
+        let source: String = pairs.iter().map(|(a, b)| format!("({}) % ({})\n", a, b)).collect();
+        let output = run_source(&source);
+        let results: Vec<i32> = output.lines().map(|line| line.parse().unwrap()).collect();
+        assert_eq!(results.len(), pairs.len());
+        for (&(a, b), &r) in pairs.iter().zip(&results) {
+            assert!(r.abs() < b.abs(), "{} % {} = {}", a, b, r);
+            assert_eq!(r, a % b, "{} % {}", a, b);
+        }
+
+        // An exact division leaves nothing over
+        assert_eq!(run_source("10 % 5\n(0-10) % 5\n100 % 10\n"), "0\r\n0\r\n0\r\n");
+    }
+
+    #[test]
+    fn test_div_mod_identity() {
+        // At scale 0, / truncates toward zero and (a/b)*b + a%b == a
+        assert_eq!(run_source("-7 / 2\n-7 % 2\n"), "-3\r\n-1\r\n");
+
+        let pairs: Vec<(i32, i32)> = [-100, -7, -6, -1, 0, 7, 13, 99]
+            .iter()
+            .flat_map(|&a| [-7, -2, 1, 2, 3].iter().map(move |&b| (a, b)))
+            .collect();
+        let source: String = pairs
+            .iter()
+            .map(|(a, b)| format!("({a}) / ({b})\n({a}) % ({b})\n(({a}) / ({b})) * ({b}) + ({a}) % ({b})\n"))
+            .collect();
+        // Eighty divisions and as many remainders take longer than run_source allows
+        let mut machine = Machine::new(&generate_rom(&Compiler::compile(&source).unwrap()));
+        assert!(machine.run(200_000_000));
+        let output = machine.output();
+        let results: Vec<i32> = output.lines().map(|line| line.parse().unwrap()).collect();
+        assert_eq!(results.len(), pairs.len() * 3);
+        for (&(a, b), r) in pairs.iter().zip(results.chunks(3)) {
+            assert_eq!((r[0], r[1]), (a / b, a % b), "{} / {} and {} % {}", a, b, a, b);
+            assert_eq!(r[2], a, "({} / {}) * {} + {} % {}", a, b, b, a, b);
+        }
+    }
+
+    #[test]
+    fn test_swap() {
+        let mut module = CompiledModule::new();
+        let five = module.add_number(BcNum::parse("5"));
+        let three = module.add_number(BcNum::parse("3"));
+        module.emit(Op::LoadNum);
+        module.emit_u16(five);
+        module.emit(Op::LoadNum);
+        module.emit_u16(three);
+        module.emit(Op::Swap);
+        module.emit(Op::Sub);
+        module.emit(Op::Print);
+        module.emit(Op::PrintNewline);
+        module.emit(Op::Halt);
+        assert_eq!(module.bytecode[6], 0x04);
+
+        let mut machine = Machine::new(&generate_rom(&module));
+        assert!(machine.run(1_000_000));
+        assert_eq!(machine.output(), "-2\r\n");
+    }
+
+    #[test]
+    fn test_subtraction_underflow() {
+        // The REPL subtracts without comparing first, so 2 - 9 borrows out
+        // of the top digit and must be turned back into -7, not 99...93
+        let mut machine = Machine::new(&generate_repl_rom());
+        machine.send("2-9\r100-250\r");
+        machine.run(40_000_000);
+        assert!(machine.output().contains("-7\r\n"), "{:?}", machine.output());
+        assert!(machine.output().contains("-150\r\n"));
+        assert!(!machine.output().contains("99"));
+
+        assert_eq!(run_source("2-9\n9-2\n"), "-7\r\n7\r\n");
     }
 }